@@ -0,0 +1,222 @@
+//! Benchmarks for the per-request hot path: upstream and key selection, upstream URI building,
+//! and usage parsing of JSON and SSE responses.
+//!
+//! Run with `cargo bench --bench hot_path`. Save a baseline before touching the ArcSwap/atomic
+//! code (`-- --save-baseline before`) and compare after (`-- --baseline before`).
+
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use ahash::AHashSet;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gptload_rs::config::Config;
+use gptload_rs::proxy::{parse_sse_usage, usage_from_json_bytes};
+use gptload_rs::state::{build_key_states, RouterState};
+use gptload_rs::util::now_ms;
+
+const MODEL: &str = "gpt-4o-mini";
+
+/// (upstreams, keys per upstream)
+const SCENARIOS: &[(usize, usize)] = &[(1, 1), (4, 16), (16, 128), (64, 512)];
+
+/// A `RouterState` with every upstream serving `MODEL`, backed by a scratch data dir.
+struct Fixture {
+    name: String,
+    state: Arc<RouterState>,
+    dir: PathBuf,
+    // RouterState::new spawns background tasks, so it needs a runtime to live in.
+    _rt: tokio::runtime::Runtime,
+}
+
+impl Fixture {
+    fn new(upstreams: usize, keys: usize) -> Self {
+        let name = format!("{upstreams}x{keys}");
+        let dir = std::env::temp_dir().join(format!("gptload-bench-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut toml = format!(
+            r#"
+listen_addr = "127.0.0.1:0"
+request_timeout_ms = 30000
+admin_tokens = ["bench"]
+data_dir = "{}"
+
+[ban]
+rate_limit_ms = 30000
+server_error_ms = 5000
+network_error_ms = 5000
+auth_error_ms = 300000
+max_backoff_pow = 7
+"#,
+            dir.display()
+        );
+        for i in 0..upstreams {
+            // Every other upstream has a base path, so build_uri sees both branches.
+            let path = if i % 2 == 1 { "/prefix" } else { "" };
+            let weight = 1 + i % 3;
+            toml.push_str(&format!(
+                "\n[[upstreams]]\nid = \"u{i}\"\nbase_url = \"http://127.0.0.1:9{path}\"\n"
+            ));
+            toml.push_str(&format!("weight = {weight}\n"));
+        }
+        let config_path = dir.join("config.toml");
+        std::fs::write(&config_path, toml).unwrap();
+        let cfg = Config::load(config_path.to_str().unwrap()).unwrap();
+
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let state = {
+            let _guard = rt.enter();
+            RouterState::new(cfg).unwrap()
+        };
+
+        let models: Arc<AHashSet<String>> = Arc::new([MODEL.to_string()].into_iter().collect());
+        for u in state.snapshot.load().upstreams.iter() {
+            let list: Vec<String> = (0..keys).map(|k| format!("sk-{}-{k}", u.id)).collect();
+            state.store.add_keys(&u.id, &list, None).unwrap();
+            let stored = state.store.load_all_keys(&u.id).unwrap();
+            u.keys.store(build_key_states(stored, &u.auth_scheme).unwrap());
+            u.models.store(models.clone());
+        }
+
+        Self { name, state, dir, _rt: rt }
+    }
+
+    /// Put every other key of every upstream into a long cooldown.
+    fn cool_half_the_keys(&self) {
+        for u in self.state.snapshot.load().upstreams.iter() {
+            for k in u.keys.load().iter().step_by(2) {
+                k.cooldown_until_ms.store(u64::MAX, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn selection(c: &mut Criterion) {
+    let fixtures: Vec<Fixture> = SCENARIOS.iter().map(|&(n, m)| Fixture::new(n, m)).collect();
+
+    let mut group = c.benchmark_group("select_for_model");
+    for f in &fixtures {
+        group.bench_with_input(BenchmarkId::from_parameter(&f.name), f, |b, f| {
+            b.iter(|| black_box(f.state.select_for_model(black_box(MODEL), None, now_ms())))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("select_key");
+    for f in &fixtures {
+        let u = f.state.snapshot.load().upstreams[0].clone();
+        group.bench_with_input(BenchmarkId::from_parameter(&f.name), &u, |b, u| {
+            b.iter(|| black_box(u.select_key(f.state.key_select, now_ms())))
+        });
+    }
+    group.finish();
+
+    // Cooling keys are skipped by probing, which is the slow path of select_key.
+    let mut group = c.benchmark_group("select_key_half_cooling");
+    for f in fixtures.iter().filter(|f| f.name != "1x1") {
+        f.cool_half_the_keys();
+        let u = f.state.snapshot.load().upstreams[0].clone();
+        group.bench_with_input(BenchmarkId::from_parameter(&f.name), &u, |b, u| {
+            b.iter(|| black_box(u.select_key(f.state.key_select, now_ms())))
+        });
+    }
+    group.finish();
+}
+
+fn uri(c: &mut Criterion) {
+    let f = Fixture::new(2, 1);
+    let snap = f.state.snapshot.load();
+    let pq: http::uri::PathAndQuery =
+        "/v1/chat/completions?api-version=2024-06-01".parse().unwrap();
+
+    let mut group = c.benchmark_group("build_uri");
+    for (name, u) in [("no_base_path", &snap.upstreams[0]), ("base_path", &snap.upstreams[1])] {
+        group.bench_function(name, |b| b.iter(|| black_box(u.build_uri(black_box(&pq)))));
+    }
+    group.finish();
+}
+
+fn json_response() -> Vec<u8> {
+    let content = "The quick brown fox jumps over the lazy dog. ".repeat(20);
+    serde_json::to_vec(&serde_json::json!({
+        "id": "chatcmpl-bench",
+        "object": "chat.completion",
+        "created": 1_700_000_000,
+        "model": MODEL,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": "stop"
+        }],
+        "usage": {"prompt_tokens": 42, "completion_tokens": 200, "total_tokens": 242}
+    }))
+    .unwrap()
+}
+
+/// A 200-chunk stream ending with an `include_usage` chunk, as OpenAI sends it.
+fn sse_response() -> Vec<u8> {
+    let mut out = String::new();
+    for i in 0..200 {
+        let chunk = serde_json::json!({
+            "id": "chatcmpl-bench",
+            "object": "chat.completion.chunk",
+            "model": MODEL,
+            "choices": [{"index": 0, "delta": {"content": format!("token{i} ")}}]
+        });
+        out.push_str(&format!("data: {chunk}\n\n"));
+    }
+    let last = serde_json::json!({
+        "id": "chatcmpl-bench",
+        "object": "chat.completion.chunk",
+        "model": MODEL,
+        "choices": [],
+        "usage": {"prompt_tokens": 42, "completion_tokens": 200, "total_tokens": 242}
+    });
+    out.push_str(&format!("data: {last}\n\ndata: [DONE]\n\n"));
+    out.into_bytes()
+}
+
+fn usage(c: &mut Criterion) {
+    let json = json_response();
+    let sse = sse_response();
+
+    let mut group = c.benchmark_group("usage");
+    group.throughput(Throughput::Bytes(json.len() as u64));
+    group.bench_function("json", |b| b.iter(|| black_box(usage_from_json_bytes(black_box(&json)))));
+
+    group.throughput(Throughput::Bytes(sse.len() as u64));
+    group.bench_function("sse_whole", |b| {
+        b.iter(|| {
+            let mut buf = String::new();
+            black_box(parse_sse_usage(&mut buf, black_box(&sse)))
+        })
+    });
+    // Network reads split lines across chunks; 1 KiB reads exercise the line buffer.
+    group.bench_function("sse_1k_chunks", |b| {
+        b.iter(|| {
+            let mut buf = String::new();
+            let mut found = None;
+            for chunk in sse.chunks(1024) {
+                found = parse_sse_usage(&mut buf, black_box(chunk)).or(found);
+            }
+            black_box(found)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, selection, uri, usage);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+"""
+Shared fixture for the end-to-end test scripts.
+
+`MockUpstream` is a base request handler: it answers `GET /v1/models` with `models()` and
+leaves `do_POST` to each script. `mock_upstreams()` serves it on a free port, registers it on
+the running proxy under one or more upstream ids (each with the single key `sk-<id>`),
+refreshes their models, creates the billing keys, and removes all of it again on exit.
+"""
+
+import json
+import threading
+from contextlib import contextmanager
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+
+# Configuration
+PROXY_URL = "http://localhost:8080"
+ADMIN_TOKEN = "admin-token-1"
+
+ADMIN_HEADERS = {"X-Admin-Token": ADMIN_TOKEN, "Content-Type": "application/json"}
+
+
+def admin(method: str, path: str, body=None) -> requests.Response:
+    return requests.request(
+        method, f"{PROXY_URL}/admin/api/v1{path}", headers=ADMIN_HEADERS, json=body, timeout=30
+    )
+
+
+class MockUpstream(BaseHTTPRequestHandler):
+    # Model ids listed by GET /v1/models; override `models()` to vary them per upstream.
+    MODELS = []
+
+    def models(self) -> list:
+        return self.MODELS
+
+    def send(self, status: int, data: bytes, content_type: str = "application/json", headers=None):
+        self.send_response(status)
+        self.send_header("Content-Type", content_type)
+        for name, value in (headers or {}).items():
+            self.send_header(name, value)
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def send_json(self, status: int, body):
+        self.send(status, json.dumps(body).encode())
+
+    def read_body(self) -> bytes:
+        return self.rfile.read(int(self.headers.get("Content-Length", 0)))
+
+    def do_GET(self):
+        if self.path.split("?")[0].endswith("/v1/models"):
+            self.send_json(200, {"object": "list", "data": [{"id": m, "object": "model"} for m in self.models()]})
+        else:
+            self.send_json(404, {"error": "not found"})
+
+    def log_message(self, *args):
+        pass
+
+
+@contextmanager
+def mock_upstreams(handler, upstreams: dict, billing_keys: dict):
+    """Serve `handler` and register it as each of `upstreams` (id -> extra upstream fields).
+
+    `billing_keys` maps client API keys to their starting balance. Yields the mock's base URL.
+    """
+    server = ThreadingHTTPServer(("127.0.0.1", 0), handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    base_url = f"http://127.0.0.1:{server.server_address[1]}"
+    try:
+        for upstream, fields in upstreams.items():
+            admin("POST", "/upstreams", {"id": upstream, "base_url": base_url, **fields})
+            admin("PUT", f"/upstreams/{upstream}/keys", {"keys": [f"sk-{upstream}"]})
+            admin("POST", f"/upstreams/{upstream}/models/refresh")
+        for key, balance in billing_keys.items():
+            admin("POST", "/billing/keys", {"key": key, "balance": balance})
+        yield base_url
+    finally:
+        for upstream in upstreams:
+            admin("DELETE", f"/upstreams/{upstream}?delete_keys=true")
+        for key in billing_keys:
+            admin("DELETE", f"/billing/keys/{key}")
+        server.shutdown()
@@ -1,10 +1,14 @@
-use crate::config::UpstreamConfig;
-use crate::state::{build_key_states, validate_keys, MetricsWindow, RouterState};
-use crate::util::{now_ms, query_get};
+use crate::config::{UpstreamConfig, UpstreamFormat};
+use crate::state::{
+    build_key_states, inherit_key_breakers, validate_keys, KeyState, MetricsWindow, RouterState,
+    Upstream, UpstreamUpdate, VersionConflict,
+};
+use crate::util::{mask_key, now_ms, percent_decode, query_get};
 use bytes::Bytes;
 use hyper::{Body, Method, Request, Response};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio_stream::wrappers::ReceiverStream;
@@ -12,7 +16,15 @@ use tokio_stream::wrappers::ReceiverStream;
 const INDEX_HTML: &str = include_str!("static/index.html");
 const APP_JS: &str = include_str!("static/app.js");
 
-pub async fn handle_admin(req: Request<Body>, state: Arc<RouterState>) -> Response<Body> {
+pub async fn handle_admin(
+    req: Request<Body>,
+    state: Arc<RouterState>,
+    client_addr: SocketAddr,
+) -> Response<Body> {
+    if !state.admin_ip_allowed(client_addr.ip()) {
+        return ip_forbidden();
+    }
+
     let path = req.uri().path();
 
     // Redirect /admin -> /admin/
@@ -54,6 +66,15 @@ pub async fn handle_admin(req: Request<Body>, state: Arc<RouterState>) -> Respon
         .unwrap()
 }
 
+/// 403 for clients outside `admin_ip_allowlist`.
+pub fn ip_forbidden() -> Response<Body> {
+    RouterState::json_error(
+        http::StatusCode::FORBIDDEN,
+        "client address not allowed",
+        "admin_ip_forbidden",
+    )
+}
+
 async fn handle_api(req: Request<Body>, state: Arc<RouterState>) -> Response<Body> {
     let path = req.uri().path().to_string();
     let method = req.method().clone();
@@ -67,6 +88,13 @@ async fn handle_api(req: Request<Body>, state: Arc<RouterState>) -> Response<Bod
             "admin_unauthorized",
         );
     }
+    if state.read_only && method != Method::GET && method != Method::HEAD {
+        return RouterState::json_error(
+            http::StatusCode::FORBIDDEN,
+            "instance is read-only",
+            "read_only",
+        );
+    }
 
     match (&method, path.as_str()) {
         (&Method::GET, "/admin/api/v1/stats/stream") => stats_stream(state).await,
@@ -74,10 +102,19 @@ async fn handle_api(req: Request<Body>, state: Arc<RouterState>) -> Response<Bod
         (&Method::POST, "/admin/api/v1/upstreams") => api_add_upstream(req, state).await,
         (&Method::GET, "/admin/api/v1/stats") => api_stats_snapshot(state).await,
         (&Method::POST, "/admin/api/v1/reload") => api_reload_all(state).await,
+        (&Method::POST, "/admin/api/v1/flush") => api_flush(state).await,
         (&Method::GET, "/admin/api/v1/models/routes") => api_get_model_routes(state).await,
         (&Method::PUT, "/admin/api/v1/models/routes") => api_put_model_routes(req, state).await,
+        (&Method::GET, "/admin/api/v1/models/aliases") => json_ok(&state.get_model_aliases()),
+        (&Method::PUT, "/admin/api/v1/models/aliases") => api_put_model_aliases(req, state).await,
+        (&Method::GET, "/admin/api/v1/models/stats") => api_model_stats(state).await,
+        (&Method::GET, "/admin/api/v1/schedule") => api_schedule(state),
+        (&Method::GET, "/admin/api/v1/route") => api_route(state, req.uri()),
+        (&Method::POST, "/admin/api/v1/cooldowns/clear") => api_clear_cooldowns(state, None, req.uri()),
         (&Method::GET, "/admin/api/v1/requests") => api_requests(state, req.uri()).await,
         (&Method::GET, "/admin/api/v1/metrics") => api_metrics(state, req.uri()).await,
+        (&Method::GET, "/admin/api/v1/keys") => api_export_keys(state, req.uri()),
+        (&Method::GET, "/admin/api/v1/billing/keys") => api_billing_list_keys(state, req.uri()).await,
         (&Method::POST, "/admin/api/v1/billing/keys") => api_billing_create_key(req, state).await,
         _ => {
             // Dynamic routes:
@@ -117,6 +154,19 @@ async fn handle_billing_key_subroutes(
     if action.is_empty() {
         return match *req.method() {
             Method::GET => api_billing_get_balance(state, key).await,
+            Method::PATCH => api_billing_patch_key(req, state, key).await,
+            Method::DELETE => api_billing_delete_key(state, key).await,
+            _ => Response::builder()
+                .status(405)
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"error":"method_not_allowed"}"#))
+                .unwrap(),
+        };
+    }
+
+    if action == "quota" {
+        return match *req.method() {
+            Method::POST => api_billing_set_quota(req, state, key).await,
             _ => Response::builder()
                 .status(405)
                 .header("content-type", "application/json")
@@ -147,6 +197,24 @@ async fn handle_billing_key_subroutes(
 struct BillingCreateBody {
     key: String,
     balance: Option<i64>,
+    /// Daily token quota; 0 or omitted means unlimited.
+    quota_tokens: Option<u64>,
+    /// Upstream ids this key may be routed to; omitted or empty allows any.
+    #[serde(default)]
+    allowed_upstreams: Vec<String>,
+}
+
+/// `PATCH /billing/keys/{key}`: fields left out are unchanged.
+#[derive(Deserialize)]
+struct BillingPatchBody {
+    quota_tokens: Option<u64>,
+    /// `[]` lifts the restriction.
+    allowed_upstreams: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct BillingQuotaBody {
+    quota_tokens: u64,
 }
 
 #[derive(Deserialize)]
@@ -183,8 +251,17 @@ async fn api_billing_create_key(req: Request<Body>, state: Arc<RouterState>) ->
             "bad_request",
         );
     }
+    if let Err(e) = check_upstream_ids(&state, &payload.allowed_upstreams) {
+        return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request");
+    }
     let balance = payload.balance.unwrap_or(0);
-    let created = match state.billing.create_key(key.to_string(), balance) {
+    let quota_tokens = payload.quota_tokens.unwrap_or(0);
+    let created = match state.billing.create_key(
+        key.to_string(),
+        balance,
+        quota_tokens,
+        payload.allowed_upstreams,
+    ) {
         Ok(v) => v,
         Err(e) => {
             return RouterState::json_error(
@@ -204,15 +281,167 @@ async fn api_billing_create_key(req: Request<Body>, state: Arc<RouterState>) ->
     json_ok(&serde_json::json!({
         "key": key,
         "balance": balance,
+        "quota_tokens": quota_tokens,
+        "allowed_upstreams": state.billing.allowed_upstreams(key).as_deref(),
         "created": true
     }))
 }
 
+async fn api_billing_list_keys(state: Arc<RouterState>, uri: &http::Uri) -> Response<Body> {
+    let limit: usize = query_get(uri, "limit")
+        .and_then(|s: &str| s.parse::<usize>().ok())
+        .unwrap_or(100)
+        .clamp(1, 5000);
+    let offset: usize = query_get(uri, "offset")
+        .and_then(|s: &str| s.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let keys = state.billing.list_keys();
+    let total = keys.len();
+    let out: Vec<serde_json::Value> = keys
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(key, balance)| serde_json::json!({ "key": key, "balance": balance }))
+        .collect();
+
+    json_ok(&serde_json::json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "keys": out
+    }))
+}
+
+async fn api_billing_delete_key(state: Arc<RouterState>, key: &str) -> Response<Body> {
+    match state.billing.delete_key(key) {
+        Ok(true) => json_ok(&serde_json::json!({
+            "key": key,
+            "deleted": true
+        })),
+        Ok(false) => RouterState::json_error(
+            http::StatusCode::NOT_FOUND,
+            "key not found",
+            "key_not_found",
+        ),
+        Err(e) => RouterState::json_error(
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("delete key failed: {e}"),
+            "billing_error",
+        ),
+    }
+}
+
 async fn api_billing_get_balance(state: Arc<RouterState>, key: &str) -> Response<Body> {
-    match state.billing.get_balance(key) {
-        Some(balance) => json_ok(&serde_json::json!({
+    match (state.billing.get_balance(key), state.billing.get_quota(key)) {
+        (Some(balance), Some(quota)) => json_ok(&serde_json::json!({
             "key": key,
-            "balance": balance
+            "balance": balance,
+            "quota_tokens": quota.quota_tokens,
+            "used_today": quota.used_today,
+            "quota_remaining": quota_remaining(&quota),
+            "allowed_upstreams": state.billing.allowed_upstreams(key).as_deref()
+        })),
+        _ => RouterState::json_error(
+            http::StatusCode::NOT_FOUND,
+            "key not found",
+            "key_not_found",
+        ),
+    }
+}
+
+async fn api_billing_patch_key(
+    req: Request<Body>,
+    state: Arc<RouterState>,
+    key: &str,
+) -> Response<Body> {
+    let body = match read_body_limit(req, 256 * 1024).await {
+        Ok(b) => b,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("read body: {e}"),
+                "bad_request",
+            )
+        }
+    };
+    let payload: BillingPatchBody = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("invalid json: {e}"),
+                "bad_request",
+            )
+        }
+    };
+    if let Some(list) = &payload.allowed_upstreams {
+        if let Err(e) = check_upstream_ids(&state, list) {
+            return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request");
+        }
+    }
+    if state.billing.get_balance(key).is_none() {
+        return RouterState::json_error(
+            http::StatusCode::NOT_FOUND,
+            "key not found",
+            "key_not_found",
+        );
+    }
+    if let Some(quota_tokens) = payload.quota_tokens {
+        state.billing.set_quota(key, quota_tokens);
+    }
+    if let Some(list) = payload.allowed_upstreams {
+        state.billing.set_allowed_upstreams(key, list);
+    }
+    api_billing_get_balance(state, key).await
+}
+
+/// Ids in a billing key's `allowed_upstreams` must name configured upstreams, so a typo
+/// can't silently lock a customer out.
+fn check_upstream_ids(state: &RouterState, ids: &[String]) -> Result<(), String> {
+    match ids.iter().find(|id| state.upstream_by_id(id).is_none()) {
+        Some(id) => Err(format!("allowed_upstreams: unknown upstream id {id:?}")),
+        None => Ok(()),
+    }
+}
+
+/// `None` (JSON null) when the key has no daily quota.
+fn quota_remaining(quota: &crate::billing::QuotaInfo) -> Option<u64> {
+    (quota.quota_tokens > 0).then(|| quota.quota_tokens.saturating_sub(quota.used_today))
+}
+
+async fn api_billing_set_quota(
+    req: Request<Body>,
+    state: Arc<RouterState>,
+    key: &str,
+) -> Response<Body> {
+    let body = match read_body_limit(req, 256 * 1024).await {
+        Ok(b) => b,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("read body: {e}"),
+                "bad_request",
+            )
+        }
+    };
+    let payload: BillingQuotaBody = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("invalid json: {e}"),
+                "bad_request",
+            )
+        }
+    };
+
+    match state.billing.set_quota(key, payload.quota_tokens) {
+        Some(quota) => json_ok(&serde_json::json!({
+            "key": key,
+            "quota_tokens": quota.quota_tokens,
+            "used_today": quota.used_today,
+            "quota_remaining": quota_remaining(&quota)
         })),
         None => RouterState::json_error(
             http::StatusCode::NOT_FOUND,
@@ -314,6 +543,65 @@ async fn handle_upstream_subroutes(
             .unwrap();
     }
 
+    if sub == "cooldown" && parts.next() == Some("clear") {
+        if *req.method() == Method::POST {
+            return api_clear_cooldowns(state, Some(upstream_id), req.uri());
+        }
+        return Response::builder()
+            .status(405)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"error":"method_not_allowed"}"#))
+            .unwrap();
+    }
+
+    if sub == "enabled" {
+        if *req.method() == Method::PUT {
+            return api_set_upstream_enabled(req, state, upstream_id).await;
+        }
+        return Response::builder()
+            .status(405)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"error":"method_not_allowed"}"#))
+            .unwrap();
+    }
+
+    if sub == "reload" {
+        if *req.method() == Method::POST {
+            return api_reload_upstream(state, upstream_id).await;
+        }
+        return Response::builder()
+            .status(405)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"error":"method_not_allowed"}"#))
+            .unwrap();
+    }
+
+    if sub == "ban" {
+        if *req.method() == Method::POST {
+            return api_ban(req, state, upstream_id, false).await;
+        }
+        return Response::builder()
+            .status(405)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"error":"method_not_allowed"}"#))
+            .unwrap();
+    }
+
+    let key_action = if sub == "keys" { parts.next().unwrap_or("") } else { "" };
+    if key_action == "prune" || key_action == "ban" {
+        if *req.method() == Method::POST {
+            if key_action == "ban" {
+                return api_ban(req, state, upstream_id, true).await;
+            }
+            return api_prune_keys(req, state, upstream_id).await;
+        }
+        return Response::builder()
+            .status(405)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"error":"method_not_allowed"}"#))
+            .unwrap();
+    }
+
     if sub != "keys" {
         return Response::builder()
             .status(404)
@@ -336,8 +624,13 @@ async fn handle_upstream_subroutes(
 }
 
 async fn api_get_model_routes(state: Arc<RouterState>) -> Response<Body> {
-    let routes = state.get_model_routes();
-    json_ok(&routes)
+    // Version first: if a save lands in between, the stale ETag makes the next PUT conflict
+    // instead of silently overwriting.
+    let res = tokio::task::spawn_blocking(move || (state.model_routes_version(), state.get_model_routes())).await;
+    match res {
+        Ok((version, routes)) => with_etag(json_ok(&routes), version),
+        Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
+    }
 }
 
 #[derive(Deserialize)]
@@ -346,6 +639,10 @@ struct ModelRoutesBody {
 }
 
 async fn api_put_model_routes(req: Request<Body>, state: Arc<RouterState>) -> Response<Body> {
+    let expected = match if_match_version(&req) {
+        Ok(v) => v,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
+    };
     let body = match read_body_limit(req, 10 * 1024 * 1024).await {
         Ok(b) => b,
         Err(e) => {
@@ -368,9 +665,47 @@ async fn api_put_model_routes(req: Request<Body>, state: Arc<RouterState>) -> Re
         }
     };
 
-    match state.save_model_routes(routes_body.upstreams) {
-        Ok(routes) => json_ok(&routes),
-        Err(e) => RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+    let state2 = state.clone();
+    let res =
+        tokio::task::spawn_blocking(move || state2.save_model_routes(routes_body.upstreams, expected)).await;
+    state.record_reload("routes", matches!(res, Ok(Ok(_))));
+    match res {
+        Ok(Ok(routes)) => {
+            let version = routes.updated_at_ms;
+            with_etag(json_ok(&routes), version)
+        }
+        Ok(Err(e)) => edit_error(e),
+        Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelAliasesBody {
+    aliases: BTreeMap<String, String>,
+}
+
+async fn api_put_model_aliases(req: Request<Body>, state: Arc<RouterState>) -> Response<Body> {
+    let body = match read_body_limit(req, 1024 * 1024).await {
+        Ok(b) => b,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+    };
+    let input: ModelAliasesBody = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("invalid json: {e}"),
+                "bad_request",
+            )
+        }
+    };
+    let state2 = state.clone();
+    let res = tokio::task::spawn_blocking(move || state2.save_model_aliases(input.aliases)).await;
+    state.record_reload("aliases", matches!(res, Ok(Ok(_))));
+    match res {
+        Ok(Ok(file)) => json_ok(&file),
+        Ok(Err(e)) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
+        Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
     }
 }
 
@@ -390,15 +725,59 @@ struct UpstreamBody {
     id: String,
     base_url: String,
     weight: Option<usize>,
+    enabled: Option<bool>,
+    timeout_ms: Option<u64>,
+    format: Option<UpstreamFormat>,
+    group: Option<String>,
+    tags: Option<Vec<String>>,
+    tier: Option<u32>,
+    model_allow: Option<Vec<String>>,
+    model_deny: Option<Vec<String>>,
+    sse_usage_tail_only: Option<bool>,
+    auth_header: Option<String>,
+    auth_scheme: Option<String>,
+    host_header: Option<String>,
+    azure_api_version: Option<String>,
+    azure_deployments: Option<BTreeMap<String, String>>,
 }
 
 #[derive(Deserialize)]
 struct UpstreamUpdateBody {
     base_url: String,
     weight: Option<usize>,
+    /// `null` or 0 clears the override.
+    #[serde(default, deserialize_with = "nullable")]
+    timeout_ms: Option<Option<u64>>,
+    format: Option<UpstreamFormat>,
+    group: Option<String>,
+    tags: Option<Vec<String>>,
+    /// `null` clears the upstream's own tier (its group's tier applies again).
+    #[serde(default, deserialize_with = "nullable")]
+    tier: Option<Option<u32>>,
+    model_allow: Option<Vec<String>>,
+    model_deny: Option<Vec<String>>,
+    sse_usage_tail_only: Option<bool>,
+    auth_header: Option<String>,
+    auth_scheme: Option<String>,
+    host_header: Option<String>,
+    azure_api_version: Option<String>,
+    azure_deployments: Option<BTreeMap<String, String>>,
+}
+
+/// Tell an explicit `null` (`Some(None)`) apart from an absent field (`None`).
+fn nullable<'de, D, T>(d: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(d).map(Some)
 }
 
 async fn api_add_upstream(req: Request<Body>, state: Arc<RouterState>) -> Response<Body> {
+    let expected = match if_match_version(&req) {
+        Ok(v) => v,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
+    };
     let body = match read_body_limit(req, 256 * 1024).await {
         Ok(b) => b,
         Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
@@ -423,9 +802,24 @@ async fn api_add_upstream(req: Request<Body>, state: Arc<RouterState>) -> Respon
         id: input.id.trim().to_string(),
         base_url: input.base_url.trim().to_string(),
         weight: input.weight,
+        enabled: input.enabled.filter(|e| !e),
+        timeout_ms: input.timeout_ms,
+        format: input.format,
+        group: input.group,
+        tags: input.tags,
+        tier: input.tier,
+        model_allow: input.model_allow,
+        model_deny: input.model_deny,
+        sse_usage_tail_only: input.sse_usage_tail_only,
+        auth_header: input.auth_header,
+        auth_scheme: input.auth_scheme,
+        host_header: input.host_header,
+        azure_api_version: input.azure_api_version,
+        azure_deployments: input.azure_deployments,
     };
     let state2 = state.clone();
-    let res = tokio::task::spawn_blocking(move || state2.add_upstream(cfg)).await;
+    let res = tokio::task::spawn_blocking(move || state2.add_upstream(cfg, expected)).await;
+    state.record_reload("upstreams", matches!(res, Ok(Ok(_))));
     match res {
         Ok(Ok(_)) => {
             let state3 = state.clone();
@@ -433,14 +827,18 @@ async fn api_add_upstream(req: Request<Body>, state: Arc<RouterState>) -> Respon
             tokio::spawn(async move {
                 state3.refresh_missing_models_for_upstream(&id).await;
             });
-            json_ok(&serde_json::json!({"ok": true}))
+            upstreams_edited(&state, serde_json::json!({"ok": true}))
         }
-        Ok(Err(e)) => RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+        Ok(Err(e)) => edit_error(e),
         Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
     }
 }
 
 async fn api_update_upstream(req: Request<Body>, state: Arc<RouterState>, upstream_id: &str) -> Response<Body> {
+    let expected = match if_match_version(&req) {
+        Ok(v) => v,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
+    };
     let body = match read_body_limit(req, 256 * 1024).await {
         Ok(b) => b,
         Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
@@ -460,26 +858,99 @@ async fn api_update_upstream(req: Request<Body>, state: Arc<RouterState>, upstre
     }
     let state2 = state.clone();
     let id = upstream_id.to_string();
-    let base_url = input.base_url.trim().to_string();
-    let weight = input.weight;
-    let res = tokio::task::spawn_blocking(move || state2.update_upstream(&id, base_url, weight)).await;
+    let update = UpstreamUpdate {
+        base_url: input.base_url.trim().to_string(),
+        weight: input.weight,
+        timeout_ms: input.timeout_ms,
+        format: input.format,
+        group: input.group,
+        tags: input.tags,
+        tier: input.tier,
+        model_allow: input.model_allow,
+        model_deny: input.model_deny,
+        sse_usage_tail_only: input.sse_usage_tail_only,
+        auth_header: input.auth_header,
+        auth_scheme: input.auth_scheme,
+        host_header: input.host_header,
+        azure_api_version: input.azure_api_version,
+        azure_deployments: input.azure_deployments,
+    };
+    let res = tokio::task::spawn_blocking(move || state2.update_upstream(&id, update, expected)).await;
+    state.record_reload("upstreams", matches!(res, Ok(Ok(_))));
+    match res {
+        Ok(Ok(_)) => upstreams_edited(&state, serde_json::json!({"ok": true})),
+        Ok(Err(e)) => edit_error(e),
+        Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
+    }
+}
+
+#[derive(Deserialize)]
+struct UpstreamEnabledBody {
+    enabled: bool,
+}
+
+/// `PUT /upstreams/{id}/enabled`: pause or resume an upstream without touching its keys.
+async fn api_set_upstream_enabled(
+    req: Request<Body>,
+    state: Arc<RouterState>,
+    upstream_id: &str,
+) -> Response<Body> {
+    let expected = match if_match_version(&req) {
+        Ok(v) => v,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
+    };
+    let body = match read_body_limit(req, 4 * 1024).await {
+        Ok(b) => b,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+    };
+    let input: UpstreamEnabledBody = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("invalid json: {e}"),
+                "bad_request",
+            )
+        }
+    };
+    let state2 = state.clone();
+    let id = upstream_id.to_string();
+    let enabled = input.enabled;
+    let res = tokio::task::spawn_blocking(move || state2.set_upstream_enabled(&id, enabled, expected)).await;
+    state.record_reload("upstreams", matches!(res, Ok(Ok(_))));
     match res {
-        Ok(Ok(_)) => json_ok(&serde_json::json!({"ok": true})),
-        Ok(Err(e)) => RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+        Ok(Ok(changed)) => {
+            tracing::info!(upstream = upstream_id, enabled, changed, "upstream enabled via admin");
+            upstreams_edited(
+                &state,
+                serde_json::json!({
+                    "ok": true,
+                    "upstream": upstream_id,
+                    "enabled": enabled,
+                    "changed": changed
+                }),
+            )
+        }
+        Ok(Err(e)) => edit_error(e),
         Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
     }
 }
 
 async fn api_delete_upstream(req: Request<Body>, state: Arc<RouterState>, upstream_id: &str) -> Response<Body> {
+    let expected = match if_match_version(&req) {
+        Ok(v) => v,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
+    };
     let delete_keys = query_get(req.uri(), "delete_keys")
         .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
         .unwrap_or(false);
     let state2 = state.clone();
     let id = upstream_id.to_string();
-    let res = tokio::task::spawn_blocking(move || state2.delete_upstream(&id, delete_keys)).await;
+    let res = tokio::task::spawn_blocking(move || state2.delete_upstream(&id, delete_keys, expected)).await;
+    state.record_reload("upstreams", matches!(res, Ok(Ok(_))));
     match res {
-        Ok(Ok(_)) => json_ok(&serde_json::json!({"ok": true, "delete_keys": delete_keys})),
-        Ok(Err(e)) => RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+        Ok(Ok(_)) => upstreams_edited(&state, serde_json::json!({"ok": true, "delete_keys": delete_keys})),
+        Ok(Err(e)) => edit_error(e),
         Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
     }
 }
@@ -489,13 +960,38 @@ struct UpstreamInfo {
     id: String,
     base_url: String,
     weight: usize,
+    enabled: bool,
+    timeout_ms: Option<u64>,
+    format: UpstreamFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Effective failover tier (own `tier`, else the group's).
+    tier: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    model_allow: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    model_deny: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    sse_usage_tail_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_header: Option<String>,
     keys_total: usize,
     keys_healthy: usize,
     keys_banned: usize,
     upstream_cooldown_until_ms: u64,
     upstream_fail_streak: u32,
+    /// Half-open: a request is testing the upstream after its cooldown expired.
+    probe_inflight: bool,
 
     selected_total: u64,
+    inflight: u64,
+    latency_ewma_ms: f64,
+
+    conn_opened: u64,
+    conn_failed: u64,
+    conn_open: u64,
 
     responses_2xx: u64,
     responses_3xx: u64,
@@ -503,10 +999,21 @@ struct UpstreamInfo {
     responses_5xx: u64,
     errors_timeout: u64,
     errors_network: u64,
+    errors_dns: u64,
+    errors_connect: u64,
+    errors_tls: u64,
+    /// Selections that found every loaded key cooling down (vs. `keys_total` 0: no keys).
+    key_exhausted_total: u64,
 }
 
-fn build_upstream_info(u: &crate::state::Upstream, now: u64) -> UpstreamInfo {
+fn build_upstream_info(state: &RouterState, u: &crate::state::Upstream, now: u64) -> UpstreamInfo {
     let keys_arc = u.keys.load_full();
+    let conn = state.conn_stats.get(u.base_authority.as_str());
+    let conn_load = |f: fn(&crate::conn::ConnStats) -> &std::sync::atomic::AtomicU64| {
+        conn.as_deref()
+            .map(|c| f(c).load(std::sync::atomic::Ordering::Relaxed))
+            .unwrap_or(0)
+    };
     let total = keys_arc.len();
     let banned = keys_arc.iter().filter(|k| {
         k.cooldown_until_ms.load(std::sync::atomic::Ordering::Relaxed) > now
@@ -515,32 +1022,58 @@ fn build_upstream_info(u: &crate::state::Upstream, now: u64) -> UpstreamInfo {
         id: u.id.to_string(),
         base_url: u.base_url.to_string(),
         weight: u.weight,
+        enabled: u.enabled.load(std::sync::atomic::Ordering::Relaxed),
+        timeout_ms: u.timeout.map(|t| t.as_millis() as u64),
+        format: u.format,
+        group: u.group.as_deref().map(str::to_string),
+        tags: u.tags.to_vec(),
+        tier: state.upstream_tier(u),
+        model_allow: u.model_filter.allow.clone(),
+        model_deny: u.model_filter.deny.clone(),
+        sse_usage_tail_only: u.sse_usage_tail_only,
+        host_header: u.host_header.as_ref().and_then(|h| h.to_str().ok()).map(str::to_string),
         keys_total: total,
         keys_healthy: total.saturating_sub(banned),
         keys_banned: banned,
         upstream_cooldown_until_ms: u.cooldown_until_ms.load(std::sync::atomic::Ordering::Relaxed),
         upstream_fail_streak: u.fail_streak.load(std::sync::atomic::Ordering::Relaxed),
+        probe_inflight: u.probe_inflight.load(std::sync::atomic::Ordering::Relaxed),
         selected_total: u.stats.selected_total.load(std::sync::atomic::Ordering::Relaxed),
+        inflight: u.inflight.load(std::sync::atomic::Ordering::Relaxed),
+        latency_ewma_ms: u.latency_ewma_us.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1000.0,
+        conn_opened: conn_load(|c| &c.opened),
+        conn_failed: conn_load(|c| &c.failed),
+        conn_open: conn_load(|c| &c.open),
         responses_2xx: u.stats.responses_2xx.load(std::sync::atomic::Ordering::Relaxed),
         responses_3xx: u.stats.responses_3xx.load(std::sync::atomic::Ordering::Relaxed),
         responses_4xx: u.stats.responses_4xx.load(std::sync::atomic::Ordering::Relaxed),
         responses_5xx: u.stats.responses_5xx.load(std::sync::atomic::Ordering::Relaxed),
         errors_timeout: u.stats.errors_timeout.load(std::sync::atomic::Ordering::Relaxed),
         errors_network: u.stats.errors_network.load(std::sync::atomic::Ordering::Relaxed),
+        errors_dns: u.stats.errors_dns.load(std::sync::atomic::Ordering::Relaxed),
+        errors_connect: u.stats.errors_connect.load(std::sync::atomic::Ordering::Relaxed),
+        errors_tls: u.stats.errors_tls.load(std::sync::atomic::Ordering::Relaxed),
+        key_exhausted_total: u.stats.key_exhausted_total.load(std::sync::atomic::Ordering::Relaxed),
     }
 }
 
 async fn api_list_upstreams(state: Arc<RouterState>) -> Response<Body> {
     let snap = state.snapshot.load_full();
     let now = now_ms();
-    let ups: Vec<UpstreamInfo> = snap.upstreams.iter().map(|u| build_upstream_info(u, now)).collect();
-    json_ok(&ups)
+    let ups: Vec<UpstreamInfo> = snap.upstreams.iter().map(|u| build_upstream_info(&state, u, now)).collect();
+    with_etag(json_ok(&ups), state.upstreams_version.load(std::sync::atomic::Ordering::Relaxed))
+}
+
+/// Success response for an upstream edit, carrying the new list version as ETag.
+fn upstreams_edited(state: &RouterState, v: serde_json::Value) -> Response<Body> {
+    with_etag(json_ok(&v), state.upstreams_version.load(std::sync::atomic::Ordering::Relaxed))
 }
 
 #[derive(Serialize)]
 struct StatsSnapshot {
     ts_ms: u64,
     uptime_s: u64,
+    read_only: bool,
 
     max_retries: usize,
     retry_status_codes: Vec<u16>,
@@ -556,17 +1089,80 @@ struct StatsSnapshot {
 
     errors_timeout: u64,
     errors_network: u64,
+    errors_dns: u64,
+    errors_connect: u64,
+    errors_tls: u64,
 
     latency_avg_ms: f64,
     latency_max_ms: f64,
+    latency_p50_ms: f64,
+    latency_p95_ms: f64,
+    latency_p99_ms: f64,
     latency_count: u64,
 
+    billing_persister: crate::billing::PersisterHealth,
+
+    reload_count: u64,
+    reload_failures: u64,
+    last_reload_ms: u64,
+
     upstreams: Vec<UpstreamInfo>,
+    /// Per-`group` totals; empty when no upstream has a group.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<GroupInfo>,
 }
 
-fn build_snapshot(state: &RouterState) -> StatsSnapshot {
-    let ts = now_ms();
-    let uptime_s = (ts.saturating_sub(state.stats.started_at_ms)) / 1000;
+#[derive(Serialize, Default)]
+struct GroupInfo {
+    group: String,
+    /// Failover tier from `group_tiers` (0 when unlisted).
+    tier: u32,
+    upstreams: usize,
+    keys_total: usize,
+    keys_healthy: usize,
+    selected_total: u64,
+    inflight: u64,
+    responses_2xx: u64,
+    responses_4xx: u64,
+    responses_5xx: u64,
+    errors_timeout: u64,
+    errors_network: u64,
+    errors_dns: u64,
+    errors_connect: u64,
+    errors_tls: u64,
+}
+
+fn build_group_info(state: &RouterState, ups: &[UpstreamInfo]) -> Vec<GroupInfo> {
+    let mut groups: BTreeMap<&str, GroupInfo> = BTreeMap::new();
+    for u in ups {
+        let Some(name) = u.group.as_deref() else {
+            continue;
+        };
+        let g = groups.entry(name).or_insert_with(|| GroupInfo {
+            group: name.to_string(),
+            tier: state.group_tiers.get(name).copied().unwrap_or(0),
+            ..Default::default()
+        });
+        g.upstreams += 1;
+        g.keys_total += u.keys_total;
+        g.keys_healthy += u.keys_healthy;
+        g.selected_total += u.selected_total;
+        g.inflight += u.inflight;
+        g.responses_2xx += u.responses_2xx;
+        g.responses_4xx += u.responses_4xx;
+        g.responses_5xx += u.responses_5xx;
+        g.errors_timeout += u.errors_timeout;
+        g.errors_network += u.errors_network;
+        g.errors_dns += u.errors_dns;
+        g.errors_connect += u.errors_connect;
+        g.errors_tls += u.errors_tls;
+    }
+    groups.into_values().collect()
+}
+
+fn build_snapshot(state: &RouterState) -> StatsSnapshot {
+    let ts = now_ms();
+    let uptime_s = (ts.saturating_sub(state.stats.started_at_ms)) / 1000;
 
     let latency_count = state.stats.latency_count.load(std::sync::atomic::Ordering::Relaxed);
     let latency_total = state.stats.latency_ns_total.load(std::sync::atomic::Ordering::Relaxed);
@@ -578,14 +1174,17 @@ fn build_snapshot(state: &RouterState) -> StatsSnapshot {
         (latency_total as f64) / (latency_count as f64) / 1_000_000.0
     };
     let latency_max_ms = (latency_max as f64) / 1_000_000.0;
+    let pcts = state.stats.latency_hist.quantiles_ms(&[0.50, 0.95, 0.99]);
 
     let snap = state.snapshot.load_full();
     let now = ts;
-    let ups: Vec<UpstreamInfo> = snap.upstreams.iter().map(|u| build_upstream_info(u, now)).collect();
+    let ups: Vec<UpstreamInfo> = snap.upstreams.iter().map(|u| build_upstream_info(state, u, now)).collect();
+    let groups = build_group_info(state, &ups);
 
     StatsSnapshot {
         ts_ms: ts,
         uptime_s,
+        read_only: state.read_only,
         max_retries: state.max_retries,
         retry_status_codes: state.retry_status_codes_sorted(),
         requests_total: state.stats.requests_total.load(std::sync::atomic::Ordering::Relaxed),
@@ -597,10 +1196,21 @@ fn build_snapshot(state: &RouterState) -> StatsSnapshot {
         responses_5xx: state.stats.responses_5xx.load(std::sync::atomic::Ordering::Relaxed),
         errors_timeout: state.stats.errors_timeout.load(std::sync::atomic::Ordering::Relaxed),
         errors_network: state.stats.errors_network.load(std::sync::atomic::Ordering::Relaxed),
+        errors_dns: state.stats.errors_dns.load(std::sync::atomic::Ordering::Relaxed),
+        errors_connect: state.stats.errors_connect.load(std::sync::atomic::Ordering::Relaxed),
+        errors_tls: state.stats.errors_tls.load(std::sync::atomic::Ordering::Relaxed),
         latency_avg_ms,
         latency_max_ms,
+        latency_p50_ms: pcts[0],
+        latency_p95_ms: pcts[1],
+        latency_p99_ms: pcts[2],
         latency_count,
+        billing_persister: state.billing.persister_health(),
+        reload_count: state.stats.reload_count.load(std::sync::atomic::Ordering::Relaxed),
+        reload_failures: state.stats.reload_failures.load(std::sync::atomic::Ordering::Relaxed),
+        last_reload_ms: state.stats.last_reload_ms.load(std::sync::atomic::Ordering::Relaxed),
         upstreams: ups,
+        groups,
     }
 }
 
@@ -614,7 +1224,25 @@ async fn api_requests(state: Arc<RouterState>, uri: &http::Uri) -> Response<Body
         .and_then(|s| s.parse::<usize>().ok())
         .unwrap_or(200)
         .clamp(1, 5000);
-    let list = state.recent_requests(limit);
+    let status_class = match query_get(uri, "status_class") {
+        Some(v) => match v.as_bytes() {
+            [c @ b'1'..=b'5', b'x' | b'X', b'x' | b'X'] => Some((c - b'0') as u16),
+            _ => {
+                return RouterState::json_error(
+                    http::StatusCode::BAD_REQUEST,
+                    "status_class must be one of 1xx..5xx",
+                    "bad_request",
+                )
+            }
+        },
+        None => None,
+    };
+    let filter = crate::state::RequestLogFilter {
+        upstream: query_get(uri, "upstream").filter(|v| !v.is_empty()).map(percent_decode),
+        model: query_get(uri, "model").filter(|v| !v.is_empty()).map(percent_decode),
+        status_class,
+    };
+    let list = state.recent_requests(limit, &filter);
     json_ok(&serde_json::json!({
         "now_ms": now_ms(),
         "count": list.len(),
@@ -622,9 +1250,197 @@ async fn api_requests(state: Arc<RouterState>, uri: &http::Uri) -> Response<Body
     }))
 }
 
+async fn api_model_stats(state: Arc<RouterState>) -> Response<Body> {
+    let models = state.model_stats.snapshot();
+    json_ok(&serde_json::json!({
+        "now_ms": now_ms(),
+        "count": models.len(),
+        "models": models
+    }))
+}
+
+/// Clear cooldowns and fail streaks of one upstream (or all), and with `keys=1` of their keys.
+fn api_clear_cooldowns(state: Arc<RouterState>, upstream_id: Option<&str>, uri: &http::Uri) -> Response<Body> {
+    let include_keys = query_get(uri, "keys")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    let upstreams: Vec<Arc<Upstream>> = match upstream_id {
+        Some(id) => match state.upstream_by_id(id) {
+            Some((_idx, u)) => vec![u],
+            None => return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found"),
+        },
+        None => state.snapshot.load().upstreams.clone(),
+    };
+
+    let now = now_ms();
+    let (mut upstreams_cleared, mut keys_cleared) = (0usize, 0usize);
+    for u in &upstreams {
+        let (cleared, keys) = u.clear_cooldown(include_keys, now);
+        upstreams_cleared += cleared as usize;
+        keys_cleared += keys;
+    }
+    tracing::info!(
+        upstream = upstream_id.unwrap_or("*"),
+        upstreams_cleared,
+        keys_cleared,
+        "cooldowns cleared via admin"
+    );
+    json_ok(&serde_json::json!({
+        "ok": true,
+        "upstreams": upstreams.len(),
+        "upstreams_cleared": upstreams_cleared,
+        "keys_cleared": keys_cleared
+    }))
+}
+
+/// Body for `POST /upstreams/{id}/ban` and `POST /upstreams/{id}/keys/ban` (which also takes `key`).
+#[derive(Deserialize)]
+struct BanBody {
+    /// 0 lifts the ban.
+    duration_ms: u64,
+    #[serde(default)]
+    key: Option<String>,
+}
+
+/// Manually take an upstream (or, with `for_key`, one of its keys) out of rotation by setting
+/// its cooldown, e.g. for maintenance. Selection already skips anything cooling down.
+async fn api_ban(req: Request<Body>, state: Arc<RouterState>, upstream_id: &str, for_key: bool) -> Response<Body> {
+    let Some((_idx, upstream)) = state.upstream_by_id(upstream_id) else {
+        return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found");
+    };
+    let body = match read_body_limit(req, 64 * 1024).await {
+        Ok(b) => b,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+    };
+    let input: BanBody = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("invalid json: {e}"),
+                "bad_request",
+            )
+        }
+    };
+
+    let now = now_ms();
+    if !for_key {
+        let until = upstream.ban(input.duration_ms, now);
+        tracing::info!(upstream = upstream_id, duration_ms = input.duration_ms, "upstream banned via admin");
+        return json_ok(&serde_json::json!({
+            "ok": true,
+            "upstream": upstream_id,
+            "cooldown_until_ms": until
+        }));
+    }
+
+    let Some(key) = input.key.filter(|k| !k.is_empty()) else {
+        return RouterState::json_error(http::StatusCode::BAD_REQUEST, "missing key", "bad_request");
+    };
+    let keys = upstream.keys.load();
+    let Some(k) = keys.iter().find(|k| *k.key == *key) else {
+        return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown key", "not_found");
+    };
+    let until = if input.duration_ms == 0 { 0 } else { now.saturating_add(input.duration_ms) };
+    k.cooldown_until_ms.store(until, std::sync::atomic::Ordering::Relaxed);
+    if until == 0 {
+        k.fail_streak.store(0, std::sync::atomic::Ordering::Relaxed);
+    }
+    tracing::info!(
+        upstream = upstream_id,
+        key = %mask_key(&key),
+        duration_ms = input.duration_ms,
+        "key banned via admin"
+    );
+    json_ok(&serde_json::json!({
+        "ok": true,
+        "upstream": upstream_id,
+        "key": mask_key(&key),
+        "cooldown_until_ms": until
+    }))
+}
+
+/// The live snapshot's scheduling: effective (clamped) weights, each upstream's round-robin
+/// share of the total weight and, for the latency strategy, its `weight / latency_ewma` share.
+fn api_schedule(state: Arc<RouterState>) -> Response<Body> {
+    use crate::config::SelectStrategy;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    let snap = state.snapshot.load_full();
+    let now = now_ms();
+    let total_weight: usize = snap.upstreams.iter().map(|u| u.weight).sum();
+
+    // Unfiltered by model or cooldown; None until every upstream has a latency sample
+    // (selection is plain round-robin until then).
+    let latency = state.select_strategy == SelectStrategy::Latency;
+    let latency_scores: Option<Vec<f64>> = snap
+        .upstreams
+        .iter()
+        .map(|u| match u.latency_ewma_us.load(Relaxed) {
+            0 => None,
+            ewma => Some(u.weight as f64 / ewma as f64),
+        })
+        .collect();
+    let latency_total: f64 = latency_scores.iter().flatten().sum();
+
+    let upstreams: Vec<serde_json::Value> = snap
+        .upstreams
+        .iter()
+        .enumerate()
+        .map(|(i, u)| {
+            let mut v = serde_json::json!({
+                "id": &*u.id,
+                "group": u.group.as_deref(),
+                "tier": state.upstream_tier(u),
+                "weight": u.weight,
+                "weight_share": u.weight as f64 / total_weight.max(1) as f64,
+                "rr_current": u.rr_current.load(Relaxed),
+                "cooling_down": u.cooldown_until_ms.load(Relaxed) > now,
+            });
+            if latency {
+                v["latency_ewma_us"] = u.latency_ewma_us.load(Relaxed).into();
+                v["latency_share"] = latency_scores.as_ref().map(|s| s[i] / latency_total).into();
+            }
+            v
+        })
+        .collect();
+
+    json_ok(&serde_json::json!({
+        "now_ms": now,
+        "select_strategy": state.select_strategy,
+        "key_select": state.key_select,
+        "total_weight": total_weight,
+        "upstreams": upstreams
+    }))
+}
+
+/// `GET /route?model=...`: which upstreams would serve `model`, in the order selection tries
+/// them, without selecting anything.
+fn api_route(state: Arc<RouterState>, uri: &http::Uri) -> Response<Body> {
+    let Some(model) = query_get(uri, "model").map(percent_decode).filter(|m| !m.is_empty()) else {
+        return RouterState::json_error(http::StatusCode::BAD_REQUEST, "missing model", "bad_request");
+    };
+    let upstream_model = state.resolve_model_alias(&model).unwrap_or_else(|| model.clone());
+    let candidates = state.route_candidates(&upstream_model, None, now_ms());
+    let routable = candidates.iter().any(|c| c.eligible);
+    let mut v = serde_json::json!({
+        "model": model,
+        "upstream_model": upstream_model,
+        "select_strategy": state.select_strategy,
+        "routable": routable,
+        "candidates": candidates,
+    });
+    // Nobody serves it: the request is rejected or passed through (see unknown_model_behavior).
+    if candidates.is_empty() {
+        v["unknown_model_behavior"] = serde_json::json!(state.unknown_model_behavior);
+        v["default_upstream_id"] = state.default_upstream_id.as_deref().into();
+    }
+    json_ok(&v)
+}
+
 async fn api_metrics(state: Arc<RouterState>, uri: &http::Uri) -> Response<Body> {
     let window = query_get(uri, "window").unwrap_or("minute");
-    let win = MetricsWindow::from_str(window);
+    let win = MetricsWindow::parse(window);
     let buckets = state.metrics_snapshot(win);
     json_ok(&serde_json::json!({
         "window": win.as_str(),
@@ -633,6 +1449,209 @@ async fn api_metrics(state: Arc<RouterState>, uri: &http::Uri) -> Response<Body>
     }))
 }
 
+/// `GET /metrics` in Prometheus text format. Takes the admin token as `X-Admin-Token` or
+/// `Authorization: Bearer` (Prometheus `authorization` config). Billing is aggregate only.
+pub fn prometheus_metrics(req: &Request<Body>, state: &RouterState) -> Response<Body> {
+    let bearer = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.trim().strip_prefix("Bearer "))
+        .is_some_and(|t| state.authorize_admin_token_str(t.trim()));
+    if !bearer && !state.authorize_admin_header(req) {
+        return RouterState::json_error(
+            http::StatusCode::UNAUTHORIZED,
+            "missing or invalid admin token",
+            "admin_unauthorized",
+        );
+    }
+
+    use std::fmt::Write;
+    use std::sync::atomic::Ordering::Relaxed;
+    let st = &state.stats;
+    let mut out = String::with_capacity(2048);
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+
+    metric(
+        "gptload_requests_total",
+        "counter",
+        "Proxied requests received.",
+        &[("", st.requests_total.load(Relaxed).to_string())],
+    );
+    metric(
+        "gptload_requests_inflight",
+        "gauge",
+        "Proxied requests in flight.",
+        &[("", st.requests_inflight.load(Relaxed).to_string())],
+    );
+    metric(
+        "gptload_responses_total",
+        "counter",
+        "Upstream responses by status class.",
+        &[
+            ("{class=\"2xx\"}", st.responses_2xx.load(Relaxed).to_string()),
+            ("{class=\"3xx\"}", st.responses_3xx.load(Relaxed).to_string()),
+            ("{class=\"4xx\"}", st.responses_4xx.load(Relaxed).to_string()),
+            ("{class=\"5xx\"}", st.responses_5xx.load(Relaxed).to_string()),
+        ],
+    );
+    metric(
+        "gptload_upstream_errors_total",
+        "counter",
+        "Upstream requests that failed without a response.",
+        &[
+            ("{kind=\"timeout\"}", st.errors_timeout.load(Relaxed).to_string()),
+            ("{kind=\"network\"}", st.errors_network.load(Relaxed).to_string()),
+        ],
+    );
+    metric(
+        "gptload_upstream_network_errors_total",
+        "counter",
+        "Network errors by cause; the remainder of kind=\"network\" broke mid-request.",
+        &[
+            ("{cause=\"dns\"}", st.errors_dns.load(Relaxed).to_string()),
+            ("{cause=\"connect\"}", st.errors_connect.load(Relaxed).to_string()),
+            ("{cause=\"tls\"}", st.errors_tls.load(Relaxed).to_string()),
+        ],
+    );
+    metric(
+        "gptload_config_reloads_total",
+        "counter",
+        "Configuration changes (reloads, model refreshes, route/alias/upstream edits).",
+        &[
+            ("{result=\"ok\"}", st.reload_successes.load(Relaxed).to_string()),
+            ("{result=\"error\"}", st.reload_failures.load(Relaxed).to_string()),
+        ],
+    );
+    metric(
+        "gptload_config_last_reload_timestamp_seconds",
+        "gauge",
+        "Unix time of the last successful configuration change (0 if none).",
+        &[("", (st.last_reload_ms.load(Relaxed) / 1000).to_string())],
+    );
+
+    let billing = state.billing.aggregates();
+    metric(
+        "gptload_billing_keys",
+        "gauge",
+        "Billing keys.",
+        &[("", billing.keys.to_string())],
+    );
+    metric(
+        "gptload_billing_balance_total",
+        "gauge",
+        "Sum of balances across all billing keys.",
+        &[("", billing.balance_total.to_string())],
+    );
+    metric(
+        "gptload_billing_keys_depleted",
+        "gauge",
+        "Billing keys with a zero or negative balance.",
+        &[("", billing.keys_depleted.to_string())],
+    );
+    metric(
+        "gptload_billing_tokens_charged_total",
+        "counter",
+        "Tokens charged to billing keys since startup.",
+        &[("", billing.tokens_charged.to_string())],
+    );
+    metric(
+        "gptload_billing_cost_charged_total",
+        "counter",
+        "Balance units deducted from billing keys since startup.",
+        &[("", billing.cost_charged.to_string())],
+    );
+    metric(
+        "gptload_billing_persister_up",
+        "gauge",
+        "1 while the billing persist thread is heartbeating.",
+        &[("", u8::from(state.billing.persister_health().healthy).to_string())],
+    );
+
+    // Per-upstream series, labelled with `upstream` and `group` (empty when unset).
+    let snap = state.snapshot.load_full();
+    let series: Vec<(&Upstream, String)> = snap
+        .upstreams
+        .iter()
+        .map(|u| {
+            let labels = format!(
+                "upstream=\"{}\",group=\"{}\"",
+                prom_label_value(&u.id),
+                prom_label_value(u.group.as_deref().unwrap_or(""))
+            );
+            (u.as_ref(), labels)
+        })
+        .collect();
+    prom_header(&mut out, "gptload_upstream_info", "gauge", "Upstream metadata; tags are comma-separated.");
+    for (u, labels) in &series {
+        let tags = prom_label_value(&u.tags.join(","));
+        let enabled = u8::from(u.enabled.load(Relaxed));
+        let _ = writeln!(out, "gptload_upstream_info{{{labels},tags=\"{tags}\",enabled=\"{enabled}\"}} 1");
+    }
+    prom_header(&mut out, "gptload_upstream_selected_total", "counter", "Times the upstream was selected.");
+    for (u, labels) in &series {
+        let _ = writeln!(out, "gptload_upstream_selected_total{{{labels}}} {}", u.stats.selected_total.load(Relaxed));
+    }
+    prom_header(&mut out, "gptload_upstream_responses_total", "counter", "Upstream responses by status class.");
+    for (u, labels) in &series {
+        for (class, n) in [
+            ("2xx", &u.stats.responses_2xx),
+            ("3xx", &u.stats.responses_3xx),
+            ("4xx", &u.stats.responses_4xx),
+            ("5xx", &u.stats.responses_5xx),
+        ] {
+            let _ = writeln!(
+                out,
+                "gptload_upstream_responses_total{{{labels},class=\"{class}\"}} {}",
+                n.load(Relaxed)
+            );
+        }
+    }
+    prom_header(
+        &mut out,
+        "gptload_upstream_failures_total",
+        "counter",
+        "Upstream requests that failed without a response.",
+    );
+    for (u, labels) in &series {
+        for (kind, n) in [("timeout", &u.stats.errors_timeout), ("network", &u.stats.errors_network)] {
+            let _ = writeln!(
+                out,
+                "gptload_upstream_failures_total{{{labels},kind=\"{kind}\"}} {}",
+                n.load(Relaxed)
+            );
+        }
+    }
+    prom_header(&mut out, "gptload_upstream_inflight", "gauge", "Requests in flight to the upstream.");
+    for (u, labels) in &series {
+        let _ = writeln!(out, "gptload_upstream_inflight{{{labels}}} {}", u.inflight.load(Relaxed));
+    }
+
+    Response::builder()
+        .status(200)
+        .header("content-type", "text/plain; version=0.0.4; charset=utf-8")
+        .header("cache-control", "no-store")
+        .body(Body::from(out))
+        .unwrap()
+}
+
+fn prom_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    use std::fmt::Write;
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escape a Prometheus label value (backslash, `"` and newline).
+fn prom_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
 async fn stats_stream(state: Arc<RouterState>) -> Response<Body> {
     let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, std::io::Error>>(32);
     let state2 = state.clone();
@@ -659,6 +1678,10 @@ async fn stats_stream(state: Arc<RouterState>) -> Response<Body> {
                 break;
             }
             tokio::time::sleep(Duration::from_secs(1)).await;
+            // End the stream so it doesn't hold up a graceful shutdown.
+            if state2.is_shutting_down() {
+                break;
+            }
         }
     });
 
@@ -671,6 +1694,32 @@ async fn stats_stream(state: Arc<RouterState>) -> Response<Body> {
         .unwrap()
 }
 
+/// Durability barrier: returns once pending billing writes and the key DB are on disk.
+async fn api_flush(state: Arc<RouterState>) -> Response<Body> {
+    let t0 = std::time::Instant::now();
+    let res = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        state.billing.flush(Duration::from_secs(30))?;
+        state.store.flush()
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => json_ok(&serde_json::json!({
+            "ok": true,
+            "elapsed_ms": t0.elapsed().as_millis() as u64
+        })),
+        Ok(Err(e)) => RouterState::json_error(
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("flush failed: {e}"),
+            "flush_failed",
+        ),
+        Err(e) => RouterState::json_error(
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("flush task failed: {e}"),
+            "flush_failed",
+        ),
+    }
+}
+
 async fn api_reload_all(state: Arc<RouterState>) -> Response<Body> {
     let mut results = Vec::new();
     let snap = state.snapshot.load_full();
@@ -683,7 +1732,8 @@ async fn api_reload_all(state: Arc<RouterState>) -> Response<Body> {
         // Reload in blocking thread.
         let res = tokio::task::spawn_blocking(move || -> anyhow::Result<usize> {
             let keys = store.load_all_keys(&id_clone)?;
-            let ks = build_key_states(keys)?;
+            let ks = build_key_states(keys, &u2.auth_scheme)?;
+            inherit_key_breakers(&ks, &u2.keys.load());
             let n = ks.len();
             u2.keys.store(ks);
             Ok(n)
@@ -697,6 +1747,9 @@ async fn api_reload_all(state: Arc<RouterState>) -> Response<Body> {
         }
     }
 
+    let all_ok = results.iter().all(|r| r["ok"] == true);
+    state.record_reload("keys", all_ok);
+
     let state2 = state.clone();
     tokio::spawn(async move {
         state2.refresh_missing_models_routes().await;
@@ -705,10 +1758,53 @@ async fn api_reload_all(state: Arc<RouterState>) -> Response<Body> {
     json_ok(&serde_json::json!({ "reloaded": results }))
 }
 
+/// Reload one upstream's keys from the store (after an out-of-band edit), like `api_reload_all`
+/// but for a single upstream.
+async fn api_reload_upstream(state: Arc<RouterState>, upstream_id: &str) -> Response<Body> {
+    let Some((_idx, upstream)) = state.upstream_by_id(upstream_id) else {
+        return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found");
+    };
+    let store = state.store.clone();
+    let id = upstream_id.to_string();
+    let res = tokio::task::spawn_blocking(move || -> anyhow::Result<(usize, usize)> {
+        let keys = store.load_all_keys(&id)?;
+        let ks = build_key_states(keys, &upstream.auth_scheme)?;
+        inherit_key_breakers(&ks, &upstream.keys.load());
+        let n = ks.len();
+        let previous = upstream.keys.swap(ks).len();
+        Ok((previous, n))
+    })
+    .await;
+
+    let res = match res {
+        Ok(r) => r,
+        Err(e) => Err(e.into()),
+    };
+    state.record_reload("keys", res.is_ok());
+    match res {
+        Ok((previous, n)) => {
+            let state2 = state.clone();
+            let id2 = upstream_id.to_string();
+            tokio::spawn(async move {
+                state2.refresh_missing_models_for_upstream(&id2).await;
+            });
+            json_ok(&serde_json::json!({
+                "ok": true,
+                "upstream": upstream_id,
+                "keys_total": n,
+                "keys_before": previous
+            }))
+        }
+        Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
+    }
+}
+
 #[derive(Deserialize)]
 struct JsonKeysBody {
     keys: Vec<String>,
     dedupe: Option<bool>,
+    /// Note stored with newly added keys.
+    label: Option<String>,
 }
 
 async fn api_add_keys(req: Request<Body>, state: Arc<RouterState>, upstream_id: &str) -> Response<Body> {
@@ -716,7 +1812,7 @@ async fn api_add_keys(req: Request<Body>, state: Arc<RouterState>, upstream_id:
         return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found");
     };
 
-    let (keys, dedupe) = match parse_keys_body(req).await {
+    let (keys, dedupe, label) = match parse_keys_body(req).await {
         Ok(v) => v,
         Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
     };
@@ -734,12 +1830,12 @@ async fn api_add_keys(req: Request<Body>, state: Arc<RouterState>, upstream_id:
     let upstream2 = upstream.clone();
 
     let res = tokio::task::spawn_blocking(move || -> anyhow::Result<serde_json::Value> {
-        let add_res = store.add_keys(&id, &keys)?;
+        let add_res = store.add_keys(&id, &keys, label.as_deref())?;
         let inserted = add_res.inserted;
         let existed = add_res.existed;
 
         // Build new KeyState arcs only for inserted keys and append to in-memory list.
-        let inserted_states = build_key_states(add_res.inserted_keys)?;
+        let inserted_states = build_key_states(add_res.inserted_keys, &upstream2.auth_scheme)?;
         let old = upstream2.keys.load_full();
         let mut merged: Vec<Arc<crate::state::KeyState>> = Vec::with_capacity(old.len() + inserted_states.len());
         merged.extend(old.iter().cloned());
@@ -775,7 +1871,7 @@ async fn api_replace_keys(req: Request<Body>, state: Arc<RouterState>, upstream_
         return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found");
     };
 
-    let (keys, dedupe) = match parse_keys_body(req).await {
+    let (keys, dedupe, label) = match parse_keys_body(req).await {
         Ok(v) => v,
         Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
     };
@@ -793,8 +1889,9 @@ async fn api_replace_keys(req: Request<Body>, state: Arc<RouterState>, upstream_
     let upstream2 = upstream.clone();
 
     let res = tokio::task::spawn_blocking(move || -> anyhow::Result<serde_json::Value> {
-        store.replace_keys(&id, &keys)?;
-        let ks = build_key_states(keys)?;
+        let stored = store.replace_keys(&id, &keys, label.as_deref())?;
+        let ks = build_key_states(stored, &upstream2.auth_scheme)?;
+        inherit_key_breakers(&ks, &upstream2.keys.load());
         let n = ks.len();
         upstream2.keys.store(ks);
         Ok(serde_json::json!({
@@ -824,7 +1921,7 @@ async fn api_delete_keys(req: Request<Body>, state: Arc<RouterState>, upstream_i
         return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found");
     };
 
-    let (keys, dedupe) = match parse_keys_body(req).await {
+    let (keys, dedupe, _label) = match parse_keys_body(req).await {
         Ok(v) => v,
         Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e, "bad_request"),
     };
@@ -867,19 +1964,113 @@ async fn api_delete_keys(req: Request<Body>, state: Arc<RouterState>, upstream_i
     }
 }
 
+/// Criteria for `POST /upstreams/{id}/keys/prune`. All given criteria must match.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PruneKeysBody {
+    in_cooldown: bool,
+    prefix: Option<String>,
+    fail_streak_gte: Option<u32>,
+}
+
+async fn api_prune_keys(req: Request<Body>, state: Arc<RouterState>, upstream_id: &str) -> Response<Body> {
+    let Some((_idx, upstream)) = state.upstream_by_id(upstream_id) else {
+        return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found");
+    };
+    let body = match read_body_limit(req, 64 * 1024).await {
+        Ok(b) => b,
+        Err(e) => return RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request"),
+    };
+    let input: PruneKeysBody = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("invalid json: {e}"),
+                "bad_request",
+            )
+        }
+    };
+    let prefix = input.prefix.filter(|p| !p.is_empty());
+    if !input.in_cooldown && prefix.is_none() && input.fail_streak_gte.is_none() {
+        return RouterState::json_error(
+            http::StatusCode::BAD_REQUEST,
+            "no criteria provided (in_cooldown, prefix, fail_streak_gte)",
+            "bad_request",
+        );
+    }
+
+    let store = state.store.clone();
+    let id = upstream_id.to_string();
+    let res = tokio::task::spawn_blocking(move || -> anyhow::Result<serde_json::Value> {
+        let now = now_ms();
+        let matches = |k: &crate::state::KeyState| {
+            let cooling = k.cooldown_until_ms.load(std::sync::atomic::Ordering::Relaxed) > now;
+            let streak = k.fail_streak.load(std::sync::atomic::Ordering::Relaxed);
+            (!input.in_cooldown || cooling)
+                && prefix.as_deref().is_none_or(|p| k.key.starts_with(p))
+                && input.fail_streak_gte.is_none_or(|n| streak >= n)
+        };
+        // Filter the current list inside `rcu` so keys added or replaced meanwhile are kept.
+        let mut matched: Vec<Arc<crate::state::KeyState>> = Vec::new();
+        upstream.keys.rcu(|cur| {
+            let (m, kept): (Vec<_>, Vec<_>) = cur.iter().cloned().partition(|k| matches(k));
+            matched = m;
+            kept
+        });
+        let keys: Vec<String> = matched.iter().map(|k| k.key.to_string()).collect();
+        let removed = if keys.is_empty() { 0 } else { store.delete_keys(&id, &keys)? };
+
+        Ok(serde_json::json!({
+            "ok": true,
+            "upstream": id,
+            "removed": removed,
+            "keys_total": upstream.keys_len()
+        }))
+    })
+    .await;
+
+    state.record_reload("keys", matches!(res, Ok(Ok(_))));
+    match res {
+        Ok(Ok(v)) => json_ok(&v),
+        Ok(Err(e)) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
+        Err(e) => RouterState::json_error(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string(), "internal_error"),
+    }
+}
+
 #[derive(Serialize)]
 struct KeyInfo {
+    /// Masked (`sk-...abcd`) unless the listing was requested with `reveal=1`.
     key: String,
     cooldown_until_ms: u64,
     cooldown_remaining_ms: i64,
     fail_streak: u32,
     status: &'static str,
+    requests_total: u64,
+    responses_2xx: u64,
+    responses_4xx: u64,
+    responses_5xx: u64,
+    last_used_ms: u64,
+    /// Estimated remaining requests from rate-limit headers (`key_select = "remaining"`);
+    /// absent until the key has a recent reading.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ratelimit_remaining: Option<u64>,
+    /// 0 for keys added before this was recorded.
+    added_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
 }
 
 async fn api_list_keys(state: Arc<RouterState>, upstream_id: &str, uri: &http::Uri) -> Response<Body> {
     let Some((_idx, upstream)) = state.upstream_by_id(upstream_id) else {
         return RouterState::json_error(http::StatusCode::NOT_FOUND, "unknown upstream id", "not_found");
     };
+    match query_get(uri, "format") {
+        None | Some("") | Some("json") => {}
+        Some("csv") if !reveal_requested(uri) => return csv_needs_reveal(),
+        Some("csv") => return keys_csv(vec![upstream], &format!("keys-{upstream_id}.csv")),
+        Some(other) => return unknown_export_format(other),
+    }
 
     let limit: usize = query_get(uri, "limit")
         .and_then(|s: &str| s.parse::<usize>().ok())
@@ -889,42 +2080,207 @@ async fn api_list_keys(state: Arc<RouterState>, upstream_id: &str, uri: &http::U
         .and_then(|s: &str| s.parse::<usize>().ok())
         .unwrap_or(0);
 
+    // Default order is the in-memory list (insertion order), which shifts as keys are added or
+    // removed, so offset paging can skip or repeat keys under concurrent edits. `sort=key`
+    // orders by key; with `after=<last key of the previous page>` paging is stable.
+    let after = query_get(uri, "after").filter(|s| !s.is_empty());
+    let sort_by_key = match query_get(uri, "sort") {
+        None | Some("") => after.is_some(),
+        Some("key") => true,
+        Some("insertion") if after.is_some() => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                "after requires sort=key",
+                "bad_request",
+            )
+        }
+        Some("insertion") => false,
+        Some(other) => {
+            return RouterState::json_error(
+                http::StatusCode::BAD_REQUEST,
+                &format!("unknown sort {other:?} (expected key or insertion)"),
+                "bad_request",
+            )
+        }
+    };
+
+    let reveal = reveal_requested(uri);
+
     let now = now_ms();
 
     let keys_arc = upstream.keys.load_full();
-    let keys = keys_arc.as_ref();
-    let total = keys.len();
-    let end = (offset + limit).min(total);
+    let mut keys: Vec<&Arc<KeyState>> = keys_arc.iter().collect();
+    if sort_by_key {
+        keys.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+    }
+    if let Some(after) = after {
+        let after = cursor_key(&keys, after).unwrap_or(after);
+        let start = keys.partition_point(|k| &*k.key <= after);
+        keys.drain(..start);
+    }
+    let total = keys_arc.len();
+    let end = (offset + limit).min(keys.len());
 
     let mut out: Vec<KeyInfo> = Vec::with_capacity(end.saturating_sub(offset));
-    for k in keys.iter().skip(offset).take(end - offset) {
+    for k in keys.iter().skip(offset).take(end.saturating_sub(offset)) {
         let cd = k.cooldown_until_ms.load(std::sync::atomic::Ordering::Relaxed);
         let remaining = if cd > now { (cd - now) as i64 } else { 0 };
         let streak = k.fail_streak.load(std::sync::atomic::Ordering::Relaxed);
         let status = if cd > now { "banned" } else { "ok" };
         out.push(KeyInfo {
-            key: k.key.to_string(),
+            key: if reveal { k.key.to_string() } else { mask_key(&k.key) },
             cooldown_until_ms: cd,
             cooldown_remaining_ms: remaining,
             fail_streak: streak,
             status,
+            requests_total: k.requests_total.load(std::sync::atomic::Ordering::Relaxed),
+            responses_2xx: k.responses_2xx.load(std::sync::atomic::Ordering::Relaxed),
+            responses_4xx: k.responses_4xx.load(std::sync::atomic::Ordering::Relaxed),
+            responses_5xx: k.responses_5xx.load(std::sync::atomic::Ordering::Relaxed),
+            last_used_ms: k.last_used_ms.load(std::sync::atomic::Ordering::Relaxed),
+            ratelimit_remaining: Some(k.ratelimit_estimate(now)).filter(|&r| r != u64::MAX),
+            added_at_ms: k.added_at_ms,
+            label: k.label.as_deref().map(str::to_string),
         });
     }
 
+    let next_after = if sort_by_key && end < keys.len() && end > offset {
+        let (last, next) = (&keys[end - 1].key, &keys[end].key);
+        Some(if reveal { last.to_string() } else { masked_cursor(last, next) })
+    } else {
+        None
+    };
+
     json_ok(&serde_json::json!({
         "upstream": upstream_id,
         "total": total,
         "offset": offset,
         "limit": limit,
+        "sort": if sort_by_key { "key" } else { "insertion" },
+        "next_after": next_after,
         "now_ms": now,
         "keys": out
     }))
 }
 
-async fn parse_keys_body(req: Request<Body>) -> Result<(Vec<String>, bool), String> {
+/// `GET /keys?format=csv`: every upstream's keys in one CSV (CSV is the only format).
+fn api_export_keys(state: Arc<RouterState>, uri: &http::Uri) -> Response<Body> {
+    match query_get(uri, "format") {
+        Some("csv") if !reveal_requested(uri) => csv_needs_reveal(),
+        Some("csv") => keys_csv(state.snapshot.load_full().upstreams.clone(), "keys.csv"),
+        other => unknown_export_format(other.unwrap_or("")),
+    }
+}
+
+/// `reveal=1` (or `true`): the caller asked for full keys rather than masked ones.
+fn reveal_requested(uri: &http::Uri) -> bool {
+    query_get(uri, "reveal").is_some_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+/// CSV exports carry full keys, so like the JSON listing they need an explicit `reveal=1`.
+fn csv_needs_reveal() -> Response<Body> {
+    RouterState::json_error(
+        http::StatusCode::BAD_REQUEST,
+        "format=csv exports full keys; add reveal=1",
+        "bad_request",
+    )
+}
+
+fn unknown_export_format(format: &str) -> Response<Body> {
+    RouterState::json_error(
+        http::StatusCode::BAD_REQUEST,
+        &format!("unknown format {format:?} (expected csv)"),
+        "bad_request",
+    )
+}
+
+/// Stream `upstream_id,key,cooldown_until_ms,fail_streak` rows (full keys, for backups) from
+/// the in-memory key lists, as a download named `filename`.
+fn keys_csv(upstreams: Vec<Arc<Upstream>>, filename: &str) -> Response<Body> {
+    const ROWS_PER_CHUNK: usize = 1000;
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, std::io::Error>>(4);
+
+    tokio::spawn(async move {
+        let mut buf = String::from("upstream_id,key,cooldown_until_ms,fail_streak,added_at_ms,label\n");
+        let mut rows = 0;
+        for upstream in &upstreams {
+            let id = csv_field(&upstream.id);
+            for k in upstream.keys.load_full().iter() {
+                let cd = k.cooldown_until_ms.load(std::sync::atomic::Ordering::Relaxed);
+                let streak = k.fail_streak.load(std::sync::atomic::Ordering::Relaxed);
+                let label = csv_field(k.label.as_deref().unwrap_or(""));
+                buf.push_str(&format!("{id},{},{cd},{streak},{},{label}\n", csv_field(&k.key), k.added_at_ms));
+                rows += 1;
+                if rows % ROWS_PER_CHUNK == 0 && tx.send(Ok(Bytes::from(std::mem::take(&mut buf)))).await.is_err() {
+                    return;
+                }
+            }
+        }
+        if !buf.is_empty() {
+            let _ = tx.send(Ok(Bytes::from(buf))).await;
+        }
+    });
+
+    Response::builder()
+        .status(200)
+        .header("content-type", "text/csv; charset=utf-8")
+        .header("content-disposition", format!("attachment; filename=\"{filename}\""))
+        .header("cache-control", "no-store")
+        .body(Body::wrap_stream(ReceiverStream::new(rx)))
+        .unwrap()
+}
+
+/// RFC 4180 quoting, only where needed. Text a spreadsheet would evaluate as a formula
+/// (leading `=`, `+`, `-`, `@`, tab or CR) gets a `'` prefix.
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    let s: std::borrow::Cow<'_, str> = if s.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{s}").into()
+    } else {
+        s.into()
+    };
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s
+    }
+}
+
+/// `next_after` for a masked page: the shortest prefix of `next` that still sorts after `last`,
+/// so the cursor reveals no more than the masked listing does. When no proper prefix of `next`
+/// fits (`next` extends a prefix of `last` by one character) it is an opaque `#<hash>` of
+/// `last` instead, resolved by `cursor_key`.
+fn masked_cursor(last: &str, next: &str) -> String {
+    let common = last
+        .char_indices()
+        .zip(next.chars())
+        .find(|((_, a), b)| a != b)
+        .map(|((i, _), _)| i)
+        .unwrap_or(last.len());
+    let end = next[common..].chars().next().map_or(next.len(), |c| common + c.len_utf8());
+    if end < next.len() {
+        next[..end].to_string()
+    } else {
+        format!("#{:016x}", key_cursor_hash(last))
+    }
+}
+
+/// Keyed per process, so a cursor says nothing about the key and is only valid until restart.
+fn key_cursor_hash(key: &str) -> u64 {
+    static STATE: std::sync::OnceLock<ahash::RandomState> = std::sync::OnceLock::new();
+    STATE.get_or_init(ahash::RandomState::new).hash_one(key)
+}
+
+/// The key an opaque `#<hash>` cursor from `masked_cursor` stands for, if it is one.
+fn cursor_key<'a>(keys: &[&'a Arc<KeyState>], cursor: &str) -> Option<&'a str> {
+    let h = u64::from_str_radix(cursor.strip_prefix('#')?, 16).ok()?;
+    keys.iter().find(|k| key_cursor_hash(&k.key) == h).map(|k| &*k.key)
+}
+
+async fn parse_keys_body(req: Request<Body>) -> Result<(Vec<String>, bool, Option<String>), String> {
+    const MAX_LABEL_LEN: usize = 256;
     // Accept:
     // - text/plain: newline-separated keys
-    // - application/json: {"keys": ["k1", "k2"], "dedupe": true}
+    // - application/json: {"keys": ["k1", "k2"], "dedupe": true, "label": "team-a"}
     let content_type = req
         .headers()
         .get(http::header::CONTENT_TYPE)
@@ -936,7 +2292,11 @@ async fn parse_keys_body(req: Request<Body>) -> Result<(Vec<String>, bool), Stri
 
     if content_type.starts_with("application/json") {
         let v: JsonKeysBody = serde_json::from_slice(&body_bytes).map_err(|e| format!("invalid json: {e}"))?;
-        Ok((v.keys, v.dedupe.unwrap_or(true)))
+        let label = v.label.map(|l| l.trim().to_string()).filter(|l| !l.is_empty());
+        if label.as_ref().is_some_and(|l| l.len() > MAX_LABEL_LEN) {
+            return Err(format!("label too long (max {MAX_LABEL_LEN} bytes)"));
+        }
+        Ok((v.keys, v.dedupe.unwrap_or(true), label))
     } else {
         // Treat as plain text.
         let s = std::str::from_utf8(&body_bytes).map_err(|_| "body is not utf-8".to_string())?;
@@ -947,7 +2307,7 @@ async fn parse_keys_body(req: Request<Body>) -> Result<(Vec<String>, bool), Stri
                 keys.push(k.to_string());
             }
         }
-        Ok((keys, true))
+        Ok((keys, true, None))
     }
 }
 
@@ -979,6 +2339,37 @@ fn dedupe_keys(keys: Vec<String>) -> Vec<String> {
     out
 }
 
+/// Expected version from `If-Match` (`"123"`, `W/"123"` or `123`); `None` when absent or `*`.
+fn if_match_version(req: &Request<Body>) -> Result<Option<u64>, String> {
+    let Some(v) = req.headers().get(http::header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let s = v.to_str().unwrap_or("").trim();
+    if s == "*" {
+        return Ok(None);
+    }
+    let s = s.strip_prefix("W/").unwrap_or(s).trim_matches('"');
+    s.parse()
+        .map(Some)
+        .map_err(|_| format!("invalid If-Match version: {s:?}"))
+}
+
+fn with_etag(mut resp: Response<Body>, version: u64) -> Response<Body> {
+    if let Ok(v) = http::HeaderValue::from_str(&format!("\"{version}\"")) {
+        resp.headers_mut().insert(http::header::ETAG, v);
+    }
+    resp
+}
+
+/// 409 `version_conflict` for a stale `If-Match`, 400 for any other edit error.
+fn edit_error(e: anyhow::Error) -> Response<Body> {
+    if e.downcast_ref::<VersionConflict>().is_some() {
+        RouterState::json_error(http::StatusCode::CONFLICT, &e.to_string(), "version_conflict")
+    } else {
+        RouterState::json_error(http::StatusCode::BAD_REQUEST, &e.to_string(), "bad_request")
+    }
+}
+
 fn json_ok<T: ?Sized + Serialize>(v: &T) -> Response<Body> {
     let body = match serde_json::to_vec(v) {
         Ok(b) => b,
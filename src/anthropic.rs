@@ -0,0 +1,502 @@
+//! OpenAI chat completions <-> Anthropic Messages API translation, used for upstreams
+//! configured with `format = "anthropic"`.
+
+use serde_json::{json, Map, Value};
+
+/// Sent as `anthropic-version` unless the client already set one.
+pub const API_VERSION: &str = "2023-06-01";
+
+/// Anthropic requires `max_tokens`; used when the client did not send one.
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+/// Non-stream responses larger than this are relayed untranslated.
+const MAX_JSON_BYTES: usize = 32 * 1024 * 1024;
+
+/// Prompt tokens from an Anthropic `usage` object (cache reads/writes count as input).
+pub fn input_tokens(usage: &Value) -> Option<u64> {
+    let base = usage.get("input_tokens").and_then(|v| v.as_u64())?;
+    let cached = ["cache_creation_input_tokens", "cache_read_input_tokens"]
+        .iter()
+        .filter_map(|k| usage.get(*k).and_then(|v| v.as_u64()))
+        .sum::<u64>();
+    Some(base + cached)
+}
+
+/// Translate a `/v1/chat/completions` body into a `/v1/messages` body.
+/// `None` when the body is not a chat completions request.
+pub fn chat_request_to_messages(req: &Value) -> Option<Value> {
+    let obj = req.as_object()?;
+    let mut out = Map::new();
+    out.insert("model".into(), obj.get("model")?.clone());
+
+    let mut system: Vec<String> = Vec::new();
+    let mut messages: Vec<Value> = Vec::new();
+    for m in obj.get("messages")?.as_array()? {
+        let content = m.get("content");
+        match m.get("role").and_then(|v| v.as_str()).unwrap_or("user") {
+            "system" | "developer" => system.push(text_of(content)),
+            "assistant" => {
+                let mut blocks = content_blocks(content);
+                let calls = m.get("tool_calls").and_then(|v| v.as_array());
+                for call in calls.into_iter().flatten() {
+                    let f = call.get("function");
+                    let args = f
+                        .and_then(|f| f.get("arguments"))
+                        .and_then(|v| v.as_str())
+                        .and_then(|s| serde_json::from_str::<Value>(s).ok())
+                        .unwrap_or_else(|| json!({}));
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call.get("id").cloned().unwrap_or(Value::Null),
+                        "name": f.and_then(|f| f.get("name")).cloned().unwrap_or(Value::Null),
+                        "input": args,
+                    }));
+                }
+                push_message(&mut messages, "assistant", blocks);
+            }
+            "tool" => {
+                let block = json!({
+                    "type": "tool_result",
+                    "tool_use_id": m.get("tool_call_id").cloned().unwrap_or(Value::Null),
+                    "content": text_of(content),
+                });
+                push_message(&mut messages, "user", vec![block]);
+            }
+            _ => push_message(&mut messages, "user", content_blocks(content)),
+        }
+    }
+    out.insert("messages".into(), Value::Array(messages));
+
+    let system: Vec<String> = system.into_iter().filter(|s| !s.is_empty()).collect();
+    if !system.is_empty() {
+        out.insert("system".into(), Value::String(system.join("\n\n")));
+    }
+
+    let max_tokens = obj
+        .get("max_completion_tokens")
+        .or_else(|| obj.get("max_tokens"))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(DEFAULT_MAX_TOKENS);
+    out.insert("max_tokens".into(), json!(max_tokens));
+
+    for key in ["temperature", "top_p", "stream"] {
+        if let Some(v) = obj.get(key).filter(|v| !v.is_null()) {
+            out.insert(key.into(), v.clone());
+        }
+    }
+    match obj.get("stop") {
+        Some(Value::String(s)) => {
+            out.insert("stop_sequences".into(), json!([s]));
+        }
+        Some(Value::Array(a)) if !a.is_empty() => {
+            out.insert("stop_sequences".into(), Value::Array(a.clone()));
+        }
+        _ => {}
+    }
+    if let Some(user) = obj.get("user").and_then(|v| v.as_str()) {
+        out.insert("metadata".into(), json!({ "user_id": user }));
+    }
+
+    if let Some(tools) = obj.get("tools").and_then(|v| v.as_array()) {
+        let tools: Vec<Value> = tools
+            .iter()
+            .filter_map(|t| t.get("function"))
+            .map(|f| {
+                let mut tool = Map::new();
+                tool.insert("name".into(), f.get("name").cloned().unwrap_or(Value::Null));
+                if let Some(d) = f.get("description") {
+                    tool.insert("description".into(), d.clone());
+                }
+                let schema = f.get("parameters").cloned().unwrap_or_else(|| json!({"type": "object"}));
+                tool.insert("input_schema".into(), schema);
+                Value::Object(tool)
+            })
+            .collect();
+        if !tools.is_empty() {
+            out.insert("tools".into(), Value::Array(tools));
+        }
+    }
+    let tool_choice = match obj.get("tool_choice") {
+        Some(Value::String(s)) if s == "auto" => Some(json!({"type": "auto"})),
+        Some(Value::String(s)) if s == "required" => Some(json!({"type": "any"})),
+        Some(Value::String(s)) if s == "none" => Some(json!({"type": "none"})),
+        Some(Value::Object(o)) => o
+            .get("function")
+            .and_then(|f| f.get("name"))
+            .map(|name| json!({"type": "tool", "name": name})),
+        _ => None,
+    };
+    if let Some(tc) = tool_choice {
+        out.insert("tool_choice".into(), tc);
+    }
+
+    Some(Value::Object(out))
+}
+
+/// Anthropic requires alternating roles, so consecutive same-role messages are merged.
+fn push_message(messages: &mut Vec<Value>, role: &str, blocks: Vec<Value>) {
+    if blocks.is_empty() {
+        return;
+    }
+    if let Some(last) = messages.last_mut() {
+        if last.get("role").and_then(|v| v.as_str()) == Some(role) {
+            if let Some(content) = last.get_mut("content").and_then(|v| v.as_array_mut()) {
+                content.extend(blocks);
+                return;
+            }
+        }
+    }
+    messages.push(json!({ "role": role, "content": blocks }));
+}
+
+fn text_of(content: Option<&Value>) -> String {
+    match content {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .filter_map(|p| p.get("text").and_then(|v| v.as_str()))
+            .collect::<Vec<_>>()
+            .join(""),
+        _ => String::new(),
+    }
+}
+
+fn content_blocks(content: Option<&Value>) -> Vec<Value> {
+    match content {
+        Some(Value::String(s)) if !s.is_empty() => vec![json!({"type": "text", "text": s})],
+        Some(Value::Array(parts)) => parts.iter().filter_map(content_block).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn content_block(part: &Value) -> Option<Value> {
+    match part.get("type").and_then(|v| v.as_str())? {
+        "text" => {
+            let text = part.get("text").and_then(|v| v.as_str()).filter(|s| !s.is_empty())?;
+            Some(json!({"type": "text", "text": text}))
+        }
+        "image_url" => {
+            let url = part.get("image_url")?.get("url")?.as_str()?;
+            // data:<media type>;base64,<data>
+            let source = match url.strip_prefix("data:").and_then(|r| r.split_once(";base64,")) {
+                Some((media_type, data)) => {
+                    json!({"type": "base64", "media_type": media_type, "data": data})
+                }
+                None => json!({"type": "url", "url": url}),
+            };
+            Some(json!({"type": "image", "source": source}))
+        }
+        _ => None,
+    }
+}
+
+fn finish_reason(stop_reason: Option<&str>) -> &'static str {
+    match stop_reason {
+        Some("max_tokens") => "length",
+        Some("tool_use") => "tool_calls",
+        Some("refusal") => "content_filter",
+        _ => "stop",
+    }
+}
+
+fn openai_usage(prompt: u64, completion: u64) -> Value {
+    json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+    })
+}
+
+fn openai_error(v: &Value) -> Value {
+    let err = v.get("error");
+    json!({
+        "error": {
+            "message": err.and_then(|e| e.get("message")).cloned().unwrap_or(Value::Null),
+            "type": err.and_then(|e| e.get("type")).cloned().unwrap_or(Value::Null),
+            "param": null,
+            "code": null,
+        }
+    })
+}
+
+fn unix_secs() -> u64 {
+    crate::util::now_ms() / 1000
+}
+
+/// Translate a non-stream Messages response (or error) into chat completions shape;
+/// `None` for bodies that are neither.
+pub fn messages_response_to_chat(v: &Value) -> Option<Value> {
+    match v.get("type").and_then(|t| t.as_str())? {
+        "error" => Some(openai_error(v)),
+        "message" => {
+            let mut text = String::new();
+            let mut tool_calls: Vec<Value> = Vec::new();
+            for block in v.get("content").and_then(|c| c.as_array()).into_iter().flatten() {
+                match block.get("type").and_then(|t| t.as_str()) {
+                    Some("text") => {
+                        text.push_str(block.get("text").and_then(|t| t.as_str()).unwrap_or(""))
+                    }
+                    Some("tool_use") => tool_calls.push(json!({
+                        "id": block.get("id").cloned().unwrap_or(Value::Null),
+                        "type": "function",
+                        "function": {
+                            "name": block.get("name").cloned().unwrap_or(Value::Null),
+                            "arguments": block.get("input").map(|i| i.to_string()).unwrap_or_default(),
+                        }
+                    })),
+                    _ => {}
+                }
+            }
+
+            let mut message = Map::new();
+            message.insert("role".into(), json!("assistant"));
+            let content = if text.is_empty() && !tool_calls.is_empty() { Value::Null } else { json!(text) };
+            message.insert("content".into(), content);
+            if !tool_calls.is_empty() {
+                message.insert("tool_calls".into(), Value::Array(tool_calls));
+            }
+
+            let usage = v.get("usage");
+            let prompt = usage.and_then(input_tokens).unwrap_or(0);
+            let completion = usage
+                .and_then(|u| u.get("output_tokens"))
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0);
+            Some(json!({
+                "id": v.get("id").cloned().unwrap_or(Value::Null),
+                "object": "chat.completion",
+                "created": unix_secs(),
+                "model": v.get("model").cloned().unwrap_or(Value::Null),
+                "choices": [{
+                    "index": 0,
+                    "message": Value::Object(message),
+                    "finish_reason": finish_reason(v.get("stop_reason").and_then(|s| s.as_str())),
+                }],
+                "usage": openai_usage(prompt, completion),
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Incremental rewriter for an Anthropic response body.
+pub enum ResponseTranslator {
+    /// Buffered until the end; `None` once the body proved too large to translate.
+    Json(Option<Vec<u8>>),
+    Sse(SseTranslator),
+}
+
+impl ResponseTranslator {
+    /// Bytes to relay for this chunk (possibly empty).
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Json(slot) => match slot {
+                Some(buf) if buf.len().saturating_add(chunk.len()) <= MAX_JSON_BYTES => {
+                    buf.extend_from_slice(chunk);
+                    Vec::new()
+                }
+                Some(buf) => {
+                    let mut out = std::mem::take(buf);
+                    out.extend_from_slice(chunk);
+                    *slot = None;
+                    out
+                }
+                None => chunk.to_vec(),
+            },
+            Self::Sse(t) => t.push(chunk),
+        }
+    }
+
+    /// Remaining bytes to relay once the upstream body has ended.
+    pub fn finish(&mut self) -> Vec<u8> {
+        match self {
+            Self::Json(slot) => {
+                let Some(buf) = slot.take() else {
+                    return Vec::new();
+                };
+                serde_json::from_slice::<Value>(&buf)
+                    .ok()
+                    .and_then(|v| messages_response_to_chat(&v))
+                    .and_then(|v| serde_json::to_vec(&v).ok())
+                    .unwrap_or(buf)
+            }
+            Self::Sse(t) => t.finish(),
+        }
+    }
+
+    /// (prompt, completion) tokens seen in a translated stream.
+    pub fn usage(&self) -> Option<(u64, u64)> {
+        match self {
+            Self::Json(_) => None,
+            Self::Sse(t) => t.usage,
+        }
+    }
+}
+
+/// Rewrites Anthropic SSE events into `chat.completion.chunk` events.
+pub struct SseTranslator {
+    /// Raw bytes of the unfinished line; decoded only once its `\n` arrives, so a character
+    /// split across network chunks is not mangled.
+    buf: Vec<u8>,
+    id: String,
+    model: String,
+    created: u64,
+    include_usage: bool,
+    usage: Option<(u64, u64)>,
+    /// Content block index -> tool call index, for tool_use blocks.
+    tool_blocks: Vec<(u64, usize)>,
+}
+
+impl SseTranslator {
+    pub fn new(include_usage: bool) -> Self {
+        Self {
+            buf: Vec::new(),
+            id: String::new(),
+            model: String::new(),
+            created: unix_secs(),
+            include_usage,
+            usage: None,
+            tool_blocks: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        let mut start = 0;
+        while let Some(pos) = self.buf[start..].iter().position(|&b| b == b'\n') {
+            let end = start + pos;
+            let line = String::from_utf8_lossy(&self.buf[start..end]).into_owned();
+            self.line(line.trim_end_matches('\r'), &mut out);
+            start = end + 1;
+        }
+        self.buf.drain(..start);
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let rest = std::mem::take(&mut self.buf);
+        let rest = String::from_utf8_lossy(&rest);
+        let mut out = Vec::new();
+        self.line(rest.trim_end_matches('\r'), &mut out);
+        out
+    }
+
+    fn line(&mut self, line: &str, out: &mut Vec<u8>) {
+        let Some(data) = line.strip_prefix("data:") else {
+            return;
+        };
+        let Ok(ev) = serde_json::from_str::<Value>(data.trim()) else {
+            return;
+        };
+        match ev.get("type").and_then(|t| t.as_str()).unwrap_or("") {
+            "message_start" => {
+                let msg = ev.get("message");
+                let field = |k: &str| {
+                    msg.and_then(|m| m.get(k)).and_then(|v| v.as_str()).unwrap_or("").to_string()
+                };
+                self.id = field("id");
+                self.model = field("model");
+                if let Some(u) = msg.and_then(|m| m.get("usage")) {
+                    self.merge_usage(u);
+                }
+                self.emit(out, json!({"role": "assistant", "content": ""}), None);
+            }
+            "content_block_start" => {
+                let block = ev.get("content_block");
+                if block.and_then(|b| b.get("type")).and_then(|t| t.as_str()) != Some("tool_use") {
+                    return;
+                }
+                let index = ev.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                let call = self.tool_blocks.len();
+                self.tool_blocks.push((index, call));
+                let delta = json!({"tool_calls": [{
+                    "index": call,
+                    "id": block.and_then(|b| b.get("id")).cloned().unwrap_or(Value::Null),
+                    "type": "function",
+                    "function": {
+                        "name": block.and_then(|b| b.get("name")).cloned().unwrap_or(Value::Null),
+                        "arguments": "",
+                    },
+                }]});
+                self.emit(out, delta, None);
+            }
+            "content_block_delta" => {
+                let delta = ev.get("delta");
+                let field = |k: &str| delta.and_then(|d| d.get(k)).and_then(|v| v.as_str());
+                match field("type") {
+                    Some("text_delta") => {
+                        let text = field("text").unwrap_or("");
+                        self.emit(out, json!({"content": text}), None);
+                    }
+                    Some("input_json_delta") => {
+                        let index = ev.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                        let Some(&(_, call)) = self.tool_blocks.iter().find(|(i, _)| *i == index)
+                        else {
+                            return;
+                        };
+                        let args = field("partial_json").unwrap_or("");
+                        let delta = json!({"tool_calls": [{
+                            "index": call,
+                            "function": {"arguments": args},
+                        }]});
+                        self.emit(out, delta, None);
+                    }
+                    _ => {}
+                }
+            }
+            "message_delta" => {
+                if let Some(u) = ev.get("usage") {
+                    self.merge_usage(u);
+                }
+                let stop = ev.get("delta").and_then(|d| d.get("stop_reason")).and_then(|s| s.as_str());
+                self.emit(out, json!({}), Some(finish_reason(stop)));
+            }
+            "message_stop" => {
+                if self.include_usage {
+                    let (prompt, completion) = self.usage.unwrap_or((0, 0));
+                    let chunk = json!({
+                        "id": self.id,
+                        "object": "chat.completion.chunk",
+                        "created": self.created,
+                        "model": self.model,
+                        "choices": [],
+                        "usage": openai_usage(prompt, completion),
+                    });
+                    write_event(out, &chunk);
+                }
+                out.extend_from_slice(b"data: [DONE]\n\n");
+            }
+            "error" => write_event(out, &openai_error(&ev)),
+            _ => {}
+        }
+    }
+
+    fn merge_usage(&mut self, u: &Value) {
+        let (mut prompt, mut completion) = self.usage.unwrap_or((0, 0));
+        if let Some(p) = input_tokens(u) {
+            prompt = p;
+        }
+        if let Some(c) = u.get("output_tokens").and_then(|v| v.as_u64()) {
+            completion = c;
+        }
+        self.usage = Some((prompt, completion));
+    }
+
+    fn emit(&self, out: &mut Vec<u8>, delta: Value, finish: Option<&str>) {
+        let chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish}],
+        });
+        write_event(out, &chunk);
+    }
+}
+
+fn write_event(out: &mut Vec<u8>, v: &Value) {
+    out.extend_from_slice(b"data: ");
+    out.extend_from_slice(v.to_string().as_bytes());
+    out.extend_from_slice(b"\n\n");
+}
@@ -1,105 +1,336 @@
+use crate::config::ModelPrice;
 use crate::storage::KeyStore;
+use crate::util::now_ms;
 use ahash::AHashMap;
-use std::sync::atomic::{AtomicI64, Ordering};
-use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use arc_swap::ArcSwapOption;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
+const DAY_MS: u64 = 86_400_000;
+
 pub struct BillingStore {
-    balances: Arc<RwLock<AHashMap<String, Arc<AtomicI64>>>>,
+    accounts: Arc<RwLock<AHashMap<String, Arc<Account>>>>,
     persist_tx: Sender<PersistUpdate>,
+    /// `model_prices`; models not listed are billed one unit per token.
+    prices: AHashMap<String, ModelPrice>,
+    /// Charged since startup, for `/metrics`.
+    tokens_charged: AtomicU64,
+    cost_charged: AtomicU64,
+    persister: Arc<PersisterState>,
+    /// `read_only`: balances are checked but never charged or written.
+    read_only: bool,
+}
+
+/// Liveness of the persist thread: it bumps `heartbeat_ms` at least every 500ms.
+struct PersisterState {
+    heartbeat_ms: AtomicU64,
+    restarts: AtomicU64,
+}
+
+/// How long the persist thread may go without a heartbeat before it counts as stalled.
+const PERSISTER_STALE_MS: u64 = 5_000;
+
+#[derive(Clone, Copy, serde::Serialize)]
+pub struct PersisterHealth {
+    pub healthy: bool,
+    pub last_heartbeat_ms: u64,
+    /// Times the persist loop was restarted after a panic.
+    pub restarts: u64,
+}
+
+/// Totals across all keys, for `/metrics` (no per-key series).
+pub struct BillingAggregates {
+    pub keys: u64,
+    pub balance_total: i64,
+    /// Keys with a zero or negative balance.
+    pub keys_depleted: u64,
+    pub tokens_charged: u64,
+    pub cost_charged: u64,
+}
+
+/// Balance plus the optional daily token quota (`quota_tokens == 0` means unlimited).
+/// `used_today` counts tokens charged on UTC day `quota_day` (days since the epoch).
+/// `allowed_upstreams` restricts routing to those upstream ids; `None` allows any.
+#[derive(Default)]
+struct Account {
+    balance: AtomicI64,
+    quota_tokens: AtomicU64,
+    used_today: AtomicU64,
+    quota_day: AtomicU64,
+    allowed_upstreams: ArcSwapOption<Vec<String>>,
+}
+
+impl Account {
+    fn from_record(r: AccountRecord) -> Self {
+        Self {
+            balance: AtomicI64::new(r.balance),
+            quota_tokens: AtomicU64::new(r.quota_tokens),
+            used_today: AtomicU64::new(r.used_today),
+            quota_day: AtomicU64::new(r.quota_day),
+            allowed_upstreams: ArcSwapOption::new(
+                (!r.allowed_upstreams.is_empty()).then(|| Arc::new(r.allowed_upstreams)),
+            ),
+        }
+    }
+
+    fn record(&self) -> AccountRecord {
+        AccountRecord {
+            balance: self.balance.load(Ordering::Relaxed),
+            quota_tokens: self.quota_tokens.load(Ordering::Relaxed),
+            used_today: self.used_today.load(Ordering::Relaxed),
+            quota_day: self.quota_day.load(Ordering::Relaxed),
+            allowed_upstreams: self
+                .allowed_upstreams
+                .load()
+                .as_deref()
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Reset `used_today` when the UTC day changed since the last charge.
+    fn roll_day(&self, today: u64) {
+        let day = self.quota_day.load(Ordering::Acquire);
+        if day == today {
+            return;
+        }
+        // Subtract what was read before the day flipped instead of storing 0: a charge that
+        // lands between the CAS and the reset (already on the new day) must not be wiped.
+        let used = self.used_today.load(Ordering::Relaxed);
+        if self
+            .quota_day
+            .compare_exchange(day, today, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.used_today.fetch_sub(used, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct AccountRecord {
+    balance: i64,
+    quota_tokens: u64,
+    used_today: u64,
+    quota_day: u64,
+    /// Empty allows any upstream.
+    allowed_upstreams: Vec<String>,
+}
+
+/// Balance held for an in-flight request. `settle` charges the actual usage against it;
+/// dropping it unsettled (no usage, or the request failed) refunds it in full.
+pub struct Reservation {
+    billing: Arc<BillingStore>,
+    key: String,
+    amount: u64,
+}
+
+impl Reservation {
+    pub fn settle(mut self, model: Option<&str>, prompt_tokens: u64, completion_tokens: u64, total_tokens: u64) {
+        let amount = std::mem::take(&mut self.amount);
+        let _ = self.billing.apply_usage(
+            &self.key,
+            model,
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+            amount,
+        );
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.amount > 0 {
+            if let Ok(delta) = i64::try_from(self.amount) {
+                let _ = self.billing.adjust_balance(&self.key, delta);
+            }
+        }
+    }
+}
+
+/// Daily quota state for the admin API.
+pub struct QuotaInfo {
+    pub quota_tokens: u64,
+    pub used_today: u64,
 }
 
 enum PersistUpdate {
-    Set { key: String, balance: i64 },
+    Set { key: String, record: AccountRecord },
+    Delete { key: String },
+    /// Write out everything pending now and report the tree flush result.
+    Flush { done: Sender<Result<(), String>> },
+}
+
+fn today() -> u64 {
+    now_ms() / DAY_MS
+}
+
+/// Milliseconds until the next UTC midnight, when daily quotas reset.
+pub fn ms_until_quota_reset() -> u64 {
+    DAY_MS - now_ms() % DAY_MS
 }
 
 impl BillingStore {
-    pub fn new(store: &KeyStore) -> anyhow::Result<Self> {
+    pub fn new(
+        store: &KeyStore,
+        prices: AHashMap<String, ModelPrice>,
+        read_only: bool,
+    ) -> anyhow::Result<Self> {
         let tree = store.open_billing_tree()?;
-        let balances = Arc::new(RwLock::new(AHashMap::new()));
+        let accounts = Arc::new(RwLock::new(AHashMap::new()));
 
         {
-            let mut map = balances
+            let mut map = accounts
                 .write()
                 .map_err(|_| anyhow::anyhow!("billing balances lock poisoned"))?;
             for item in tree.iter() {
                 let (k, v) = item?;
                 let key = String::from_utf8_lossy(&k).to_string();
-                if let Some(balance) = decode_balance(&v) {
-                    map.insert(key, Arc::new(AtomicI64::new(balance)));
+                if let Some(record) = decode_record(&v) {
+                    map.insert(key, Arc::new(Account::from_record(record)));
                 }
             }
         }
 
         let (tx, rx) = mpsc::channel::<PersistUpdate>();
+        let persister = Arc::new(PersisterState {
+            heartbeat_ms: AtomicU64::new(now_ms()),
+            restarts: AtomicU64::new(0),
+        });
         let persist_tree = tree.clone();
-        thread::spawn(move || {
-            let mut pending: AHashMap<String, i64> = AHashMap::new();
-            let mut last_flush = Instant::now();
-            loop {
-                match rx.recv_timeout(Duration::from_millis(500)) {
-                    Ok(msg) => match msg {
-                        PersistUpdate::Set { key, balance } => {
-                            pending.insert(key, balance);
+        let thread_state = persister.clone();
+        thread::Builder::new()
+            .name("billing-persist".into())
+            .spawn(move || {
+                // `None` is a tombstone: the key is removed from the tree on flush.
+                let mut pending: AHashMap<String, Option<AccountRecord>> = AHashMap::new();
+                // A panic (e.g. inside sled) restarts the loop on the same channel, so queued
+                // and future writes are not lost with the thread.
+                loop {
+                    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+                        persist_loop(&rx, &persist_tree, &mut pending, &thread_state)
+                    }));
+                    match run {
+                        Ok(()) => break,
+                        Err(_) => {
+                            let n = thread_state.restarts.fetch_add(1, Ordering::Relaxed) + 1;
+                            tracing::error!(restarts = n, "billing persist loop panicked; restarting");
                         }
-                    },
-                    Err(RecvTimeoutError::Timeout) => {}
-                    Err(RecvTimeoutError::Disconnected) => break,
+                    }
                 }
-
-                if pending.len() >= 1024 || last_flush.elapsed() >= Duration::from_secs(1) {
+                if !pending.is_empty() {
                     flush_pending(&persist_tree, &mut pending);
-                    last_flush = Instant::now();
                 }
-            }
-
-            if !pending.is_empty() {
-                flush_pending(&persist_tree, &mut pending);
-            }
-        });
+            })?;
 
         Ok(Self {
-            balances,
+            accounts,
             persist_tx: tx,
+            prices,
+            tokens_charged: AtomicU64::new(0),
+            cost_charged: AtomicU64::new(0),
+            persister,
+            read_only,
         })
     }
 
-    pub fn create_key(&self, key: String, balance: i64) -> anyhow::Result<bool> {
+    fn account(&self, key: &str) -> Option<Arc<Account>> {
+        let map = self.accounts.read().ok()?;
+        map.get(key).cloned()
+    }
+
+    /// Queue a write of `account`, unless the key was deleted meanwhile. The check and send
+    /// happen under the read lock so a write can't be queued after the delete's tombstone.
+    fn persist(&self, key: &str, account: &Account) {
+        if self.read_only {
+            return;
+        }
+        let Ok(map) = self.accounts.read() else {
+            return;
+        };
+        if !map.get(key).is_some_and(|a| std::ptr::eq(&**a, account)) {
+            return;
+        }
+        let _ = self.persist_tx.send(PersistUpdate::Set {
+            key: key.to_string(),
+            record: account.record(),
+        });
+    }
+
+    pub fn create_key(
+        &self,
+        key: String,
+        balance: i64,
+        quota_tokens: u64,
+        allowed_upstreams: Vec<String>,
+    ) -> anyhow::Result<bool> {
         let mut map = self
-            .balances
+            .accounts
             .write()
             .map_err(|_| anyhow::anyhow!("billing balances lock poisoned"))?;
         if map.contains_key(&key) {
             return Ok(false);
         }
-        map.insert(key.clone(), Arc::new(AtomicI64::new(balance)));
+        let account = Arc::new(Account::from_record(AccountRecord {
+            balance,
+            quota_tokens,
+            used_today: 0,
+            quota_day: today(),
+            allowed_upstreams: normalize_upstream_list(allowed_upstreams),
+        }));
+        map.insert(key.clone(), account.clone());
         drop(map);
-        let _ = self
-            .persist_tx
-            .send(PersistUpdate::Set { key, balance });
+        self.persist(&key, &account);
         Ok(true)
     }
 
+    /// Remove the key from memory and the billing tree. Returns false if it did not exist.
+    pub fn delete_key(&self, key: &str) -> anyhow::Result<bool> {
+        let mut map = self
+            .accounts
+            .write()
+            .map_err(|_| anyhow::anyhow!("billing balances lock poisoned"))?;
+        if map.remove(key).is_none() {
+            return Ok(false);
+        }
+        let _ = self.persist_tx.send(PersistUpdate::Delete {
+            key: key.to_string(),
+        });
+        Ok(true)
+    }
+
+    /// All keys with their balances, sorted by key.
+    pub fn list_keys(&self) -> Vec<(String, i64)> {
+        let Ok(map) = self.accounts.read() else {
+            return Vec::new();
+        };
+        let mut out: Vec<(String, i64)> = map
+            .iter()
+            .map(|(k, a)| (k.clone(), a.balance.load(Ordering::Relaxed)))
+            .collect();
+        drop(map);
+        out.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+
     pub fn get_balance(&self, key: &str) -> Option<i64> {
-        let map = self.balances.read().ok()?;
-        map.get(key).map(|v| v.load(Ordering::Relaxed))
+        let map = self.accounts.read().ok()?;
+        map.get(key).map(|v| v.balance.load(Ordering::Relaxed))
     }
 
     pub fn adjust_balance(&self, key: &str, delta: i64) -> Option<i64> {
-        let map = self.balances.read().ok()?;
-        let balance = map.get(key)?.clone();
-        drop(map);
-        let mut cur = balance.load(Ordering::Relaxed);
+        let account = self.account(key)?;
+        let mut cur = account.balance.load(Ordering::Relaxed);
         loop {
             let new_balance = cur.saturating_add(delta);
-            match balance.compare_exchange(cur, new_balance, Ordering::Relaxed, Ordering::Relaxed) {
+            match account.balance.compare_exchange(cur, new_balance, Ordering::Relaxed, Ordering::Relaxed) {
                 Ok(_) => {
-                    let _ = self.persist_tx.send(PersistUpdate::Set {
-                        key: key.to_string(),
-                        balance: new_balance,
-                    });
+                    self.persist(key, &account);
                     return Some(new_balance);
                 }
                 Err(v) => cur = v,
@@ -107,32 +338,356 @@ impl BillingStore {
         }
     }
 
-    pub fn apply_usage(&self, key: &str, total_tokens: u64) -> Option<i64> {
-        let delta = i64::try_from(total_tokens).ok()?;
+    /// Balance units charged for a response: `model_prices` when the model has an entry,
+    /// otherwise one unit per token. Fractions round up.
+    pub fn usage_cost(&self, model: Option<&str>, prompt_tokens: u64, completion_tokens: u64) -> u64 {
+        match model.and_then(|m| self.prices.get(m)) {
+            Some(p) => {
+                let cost = (prompt_tokens as f64 * p.prompt_per_1k
+                    + completion_tokens as f64 * p.completion_per_1k)
+                    / 1000.0;
+                cost.ceil() as u64
+            }
+            None => prompt_tokens.saturating_add(completion_tokens),
+        }
+    }
+
+    /// Charge a response: `total_tokens` counts toward the daily quota and the priced cost of
+    /// the prompt/completion split is deducted from the balance, less `prepaid` (an already
+    /// deducted reservation; the difference is refunded when it exceeds the cost).
+    pub fn apply_usage(
+        &self,
+        key: &str,
+        model: Option<&str>,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+        total_tokens: u64,
+        prepaid: u64,
+    ) -> Option<i64> {
+        if self.read_only {
+            return self.get_balance(key);
+        }
+        let account = self.account(key)?;
+        if total_tokens > 0 {
+            account.roll_day(today());
+            account.used_today.fetch_add(total_tokens, Ordering::Relaxed);
+        }
+        let cost = self.usage_cost(model, prompt_tokens, completion_tokens);
+        self.tokens_charged.fetch_add(total_tokens, Ordering::Relaxed);
+        self.cost_charged.fetch_add(cost, Ordering::Relaxed);
+        // Absurd usage saturates the charge rather than skipping it.
+        let delta = i64::try_from(cost)
+            .unwrap_or(i64::MAX)
+            .saturating_sub(i64::try_from(prepaid).unwrap_or(i64::MAX));
         if delta == 0 {
+            if total_tokens > 0 {
+                self.persist(key, &account);
+            }
             return self.get_balance(key);
         }
         self.adjust_balance(key, -delta)
     }
+
+    /// Deduct `amount` up front for a request (`billing_reserve`). Returns None, with nothing
+    /// deducted, when the key is unknown or its balance can't cover the reservation.
+    pub fn reserve(self: &Arc<Self>, key: &str, amount: u64) -> Option<Reservation> {
+        let delta = i64::try_from(amount).ok()?;
+        if self.read_only {
+            // Admission is still checked; nothing is held, so settle and drop are no-ops.
+            return (self.get_balance(key)? >= delta).then(|| Reservation {
+                billing: self.clone(),
+                key: key.to_string(),
+                amount: 0,
+            });
+        }
+        let balance = self.adjust_balance(key, -delta)?;
+        if balance < 0 {
+            self.adjust_balance(key, delta);
+            return None;
+        }
+        Some(Reservation {
+            billing: self.clone(),
+            key: key.to_string(),
+            amount,
+        })
+    }
+
+    /// Set the daily token quota (0 = unlimited).
+    pub fn set_quota(&self, key: &str, quota_tokens: u64) -> Option<QuotaInfo> {
+        let account = self.account(key)?;
+        account.quota_tokens.store(quota_tokens, Ordering::Relaxed);
+        self.persist(key, &account);
+        self.get_quota(key)
+    }
+
+    /// Restrict the key to these upstream ids; an empty list allows any. Returns the stored
+    /// (sorted, deduplicated) list, or None when the key doesn't exist.
+    pub fn set_allowed_upstreams(&self, key: &str, upstreams: Vec<String>) -> Option<Vec<String>> {
+        let account = self.account(key)?;
+        let list = normalize_upstream_list(upstreams);
+        account
+            .allowed_upstreams
+            .store((!list.is_empty()).then(|| Arc::new(list.clone())));
+        self.persist(key, &account);
+        Some(list)
+    }
+
+    /// Upstream ids the key may be routed to; None when unrestricted (or the key is unknown).
+    pub fn allowed_upstreams(&self, key: &str) -> Option<Arc<Vec<String>>> {
+        self.account(key)?.allowed_upstreams.load_full()
+    }
+
+    pub fn get_quota(&self, key: &str) -> Option<QuotaInfo> {
+        let account = self.account(key)?;
+        account.roll_day(today());
+        Some(QuotaInfo {
+            quota_tokens: account.quota_tokens.load(Ordering::Relaxed),
+            used_today: account.used_today.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Durability barrier: blocks until every balance change queued so far is on disk.
+    pub fn flush(&self, timeout: Duration) -> anyhow::Result<()> {
+        let (done, rx) = mpsc::channel();
+        self.persist_tx
+            .send(PersistUpdate::Flush { done })
+            .map_err(|_| anyhow::anyhow!("billing persist thread is gone"))?;
+        match rx.recv_timeout(timeout) {
+            Ok(res) => res.map_err(|e| anyhow::anyhow!("billing flush: {e}")),
+            Err(_) => anyhow::bail!("billing flush timed out"),
+        }
+    }
+
+    pub fn persister_health(&self) -> PersisterHealth {
+        let last = self.persister.heartbeat_ms.load(Ordering::Relaxed);
+        PersisterHealth {
+            healthy: now_ms().saturating_sub(last) <= PERSISTER_STALE_MS,
+            last_heartbeat_ms: last,
+            restarts: self.persister.restarts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn aggregates(&self) -> BillingAggregates {
+        let mut agg = BillingAggregates {
+            keys: 0,
+            balance_total: 0,
+            keys_depleted: 0,
+            tokens_charged: self.tokens_charged.load(Ordering::Relaxed),
+            cost_charged: self.cost_charged.load(Ordering::Relaxed),
+        };
+        if let Ok(map) = self.accounts.read() {
+            for a in map.values() {
+                let balance = a.balance.load(Ordering::Relaxed);
+                agg.keys += 1;
+                agg.balance_total = agg.balance_total.saturating_add(balance);
+                if balance <= 0 {
+                    agg.keys_depleted += 1;
+                }
+            }
+        }
+        agg
+    }
+
+    /// True when the key has a daily quota and today's usage has reached it.
+    pub fn quota_exceeded(&self, key: &str) -> bool {
+        self.get_quota(key)
+            .is_some_and(|q| q.quota_tokens > 0 && q.used_today >= q.quota_tokens)
+    }
 }
 
-fn decode_balance(bytes: &[u8]) -> Option<i64> {
-    if bytes.len() == 8 {
+fn persist_loop(
+    rx: &Receiver<PersistUpdate>,
+    tree: &sled::Tree,
+    pending: &mut AHashMap<String, Option<AccountRecord>>,
+    state: &PersisterState,
+) {
+    let mut last_flush = Instant::now();
+    loop {
+        state.heartbeat_ms.store(now_ms(), Ordering::Relaxed);
+        match rx.recv_timeout(Duration::from_millis(500)) {
+            Ok(msg) => match msg {
+                PersistUpdate::Set { key, record } => {
+                    pending.insert(key, Some(record));
+                }
+                PersistUpdate::Delete { key } => {
+                    pending.insert(key, None);
+                }
+                PersistUpdate::Flush { done } => {
+                    flush_pending(tree, pending);
+                    last_flush = Instant::now();
+                    let _ = done.send(tree.flush().map(|_| ()).map_err(|e| e.to_string()));
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if pending.len() >= 1024 || last_flush.elapsed() >= Duration::from_secs(1) {
+            flush_pending(tree, pending);
+            last_flush = Instant::now();
+        }
+    }
+}
+
+/// 8 bytes: legacy balance-only record; 32 bytes: balance, quota, used today, quota day;
+/// longer: the same 32 bytes followed by `allowed_upstreams` as a JSON array.
+fn decode_record(bytes: &[u8]) -> Option<AccountRecord> {
+    let word = |i: usize| {
         let mut arr = [0u8; 8];
-        arr.copy_from_slice(bytes);
-        Some(i64::from_le_bytes(arr))
-    } else {
-        None
+        arr.copy_from_slice(&bytes[i * 8..(i + 1) * 8]);
+        arr
+    };
+    match bytes.len() {
+        8 => Some(AccountRecord {
+            balance: i64::from_le_bytes(word(0)),
+            ..Default::default()
+        }),
+        n if n >= 32 => Some(AccountRecord {
+            balance: i64::from_le_bytes(word(0)),
+            quota_tokens: u64::from_le_bytes(word(1)),
+            used_today: u64::from_le_bytes(word(2)),
+            quota_day: u64::from_le_bytes(word(3)),
+            allowed_upstreams: if n > 32 {
+                // Keep the balance and quota even if the list is unreadable.
+                serde_json::from_slice(&bytes[32..]).unwrap_or_else(|e| {
+                    tracing::warn!(error = %e, "billing record has a corrupt allowed_upstreams list; ignoring it");
+                    Vec::new()
+                })
+            } else {
+                Vec::new()
+            },
+        }),
+        _ => None,
     }
 }
 
-fn flush_pending(tree: &sled::Tree, pending: &mut AHashMap<String, i64>) {
+fn encode_record(r: &AccountRecord) -> Vec<u8> {
+    let mut out = Vec::with_capacity(32);
+    out.extend_from_slice(&r.balance.to_le_bytes());
+    out.extend_from_slice(&r.quota_tokens.to_le_bytes());
+    out.extend_from_slice(&r.used_today.to_le_bytes());
+    out.extend_from_slice(&r.quota_day.to_le_bytes());
+    if !r.allowed_upstreams.is_empty() {
+        if let Ok(list) = serde_json::to_vec(&r.allowed_upstreams) {
+            out.extend_from_slice(&list);
+        }
+    }
+    out
+}
+
+fn normalize_upstream_list(mut list: Vec<String>) -> Vec<String> {
+    list.sort_unstable();
+    list.dedup();
+    list
+}
+
+fn flush_pending(tree: &sled::Tree, pending: &mut AHashMap<String, Option<AccountRecord>>) {
     if pending.is_empty() {
         return;
     }
-    for (key, balance) in pending.drain() {
-        let encoded = balance.to_le_bytes();
-        let _ = tree.insert(key.as_bytes(), &encoded);
+    for (key, record) in pending.drain() {
+        match record {
+            Some(record) => {
+                let _ = tree.insert(key.as_bytes(), encode_record(&record));
+            }
+            None => {
+                let _ = tree.remove(key.as_bytes());
+            }
+        }
     }
     let _ = tree.flush();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const KEY: &str = "sk-client";
+
+    /// A store holding `KEY` with `balance`, backed by a scratch data dir. `gpt-4o` is priced
+    /// at 1 unit per 1k prompt and 2 per 1k completion tokens; other models cost 1 per token.
+    fn billing(name: &str, balance: i64, read_only: bool) -> (Arc<BillingStore>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("gptload-billing-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = KeyStore::open(&dir).unwrap();
+        let price = ModelPrice { prompt_per_1k: 1.0, completion_per_1k: 2.0 };
+        let prices = [("gpt-4o".to_string(), price)].into_iter().collect();
+        let billing = Arc::new(BillingStore::new(&store, prices, read_only).unwrap());
+        billing.create_key(KEY.to_string(), balance, 0, Vec::new()).unwrap();
+        (billing, dir)
+    }
+
+    #[test]
+    fn usage_cost_uses_prices_and_rounds_up() {
+        let (b, dir) = billing("cost", 0, false);
+        assert_eq!(b.usage_cost(Some("other"), 11, 7), 18);
+        assert_eq!(b.usage_cost(None, 11, 7), 18);
+        assert_eq!(b.usage_cost(Some("gpt-4o"), 1000, 1000), 3);
+        assert_eq!(b.usage_cost(Some("gpt-4o"), 1, 0), 1);
+        assert_eq!(b.usage_cost(Some("other"), u64::MAX, 7), u64::MAX);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn settle_charges_usage_against_the_reservation() {
+        let (b, dir) = billing("settle", 1_000, false);
+        let r = b.reserve(KEY, 100).unwrap();
+        assert_eq!(b.get_balance(KEY), Some(900));
+        // Cheaper than reserved: the rest is refunded.
+        r.settle(Some("other"), 11, 7, 18);
+        assert_eq!(b.get_balance(KEY), Some(982));
+
+        // Dearer than reserved: the difference is charged on top.
+        let r = b.reserve(KEY, 10).unwrap();
+        r.settle(Some("other"), 30, 0, 30);
+        assert_eq!(b.get_balance(KEY), Some(952));
+        assert_eq!(b.get_quota(KEY).unwrap().used_today, 48);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn unsettled_reservation_is_refunded() {
+        let (b, dir) = billing("refund", 1_000, false);
+        let r = b.reserve(KEY, 250).unwrap();
+        assert_eq!(b.get_balance(KEY), Some(750));
+        drop(r);
+        assert_eq!(b.get_balance(KEY), Some(1_000));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reserve_rejects_what_the_balance_cannot_cover() {
+        let (b, dir) = billing("reject", 100, false);
+        assert!(b.reserve(KEY, 101).is_none());
+        assert!(b.reserve("sk-unknown", 1).is_none());
+        assert!(b.reserve(KEY, u64::MAX).is_none());
+        assert_eq!(b.get_balance(KEY), Some(100));
+        assert!(b.reserve(KEY, 100).is_some());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn oversized_usage_saturates_the_charge() {
+        let (b, dir) = billing("huge", 1_000, false);
+        let r = b.reserve(KEY, 100).unwrap();
+        r.settle(Some("other"), u64::MAX, 7, u64::MAX);
+        assert_eq!(b.get_balance(KEY), Some(1_000 - i64::MAX));
+        // Already negative: a second one bottoms out instead of wrapping around.
+        b.apply_usage(KEY, Some("other"), u64::MAX, 7, u64::MAX, 0);
+        b.apply_usage(KEY, Some("other"), u64::MAX, 7, u64::MAX, 0);
+        assert_eq!(b.get_balance(KEY), Some(i64::MIN));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn read_only_admits_without_charging() {
+        let (b, dir) = billing("read-only", 100, true);
+        assert!(b.reserve(KEY, 101).is_none());
+        let r = b.reserve(KEY, 50).unwrap();
+        assert_eq!(b.get_balance(KEY), Some(100));
+        r.settle(Some("other"), 11, 7, 18);
+        assert_eq!(b.get_balance(KEY), Some(100));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
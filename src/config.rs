@@ -1,52 +1,405 @@
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
-    /// Proxy listen address, HTTP only.
-    pub listen_addr: String,
+    /// Proxy listen address(es): a string (comma-separated allowed) or a list.
+    pub listen_addr: ListenAddr,
+
+    /// Accept queue length for the listening socket (default 1024).
+    pub listen_backlog: Option<u32>,
+
+    /// Set SO_REUSEADDR on the listening socket (default true).
+    pub reuse_addr: Option<bool>,
+
+    /// Set SO_REUSEPORT so several processes can share `listen_addr` (default false, unix only).
+    pub reuse_port: Option<bool>,
+
+    /// PEM certificate chain; with `tls_key_path`, the listener serves HTTPS.
+    pub tls_cert_path: Option<String>,
+
+    /// PEM private key for `tls_cert_path`.
+    pub tls_key_path: Option<String>,
 
     /// Tokio runtime worker threads.
     pub worker_threads: Option<usize>,
 
+    /// Standby mode: proxy traffic is served from the loaded state, but nothing is changed or
+    /// persisted (mutating admin endpoints return 403 `read_only`, billing is not charged).
+    pub read_only: Option<bool>,
+
+    /// On SIGTERM/ctrl-c, how long to wait for in-flight requests before exiting (ms,
+    /// default 30000). Pending billing and request-log writes are flushed either way.
+    pub shutdown_timeout_ms: Option<u64>,
+
     /// Upstream request timeout (ms).
     pub request_timeout_ms: u64,
 
+    /// Per-model upstream timeout overrides (model -> ms), e.g. longer for reasoning models.
+    pub model_timeouts: Option<BTreeMap<String, u64>>,
+
+    /// Client-facing model names rewritten to upstream names (alias -> model) before routing.
+    /// `data_dir/model_aliases.json`, written by the admin API, takes precedence.
+    pub model_aliases: Option<BTreeMap<String, String>>,
+
+    /// Declarative edits of the JSON request body, applied in order to requests whose model
+    /// and upstream match (`[[request_rules]]`). Reloadable via SIGHUP.
+    pub request_rules: Option<Vec<RequestRule>>,
+
+    /// For requests sent with a model alias, rewrite the `model` field of JSON responses and
+    /// stream chunks back to the alias the client used (default false).
+    pub rewrite_response_model: Option<bool>,
+
+    /// Record every upstream attempt of a request (upstream, masked key, status or error,
+    /// latency) in the request log's `attempts` (default false). Adds about 100 bytes per
+    /// attempt to each entry.
+    pub detailed_request_log: Option<bool>,
+
+    /// Per-model billing prices (upstream model name -> price). Models without an entry are
+    /// billed 1:1, one balance unit per token.
+    pub model_prices: Option<BTreeMap<String, ModelPrice>>,
+
+    /// Balance units reserved (deducted) when a request is accepted and settled against the
+    /// actual cost once usage is known, so concurrent requests can't overspend. Requests whose
+    /// balance can't cover the reservation are rejected. 0 or omitted disables.
+    pub billing_reserve: Option<u64>,
+
+    /// Models (upstream names, after aliases) clients may call without an API key. Such requests
+    /// are not billed and are limited per client IP by `anonymous_rpm`. Default: none.
+    pub anonymous_models: Option<Vec<String>>,
+
+    /// Requests per minute per client IP (per /64 for IPv6) for keyless `anonymous_models`
+    /// requests; over the limit gets 429 `anonymous_rate_limited`. Default 60; 0 disables the
+    /// limit.
+    pub anonymous_rpm: Option<u32>,
+
+    /// Failover tier per upstream `group` (group -> tier). Lower tiers are tried first and a
+    /// higher tier is only used when every upstream in the lower ones is unavailable.
+    /// Ungrouped upstreams and unlisted groups are tier 0.
+    pub group_tiers: Option<BTreeMap<String, u32>>,
+
+    /// Wall-clock budget for a proxied request across all retries (ms). Unbounded when omitted.
+    pub max_request_duration_ms: Option<u64>,
+
+    /// Longest wait (ms) between upstream response headers and the first body chunk; a stream
+    /// that stalls longer is aborted and counted as a timeout. 0 or omitted disables.
+    pub first_byte_timeout_ms: Option<u64>,
+
+    /// Longest gap (ms) between body chunks once streaming has started; a stalled stream is
+    /// aborted and counted as a timeout. 0 or omitted disables.
+    pub stream_idle_timeout_ms: Option<u64>,
+
+    /// Requests slower than this (ms) are logged with `tracing::warn!` and flagged `slow`.
+    /// 0 or omitted disables.
+    pub slow_request_ms: Option<u64>,
+
+    /// Recent requests kept in memory for the admin `/requests` view (default 5000, clamped to
+    /// 100..=1000000). Each entry takes roughly 0.5 KB, so the default costs about 2.5 MB.
+    pub request_log_capacity: Option<usize>,
+
+    /// How many buckets the admin request metrics (`/metrics?window=`) keep per window.
+    pub request_metrics: Option<RequestMetricsConfig>,
+
+    /// Where the request log is written in `data_dir` (default `jsonl`).
+    pub request_log_backend: Option<RequestLogBackend>,
+
+    /// Rotation of `requests.jsonl` in `data_dir` (disabled when omitted: the file grows
+    /// forever).
+    pub request_log_rotation: Option<RequestLogRotationConfig>,
+
     /// Maximum retry attempts for retryable upstream responses.
     pub max_retries: Option<usize>,
 
     /// Upstream HTTP status codes that should trigger retry.
     pub retry_status_codes: Option<Vec<u16>>,
 
+    /// Per-status overrides of how upstream responses are handled, keyed by status code.
+    /// Codes not listed keep the built-in behavior (429/401/403 ban the key, 5xx bans the upstream).
+    pub status_actions: Option<BTreeMap<String, StatusAction>>,
+
+    /// Upstream selection strategy (default round-robin by weight).
+    pub select_strategy: Option<SelectStrategy>,
+
+    /// How a key is picked within an upstream (default round-robin).
+    pub key_select: Option<KeySelect>,
+
+    /// Tuning for `select_strategy = "latency"`.
+    pub latency_routing: Option<LatencyRoutingConfig>,
+
     /// Optional list of tokens required in `X-Proxy-Token` for non-admin requests.
     pub proxy_tokens: Option<Vec<String>>,
 
     /// List of tokens required in `X-Admin-Token` for admin API requests.
     pub admin_tokens: Vec<String>,
 
+    /// CIDRs allowed to reach `/admin` and `/metrics`; others get 403. Empty or omitted
+    /// allows all.
+    pub admin_ip_allowlist: Option<Vec<String>>,
+
     /// Directory for persistent data (keys DB).
     pub data_dir: PathBuf,
 
+    /// Hold an exclusive lock on `data_dir/gptload.lock` so a second instance on the same
+    /// `data_dir` fails at startup (default true). Disable only for intentionally shared dirs.
+    pub data_dir_lock: Option<bool>,
+
+    /// Unix permission bits applied to `data_dir` at startup, e.g. `0o700`.
+    pub data_dir_mode: Option<u32>,
+
     /// Upstream ids eligible for stream usage injection.
     pub usage_inject_upstreams: Option<Vec<String>>,
 
+    /// What to do with models no upstream advertises (default `reject`).
+    pub unknown_model_behavior: Option<UnknownModelBehavior>,
+
+    /// With `unknown_model_behavior = "passthrough"`, unknown models go to this upstream
+    /// instead of round-robin over all upstreams.
+    pub default_upstream_id: Option<String>,
+
+    /// Maximum accepted length of the request `model` field, in bytes (default 256).
+    pub max_model_len: Option<usize>,
+
+    /// Client request headers removed before forwarding, on top of hop-by-hop headers.
+    /// Case-insensitive; a trailing `*` matches by prefix (e.g. `x-stainless-*`).
+    pub strip_request_headers: Option<Vec<String>>,
+
+    /// Request headers the client's API key is read from, in order; the first non-empty one wins.
+    /// `authorization` has a `Bearer ` prefix stripped. Default `["x-api-key", "authorization"]`.
+    pub client_key_headers: Option<Vec<String>>,
+
+    /// Browser origins (e.g. `https://app.example.com`, or `*` for any) allowed to call the
+    /// proxy endpoints cross-origin. Preflight `OPTIONS` is answered by the proxy. Default: no
+    /// CORS.
+    pub proxy_cors_origins: Option<Vec<String>>,
+
+    /// Upstream response headers holding the upstream's own request id, recorded as
+    /// `upstream_request_id` in the request log. The first one present wins
+    /// (default `["x-request-id", "request-id"]`: OpenAI and Anthropic).
+    pub upstream_request_id_headers: Option<Vec<String>>,
+
     pub ban: BanConfig,
 
+    /// Background probing of upstreams in cooldown (disabled when omitted).
+    pub health_probe: Option<HealthProbeConfig>,
+
+    /// Re-fetch every upstream's `/v1/models` this often (seconds) and update the model
+    /// routes. 0 or omitted disables (models are then only fetched at startup and on admin
+    /// actions).
+    pub model_refresh_interval_s: Option<u64>,
+
+    /// Reuse upstream DNS answers for this many seconds instead of resolving on every new
+    /// connection. 0 or omitted disables.
+    pub dns_cache_ttl_s: Option<u64>,
+
+    /// Pin upstream host names to fixed addresses, bypassing DNS (like `/etc/hosts`), e.g.
+    /// `{ "api.example.com" = ["10.0.0.5"] }`. TLS still verifies against the host name.
+    pub upstream_host_override: Option<BTreeMap<String, Vec<IpAddr>>>,
+
     pub upstreams: Vec<UpstreamConfig>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// How an upstream response status is handled by the circuit breaker and retry loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusAction {
+    /// Retry on another key/upstream without banning anything.
+    Retry,
+    /// Cool down the key (`ban.rate_limit_ms`) and retry.
+    BanKey,
+    /// Cool down the upstream (`ban.server_error_ms`) and retry.
+    BanUpstream,
+    /// Return the response to the client as-is.
+    Pass,
+}
+
+/// How `select`/`select_for_model` pick an upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectStrategy {
+    /// Weighted round-robin over the schedule.
+    #[default]
+    RoundRobin,
+    /// Upstream with the fewest inflight requests.
+    LeastConn,
+    /// Weighted random, weight scaled inversely to the upstream's latency EWMA.
+    Latency,
+    /// Rendezvous hashing on the client's API key, so a key keeps hitting the same upstream.
+    Sticky,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownModelBehavior {
+    /// 404 `model_not_found`.
+    #[default]
+    Reject,
+    /// Forward to any available upstream (no model-based routing) and let it decide.
+    Passthrough,
+}
+
+/// Starting point of the key probe in `Upstream::select_key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySelect {
+    /// Shared incrementing cursor (`key_rr`).
+    #[default]
+    RoundRobin,
+    /// Random start, so concurrent bursts do not pile onto neighbouring keys.
+    Random,
+    /// Of two random available keys, the one with more remaining requests per the upstream's
+    /// rate-limit headers (`x-ratelimit-remaining-requests`).
+    Remaining,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LatencyRoutingConfig {
+    /// EWMA smoothing factor in (0, 1]: weight of the newest sample (default 0.2).
+    pub ewma_alpha: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct BanConfig {
     pub rate_limit_ms: u64,
     pub server_error_ms: u64,
     pub network_error_ms: u64,
     pub auth_error_ms: u64,
     pub max_backoff_pow: u32,
+    /// Cap on a backed-off cooldown (default 300000), so a long failure streak cannot lock a
+    /// recovered key or upstream out for days. A base above the cap (typically
+    /// `auth_error_ms`) is used as-is, without backoff.
+    pub max_ban_ms: Option<u64>,
+    /// A failure this long (ms) after the previous one starts a new fail streak instead of
+    /// extending it, so intermittently failing keys/upstreams don't get ever-longer bans.
+    /// 0 or omitted: streaks only reset on success.
+    pub streak_reset_ms: Option<u64>,
+    /// Upper bound for a key cooldown taken from an upstream's `Retry-After` on rate-limit
+    /// responses (default 600000). 0 ignores `Retry-After` and always uses `rate_limit_ms`.
+    pub retry_after_max_ms: Option<u64>,
+    /// Randomize each backoff cooldown by up to this fraction either way (0.25 = ±25%), so keys
+    /// or upstreams banned together recover at different times. 0 or omitted: exact backoff.
+    pub jitter: Option<f64>,
+    /// Optional rolling-window error-rate breaker (disabled when omitted).
+    pub error_rate: Option<ErrorRateConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ErrorRateConfig {
+    /// Sliding window length (default 60000).
+    pub window_ms: Option<u64>,
+    /// Minimum responses in the window before the breaker may trip (default 20).
+    pub min_samples: Option<u64>,
+    /// Failure ratio in (0, 1] that trips the breaker (default 0.5). Inclusive: the breaker
+    /// trips once failures reach this share of the window, so 1.0 means "every response failed".
+    pub threshold: Option<f64>,
+    /// Upstream cooldown when tripped (default `ban.server_error_ms`).
+    pub cooldown_ms: Option<u64>,
+}
+
+/// One `[[request_rules]]` entry. Field names are top-level keys of the request body; within
+/// a rule `rename` runs first, then `remove`, `defaults` and `set`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RequestRule {
+    /// Model patterns (upstream names, after aliases; `*` wildcard). Omitted matches any model.
+    pub models: Option<Vec<String>>,
+    /// Upstream id patterns (`*` wildcard). Omitted matches any upstream.
+    pub upstreams: Option<Vec<String>>,
+    /// Fields moved to a new name (from -> to), replacing a value already under `to`.
+    pub rename: Option<BTreeMap<String, String>>,
+    /// Fields dropped from the body.
+    pub remove: Option<Vec<String>>,
+    /// Fields set only when the client didn't send them.
+    pub defaults: Option<BTreeMap<String, serde_json::Value>>,
+    /// Fields set, overriding the client's value.
+    pub set: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+/// Balance units charged per 1000 tokens; 1:1 billing is 1000 for both.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+/// Bucket counts of the request metrics windows, each clamped to 1..=10000. A bucket is about
+/// 40 bytes.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RequestMetricsConfig {
+    /// One-minute buckets (default 60).
+    pub minute_buckets: Option<usize>,
+    /// One-hour buckets (default 48).
+    pub hour_buckets: Option<usize>,
+    /// One-day buckets (default 30).
+    pub day_buckets: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestLogBackend {
+    /// JSON Lines in `requests.jsonl`.
+    #[default]
+    Jsonl,
+    /// Table `requests` in the SQLite database `requests.sqlite` (needs `--features sqlite`).
+    Sqlite,
+    /// Both of the above.
+    Both,
+}
+
+/// When `requests.jsonl` is renamed to `requests.jsonl.<unix ms>` and a new file started.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestLogRotationConfig {
+    /// Rotate once the file reaches this size. 0 or omitted: no size limit.
+    pub max_bytes: Option<u64>,
+    /// Also rotate when the UTC date changes (default false).
+    pub daily: Option<bool>,
+    /// Compress rotated files to `requests.jsonl.<unix ms>.gz` (default false).
+    pub gzip: Option<bool>,
+    /// Rotated files to keep; older ones are deleted. 0 or omitted keeps all of them.
+    pub keep: Option<usize>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthProbeConfig {
+    /// Default true when the section is present.
+    pub enabled: Option<bool>,
+    /// Probe interval (default 5000).
+    pub interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ListenAddr {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ListenAddr {
+    /// All configured addresses; `"a, b"` and `["a", "b"]` are equivalent.
+    pub fn parse(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let list: Vec<&str> = match self {
+            ListenAddr::One(s) => s.split(',').collect(),
+            ListenAddr::Many(v) => v.iter().flat_map(|s| s.split(',')).collect(),
+        };
+        let mut out = Vec::with_capacity(list.len());
+        for s in list.iter().map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let addr: SocketAddr = s
+                .parse()
+                .map_err(|e| anyhow::anyhow!("config: invalid listen_addr {s}: {e}"))?;
+            if !out.contains(&addr) {
+                out.push(addr);
+            }
+        }
+        if out.is_empty() {
+            anyhow::bail!("config: listen_addr must not be empty");
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct UpstreamConfig {
     /// Stable upstream id (used by admin API and key DB).
     pub id: String,
@@ -54,17 +407,94 @@ pub struct UpstreamConfig {
     pub base_url: String,
     /// Weighted round-robin (default 1).
     pub weight: Option<usize>,
+    /// `false` takes the upstream out of rotation without deleting it: its keys, models and
+    /// stats stay (default true). Toggled at runtime by `PUT /upstreams/{id}/enabled`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Upstream request timeout (ms); overrides `request_timeout_ms` for this upstream.
+    pub timeout_ms: Option<u64>,
+    /// API format spoken by the upstream (default openai).
+    pub format: Option<UpstreamFormat>,
+    /// Group name for aggregated stats (e.g. "openai", "azure").
+    pub group: Option<String>,
+    /// Free-form labels shown with the upstream's stats.
+    pub tags: Option<Vec<String>>,
+    /// Failover tier (0 = highest priority, the default); overrides the group's `group_tiers`
+    /// entry. Higher tiers only get traffic when every lower-tier candidate is unavailable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tier: Option<u32>,
+    /// Only route these models here (`*` wildcards), even if `/v1/models` lists more.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_allow: Option<Vec<String>>,
+    /// Never route these models here (`*` wildcards); wins over `model_allow`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_deny: Option<Vec<String>>,
+    /// Look for stream usage only in the last events of an SSE response instead of checking
+    /// every event (less CPU; usage reported earlier in the stream is missed). Default false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sse_usage_tail_only: Option<bool>,
+    /// Header carrying the key (default `Authorization`), e.g. `api-key` for Azure OpenAI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_header: Option<String>,
+    /// Scheme put before the key (default `Bearer` for `Authorization`, none otherwise);
+    /// empty sends the bare key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_scheme: Option<String>,
+    /// `Host` header sent upstream instead of the one derived from `base_url`, for gateways
+    /// that route by virtual host. TLS SNI still uses the `base_url` host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_header: Option<String>,
+    /// Azure OpenAI: rewrite `/v1/...` paths to `/openai/deployments/{deployment}/...` and
+    /// append `api-version=<this>`. The key header then defaults to `api-key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azure_api_version: Option<String>,
+    /// Azure OpenAI model -> deployment name; unmapped models use the model name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azure_deployments: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamFormat {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// Messages API: chat completions are translated to `/v1/messages` and back.
+    Anthropic,
 }
 
 impl Config {
-    pub fn load(path: &str) -> anyhow::Result<Self> {
-        let s = fs::read_to_string(path)?;
-        let mut cfg: Config = toml::from_str(&s)?;
+    /// Load from `source`: a file path, `-` for stdin, or an `http(s)://` URL fetched once.
+    /// Blocks the calling thread (run it via `spawn_blocking` from async code).
+    pub fn load(source: &str) -> anyhow::Result<Self> {
+        let s = if source == "-" {
+            let mut s = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut s)
+                .map_err(|e| anyhow::anyhow!("config: read stdin: {e}"))?;
+            s
+        } else if is_config_url(source) {
+            fetch_config(source)?
+        } else {
+            fs::read_to_string(source)?
+        };
+        let mut doc: toml::Value = toml::from_str(&s)?;
+        expand_env_vars(&mut doc, &mut String::new())?;
+        let mut cfg = Config::deserialize(doc)?;
         cfg.normalize()?;
         cfg.validate()?;
         Ok(cfg)
     }
 
+    /// Top-level keys whose values differ between `self` and `other`, for reporting a reload.
+    pub fn changed_fields(&self, other: &Config) -> Vec<String> {
+        let (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return Vec::new();
+        };
+        a.into_iter().filter(|(k, v)| b.get(k) != Some(v)).map(|(k, _)| k).collect()
+    }
+
     fn normalize(&mut self) -> anyhow::Result<()> {
         // Trim tokens.
         if let Some(v) = &mut self.proxy_tokens {
@@ -89,6 +519,51 @@ impl Config {
                 self.usage_inject_upstreams = None;
             }
         }
+        if let Some(id) = &mut self.default_upstream_id {
+            *id = id.trim().to_string();
+            if id.is_empty() {
+                self.default_upstream_id = None;
+            }
+        }
+        if let Some(v) = &mut self.strip_request_headers {
+            for h in v.iter_mut() {
+                *h = h.trim().to_ascii_lowercase();
+            }
+            v.retain(|h| !h.is_empty());
+            v.sort_unstable();
+            v.dedup();
+            if v.is_empty() {
+                self.strip_request_headers = None;
+            }
+        }
+        if let Some(v) = &mut self.client_key_headers {
+            for h in v.iter_mut() {
+                *h = h.trim().to_ascii_lowercase();
+            }
+            v.retain(|h| !h.is_empty());
+        }
+        if let Some(v) = &mut self.proxy_cors_origins {
+            for o in v.iter_mut() {
+                *o = o.trim().trim_end_matches('/').to_ascii_lowercase();
+            }
+            v.retain(|o| !o.is_empty());
+            if v.is_empty() {
+                self.proxy_cors_origins = None;
+            }
+        }
+        if let Some(m) = self.upstream_host_override.take() {
+            let m: BTreeMap<String, Vec<IpAddr>> = m
+                .into_iter()
+                .map(|(host, ips)| (host.trim().trim_end_matches('.').to_ascii_lowercase(), ips))
+                .collect();
+            self.upstream_host_override = (!m.is_empty()).then_some(m);
+        }
+        if let Some(v) = &mut self.upstream_request_id_headers {
+            for h in v.iter_mut() {
+                *h = h.trim().to_ascii_lowercase();
+            }
+            v.retain(|h| !h.is_empty());
+        }
         if let Some(v) = &mut self.retry_status_codes {
             v.retain(|code| *code >= 100 && *code <= 599);
             v.sort_unstable();
@@ -101,12 +576,27 @@ impl Config {
     }
 
     fn validate(&self) -> anyhow::Result<()> {
+        for cidr in self.admin_ip_allowlist.iter().flatten() {
+            crate::util::IpNet::parse(cidr).map_err(|e| anyhow::anyhow!("config: admin_ip_allowlist: {e}"))?;
+        }
         if self.admin_tokens.is_empty() {
             anyhow::bail!("config: admin_tokens must not be empty");
         }
         if self.upstreams.is_empty() {
             anyhow::bail!("config: upstreams must not be empty");
         }
+        if let Some(id) = &self.default_upstream_id {
+            if !self.upstreams.iter().any(|u| &u.id == id) {
+                anyhow::bail!("config: default_upstream_id {id} is not a configured upstream");
+            }
+        }
+        self.listen_addr.parse()?;
+        if self.listen_backlog == Some(0) {
+            anyhow::bail!("config: listen_backlog must be > 0");
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            anyhow::bail!("config: tls_cert_path and tls_key_path must be set together");
+        }
         for (i, u) in self.upstreams.iter().enumerate() {
             if u.id.trim().is_empty() {
                 anyhow::bail!("config: upstreams[{i}].id must not be empty");
@@ -116,6 +606,118 @@ impl Config {
                     "config: upstreams[{i}].base_url must start with http:// or https://"
                 );
             }
+            if u.timeout_ms == Some(0) {
+                anyhow::bail!("config: upstreams[{i}].timeout_ms must be > 0");
+            }
+            if let Err(e) = validate_upstream_options(u) {
+                anyhow::bail!("config: upstreams[{i}].{e}");
+            }
+        }
+        if let Some(v) = &self.strip_request_headers {
+            for h in v {
+                let name = h.strip_suffix('*').unwrap_or(h);
+                if name.is_empty()
+                    || hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                {
+                    anyhow::bail!(
+                        "config: strip_request_headers contains invalid header name: {h}"
+                    );
+                }
+            }
+        }
+        if self
+            .anonymous_models
+            .as_ref()
+            .is_some_and(|v| v.iter().any(|m| m.trim().is_empty()))
+        {
+            anyhow::bail!("config: anonymous_models must not contain empty names");
+        }
+        if let Some(v) = &self.client_key_headers {
+            if v.is_empty() {
+                anyhow::bail!("config: client_key_headers must not be empty");
+            }
+            for h in v {
+                if hyper::header::HeaderName::from_bytes(h.as_bytes()).is_err() {
+                    anyhow::bail!("config: client_key_headers contains invalid header name: {h}");
+                }
+            }
+        }
+        if let Some(v) = &self.proxy_cors_origins {
+            for o in v {
+                if o != "*" && !o.starts_with("http://") && !o.starts_with("https://") {
+                    anyhow::bail!("config: proxy_cors_origins entries must be \"*\" or start with http:// or https://: {o}");
+                }
+            }
+        }
+        if let Some(m) = &self.upstream_host_override {
+            for (host, ips) in m {
+                if host.is_empty() || ips.is_empty() {
+                    anyhow::bail!("config: upstream_host_override entries need a host and at least one address: {host:?}");
+                }
+            }
+        }
+        if let Some(v) = &self.upstream_request_id_headers {
+            for h in v {
+                if hyper::header::HeaderName::from_bytes(h.as_bytes()).is_err() {
+                    anyhow::bail!(
+                        "config: upstream_request_id_headers contains invalid header name: {h}"
+                    );
+                }
+            }
+        }
+        if !cfg!(feature = "sqlite")
+            && self.request_log_backend.is_some_and(|b| b != RequestLogBackend::Jsonl)
+        {
+            anyhow::bail!("config: request_log_backend needs a build with `--features sqlite`");
+        }
+        if let Some(r) = &self.request_log_rotation {
+            if r.max_bytes.unwrap_or(0) == 0 && !r.daily.unwrap_or(false) {
+                anyhow::bail!("config: request_log_rotation needs max_bytes > 0 or daily = true");
+            }
+        }
+        if self.health_probe.as_ref().and_then(|h| h.interval_ms) == Some(0) {
+            anyhow::bail!("config: health_probe.interval_ms must be > 0");
+        }
+        if self.max_model_len == Some(0) {
+            anyhow::bail!("config: max_model_len must be > 0");
+        }
+        if let Some(map) = &self.model_aliases {
+            for (alias, model) in map {
+                if alias.trim().is_empty() || model.trim().is_empty() {
+                    anyhow::bail!("config: model_aliases entries must be non-empty");
+                }
+            }
+        }
+        if self.data_dir_mode.is_some_and(|m| m > 0o7777) {
+            anyhow::bail!("config: data_dir_mode must be a permission mode like 0o700");
+        }
+        if let Some(map) = &self.model_prices {
+            for (model, p) in map {
+                let valid = |v: f64| v.is_finite() && v >= 0.0;
+                if !valid(p.prompt_per_1k) || !valid(p.completion_per_1k) {
+                    anyhow::bail!("config: model_prices.{model} prices must be finite and >= 0");
+                }
+            }
+        }
+        if let Some(map) = &self.group_tiers {
+            if map.keys().any(|g| g.trim().is_empty()) {
+                anyhow::bail!("config: group_tiers keys must be non-empty");
+            }
+        }
+        if let Some(rules) = &self.request_rules {
+            for (i, rule) in rules.iter().enumerate() {
+                validate_request_rule(i, rule)?;
+            }
+        }
+        if let Some(map) = &self.model_timeouts {
+            for (model, ms) in map {
+                if *ms == 0 {
+                    anyhow::bail!("config: model_timeouts.{model} must be > 0");
+                }
+            }
+        }
+        if self.max_request_duration_ms == Some(0) {
+            anyhow::bail!("config: max_request_duration_ms must be > 0");
         }
         if let Some(codes) = &self.retry_status_codes {
             for code in codes {
@@ -124,6 +726,236 @@ impl Config {
                 }
             }
         }
+        if let Some(actions) = &self.status_actions {
+            for code in actions.keys() {
+                match code.trim().parse::<u16>() {
+                    Ok(c) if (100..=599).contains(&c) => {}
+                    _ => anyhow::bail!("config: status_actions contains invalid status code: {code}"),
+                }
+            }
+        }
+        if let Some(a) = self.latency_routing.as_ref().and_then(|l| l.ewma_alpha) {
+            if !(a > 0.0 && a <= 1.0) {
+                anyhow::bail!("config: latency_routing.ewma_alpha must be in (0, 1]");
+            }
+        }
+        if let Some(max) = self.ban.max_ban_ms {
+            let b = &self.ban;
+            if max < b.rate_limit_ms.max(b.server_error_ms).max(b.network_error_ms) {
+                anyhow::bail!(
+                    "config: ban.max_ban_ms must be >= rate_limit_ms, server_error_ms and network_error_ms"
+                );
+            }
+        }
+        if let Some(j) = self.ban.jitter {
+            if !(0.0..1.0).contains(&j) {
+                anyhow::bail!("config: ban.jitter must be in [0, 1)");
+            }
+        }
+        if let Some(er) = &self.ban.error_rate {
+            if let Some(t) = er.threshold {
+                if !(t > 0.0 && t <= 1.0) {
+                    anyhow::bail!("config: ban.error_rate.threshold must be in (0, 1]");
+                }
+            }
+            if er.window_ms == Some(0) {
+                anyhow::bail!("config: ban.error_rate.window_ms must be > 0");
+            }
+        }
         Ok(())
     }
 }
+
+/// Check the auth and Azure options of an upstream (config file and admin edits alike);
+/// the error names the offending field.
+pub fn validate_upstream_options(u: &UpstreamConfig) -> Result<(), String> {
+    if let Some(h) = &u.auth_header {
+        if hyper::header::HeaderName::from_bytes(h.trim().as_bytes()).is_err() {
+            return Err(format!("auth_header is not a valid header name: {h:?}"));
+        }
+    }
+    if let Some(s) = &u.auth_scheme {
+        if s.chars().any(|c| !c.is_ascii_graphic()) {
+            return Err(format!("auth_scheme must be a single token: {s:?}"));
+        }
+    }
+    if let Some(h) = &u.host_header {
+        if h.trim().is_empty() || hyper::header::HeaderValue::from_str(h.trim()).is_err() {
+            return Err(format!("host_header is not a valid header value: {h:?}"));
+        }
+    }
+    if u.format == Some(UpstreamFormat::Anthropic)
+        && (u.auth_header.is_some() || u.auth_scheme.is_some())
+    {
+        return Err("auth_header/auth_scheme are not supported with format = \"anthropic\"".into());
+    }
+    let is_url_token =
+        |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+    if let Some(v) = &u.azure_api_version {
+        if !is_url_token(v) {
+            return Err(format!("azure_api_version is not a valid version: {v:?}"));
+        }
+        if u.format == Some(UpstreamFormat::Anthropic) {
+            return Err("azure_api_version is not supported with format = \"anthropic\"".into());
+        }
+    }
+    if let Some(m) = &u.azure_deployments {
+        if u.azure_api_version.is_none() {
+            return Err("azure_deployments requires azure_api_version".into());
+        }
+        if let Some((model, d)) = m.iter().find(|(_, d)| !is_url_token(d)) {
+            return Err(format!("azure_deployments[{model:?}] is not a valid deployment name: {d:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Check one `request_rules` entry. `model` and `stream` steer routing and response handling,
+/// so rules may not touch them.
+fn validate_request_rule(i: usize, rule: &RequestRule) -> anyhow::Result<()> {
+    let patterns = rule.models.iter().chain(rule.upstreams.iter()).flatten();
+    if patterns.into_iter().any(|p| p.trim().is_empty()) {
+        anyhow::bail!("config: request_rules[{i}] has an empty models/upstreams pattern");
+    }
+    let mut fields: Vec<&str> = Vec::new();
+    if let Some(m) = &rule.rename {
+        fields.extend(m.iter().flat_map(|(from, to)| [from.as_str(), to.as_str()]));
+    }
+    fields.extend(rule.remove.iter().flatten().map(String::as_str));
+    fields.extend(rule.defaults.iter().flat_map(|m| m.keys()).map(String::as_str));
+    fields.extend(rule.set.iter().flat_map(|m| m.keys()).map(String::as_str));
+    if fields.is_empty() {
+        anyhow::bail!("config: request_rules[{i}] has no rename/remove/defaults/set action");
+    }
+    for f in fields {
+        if f.trim().is_empty() {
+            anyhow::bail!("config: request_rules[{i}] has an empty field name");
+        }
+        if f == "model" || f == "stream" {
+            anyhow::bail!("config: request_rules[{i}] may not change the {f:?} field");
+        }
+    }
+    Ok(())
+}
+
+fn is_config_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// GET the config over HTTP(S). Runs on its own thread with a private runtime so it works
+/// both before the main runtime exists and from inside it.
+fn fetch_config(url: &str) -> anyhow::Result<String> {
+    const MAX_CONFIG_BYTES: usize = 4 * 1024 * 1024;
+    let uri: hyper::Uri = url
+        .parse()
+        .map_err(|e| anyhow::anyhow!("config: invalid url {url}: {e}"))?;
+    let fetch = async move {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+        let resp = tokio::time::timeout(std::time::Duration::from_secs(30), client.get(uri))
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out"))??;
+        if !resp.status().is_success() {
+            anyhow::bail!("server returned {}", resp.status());
+        }
+        let too_large = || anyhow::anyhow!("config larger than {MAX_CONFIG_BYTES} bytes");
+        let declared = resp
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if declared.is_some_and(|n| n > MAX_CONFIG_BYTES as u64) {
+            return Err(too_large());
+        }
+        // Count while reading: a missing or lying Content-Length must not buffer without bound.
+        use hyper::body::HttpBody;
+        let mut body = resp.into_body();
+        let mut buf = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if buf.len() + chunk.len() > MAX_CONFIG_BYTES {
+                return Err(too_large());
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(String::from_utf8(buf)?)
+    };
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(fetch)
+            })
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("fetch thread panicked")))
+    })
+    .map_err(|e| anyhow::anyhow!("config: fetch {url}: {e}"))
+}
+
+/// Expand `${VAR}` from the environment (`$$` is a literal `$`) in every string value of the
+/// parsed document, so substituted text can never change the TOML structure and comments are
+/// never looked at. A referenced variable that is not set is an error naming the key.
+fn expand_env_vars(value: &mut toml::Value, path: &mut String) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(s) if s.contains('$') => {
+            *s = expand_env_str(s).map_err(|e| anyhow::anyhow!("config: {path}: {e}"))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                let len = path.len();
+                path.push_str(&format!("[{i}]"));
+                expand_env_vars(item, path)?;
+                path.truncate(len);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (k, v) in table.iter_mut() {
+                let len = path.len();
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(k);
+                expand_env_vars(v, path)?;
+                path.truncate(len);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_env_str(input: &str) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        if let Some(tail) = after.strip_prefix('$') {
+            out.push('$');
+            rest = tail;
+        } else if let Some(tail) = after.strip_prefix('{') {
+            let Some(end) = tail.find('}') else {
+                anyhow::bail!("unterminated ${{");
+            };
+            let name = &tail[..end];
+            if name.is_empty() {
+                anyhow::bail!("empty ${{}}");
+            }
+            let value = std::env::var(name)
+                .map_err(|_| anyhow::anyhow!("environment variable {name} is not set"))?;
+            out.push_str(&value);
+            rest = &tail[end + 1..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
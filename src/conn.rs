@@ -0,0 +1,275 @@
+use ahash::AHashMap;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Connection counters for one upstream authority (`host[:port]`).
+#[derive(Default)]
+pub struct ConnStats {
+    /// Connections established (TCP + TLS handshake completed).
+    pub opened: AtomicU64,
+    /// Connect attempts that failed (DNS/TCP/TLS).
+    pub failed: AtomicU64,
+    /// Connections currently held by the client pool (idle or in use).
+    pub open: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct ConnStatsTable {
+    map: RwLock<AHashMap<String, Arc<ConnStats>>>,
+}
+
+impl ConnStatsTable {
+    pub fn get(&self, authority: &str) -> Option<Arc<ConnStats>> {
+        let map = self.map.read().unwrap_or_else(|e| e.into_inner());
+        map.get(authority).cloned()
+    }
+
+    fn get_or_insert(&self, authority: &str) -> Arc<ConnStats> {
+        if let Some(s) = self.get(authority) {
+            return s;
+        }
+        let mut map = self.map.write().unwrap_or_else(|e| e.into_inner());
+        map.entry(authority.to_string()).or_default().clone()
+    }
+}
+
+/// DNS resolver for upstream connections. Hosts in `upstream_host_override` resolve to their
+/// pinned addresses; others go to the system resolver, optionally through an answer cache.
+/// getaddrinfo reports no record TTLs, so cached answers live for `dns_cache_ttl_s`.
+#[derive(Clone)]
+pub struct UpstreamResolver {
+    inner: GaiResolver,
+    overrides: Arc<AHashMap<String, Vec<SocketAddr>>>,
+    ttl_ms: u64,
+    // Keyed by upstream host, so it stays as small as the upstream list.
+    cache: Arc<RwLock<AHashMap<String, CachedAddrs>>>,
+}
+
+/// A cached answer and when it expires (unix ms).
+type CachedAddrs = (u64, Vec<SocketAddr>);
+
+impl UpstreamResolver {
+    /// `ttl_ms` 0 disables caching. Override keys must be lowercase.
+    pub fn new(overrides: AHashMap<String, Vec<IpAddr>>, ttl_ms: u64) -> Self {
+        // Port 0: HttpConnector fills in the port from the URI.
+        let overrides = overrides
+            .into_iter()
+            .map(|(host, ips)| (host, ips.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect()))
+            .collect();
+        Self {
+            inner: GaiResolver::new(),
+            overrides: Arc::new(overrides),
+            ttl_ms,
+            cache: Arc::new(RwLock::new(AHashMap::new())),
+        }
+    }
+}
+
+impl Service<Name> for UpstreamResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_ascii_lowercase();
+        if let Some(addrs) = self.overrides.get(&host) {
+            let addrs = addrs.clone();
+            return Box::pin(async move { Ok(addrs.into_iter()) });
+        }
+        if self.ttl_ms == 0 {
+            let fut = self.inner.call(name);
+            return Box::pin(async move { Ok(fut.await?.collect::<Vec<_>>().into_iter()) });
+        }
+
+        let now = crate::util::now_ms();
+        {
+            let cache = self.cache.read().unwrap_or_else(|e| e.into_inner());
+            if let Some((expires_ms, addrs)) = cache.get(&host) {
+                if *expires_ms > now {
+                    let addrs = addrs.clone();
+                    return Box::pin(async move { Ok(addrs.into_iter()) });
+                }
+            }
+        }
+        let fut = self.inner.call(name);
+        let cache = self.cache.clone();
+        let expires_ms = now.saturating_add(self.ttl_ms);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = fut.await?.collect();
+            if !addrs.is_empty() {
+                let mut cache = cache.write().unwrap_or_else(|e| e.into_inner());
+                cache.insert(host, (expires_ms, addrs.clone()));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// Connector wrapper that counts new connections per authority. hyper 0.14 does not
+/// expose pool stats, so a high `opened` rate relative to traffic is the signal for churn.
+#[derive(Clone)]
+pub struct CountingConnector<C> {
+    inner: C,
+    stats: Arc<ConnStatsTable>,
+}
+
+impl<C> CountingConnector<C> {
+    pub fn new(inner: C, stats: Arc<ConnStatsTable>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl<C> Service<Uri> for CountingConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+    C::Response: Send + 'static,
+    C::Error: Send + 'static,
+{
+    type Response = CountedStream<C::Response>;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let stats = self
+            .stats
+            .get_or_insert(uri.authority().map(|a| a.as_str()).unwrap_or(""));
+        let fut = self.inner.call(uri);
+        Box::pin(async move {
+            match fut.await {
+                Ok(io) => {
+                    stats.opened.fetch_add(1, Ordering::Relaxed);
+                    stats.open.fetch_add(1, Ordering::Relaxed);
+                    Ok(CountedStream { inner: io, stats })
+                }
+                Err(e) => {
+                    stats.failed.fetch_add(1, Ordering::Relaxed);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// Stream wrapper that decrements the open-connection gauge when the pool drops it.
+pub struct CountedStream<T> {
+    inner: T,
+    stats: Arc<ConnStats>,
+}
+
+impl<T> Drop for CountedStream<T> {
+    fn drop(&mut self) {
+        self.stats.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: Connection> Connection for CountedStream<T> {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountedStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountedStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// What broke when an upstream request failed without a response, read off the hyper
+/// error chain. `Other` covers resets and protocol errors on an established connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetErrorKind {
+    Dns,
+    Connect,
+    Tls,
+    Other,
+}
+
+impl NetErrorKind {
+    pub fn classify(err: &hyper::Error) -> Self {
+        let mut cur: Option<&(dyn std::error::Error + 'static)> = Some(err);
+        while let Some(e) = cur {
+            if e.is::<tokio_rustls::rustls::Error>() {
+                return Self::Tls;
+            }
+            // tokio-rustls reports handshake failures as an io::Error wrapping the rustls
+            // error (which hyper-rustls wraps once more). io::Error::source() skips the
+            // wrapped error, so follow get_ref() instead.
+            cur = match e.downcast_ref::<io::Error>() {
+                Some(io) => io.get_ref().map(|inner| inner as &(dyn std::error::Error + 'static)),
+                None => e.source(),
+            };
+        }
+        if !err.is_connect() {
+            return Self::Other;
+        }
+        // HttpConnector's error type is private; its message names the failing step.
+        let dns = std::error::Error::source(err).is_some_and(|e| e.to_string().starts_with("dns error"));
+        if dns {
+            Self::Dns
+        } else {
+            Self::Connect
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Connect => "connect",
+            Self::Tls => "tls",
+            Self::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,17 @@
+#![forbid(unsafe_code)]
+
+//! The proxy's modules. `main.rs` builds the server from them; `benches/` measure the hot
+//! path (selection, URI building, usage parsing) directly.
+
+pub mod admin;
+pub mod anthropic;
+pub mod billing;
+pub mod config;
+pub mod conn;
+pub mod proxy;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_log;
+pub mod state;
+pub mod storage;
+pub mod tls;
+pub mod util;
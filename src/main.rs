@@ -5,23 +5,16 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-mod admin;
-mod billing;
-mod config;
-mod proxy;
-mod state;
-mod storage;
-mod util;
-
 use clap::Parser;
-use std::net::SocketAddr;
+use gptload_rs::{config, proxy, state, storage, tls, util};
+#[cfg(unix)]
 use std::sync::Arc;
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(name = "gptload-rs", version, about = "High-performance OpenAI-format proxy with admin UI/API, hot key reload, realtime stats")]
 struct Cli {
-    /// Path to TOML config
+    /// TOML config: a file path, `-` to read stdin, or an http(s):// URL fetched at startup
     #[arg(long, default_value = "config.toml")]
     config: String,
 }
@@ -36,6 +29,16 @@ fn main() -> anyhow::Result<()> {
         .init();
 
     let cfg = config::Config::load(&cli.config)?;
+    let config_path = cli.config.clone();
+    let tls_cert_path = cfg.tls_cert_path.clone();
+    let tls_key_path = cfg.tls_key_path.clone();
+    let listen_opts = proxy::ListenOptions {
+        backlog: cfg.listen_backlog.unwrap_or(1024),
+        reuse_addr: cfg.reuse_addr.unwrap_or(true),
+        reuse_port: cfg.reuse_port.unwrap_or(false),
+    };
+
+    let shutdown_timeout = std::time::Duration::from_millis(cfg.shutdown_timeout_ms.unwrap_or(30_000));
 
     let worker_threads = cfg.worker_threads.unwrap_or_else(num_cpus::get);
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -45,10 +48,200 @@ fn main() -> anyhow::Result<()> {
         .build()?;
 
     rt.block_on(async move {
-        let addr: SocketAddr = cfg.listen_addr.parse()?;
-        let state = Arc::new(state::RouterState::new(cfg)?);
+        let addrs = cfg.listen_addr.parse()?;
+        // A read-only data_dir (e.g. pre-seeded keys on an immutable mount) is served as is in
+        // read_only mode; otherwise every write would fail later, so refuse to start.
+        let writable = match storage::probe_data_dir(&cfg.data_dir) {
+            Ok(()) => true,
+            Err(e) if cfg.read_only.unwrap_or(false) => {
+                tracing::warn!(
+                    data_dir = %cfg.data_dir.display(),
+                    error = %e,
+                    "data_dir is not writable; skipping data_dir_mode and the data_dir lock"
+                );
+                false
+            }
+            Err(e) => anyhow::bail!(
+                "data_dir {} is not writable ({e}); mount it read-write, or set read_only = true \
+                 to serve its keys without persisting anything",
+                cfg.data_dir.display()
+            ),
+        };
+        if writable {
+            storage::prepare_data_dir(&cfg.data_dir, cfg.data_dir_mode)?;
+        }
+        // Held until the servers exit.
+        let _data_lock = if writable && cfg.data_dir_lock.unwrap_or(true) {
+            Some(storage::lock_data_dir(&cfg.data_dir)?)
+        } else {
+            None
+        };
+        #[cfg(unix)]
+        let boot_cfg = cfg.clone();
+        let state = state::RouterState::new(cfg)?;
+        #[cfg(unix)]
+        tokio::spawn(reload_on_sighup(config_path, boot_cfg, state.clone()));
+        #[cfg(not(unix))]
+        let _ = config_path;
         state.refresh_missing_models_routes().await;
-        tracing::info!(%addr, "listening (admin at /admin/)");
-        proxy::serve_http(addr, state).await
+        let acceptor = match (&tls_cert_path, &tls_key_path) {
+            (Some(cert), Some(key)) => Some(tls::load_acceptor(cert, key)?),
+            _ => None,
+        };
+
+        // One server per address, all sharing the state; bind everything before serving.
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let listener = proxy::bind_listener(addr, listen_opts)
+                .map_err(|e| anyhow::anyhow!("bind {addr}: {e}"))?;
+            listeners.push((addr, listener));
+        }
+        let mut servers = tokio::task::JoinSet::new();
+        for (addr, listener) in listeners {
+            let state = state.clone();
+            match acceptor.clone() {
+                Some(acceptor) => {
+                    tracing::info!(%addr, "listening with TLS (admin at /admin/)");
+                    servers.spawn(proxy::serve_https(listener, state, acceptor));
+                }
+                None => {
+                    tracing::info!(%addr, "listening (admin at /admin/)");
+                    servers.spawn(proxy::serve_http(listener, state));
+                }
+            }
+        }
+        // Before shutdown servers only return on error; the first one takes the process down.
+        let res = tokio::select! {
+            joined = servers.join_next() => match joined {
+                Some(Ok(res)) => res,
+                Some(Err(e)) => Err(e.into()),
+                None => Ok(()),
+            },
+            signal = shutdown_signal() => {
+                tracing::info!(
+                    signal,
+                    inflight = state.stats.requests_inflight.load(std::sync::atomic::Ordering::Relaxed),
+                    "shutting down; draining in-flight requests"
+                );
+                state.begin_shutdown();
+                let drain = async { while servers.join_next().await.is_some() {} };
+                if tokio::time::timeout(shutdown_timeout, drain).await.is_err() {
+                    tracing::warn!(
+                        inflight = state.stats.requests_inflight.load(std::sync::atomic::Ordering::Relaxed),
+                        timeout_ms = shutdown_timeout.as_millis() as u64,
+                        "shutdown timeout reached; dropping remaining requests"
+                    );
+                }
+                Ok(())
+            }
+        };
+        flush_on_exit(&state).await;
+        res
     })
 }
+
+/// Resolves on ctrl-c, or SIGTERM on unix, with the signal's name.
+async fn shutdown_signal() -> &'static str {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let term = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "SIGTERM handler not installed");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let term = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => "SIGINT",
+        _ = term => "SIGTERM",
+    }
+}
+
+/// Write out what is still buffered: billing deductions, request-log lines and the key DB.
+async fn flush_on_exit(state: &state::RouterState) {
+    let billing = state.billing.clone();
+    match tokio::task::spawn_blocking(move || billing.flush(std::time::Duration::from_secs(10))).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::error!(error = %e, "billing flush at shutdown failed"),
+        Err(e) => tracing::error!(error = %e, "billing flush at shutdown failed"),
+    }
+    if !state.requests.flush(std::time::Duration::from_secs(5)).await {
+        tracing::warn!("request log flush at shutdown failed");
+    }
+    let store = state.store.clone();
+    let cooldowns = (!state.read_only).then(|| state.cooldown_records(util::now_ms()));
+    let saved = move || {
+        if let Some(records) = cooldowns {
+            store.save_cooldowns(&records)?;
+        }
+        store.flush()
+    };
+    match tokio::task::spawn_blocking(saved).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::error!(error = %e, "key store flush at shutdown failed"),
+        Err(e) => tracing::error!(error = %e, "key store flush at shutdown failed"),
+    }
+    tracing::info!("shutdown complete");
+}
+
+/// Re-read the config on SIGHUP and apply the hot-reloadable settings. Other changes are
+/// reported against `boot`, the config the process started with, until it is restarted.
+#[cfg(unix)]
+async fn reload_on_sighup(path: String, boot: config::Config, state: Arc<state::RouterState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!(error = %e, "SIGHUP handler not installed; config reload disabled");
+            return;
+        }
+    };
+
+    if path == "-" {
+        tracing::warn!("config was read from stdin; SIGHUP reload disabled");
+        return;
+    }
+
+    while hup.recv().await.is_some() {
+        let source = path.clone();
+        let loaded = tokio::task::spawn_blocking(move || config::Config::load(&source))
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        let cfg = match loaded {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                tracing::error!(path = %path, error = %e, "config reload failed; keeping current config");
+                state.record_reload("config", false);
+                continue;
+            }
+        };
+
+        let changed = state.apply_reload(&cfg);
+        state.record_reload("config", true);
+
+        let restart_required: Vec<String> = cfg
+            .changed_fields(&boot)
+            .into_iter()
+            .filter(|f| !state::HOT_RELOADABLE.contains(&f.as_str()))
+            .collect();
+
+        tracing::info!(
+            path = %path,
+            applied = ?changed,
+            restart_required = ?restart_required,
+            "config reloaded"
+        );
+    }
+}
@@ -1,20 +1,59 @@
 
 use crate::admin;
-use crate::state::{sanitize_hop_headers, RequestLogEntry, RouterState, HDR_AUTHORIZATION};
-use crate::util::now_ms;
-use flate2::{Decompress, FlushDecompress, Status};
-use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use crate::anthropic;
+use crate::billing::Reservation;
+use crate::config::{UnknownModelBehavior, UpstreamFormat};
+use crate::conn::NetErrorKind;
+use crate::state::{
+    sanitize_hop_headers, AttemptInfo, FailureReason, HeaderDenylist, InvalidDeployment,
+    RequestLogEntry, RouterState, Selected, HDR_AUTHORIZATION,
+};
+use crate::util::{fast_rand, mask_key, now_ms};
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
-use std::io;
+use std::io::{self, Write};
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
 
-pub async fn serve_http(addr: SocketAddr, state: Arc<RouterState>) -> anyhow::Result<()> {
+const HDR_REQUEST_ID: http::HeaderName = http::HeaderName::from_static("x-request-id");
+
+/// Socket options for the proxy listener (`listen_backlog`, `reuse_addr`, `reuse_port`).
+#[derive(Debug, Clone, Copy)]
+pub struct ListenOptions {
+    pub backlog: u32,
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+}
+
+pub fn bind_listener(addr: SocketAddr, opts: ListenOptions) -> io::Result<tokio::net::TcpListener> {
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        let socket = tokio::net::TcpSocket::new_v6()?;
+        // Linux defaults v6 sockets to dual-stack, which would make `[::]:port` collide with
+        // `0.0.0.0:port` listed alongside it; `[::]` alone then only serves IPv6.
+        socket2::SockRef::from(&socket).set_only_v6(true)?;
+        socket
+    };
+    socket.set_reuseaddr(opts.reuse_addr)?;
+    #[cfg(unix)]
+    socket.set_reuseport(opts.reuse_port)?;
+    #[cfg(not(unix))]
+    if opts.reuse_port {
+        tracing::warn!("reuse_port is only supported on unix; ignored");
+    }
+    socket.bind(addr)?;
+    socket.listen(opts.backlog)
+}
+
+/// Serve until shutdown begins, then stop accepting and return once open connections finish.
+pub async fn serve_http(listener: tokio::net::TcpListener, state: Arc<RouterState>) -> anyhow::Result<()> {
+    let shutdown_state = state.clone();
     let make_svc = make_service_fn(move |conn: &AddrStream| {
         let state = state.clone();
         let remote_addr = conn.remote_addr();
@@ -26,14 +65,75 @@ pub async fn serve_http(addr: SocketAddr, state: Arc<RouterState>) -> anyhow::Re
         }
     });
 
-    let server = Server::bind(&addr)
+    let server = Server::from_tcp(listener.into_std()?)?
         .tcp_nodelay(true)
-        .serve(make_svc);
+        .serve(make_svc)
+        .with_graceful_shutdown(async move { shutdown_state.shutdown_requested().await });
 
     server.await?;
     Ok(())
 }
 
+/// HTTPS variant of `serve_http`: same routes, TLS terminated in-process.
+pub async fn serve_https(
+    listener: tokio::net::TcpListener,
+    state: Arc<RouterState>,
+    acceptor: tokio_rustls::TlsAcceptor,
+) -> anyhow::Result<()> {
+    let mut conns = tokio::task::JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            res = listener.accept() => res,
+            _ = state.shutdown_requested() => break,
+        };
+        let (tcp, remote_addr) = match accepted {
+            Ok(v) => v,
+            Err(e) => {
+                // Transient (e.g. EMFILE); back off briefly instead of spinning.
+                tracing::warn!(error = %e, "accept failed");
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+        while conns.try_join_next().is_some() {}
+        let _ = tcp.set_nodelay(true);
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        conns.spawn(async move {
+            let tls = match acceptor.accept(tcp).await {
+                Ok(s) => s,
+                Err(e) => {
+                    tracing::debug!(client = %remote_addr, error = %e, "tls handshake failed");
+                    return;
+                }
+            };
+            let shutdown_state = state.clone();
+            let svc = service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(req, state, remote_addr).await) }
+            });
+            let conn = hyper::server::conn::Http::new()
+                .http1_only(true)
+                .serve_connection(tls, svc);
+            tokio::pin!(conn);
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = shutdown_state.shutdown_requested() => {
+                    // Finish the in-flight request, then close.
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = res {
+                tracing::debug!(client = %remote_addr, error = %e, "connection error");
+            }
+        });
+    }
+    drop(listener);
+    while conns.join_next().await.is_some() {}
+    Ok(())
+}
+
 async fn handle(
     req: Request<Body>,
     state: Arc<RouterState>,
@@ -46,16 +146,72 @@ async fn handle(
         return Response::new(Body::from("ok"));
     }
 
+    // Prometheus scrape endpoint (admin token).
+    if req.method() == hyper::Method::GET && path == "/metrics" {
+        if !state.admin_ip_allowed(client_addr.ip()) {
+            return admin::ip_forbidden();
+        }
+        return admin::prometheus_metrics(&req, &state);
+    }
+
     // Admin UI/API.
     if path.starts_with("/admin") {
-        return admin::handle_admin(req, state).await;
+        return admin::handle_admin(req, state, client_addr).await;
+    }
+
+    // Browser CORS (`proxy_cors_origins`): answer preflights here, tag responses below.
+    let cors = state.proxy_cors.clone();
+    let cors_origin = cors.as_ref().and_then(|c| c.allowed_origin(req.headers()));
+    if let Some(cors) = &cors {
+        let preflight = req.method() == hyper::Method::OPTIONS
+            && req.headers().contains_key(hyper::header::ACCESS_CONTROL_REQUEST_METHOD);
+        if preflight {
+            return match cors_origin {
+                Some(origin) => cors.preflight(origin),
+                None => RouterState::json_error(
+                    http::StatusCode::FORBIDDEN,
+                    "origin not allowed",
+                    "cors_origin_forbidden",
+                ),
+            };
+        }
     }
 
+    let request_id = request_id_for(req.headers());
+    let mut resp = handle_proxy(req, state, client_addr, path, request_id.clone()).await;
+    if let Ok(v) = http::HeaderValue::from_str(&request_id) {
+        resp.headers_mut().insert(HDR_REQUEST_ID, v);
+    }
+    if let Some(origin) = cors_origin {
+        crate::state::ProxyCors::apply(resp.headers_mut(), origin);
+    }
+    resp
+}
+
+/// Reuse the client's `X-Request-Id` when it is a sane token, else mint a random one.
+fn request_id_for(headers: &hyper::HeaderMap) -> Arc<str> {
+    if let Some(id) = headers.get(HDR_REQUEST_ID).and_then(|v| v.to_str().ok()) {
+        let id = id.trim();
+        if !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()) {
+            return Arc::from(id);
+        }
+    }
+    Arc::from(format!("{:016x}{:016x}", fast_rand(), fast_rand()))
+}
+
+async fn handle_proxy(
+    req: Request<Body>,
+    state: Arc<RouterState>,
+    client_addr: SocketAddr,
+    path: String,
+    request_id: Arc<str>,
+) -> Response<Body> {
     let start = Instant::now();
     let client_ip = client_addr.ip().to_string();
     let method = req.method().clone();
     let base_log_ctx = RequestLogContext::new(
         start,
+        request_id.clone(),
         client_ip.clone(),
         method.to_string(),
         path.clone(),
@@ -75,9 +231,13 @@ async fn handle(
         );
     }
 
-    let billing_key = match extract_api_key(req.headers()) {
-        Some(key) => key,
-        None => {
+    // Without a key the request may still be for an `anonymous_models` model; `forward`
+    // decides once the model is known. The models listing always needs a key.
+    let is_models_list =
+        req.method() == hyper::Method::GET && (path == "/v1/models" || path == "/v1/models/");
+    let billing_key = extract_api_key(req.headers(), &state.client_key_headers);
+    match &billing_key {
+        None if state.anonymous_models.is_empty() || is_models_list => {
             return logged_json_error(
                 &state,
                 &base_log_ctx,
@@ -86,29 +246,28 @@ async fn handle(
                 "api_key_required",
             );
         }
-    };
-
-    let balance = match state.billing.get_balance(&billing_key) {
-        Some(b) => b,
-        None => {
-            return logged_json_error(
-                &state,
-                &base_log_ctx,
-                http::StatusCode::UNAUTHORIZED,
-                "invalid api key",
-                "api_key_invalid",
-            );
-        }
-    };
-
-    if balance < 0 {
-        return logged_json_error(
-            &state,
-            &base_log_ctx,
-            http::StatusCode::UNAUTHORIZED,
-            "insufficient balance",
-            "balance_insufficient",
-        );
+        None => {}
+        Some(key) => match state.billing.get_balance(key) {
+            None => {
+                return logged_json_error(
+                    &state,
+                    &base_log_ctx,
+                    http::StatusCode::UNAUTHORIZED,
+                    "invalid api key",
+                    "api_key_invalid",
+                );
+            }
+            Some(balance) if balance < 0 => {
+                return logged_json_error(
+                    &state,
+                    &base_log_ctx,
+                    http::StatusCode::UNAUTHORIZED,
+                    "insufficient balance",
+                    "balance_insufficient",
+                );
+            }
+            Some(_) => {}
+        },
     }
 
     // Stats: request start.
@@ -117,9 +276,7 @@ async fn handle(
     let t0 = Instant::now();
 
     let now = now_ms();
-    let resp = if req.method() == hyper::Method::GET
-        && (path == "/v1/models" || path == "/v1/models/")
-    {
+    let resp = if is_models_list {
         let (resp, resp_bytes) = models_list(&state);
         record_request(&state, &base_log_ctx, resp.status().as_u16(), resp_bytes, None);
         resp
@@ -129,6 +286,7 @@ async fn handle(
             state.clone(),
             now,
             start,
+            request_id,
             client_ip,
             method,
             path,
@@ -145,15 +303,17 @@ async fn handle(
     resp
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn forward(
     req: Request<Body>,
     state: Arc<RouterState>,
     now_ms: u64,
     start: Instant,
+    request_id: Arc<str>,
     client_ip: String,
     method: hyper::Method,
     path: String,
-    billing_key: String,
+    billing_key: Option<String>,
 ) -> Response<Body> {
     const MAX_REQUEST_BODY_BYTES: usize = 16 * 1024 * 1024;
 
@@ -171,7 +331,11 @@ async fn forward(
         .and_then(|s| if s.is_empty() { None } else { Some(s.to_string()) });
     let out_method = parts.method.clone();
     let version = parts.version;
-    let headers = parts.headers.clone();
+    let mut headers = parts.headers.clone();
+    // The client's key is for this proxy only; the upstream gets its own via `set_auth`.
+    for name in state.client_key_headers.iter() {
+        headers.remove(name);
+    }
 
     // Read body into bytes for potential retries (necessary for 429 retry)
     use hyper::body::HttpBody;
@@ -198,7 +362,7 @@ async fn forward(
             }
         }
     }
-    let body_bytes = bytes::Bytes::from(body_bytes);
+    let mut body_bytes = bytes::Bytes::from(body_bytes);
     let req_bytes = body_bytes.len();
 
     let mut req_json = parse_request_json(&headers, &body_bytes);
@@ -211,6 +375,46 @@ async fn forward(
         model = path_model;
     }
 
+    // Reject oversized model names before they reach routing maps or the request log.
+    if model.as_ref().is_some_and(|m| m.len() > state.max_model_len) {
+        let mut log_ctx = RequestLogContext::new(
+            start,
+            request_id,
+            client_ip,
+            method.to_string(),
+            path,
+            None,
+            None,
+            req_bytes,
+        );
+        log_ctx.failure_reason = Some(FailureReason::InvalidRequest);
+        return logged_json_error(
+            &state,
+            &log_ctx,
+            http::StatusCode::BAD_REQUEST,
+            "model name too long",
+            "invalid_model",
+        );
+    }
+
+    // Aliases rewrite both the routing key and the forwarded body's `model`; with
+    // `rewrite_response_model` the response gets the alias back.
+    let mut response_model: Option<Arc<str>> = None;
+    if let Some(target) = model.as_deref().and_then(|m| state.resolve_model_alias(m)) {
+        if let Some(obj) = req_json.as_mut().and_then(|v| v.as_object_mut()) {
+            if obj.contains_key("model") {
+                obj.insert("model".into(), serde_json::Value::String(target.clone()));
+                if let Ok(encoded) = serde_json::to_vec(&obj) {
+                    body_bytes = bytes::Bytes::from(encoded);
+                }
+            }
+        }
+        if state.rewrite_response_model {
+            response_model = model.as_deref().map(Arc::from);
+        }
+        model = Some(target);
+    }
+
     let stream_request = req_json
         .as_ref()
         .and_then(|v| v.get("stream"))
@@ -220,6 +424,7 @@ async fn forward(
 
     let mut log_ctx = RequestLogContext::new(
         start,
+        request_id,
         client_ip,
         method.to_string(),
         path,
@@ -229,6 +434,7 @@ async fn forward(
     );
 
     let Some(model) = model else {
+        log_ctx.failure_reason = Some(FailureReason::InvalidRequest);
         return logged_json_error(
             &state,
             &log_ctx,
@@ -238,7 +444,102 @@ async fn forward(
         );
     };
 
-    let mut sel = if !state.model_exists(&model) {
+    let billing_key = match billing_key {
+        Some(key) => key,
+        None if state.anonymous_models.contains(&model) => {
+            log_ctx.anonymous = true;
+            if let Some(limiter) = &state.anonymous_limiter {
+                if let Err(wait_ms) = limiter.check(&log_ctx.client_ip, now_ms) {
+                    log_ctx.failure_reason = Some(FailureReason::RateLimited);
+                    let secs = wait_ms.div_ceil(1000).max(1);
+                    let mut resp = RouterState::rate_limit_error(
+                        "anonymous request rate limit exceeded",
+                        "anonymous_rate_limited",
+                    );
+                    resp.headers_mut().insert(RETRY_AFTER, http::HeaderValue::from(secs));
+                    return logged_response(&state, &log_ctx, resp);
+                }
+            }
+            // Unbilled: no quota or reservation, and sticky routing keys on the client IP.
+            String::new()
+        }
+        None => {
+            log_ctx.failure_reason = Some(FailureReason::ApiKeyRequired);
+            return logged_json_error(
+                &state,
+                &log_ctx,
+                http::StatusCode::UNAUTHORIZED,
+                "missing api key",
+                "api_key_required",
+            );
+        }
+    };
+    let anonymous = log_ctx.anonymous;
+
+    if !anonymous && state.billing.quota_exceeded(&billing_key) {
+        log_ctx.failure_reason = Some(FailureReason::QuotaExceeded);
+        let secs = crate::billing::ms_until_quota_reset().div_ceil(1000);
+        let mut resp = RouterState::rate_limit_error("daily token quota exceeded", "quota_exceeded");
+        resp.headers_mut().insert(RETRY_AFTER, http::HeaderValue::from(secs));
+        return logged_response(&state, &log_ctx, resp);
+    }
+
+    // Held across retries; refunded on drop unless settled with the response's usage.
+    let mut reservation = None;
+    if state.billing_reserve > 0 && !anonymous {
+        reservation = state.billing.reserve(&billing_key, state.billing_reserve);
+        if reservation.is_none() {
+            return logged_json_error(
+                &state,
+                &log_ctx,
+                http::StatusCode::UNAUTHORIZED,
+                "insufficient balance",
+                "balance_insufficient",
+            );
+        }
+    }
+
+    // Unknown models are rejected, or (passthrough) routed without the model filter so the
+    // upstream decides: to `default_upstream_id` when set, else to any upstream.
+    let unknown_model = !state.model_exists(&model);
+    // Billing keys tied to specific upstreams (`allowed_upstreams`) only ever select those.
+    let allowed = if anonymous { None } else { state.billing.allowed_upstreams(&billing_key) };
+    let allowed = allowed.as_deref().map(Vec::as_slice);
+    let select = |now_ms: u64| {
+        if unknown_model {
+            match state.default_upstream_id.as_deref() {
+                Some(id) => state
+                    .select_from_upstream(id, now_ms)
+                    .filter(|s| allowed.is_none_or(|ids| ids.iter().any(|a| **a == *s.upstream.id))),
+                None => state.select(allowed, now_ms),
+            }
+        } else {
+            let client = if anonymous { &log_ctx.client_ip } else { &billing_key };
+            state.select_for_client(&model, client, allowed, now_ms)
+        }
+    };
+
+    let reject_unknown = unknown_model && state.unknown_model_behavior == UnknownModelBehavior::Reject;
+    if let Some(ids) = allowed.filter(|_| !reject_unknown) {
+        let permitted = match (unknown_model, state.default_upstream_id.as_deref()) {
+            (true, Some(id)) => ids.iter().any(|a| a == id),
+            (true, None) => state.any_upstream_permitted(None, ids),
+            (false, _) => state.any_upstream_permitted(Some(&model), ids),
+        };
+        if !permitted {
+            log_ctx.failure_reason = Some(FailureReason::UpstreamNotAllowed);
+            return logged_json_error(
+                &state,
+                &log_ctx,
+                http::StatusCode::FORBIDDEN,
+                "no upstream allowed for this api key serves the model",
+                "upstream_not_allowed",
+            );
+        }
+    }
+
+    let mut sel = if reject_unknown {
+        log_ctx.failure_reason = Some(FailureReason::ModelNotFound);
         return logged_json_error(
             &state,
             &log_ctx,
@@ -246,44 +547,93 @@ async fn forward(
             "model not found",
             "model_not_found",
         );
-    } else if let Some(sel) = state.select_for_model(&model, now_ms) {
+    } else if let Some(sel) = select(now_ms) {
         sel
     } else {
-        return logged_json_error(
-            &state,
-            &log_ctx,
+        log_ctx.failure_reason = Some(FailureReason::NoAvailableKeys);
+        let mut resp = RouterState::json_error(
             http::StatusCode::SERVICE_UNAVAILABLE,
             "no available upstream keys for model",
             "model_unavailable",
         );
+        // Tell clients when the nearest cooldown ends.
+        if let Some(secs) = state.retry_after_secs(&model, allowed, now_ms) {
+            resp.headers_mut().insert(RETRY_AFTER, http::HeaderValue::from(secs));
+        }
+        return logged_response(&state, &log_ctx, resp);
     };
 
-    let mut body_bytes = body_bytes;
-    let mut injected = false;
-    if stream_request && is_chat_completions && state.should_inject_usage(sel.upstream.id.as_ref())
-    {
-        if let Some(ref mut json) = req_json {
-            if ensure_stream_usage(json) {
-                if let Ok(encoded) = serde_json::to_vec(json) {
-                    body_bytes = bytes::Bytes::from(encoded);
-                    injected = true;
-                }
-            }
-        }
-    }
+    // Whether the client itself asked for a usage chunk; translated streams only emit one then.
+    let include_usage = req_json
+        .as_ref()
+        .and_then(|v| v.pointer("/stream_options/include_usage"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // Messages API body for Anthropic upstreams and the usage-injected body for
+    // `usage_inject_upstreams`, each built on first use (retries may switch between
+    // upstreams of either format). Only for bodies no `request_rules` entry edits.
+    let mut anthropic_body: Option<Option<bytes::Bytes>> = None;
+    let mut usage_body: Option<Option<bytes::Bytes>> = None;
+    let request_rules = state.request_rules.load_full();
 
     // Retry policy from config.
     let max_retries = state.max_retries;
     let mut retry_count = 0;
 
+    // Wall-clock budget across all attempts, measured from request arrival.
+    let deadline = state.max_request_duration.map(|d| start + d);
+    let budget_left = || deadline.map(|d| d.saturating_duration_since(Instant::now()));
+    let budget_spent = || budget_left().is_some_and(|d| d.is_zero());
+
     loop {
         log_ctx.upstream_id = Some(sel.upstream.id.to_string());
+        log_ctx.upstream_request_id = None;
         let upstream = &sel.upstream;
 
-        let uri = match upstream.build_uri(&original_pq) {
+        let inject_usage = stream_request
+            && is_chat_completions
+            && state.should_inject_usage(upstream.id.as_ref())
+            && upstream.format == UpstreamFormat::OpenAi;
+        // Rules may depend on the upstream, so an edited body is rebuilt on every attempt.
+        let ruled = request_rules.apply(req_json.as_ref(), &model, upstream.id.as_ref());
+        let usage_injected = if !inject_usage {
+            None
+        } else if ruled.is_some() {
+            usage_injected_body(ruled.clone())
+        } else {
+            usage_body.get_or_insert_with(|| usage_injected_body(req_json.clone())).clone()
+        };
+
+        let translated = if !(is_chat_completions && upstream.format == UpstreamFormat::Anthropic) {
+            None
+        } else if ruled.is_some() {
+            messages_body(ruled.as_ref())
+        } else {
+            anthropic_body.get_or_insert_with(|| messages_body(req_json.as_ref())).clone()
+        };
+        let pq = if translated.is_some() {
+            http::uri::PathAndQuery::from_static("/v1/messages")
+        } else {
+            original_pq.clone()
+        };
+
+        let uri = match upstream.build_model_uri(&pq, &model) {
             Ok(u) => u,
+            Err(e) if e.is::<InvalidDeployment>() => {
+                log_ctx.failure_reason = Some(FailureReason::InvalidRequest);
+                return logged_json_error(
+                    &state,
+                    &log_ctx,
+                    http::StatusCode::BAD_REQUEST,
+                    "model is not a valid deployment name",
+                    "invalid_model",
+                );
+            }
             Err(_) => {
-                return RouterState::json_error(
+                log_ctx.failure_reason = Some(FailureReason::Internal);
+                return logged_json_error(
+                    &state,
+                    &log_ctx,
                     http::StatusCode::BAD_GATEWAY,
                     "invalid upstream URI",
                     "invalid_upstream_uri",
@@ -296,27 +646,71 @@ async fn forward(
             uri,
             version,
             &headers,
-            body_bytes.clone(),
+            translated
+                .clone()
+                .or_else(|| usage_injected.clone())
+                .or_else(|| ruled.and_then(|v| serde_json::to_vec(&v).ok()).map(bytes::Bytes::from))
+                .unwrap_or_else(|| body_bytes.clone()),
             &sel,
-            injected,
+            state.strip_request_headers.as_deref(),
+            &log_ctx.request_id,
         ) {
-            Ok(req) => req,
-            Err(resp) => return logged_response(&state, &log_ctx, resp),
+            Ok(mut req) => {
+                if translated.is_some() || response_model.is_some() {
+                    // The response body is rewritten, so ask for it uncompressed.
+                    req.headers_mut()
+                        .insert(ACCEPT_ENCODING, http::HeaderValue::from_static("identity"));
+                }
+                req
+            }
+            Err(resp) => {
+                log_ctx.failure_reason = Some(FailureReason::Internal);
+                return logged_response(&state, &log_ctx, resp);
+            }
         };
 
-        // Enforce timeout.
-        let res = tokio::time::timeout(state.request_timeout, state.client.request(out_req)).await;
+        // Enforce timeout, capped by whatever is left of the request budget.
+        let mut attempt_timeout = state.timeout_for(&model, upstream);
+        let mut budget_capped = false;
+        if let Some(left) = budget_left() {
+            if left < attempt_timeout {
+                attempt_timeout = left;
+                budget_capped = true;
+            }
+        }
+        let attempt_start = Instant::now();
+        let res = tokio::time::timeout(attempt_timeout, state.client.request(out_req)).await;
 
         match res {
             Ok(Ok(up_resp)) => {
+                // Only this attempt's time: retries and other upstreams must not skew the EWMA.
+                state.record_upstream_latency(&sel.upstream, attempt_start.elapsed().as_nanos() as u64);
                 let status = up_resp.status();
-                state.on_upstream_status(&sel, status, now_ms);
-
-                // Retry on auth errors, rate limit, and configurable status codes.
-                let should_retry = should_retry_status(&state, status);
-
-                if should_retry && retry_count < max_retries {
-                    if let Some(new_sel) = state.select_for_model(&model, now_ms) {
+                push_attempt(
+                    &mut log_ctx.attempts,
+                    &state,
+                    &sel,
+                    Some(status.as_u16()),
+                    None,
+                    attempt_start,
+                );
+                state.on_upstream_status(&sel, status, up_resp.headers(), now_ms);
+                log_ctx.upstream_request_id =
+                    upstream_request_id(&state.upstream_request_id_headers, up_resp.headers());
+
+                // Retry on auth errors, rate limit, 5xx, and configurable status codes.
+                // Only the status line has been read here, so nothing has reached the client
+                // yet; once `proxy_upstream_response` starts relaying, there is no retry.
+                let should_retry = state.should_retry_status(status);
+
+                if should_retry {
+                    if retry_count >= max_retries {
+                        log_ctx.failure_reason = Some(FailureReason::RetriesExhausted);
+                    } else if budget_spent() {
+                        // Out of time: relay this response rather than retrying.
+                        log_ctx.failure_reason = Some(FailureReason::RetriesExhausted);
+                        log_ctx.budget_exhausted = true;
+                    } else if let Some(new_sel) = select(now_ms) {
                         retry_count += 1;
                         tracing::debug!(
                             status = %status,
@@ -327,23 +721,63 @@ async fn forward(
                         );
                         sel = new_sel;
                         continue;
+                    } else {
+                        log_ctx.failure_reason = Some(FailureReason::NoAvailableKeys);
+                    }
+
+                    // Giving up on a 429: answer with a uniform rate-limit error whatever the
+                    // upstream's body looked like.
+                    if status == http::StatusCode::TOO_MANY_REQUESTS {
+                        let retry_after = up_resp.headers().get(RETRY_AFTER).cloned().or_else(|| {
+                            state.retry_after_secs(&model, allowed, now_ms).map(http::HeaderValue::from)
+                        });
+                        let mut resp = RouterState::rate_limit_error(
+                            "rate limit reached for all upstream keys; retries exhausted",
+                            "proxy_rate_limited",
+                        );
+                        if let Some(v) = retry_after {
+                            resp.headers_mut().insert(RETRY_AFTER, v);
+                        }
+                        return logged_response(&state, &log_ctx, resp);
                     }
                 }
 
+                let up_resp = match response_model.as_deref() {
+                    Some(name) if !stream_request => {
+                        with_response_model(up_resp, name, attempt_timeout).await
+                    }
+                    _ => up_resp,
+                };
                 return proxy_upstream_response(
                     up_resp,
                     state.clone(),
                     log_ctx,
                     stream_request,
-                    Some(billing_key.clone()),
+                    (!anonymous).then(|| billing_key.clone()),
+                    reservation.take(),
+                    sel.clone(),
+                    translated.is_some().then_some(Translate::Anthropic { include_usage }),
+                    response_model.clone(),
                 );
             }
-            Ok(Err(_e)) => {
-                state.on_network_error(&sel, now_ms);
+            Ok(Err(e)) => {
+                let kind = NetErrorKind::classify(&e);
+                push_attempt(
+                    &mut log_ctx.attempts,
+                    &state,
+                    &sel,
+                    None,
+                    Some(kind.as_str()),
+                    attempt_start,
+                );
+                tracing::debug!(upstream = %sel.upstream.id, kind = kind.as_str(), error = %e, "upstream network error");
+                state.on_network_error(&sel, kind, now_ms);
 
                 // Retry on network error (upstream is now banned, next select picks a different one).
-                if retry_count < max_retries {
-                    if let Some(new_sel) = state.select_for_model(&model, now_ms) {
+                if budget_spent() {
+                    log_ctx.budget_exhausted = true;
+                } else if retry_count < max_retries {
+                    if let Some(new_sel) = select(now_ms) {
                         retry_count += 1;
                         tracing::debug!(
                             retry = retry_count,
@@ -356,6 +790,12 @@ async fn forward(
                     }
                 }
 
+                log_ctx.failure_reason = Some(match kind {
+                    NetErrorKind::Dns => FailureReason::UpstreamDns,
+                    NetErrorKind::Connect => FailureReason::UpstreamConnect,
+                    NetErrorKind::Tls => FailureReason::UpstreamTls,
+                    NetErrorKind::Other => FailureReason::UpstreamError,
+                });
                 let resp = RouterState::json_error(
                     http::StatusCode::BAD_GATEWAY,
                     "upstream request failed",
@@ -364,11 +804,24 @@ async fn forward(
                 return logged_response(&state, &log_ctx, resp);
             }
             Err(_) => {
-                state.on_timeout(&sel, now_ms);
+                push_attempt(
+                    &mut log_ctx.attempts,
+                    &state,
+                    &sel,
+                    None,
+                    Some("timeout"),
+                    attempt_start,
+                );
+                if budget_capped {
+                    // Cut short by the request budget, not the upstream's fault: no ban, no retry.
+                    log_ctx.budget_exhausted = true;
+                } else {
+                    state.on_timeout(&sel, now_ms);
+                }
 
                 // Retry on timeout (upstream is now banned, next select picks a different one).
-                if retry_count < max_retries {
-                    if let Some(new_sel) = state.select_for_model(&model, now_ms) {
+                if !budget_capped && retry_count < max_retries {
+                    if let Some(new_sel) = select(now_ms) {
                         retry_count += 1;
                         tracing::debug!(
                             retry = retry_count,
@@ -381,9 +834,16 @@ async fn forward(
                     }
                 }
 
+                log_ctx.failure_reason = Some(FailureReason::UpstreamTimeout);
+                // Name the upstream and the timeout that fired to make slow upstreams obvious.
+                let message = format!(
+                    "upstream request timeout (upstream={}, timeout_ms={})",
+                    sel.upstream.id,
+                    attempt_timeout.as_millis()
+                );
                 let resp = RouterState::json_error(
                     http::StatusCode::GATEWAY_TIMEOUT,
-                    "upstream request timeout",
+                    &message,
                     "upstream_timeout",
                 );
                 return logged_response(&state, &log_ctx, resp);
@@ -395,17 +855,25 @@ async fn forward(
 #[derive(Clone)]
 struct RequestLogContext {
     start: Instant,
+    request_id: Arc<str>,
     client_ip: String,
     method: String,
     path: String,
     model: Option<String>,
     upstream_id: Option<String>,
+    upstream_request_id: Option<String>,
     req_bytes: usize,
+    failure_reason: Option<FailureReason>,
+    budget_exhausted: bool,
+    anonymous: bool,
+    attempts: Vec<AttemptInfo>,
 }
 
 impl RequestLogContext {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         start: Instant,
+        request_id: Arc<str>,
         client_ip: String,
         method: String,
         path: String,
@@ -415,18 +883,55 @@ impl RequestLogContext {
     ) -> Self {
         Self {
             start,
+            request_id,
             client_ip,
             method,
             path,
             model,
             upstream_id,
+            upstream_request_id: None,
             req_bytes,
+            failure_reason: None,
+            budget_exhausted: false,
+            anonymous: false,
+            attempts: Vec::new(),
         }
     }
 }
 
+/// Note how an attempt on `sel` went, when `detailed_request_log` is on. Takes the field
+/// rather than the whole context, which the selection closure in `forward` borrows from.
+fn push_attempt(
+    attempts: &mut Vec<AttemptInfo>,
+    state: &RouterState,
+    sel: &Selected,
+    status: Option<u16>,
+    error: Option<&'static str>,
+    started: Instant,
+) {
+    if !state.detailed_request_log {
+        return;
+    }
+    attempts.push(AttemptInfo {
+        upstream_id: sel.upstream.id.to_string(),
+        key: mask_key(&sel.key.key),
+        status,
+        error,
+        latency_ms: started.elapsed().as_millis() as u64,
+    });
+}
+
+/// First of `names` present on the upstream response, as text.
+fn upstream_request_id(names: &[http::HeaderName], headers: &hyper::HeaderMap) -> Option<String> {
+    names
+        .iter()
+        .find_map(|n| headers.get(n))
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
 #[derive(Clone, Copy)]
-struct UsageTokens {
+pub struct UsageTokens {
     prompt: u64,
     completion: u64,
     total: u64,
@@ -439,20 +944,42 @@ fn record_request(
     resp_bytes: usize,
     usage: Option<UsageTokens>,
 ) {
+    let latency_ms = ctx.start.elapsed().as_millis() as u64;
+    let slow = state.slow_request_ms > 0 && latency_ms > state.slow_request_ms;
+    if slow {
+        tracing::warn!(
+            model = ctx.model.as_deref().unwrap_or("-"),
+            upstream = ctx.upstream_id.as_deref().unwrap_or("-"),
+            path = %ctx.path,
+            status,
+            latency_ms,
+            "slow request"
+        );
+    }
     let entry = RequestLogEntry {
         ts_ms: now_ms(),
+        request_id: ctx.request_id.to_string(),
+        upstream_request_id: ctx.upstream_request_id.clone(),
         client_ip: ctx.client_ip.clone(),
         method: ctx.method.clone(),
         path: ctx.path.clone(),
         model: ctx.model.clone(),
         upstream_id: ctx.upstream_id.clone(),
         status,
-        latency_ms: ctx.start.elapsed().as_millis() as u64,
+        latency_ms,
         req_bytes: ctx.req_bytes,
         resp_bytes,
         prompt_tokens: usage.map(|u| u.prompt),
         completion_tokens: usage.map(|u| u.completion),
         total_tokens: usage.map(|u| u.total),
+        cost: usage
+            .filter(|_| !ctx.anonymous)
+            .map(|u| state.billing.usage_cost(ctx.model.as_deref(), u.prompt, u.completion)),
+        failure_reason: ctx.failure_reason,
+        budget_exhausted: ctx.budget_exhausted,
+        slow,
+        anonymous: ctx.anonymous,
+        attempts: ctx.attempts.clone(),
     };
     state.record_request(entry);
 }
@@ -477,6 +1004,7 @@ fn logged_response(
     resp
 }
 
+#[allow(clippy::result_large_err, clippy::too_many_arguments)]
 fn build_upstream_request(
     method: hyper::Method,
     uri: http::Uri,
@@ -484,7 +1012,8 @@ fn build_upstream_request(
     headers: &hyper::HeaderMap,
     body_bytes: bytes::Bytes,
     sel: &crate::state::Selected,
-    injected: bool,
+    strip: Option<&HeaderDenylist>,
+    request_id: &str,
 ) -> Result<Request<Body>, Response<Body>> {
     let mut builder = hyper::Request::builder()
         .method(method)
@@ -492,6 +1021,9 @@ fn build_upstream_request(
         .version(version);
 
     for (name, value) in headers.iter() {
+        if strip.is_some_and(|d| d.matches(name)) {
+            continue;
+        }
         builder = builder.header(name.clone(), value.clone());
     }
 
@@ -504,32 +1036,42 @@ fn build_upstream_request(
     })?;
 
     sanitize_hop_headers(out_req.headers_mut());
-    out_req.headers_mut().remove(HDR_AUTHORIZATION);
-    out_req
-        .headers_mut()
-        .insert(HDR_AUTHORIZATION, sel.key.auth_header.clone());
-    if injected {
-        out_req.headers_mut().remove(CONTENT_LENGTH);
-        if let Ok(v) = http::HeaderValue::from_str(&body_bytes.len().to_string()) {
-            out_req.headers_mut().insert(CONTENT_LENGTH, v);
-        }
+    if let Ok(v) = http::HeaderValue::from_str(request_id) {
+        out_req.headers_mut().insert(HDR_REQUEST_ID, v);
+    }
+    sel.upstream.set_auth(out_req.headers_mut(), &sel.key);
+    // hyper only derives Host from the URI when the request has none.
+    if let Some(host) = &sel.upstream.host_header {
+        out_req.headers_mut().insert(hyper::header::HOST, host.clone());
+    }
+    // The body is always fully buffered and may have been rewritten, so the client's
+    // Content-Length can be stale or, for a chunked request, missing (Transfer-Encoding is
+    // dropped with the hop-by-hop headers). Empty bodies are left to hyper.
+    out_req.headers_mut().remove(CONTENT_LENGTH);
+    if !body_bytes.is_empty() {
+        out_req.headers_mut().insert(CONTENT_LENGTH, http::HeaderValue::from(body_bytes.len()));
     }
 
     Ok(out_req)
 }
 
-fn should_retry_status(state: &RouterState, status: http::StatusCode) -> bool {
-    status == http::StatusCode::UNAUTHORIZED
-        || status == http::StatusCode::FORBIDDEN
-        || state.should_retry_status(status)
+/// Response rewriting for upstreams that don't speak the OpenAI format.
+#[derive(Clone, Copy)]
+enum Translate {
+    Anthropic { include_usage: bool },
 }
 
+#[allow(clippy::too_many_arguments)]
 fn proxy_upstream_response(
     up_resp: Response<Body>,
     state: Arc<RouterState>,
-    log_ctx: RequestLogContext,
+    mut log_ctx: RequestLogContext,
     stream_request: bool,
     billing_key: Option<String>,
+    reservation: Option<Reservation>,
+    sel: Selected,
+    translate: Option<Translate>,
+    response_model: Option<Arc<str>>,
 ) -> Response<Body> {
     let (mut parts, body) = up_resp.into_parts();
     sanitize_hop_headers(&mut parts.headers);
@@ -552,33 +1094,121 @@ fn proxy_upstream_response(
         || (content_type.starts_with("application/json") && !want_sse_usage);
     let want_usage = want_sse_usage || want_json_usage;
 
-    let mut decoder = if want_usage && content_encoding.contains("gzip") {
-        Some(GzipDecoder::new())
-    } else {
-        None
+    let content_encoding = content_encoding.trim().to_ascii_lowercase();
+    let encoded = !content_encoding.is_empty() && content_encoding != "identity";
+
+    // Translated bodies are decoded before rewriting; bodies that can't be decoded are
+    // relayed as-is.
+    let mut translator = match translate {
+        Some(Translate::Anthropic { include_usage }) if is_event_stream => Some(
+            anthropic::ResponseTranslator::Sse(anthropic::SseTranslator::new(include_usage)),
+        ),
+        Some(Translate::Anthropic { .. }) if content_type.starts_with("application/json") => {
+            Some(anthropic::ResponseTranslator::Json(Some(Vec::new())))
+        }
+        _ => None,
+    };
+    let mut translate_decoder = None;
+    if translator.is_some() && encoded {
+        translate_decoder = BodyDecoder::for_encoding(&content_encoding);
+        if translate_decoder.is_some() {
+            parts.headers.remove(CONTENT_ENCODING);
+        } else {
+            translator = None;
+        }
+    }
+    if translator.is_some() {
+        parts.headers.remove(CONTENT_LENGTH);
+    }
+
+    // `rewrite_response_model` for streams (JSON bodies are done by `with_response_model`);
+    // runs on the translated stream, or on the upstream's if it came uncompressed.
+    let mut model_rewriter = match response_model {
+        Some(name) if is_event_stream && (translator.is_some() || !encoded) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Some(SseModelRewriter::new(name))
+        }
+        _ => None,
     };
 
+    // Unknown encodings (or stacked ones like "gzip, br") disable usage parsing; the body
+    // is still relayed untouched.
+    let mut decoder = None;
+    let mut decodable = true;
+    if want_usage && encoded && translator.is_none() {
+        decoder = BodyDecoder::for_encoding(&content_encoding);
+        decodable = decoder.is_some();
+    }
+
+    // `sse_usage_tail_only`: keep only the stream's last bytes and parse them once at the end.
+    // Translated streams are exempt (the translator tracks usage as it rewrites events).
+    let sse_tail_only = want_sse_usage && translator.is_none() && sel.upstream.sse_usage_tail_only;
+
     let (tx, rx) = tokio::sync::mpsc::channel::<Result<bytes::Bytes, io::Error>>(32);
     tokio::spawn(async move {
         use hyper::body::HttpBody;
+        // `sel` keeps the upstream counted as inflight until the body is fully relayed.
         const MAX_PARSE_BYTES: usize = 32 * 1024 * 1024;
         const MAX_SSE_BUF_BYTES: usize = 2 * 1024 * 1024;
         const MAX_DECOMPRESSED_BYTES: usize = 128 * 1024 * 1024;
+        const SSE_TAIL_BYTES: usize = 64 * 1024;
 
         let mut resp_bytes = 0usize;
         let mut usage: Option<UsageTokens> = None;
-        let mut parse_enabled = want_usage;
+        let mut parse_enabled = want_usage && decodable;
         let mut sse_buf = String::new();
+        let mut sse_tail: Vec<u8> = Vec::new();
+        let mut sse_tail_truncated = false;
         let mut json_buf: Vec<u8> = Vec::new();
         let mut json_overflow = false;
         let mut decompressed_bytes = 0usize;
 
         let mut body = body;
-        while let Some(chunk) = body.data().await {
+        let mut first_chunk = true;
+        let mut stalled = false;
+        loop {
+            let limit = if first_chunk {
+                state.first_byte_timeout
+            } else {
+                state.stream_idle_timeout
+            };
+            let next = match limit {
+                Some(limit) => match tokio::time::timeout(limit, body.data()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        stalled = true;
+                        break;
+                    }
+                },
+                None => body.data().await,
+            };
+            let Some(chunk) = next else {
+                break;
+            };
+            first_chunk = false;
             match chunk {
                 Ok(chunk) => {
+                    // Usage is parsed from the upstream's (decoded) body, before rewriting.
+                    let (chunk, plain) = match translator.as_mut() {
+                        Some(t) => {
+                            let plain = match translate_decoder.as_mut() {
+                                Some(dec) => dec.decompress_chunk(&chunk).unwrap_or_else(|_| {
+                                    parse_enabled = false;
+                                    Vec::new()
+                                }),
+                                None => chunk.to_vec(),
+                            };
+                            (bytes::Bytes::from(t.push(&plain)), Some(plain))
+                        }
+                        None => (chunk, None),
+                    };
+                    let chunk = match model_rewriter.as_mut() {
+                        Some(r) => bytes::Bytes::from(r.push(&chunk)),
+                        None => chunk,
+                    };
+
                     resp_bytes = resp_bytes.saturating_add(chunk.len());
-                    if tx.send(Ok(chunk.clone())).await.is_err() {
+                    if !chunk.is_empty() && tx.send(Ok(chunk.clone())).await.is_err() {
                         break;
                     }
 
@@ -586,7 +1216,9 @@ fn proxy_upstream_response(
                         continue;
                     }
 
-                    let parse_bytes = if let Some(dec) = decoder.as_mut() {
+                    let parse_bytes = if let Some(plain) = plain {
+                        plain
+                    } else if let Some(dec) = decoder.as_mut() {
                         match dec.decompress_chunk(&chunk) {
                             Ok(out) => out,
                             Err(_) => {
@@ -608,7 +1240,14 @@ fn proxy_upstream_response(
                     }
                     decompressed_bytes = decompressed_bytes.saturating_add(parse_bytes.len());
 
-                    if want_sse_usage {
+                    if sse_tail_only {
+                        sse_tail.extend_from_slice(&parse_bytes);
+                        // Trim in batches so the copy is amortized over many chunks.
+                        if sse_tail.len() > 2 * SSE_TAIL_BYTES {
+                            sse_tail.drain(..sse_tail.len() - SSE_TAIL_BYTES);
+                            sse_tail_truncated = true;
+                        }
+                    } else if want_sse_usage {
                         if sse_buf.len().saturating_add(parse_bytes.len()) > MAX_SSE_BUF_BYTES {
                             parse_enabled = false;
                             continue;
@@ -628,12 +1267,69 @@ fn proxy_upstream_response(
             }
         }
 
-        if usage.is_none() && want_json_usage && !json_overflow {
+        let mut tail = translator.as_mut().map(|t| t.finish()).unwrap_or_default();
+        if let Some(r) = model_rewriter.as_mut() {
+            tail = r.push(&tail);
+            tail.extend(r.finish());
+        }
+        resp_bytes = resp_bytes.saturating_add(tail.len());
+        // A stalled stream must not end with a clean-looking `[DONE]`.
+        if !tail.is_empty() && !stalled {
+            let _ = tx.send(Ok(bytes::Bytes::from(tail))).await;
+        }
+
+        if let Some(t) = translator.as_ref() {
+            // Anthropic streams split usage across events; the translator has the merged totals.
+            if let Some((prompt, completion)) = t.usage() {
+                usage = Some(UsageTokens {
+                    prompt,
+                    completion,
+                    total: prompt + completion,
+                });
+            }
+        }
+
+        if usage.is_none() && sse_tail_only && parse_enabled {
+            usage = parse_sse_usage_tail(&sse_tail, sse_tail_truncated);
+        }
+
+        if usage.is_none() && want_json_usage && !json_overflow && !stalled {
             usage = usage_from_json_bytes(&json_buf);
         }
 
-        if let (Some(key), Some(found)) = (billing_key.as_deref(), usage) {
-            let _ = state.billing.apply_usage(key, found.total);
+        if stalled {
+            state.on_timeout(&sel, now_ms());
+            log_ctx.failure_reason = Some(FailureReason::UpstreamTimeout);
+            tracing::warn!(
+                upstream = %sel.upstream.id,
+                model = log_ctx.model.as_deref().unwrap_or("-"),
+                first_byte = first_chunk,
+                resp_bytes,
+                "upstream response stalled; aborting it"
+            );
+            // Abort the client's response instead of ending it as if complete.
+            let _ = tx
+                .send(Err(io::Error::new(io::ErrorKind::TimedOut, "upstream response stalled")))
+                .await;
+        }
+
+        // Usage seen before a stall (e.g. an Anthropic stream's prompt tokens) is still billed.
+        // Without usage an unsettled reservation is refunded when dropped.
+        match (reservation, billing_key.as_deref(), usage) {
+            (Some(r), _, Some(found)) => {
+                r.settle(log_ctx.model.as_deref(), found.prompt, found.completion, found.total);
+            }
+            (None, Some(key), Some(found)) => {
+                let _ = state.billing.apply_usage(
+                    key,
+                    log_ctx.model.as_deref(),
+                    found.prompt,
+                    found.completion,
+                    found.total,
+                    0,
+                );
+            }
+            _ => {}
         }
         record_request(&state, &log_ctx, status.as_u16(), resp_bytes, usage);
     });
@@ -641,38 +1337,152 @@ fn proxy_upstream_response(
     Response::from_parts(parts, Body::wrap_stream(ReceiverStream::new(rx)))
 }
 
-fn extract_api_key(headers: &hyper::HeaderMap) -> Option<String> {
-    if let Some(h) = headers.get("x-api-key") {
-        if let Ok(s) = h.to_str() {
-            let key = s.trim();
-            if !key.is_empty() {
-                return Some(key.to_string());
+/// Rewrite the top-level `model` of a plain (uncompressed) JSON response to `name` and fix up
+/// Content-Length. Other responses, and bodies over 8MB, are relayed as they are.
+async fn with_response_model(resp: Response<Body>, name: &str, timeout: std::time::Duration) -> Response<Body> {
+    use hyper::body::HttpBody;
+    use tokio_stream::StreamExt;
+    const MAX_REWRITE_BYTES: usize = 8 * 1024 * 1024;
+
+    let (mut parts, mut body) = resp.into_parts();
+    let is_json = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let encoded = parts
+        .headers
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| !v.trim().eq_ignore_ascii_case("identity"));
+    if !is_json || encoded {
+        return Response::from_parts(parts, body);
+    }
+
+    let mut buf = Vec::new();
+    let read = tokio::time::timeout(timeout, async {
+        while let Some(chunk) = body.data().await {
+            buf.extend_from_slice(&chunk?);
+            if buf.len() > MAX_REWRITE_BYTES {
+                return Ok(false);
             }
         }
+        Ok::<_, hyper::Error>(true)
+    })
+    .await;
+    let body = match read {
+        Ok(Ok(true)) => {
+            let buf = rewrite_json_model(&buf, name).unwrap_or(buf);
+            parts.headers.insert(CONTENT_LENGTH, http::HeaderValue::from(buf.len()));
+            Body::from(buf)
+        }
+        // Too large: relay what was read, then the rest, untouched.
+        Ok(Ok(false)) => {
+            let head = tokio_stream::once(Ok(bytes::Bytes::from(buf)));
+            Body::wrap_stream(head.chain(body))
+        }
+        Ok(Err(e)) => Body::wrap_stream(tokio_stream::once(Err::<bytes::Bytes, _>(e))),
+        Err(_) => Body::wrap_stream(tokio_stream::once(Err::<bytes::Bytes, _>(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "upstream response body timed out",
+        )))),
+    };
+    Response::from_parts(parts, body)
+}
+
+/// `body` re-encoded with its top-level string `model` replaced by `name`; `None` if it has none.
+fn rewrite_json_model(body: &[u8], name: &str) -> Option<Vec<u8>> {
+    let mut v: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let model = v.as_object_mut()?.get_mut("model").filter(|m| m.is_string())?;
+    *model = serde_json::Value::String(name.to_string());
+    serde_json::to_vec(&v).ok()
+}
+
+/// Replaces the `model` of every `data:` event in an OpenAI-style SSE stream. Bytes are held
+/// back only up to the end of the current line.
+struct SseModelRewriter {
+    name: Arc<str>,
+    buf: Vec<u8>,
+}
+
+impl SseModelRewriter {
+    /// A line longer than this is relayed unchanged rather than buffered further.
+    const MAX_LINE_BYTES: usize = 2 * 1024 * 1024;
+
+    fn new(name: Arc<str>) -> Self {
+        Self { name, buf: Vec::new() }
     }
-    if let Some(h) = headers.get(HDR_AUTHORIZATION) {
-        if let Ok(s) = h.to_str() {
-            let raw = s.trim();
-            if raw.is_empty() {
-                return None;
+
+    fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(chunk);
+        let Some(end) = self.buf.iter().rposition(|&b| b == b'\n') else {
+            if self.buf.len() > Self::MAX_LINE_BYTES {
+                return std::mem::take(&mut self.buf);
             }
-            let key = raw
+            return Vec::new();
+        };
+        let rest = self.buf.split_off(end + 1);
+        let lines = std::mem::replace(&mut self.buf, rest);
+        let mut out = Vec::with_capacity(lines.len());
+        for line in lines.split_inclusive(|&b| b == b'\n') {
+            self.rewrite_line(line, &mut out);
+        }
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let line = std::mem::take(&mut self.buf);
+        let mut out = Vec::with_capacity(line.len());
+        self.rewrite_line(&line, &mut out);
+        out
+    }
+
+    fn rewrite_line(&self, line: &[u8], out: &mut Vec<u8>) {
+        let content = line.trim_ascii_end();
+        let rewritten = content
+            .strip_prefix(b"data:")
+            .map(|d| d.trim_ascii_start())
+            .filter(|d| d.starts_with(b"{") && d.windows(7).any(|w| w == b"\"model\""))
+            .and_then(|d| rewrite_json_model(d, &self.name));
+        match rewritten {
+            Some(json) => {
+                out.extend_from_slice(b"data: ");
+                out.extend_from_slice(&json);
+                out.extend_from_slice(&line[content.len()..]);
+            }
+            None => out.extend_from_slice(line),
+        }
+    }
+}
+
+/// The client's API key from the first of `names` (`client_key_headers`) with a non-empty value.
+fn extract_api_key(headers: &hyper::HeaderMap, names: &[http::HeaderName]) -> Option<String> {
+    for name in names {
+        let Some(s) = headers.get(name).and_then(|h| h.to_str().ok()) else {
+            continue;
+        };
+        let mut key = s.trim();
+        if name == HDR_AUTHORIZATION {
+            key = key
                 .strip_prefix("Bearer ")
-                .or_else(|| raw.strip_prefix("bearer "))
-                .unwrap_or(raw)
+                .or_else(|| key.strip_prefix("bearer "))
+                .unwrap_or(key)
                 .trim();
-            if !key.is_empty() {
-                return Some(key.to_string());
-            }
+        }
+        if !key.is_empty() {
+            return Some(key.to_string());
         }
     }
     None
 }
 
 fn models_list(state: &RouterState) -> (Response<Body>, usize) {
-    let routes = state.get_model_routes();
+    // In-memory routes rather than the saved file, so `model_deny` etc. apply.
+    let routes = state.build_model_routes();
     let mut models: Vec<String> = routes.models.keys().cloned().collect();
+    models.extend(state.model_aliases.load().keys().cloned());
     models.sort();
+    models.dedup();
 
     let data: Vec<serde_json::Value> = models
         .iter()
@@ -714,7 +1524,32 @@ fn parse_request_json(
     serde_json::from_slice(body).ok()
 }
 
-fn ensure_stream_usage(v: &mut serde_json::Value) -> bool {
+/// `json` re-encoded with `stream_options.include_usage`, or `None` if it needs no change.
+fn usage_injected_body(json: Option<serde_json::Value>) -> Option<bytes::Bytes> {
+    let mut json = json?;
+    if !ensure_stream_usage(&mut json, UpstreamFormat::OpenAi) {
+        return None;
+    }
+    serde_json::to_vec(&json).ok().map(bytes::Bytes::from)
+}
+
+/// A chat completions body translated to the Anthropic Messages API.
+fn messages_body(json: Option<&serde_json::Value>) -> Option<bytes::Bytes> {
+    json.and_then(anthropic::chat_request_to_messages)
+        .and_then(|v| serde_json::to_vec(&v).ok())
+        .map(bytes::Bytes::from)
+}
+
+/// Ask a streaming request to report token usage, in the upstream format's own terms. Returns
+/// whether `v` was changed.
+///
+/// - OpenAI: set `stream_options.include_usage = true`.
+/// - Anthropic: nothing; the Messages API always reports usage in `message_start` and
+///   `message_delta`, and strict upstreams reject unknown fields.
+fn ensure_stream_usage(v: &mut serde_json::Value, format: UpstreamFormat) -> bool {
+    if format != UpstreamFormat::OpenAi {
+        return false;
+    }
     let obj = match v.as_object_mut() {
         Some(obj) => obj,
         None => return false,
@@ -733,30 +1568,32 @@ fn ensure_stream_usage(v: &mut serde_json::Value) -> bool {
         Some(obj) => obj,
         None => return false,
     };
-    let entry = opts_obj
-        .entry("include_usage")
-        .or_insert(serde_json::Value::Bool(true));
-    match entry.as_bool() {
-        Some(true) => false,
-        _ => {
-            *entry = serde_json::Value::Bool(true);
-            true
-        }
+    if opts_obj.get("include_usage").and_then(|v| v.as_bool()) == Some(true) {
+        return false;
     }
+    opts_obj.insert("include_usage".into(), serde_json::Value::Bool(true));
+    true
 }
 
-fn usage_from_json_bytes(body: &[u8]) -> Option<UsageTokens> {
+pub fn usage_from_json_bytes(body: &[u8]) -> Option<UsageTokens> {
     let v: serde_json::Value = serde_json::from_slice(body).ok()?;
     extract_usage_from_value(&v)
 }
 
 fn extract_usage_from_value(v: &serde_json::Value) -> Option<UsageTokens> {
     let usage = v.get("usage")?;
-    let prompt = usage.get("prompt_tokens").and_then(|v| v.as_u64());
-    let completion = usage.get("completion_tokens").and_then(|v| v.as_u64());
+    // OpenAI names first, then Anthropic's input_tokens/output_tokens.
+    let prompt = usage
+        .get("prompt_tokens")
+        .and_then(|v| v.as_u64())
+        .or_else(|| anthropic::input_tokens(usage));
+    let completion = usage
+        .get("completion_tokens")
+        .or_else(|| usage.get("output_tokens"))
+        .and_then(|v| v.as_u64());
     let total = usage.get("total_tokens").and_then(|v| v.as_u64()).or_else(|| {
         match (prompt, completion) {
-            (Some(p), Some(c)) => Some(p + c),
+            (Some(p), Some(c)) => Some(p.saturating_add(c)),
             _ => None,
         }
     });
@@ -772,7 +1609,7 @@ fn extract_usage_from_value(v: &serde_json::Value) -> Option<UsageTokens> {
     })
 }
 
-fn parse_sse_usage(buf: &mut String, chunk: &[u8]) -> Option<UsageTokens> {
+pub fn parse_sse_usage(buf: &mut String, chunk: &[u8]) -> Option<UsageTokens> {
     let mut found = None;
     let text = String::from_utf8_lossy(chunk);
     buf.push_str(&text);
@@ -799,43 +1636,112 @@ fn parse_sse_usage(buf: &mut String, chunk: &[u8]) -> Option<UsageTokens> {
     found
 }
 
-struct GzipDecoder {
-    decompressor: Decompress,
+/// Usage from the buffered end of an SSE stream (`sse_usage_tail_only`). A `truncated` tail
+/// starts mid-line, so everything before its first newline is skipped.
+fn parse_sse_usage_tail(tail: &[u8], truncated: bool) -> Option<UsageTokens> {
+    let start = if truncated {
+        tail.iter().position(|&b| b == b'\n').map_or(tail.len(), |p| p + 1)
+    } else {
+        0
+    };
+    let mut buf = String::new();
+    // A final line without a trailing newline still counts once the stream has ended.
+    parse_sse_usage(&mut buf, &tail[start..]).or_else(|| parse_sse_usage(&mut buf, b"\n"))
 }
 
-impl GzipDecoder {
-    fn new() -> Self {
-        Self {
-            decompressor: Decompress::new(true),
+/// Incremental decoder for compressed upstream bodies, used only for usage parsing.
+/// Each chunk yields whatever output it completes, so the body is never buffered whole.
+enum BodyDecoder {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Deflate(flate2::write::ZlibDecoder<Vec<u8>>),
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+}
+
+impl BodyDecoder {
+    fn for_encoding(content_encoding: &str) -> Option<Self> {
+        match content_encoding {
+            "gzip" | "x-gzip" => Some(Self::Gzip(flate2::write::GzDecoder::new(Vec::new()))),
+            "deflate" => Some(Self::Deflate(flate2::write::ZlibDecoder::new(Vec::new()))),
+            "br" => Some(Self::Brotli(Box::new(brotli::DecompressorWriter::new(Vec::new(), 8192)))),
+            _ => None,
         }
     }
 
     fn decompress_chunk(&mut self, input: &[u8]) -> Result<Vec<u8>, io::Error> {
-        let mut out = Vec::new();
-        let mut offset = 0usize;
-        while offset < input.len() {
-            let mut buf = [0u8; 8192];
-            let in_before = self.decompressor.total_in();
-            let out_before = self.decompressor.total_out();
-            let status = self
-                .decompressor
-                .decompress(&input[offset..], &mut buf, FlushDecompress::None)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            let in_after = self.decompressor.total_in();
-            let out_after = self.decompressor.total_out();
-            let used_in = (in_after - in_before) as usize;
-            let produced = (out_after - out_before) as usize;
-            offset = offset.saturating_add(used_in);
-            if produced > 0 {
-                out.extend_from_slice(&buf[..produced]);
+        match self {
+            Self::Gzip(d) => {
+                d.write_all(input)?;
+                d.flush()?;
+                Ok(std::mem::take(d.get_mut()))
             }
-            if status == Status::StreamEnd {
-                break;
+            Self::Deflate(d) => {
+                d.write_all(input)?;
+                d.flush()?;
+                Ok(std::mem::take(d.get_mut()))
             }
-            if used_in == 0 && produced == 0 {
-                break;
+            Self::Brotli(d) => {
+                d.write_all(input)?;
+                Ok(std::mem::take(d.get_mut()))
             }
         }
-        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chat(stream: bool) -> serde_json::Value {
+        json!({"model": "m", "stream": stream, "messages": [{"role": "user", "content": "hi"}]})
+    }
+
+    #[test]
+    fn openai_streams_get_include_usage() {
+        let mut v = chat(true);
+        assert!(ensure_stream_usage(&mut v, UpstreamFormat::OpenAi));
+        assert_eq!(v["stream_options"], json!({"include_usage": true}));
+        // Already set: nothing to change.
+        assert!(!ensure_stream_usage(&mut v, UpstreamFormat::OpenAi));
+
+        let mut v = chat(true);
+        v["stream_options"] = json!({"include_usage": false, "other": 1});
+        assert!(ensure_stream_usage(&mut v, UpstreamFormat::OpenAi));
+        assert_eq!(v["stream_options"], json!({"include_usage": true, "other": 1}));
+    }
+
+    #[test]
+    fn only_openai_streams_are_changed() {
+        for (mut v, format) in [
+            (chat(false), UpstreamFormat::OpenAi),
+            (json!({"model": "m", "messages": []}), UpstreamFormat::OpenAi),
+            (json!([1, 2]), UpstreamFormat::OpenAi),
+            (json!({"model": "m", "stream": true, "stream_options": "x"}), UpstreamFormat::OpenAi),
+            (chat(true), UpstreamFormat::Anthropic),
+        ] {
+            let before = v.clone();
+            assert!(!ensure_stream_usage(&mut v, format), "{before}");
+            assert_eq!(v, before);
+        }
+    }
+
+    #[test]
+    fn usage_injected_body_is_reencoded_only_when_changed() {
+        assert!(usage_injected_body(None).is_none());
+        assert!(usage_injected_body(Some(chat(false))).is_none());
+        let body = usage_injected_body(Some(chat(true))).unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["stream_options"]["include_usage"], json!(true));
+        assert_eq!(v["messages"], chat(true)["messages"]);
+    }
+
+    #[test]
+    fn anthropic_messages_body_has_no_stream_options() {
+        let mut req = chat(true);
+        req["stream_options"] = json!({"include_usage": true});
+        let body = messages_body(Some(&req)).unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["stream"], json!(true));
+        assert!(v.get("stream_options").is_none(), "{v}");
     }
 }
@@ -0,0 +1,155 @@
+//! SQLite sink for the request log (`request_log_backend = "sqlite"` or `"both"`), compiled in
+//! with `--features sqlite`. Entries arrive on the same bounded channel as the JSON Lines
+//! writer, so a stalled disk drops log lines instead of slowing requests down.
+
+use crate::state::{LogWrite, RequestLogEntry};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::thread;
+use tokio::sync::mpsc;
+
+/// One row per request. `id` keeps insertion order; the indexes cover the usual questions
+/// (time range, per model/upstream, slowest requests, biggest token spenders).
+pub const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS requests (
+    id                  INTEGER PRIMARY KEY,
+    ts_ms               INTEGER NOT NULL,
+    request_id          TEXT NOT NULL,
+    upstream_request_id TEXT,
+    client_ip           TEXT NOT NULL,
+    method              TEXT NOT NULL,
+    path                TEXT NOT NULL,
+    model               TEXT,
+    upstream_id         TEXT,
+    status              INTEGER NOT NULL,
+    latency_ms          INTEGER NOT NULL,
+    req_bytes           INTEGER NOT NULL,
+    resp_bytes          INTEGER NOT NULL,
+    prompt_tokens       INTEGER,
+    completion_tokens   INTEGER,
+    total_tokens        INTEGER,
+    cost                INTEGER,
+    failure_reason      TEXT,
+    budget_exhausted    INTEGER NOT NULL,
+    slow                INTEGER NOT NULL,
+    anonymous           INTEGER NOT NULL,
+    -- JSON array of upstream attempts (`detailed_request_log`), NULL otherwise.
+    attempts            TEXT
+);
+CREATE INDEX IF NOT EXISTS requests_ts_ms ON requests (ts_ms);
+CREATE INDEX IF NOT EXISTS requests_model ON requests (model, ts_ms);
+CREATE INDEX IF NOT EXISTS requests_upstream_id ON requests (upstream_id, ts_ms);
+CREATE INDEX IF NOT EXISTS requests_latency_ms ON requests (latency_ms);
+CREATE INDEX IF NOT EXISTS requests_total_tokens ON requests (total_tokens);
+";
+
+const INSERT: &str = "
+INSERT INTO requests (
+    ts_ms, request_id, upstream_request_id, client_ip, method, path, model, upstream_id,
+    status, latency_ms, req_bytes, resp_bytes, prompt_tokens, completion_tokens, total_tokens,
+    cost, failure_reason, budget_exhausted, slow, anonymous, attempts
+) VALUES (
+    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21
+)
+";
+
+/// Rows committed per transaction at most; whatever is queued when the writer wakes up goes
+/// into one transaction, so a burst costs one fsync instead of one per request.
+const MAX_BATCH: usize = 256;
+
+/// Open (or create) the database at `path` and start the writer thread. Fails when the file
+/// cannot be opened, so a misconfigured path is reported at startup.
+pub fn start_request_log_sqlite_writer(path: &Path) -> anyhow::Result<mpsc::Sender<LogWrite>> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+    conn.execute_batch(SCHEMA)?;
+    // Databases created before the `attempts` column existed.
+    let has_attempts = conn
+        .prepare("SELECT 1 FROM pragma_table_info('requests') WHERE name = 'attempts'")?
+        .exists([])?;
+    if !has_attempts {
+        conn.execute_batch("ALTER TABLE requests ADD COLUMN attempts TEXT")?;
+    }
+
+    let (tx, mut rx) = mpsc::channel::<LogWrite>(2048);
+    let path = path.to_path_buf();
+    thread::Builder::new()
+        .name("request-log-sqlite".into())
+        .spawn(move || {
+            let mut batch: Vec<Box<RequestLogEntry>> = Vec::with_capacity(MAX_BATCH);
+            let mut flushed = Vec::new();
+            while let Some(msg) = rx.blocking_recv() {
+                let mut next = Some(msg);
+                while let Some(msg) = next.take() {
+                    match msg {
+                        LogWrite::Entry(entry) => batch.push(entry),
+                        LogWrite::Flush(done) => flushed.push(done),
+                    }
+                    if batch.len() < MAX_BATCH {
+                        next = rx.try_recv().ok();
+                    }
+                }
+                if let Err(e) = insert_batch(&mut conn, &batch) {
+                    tracing::warn!(
+                        path = %path.display(),
+                        error = %e,
+                        rows = batch.len(),
+                        "request log sqlite insert failed"
+                    );
+                }
+                batch.clear();
+                for done in flushed.drain(..) {
+                    let _ = done.send(());
+                }
+            }
+        })?;
+    Ok(tx)
+}
+
+fn insert_batch(conn: &mut Connection, batch: &[Box<RequestLogEntry>]) -> rusqlite::Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let txn = conn.transaction()?;
+    {
+        let mut stmt = txn.prepare_cached(INSERT)?;
+        for e in batch {
+            let failure_reason = e
+                .failure_reason
+                .and_then(|r| serde_json::to_value(r).ok())
+                .and_then(|v| v.as_str().map(str::to_string));
+            let attempts = (!e.attempts.is_empty())
+                .then(|| serde_json::to_string(&e.attempts).ok())
+                .flatten();
+            stmt.execute(params![
+                int(e.ts_ms),
+                e.request_id,
+                e.upstream_request_id,
+                e.client_ip,
+                e.method,
+                e.path,
+                e.model,
+                e.upstream_id,
+                e.status,
+                int(e.latency_ms),
+                int(e.req_bytes as u64),
+                int(e.resp_bytes as u64),
+                e.prompt_tokens.map(int),
+                e.completion_tokens.map(int),
+                e.total_tokens.map(int),
+                e.cost.map(int),
+                failure_reason,
+                e.budget_exhausted,
+                e.slow,
+                e.anonymous,
+                attempts,
+            ])?;
+        }
+    }
+    txn.commit()
+}
+
+/// SQLite integers are signed; saturate instead of failing the whole batch.
+fn int(v: u64) -> i64 {
+    i64::try_from(v).unwrap_or(i64::MAX)
+}
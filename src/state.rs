@@ -1,22 +1,28 @@
 use crate::billing::BillingStore;
-use crate::config::{BanConfig, Config, UpstreamConfig};
-use crate::storage::KeyStore;
-use crate::util::now_ms;
+use crate::config::{
+    BanConfig, Config, KeySelect, RequestLogBackend, RequestLogRotationConfig,
+    RequestMetricsConfig, RequestRule, SelectStrategy, StatusAction, UnknownModelBehavior,
+    UpstreamConfig, UpstreamFormat,
+};
+use crate::conn::{ConnStatsTable, CountingConnector, NetErrorKind, UpstreamResolver};
+use crate::storage::{self, CooldownRecord, KeyMeta, KeyStore};
+use crate::util::{fast_rand, now_ms, percent_encode_segment, IpNet};
 use ahash::{AHashMap, AHashSet};
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use http::uri::{Authority, PathAndQuery, Scheme};
 use hyper::client::HttpConnector;
 use hyper::header::{
-    HeaderName, CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+    HeaderName, HeaderValue, CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
     TRANSFER_ENCODING, UPGRADE,
 };
 use hyper::{Body, Client, Method, Request, Response, Uri};
 use hyper_rustls::HttpsConnectorBuilder;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
@@ -24,55 +30,138 @@ use tokio::sync::mpsc;
 pub const HDR_AUTHORIZATION: HeaderName = hyper::header::AUTHORIZATION;
 
 pub struct RouterState {
-    pub request_timeout: Duration,
+    /// Reloadable (SIGHUP); read via `request_timeout()`.
+    pub request_timeout_ms: AtomicU64,
+    pub model_timeouts: Arc<AHashMap<String, Duration>>,
+    /// Upstream group -> failover tier (`group_tiers`). Empty means a single tier.
+    pub group_tiers: Arc<AHashMap<String, u32>>,
+    pub max_request_duration: Option<Duration>,
     pub max_retries: usize,
-    pub retry_status_codes: Arc<AHashSet<u16>>,
-    pub ban: BanConfig,
-
-    pub proxy_tokens: Option<Arc<AHashSet<String>>>,
-    pub admin_tokens: Arc<AHashSet<String>>,
-    pub usage_inject_upstreams: Option<Arc<AHashSet<String>>>,
+    /// 0 disables slow-request logging.
+    pub slow_request_ms: u64,
+    /// `rewrite_response_model`: answer alias requests with the alias as `model`.
+    pub rewrite_response_model: bool,
+    /// `detailed_request_log`: fill `RequestLogEntry::attempts`.
+    pub detailed_request_log: bool,
+    /// `read_only`: serve traffic but never mutate keys, billing, routes or upstreams.
+    pub read_only: bool,
+    /// Response body stall limits (`first_byte_timeout_ms`, `stream_idle_timeout_ms`).
+    pub first_byte_timeout: Option<Duration>,
+    pub stream_idle_timeout: Option<Duration>,
+    /// Per-request balance reservation (`billing_reserve`); 0 disables.
+    pub billing_reserve: u64,
+    /// `anonymous_models`: served without an API key, unbilled.
+    pub anonymous_models: Arc<AHashSet<String>>,
+    pub anonymous_limiter: Option<Arc<IpRateLimiter>>,
+    pub status_table: Arc<StatusTable>,
+    pub select_strategy: SelectStrategy,
+    pub key_select: KeySelect,
+    pub latency_ewma_alpha: f64,
+    pub ban: ArcSwap<BanConfig>,
+    pub error_rate: ArcSwapOption<ErrorRatePolicy>,
+
+    pub proxy_tokens: ArcSwapOption<AHashSet<String>>,
+    pub admin_tokens: ArcSwap<AHashSet<String>>,
+    /// `admin_ip_allowlist`; empty allows every client. Reloadable (SIGHUP).
+    pub admin_ip_allowlist: ArcSwap<Vec<IpNet>>,
+    pub usage_inject_upstreams: ArcSwapOption<AHashSet<String>>,
+    /// `request_rules`. Reloadable (SIGHUP).
+    pub request_rules: ArcSwap<RequestRules>,
+    pub strip_request_headers: Option<Arc<HeaderDenylist>>,
+    /// `client_key_headers`, in lookup order. Never forwarded upstream.
+    pub client_key_headers: Arc<[HeaderName]>,
+    /// `proxy_cors_origins`; `None` disables CORS on the proxy endpoints.
+    pub proxy_cors: Option<Arc<ProxyCors>>,
+    /// `upstream_request_id_headers`, in lookup order; empty disables capture.
+    pub upstream_request_id_headers: Arc<[HeaderName]>,
+    pub max_model_len: usize,
+    pub unknown_model_behavior: UnknownModelBehavior,
+    pub default_upstream_id: Option<Arc<str>>,
 
     pub store: Arc<KeyStore>,
     pub billing: Arc<BillingStore>,
     pub model_routes_path: PathBuf,
     pub upstreams_path: PathBuf,
+    pub model_aliases_path: PathBuf,
+    /// alias -> upstream model name.
+    pub model_aliases: ArcSwap<AHashMap<String, String>>,
 
     pub snapshot: ArcSwap<RouterSnapshot>,
     pub sched_rr: Arc<AtomicUsize>,
+    /// Version of the upstream list for `If-Match` (ms timestamp, bumped on every edit).
+    pub upstreams_version: Arc<AtomicU64>,
+    /// Serializes admin edits of upstreams and model routes, so a version check and the
+    /// write it guards happen atomically.
+    pub edit_lock: Arc<std::sync::Mutex<()>>,
+    /// Set once on SIGTERM/ctrl-c: servers stop accepting and long-lived streams end.
+    pub shutdown: Arc<tokio::sync::watch::Sender<bool>>,
 
-    pub client: Client<hyper_rustls::HttpsConnector<HttpConnector>, Body>,
+    pub client: Client<CountingConnector<hyper_rustls::HttpsConnector<HttpConnector<UpstreamResolver>>>, Body>,
+    pub conn_stats: Arc<ConnStatsTable>,
 
     pub stats: Arc<Stats>,
     pub requests: Arc<RequestsLog>,
+    pub model_stats: Arc<ModelStatsTable>,
 }
 
 pub struct RouterSnapshot {
     pub upstreams: Vec<Arc<Upstream>>,
     pub upstream_index: AHashMap<String, usize>,
-    /// Weighted RR schedule. Contains indices into `upstreams`.
-    pub schedule: Vec<usize>,
+    /// Distinct effective failover tiers of `upstreams`, ascending (see `upstream_tier`).
+    pub tiers: Vec<u32>,
 }
 
 impl Clone for RouterState {
     fn clone(&self) -> Self {
         RouterState {
-            request_timeout: self.request_timeout,
+            request_timeout_ms: AtomicU64::new(self.request_timeout_ms.load(Ordering::Relaxed)),
+            model_timeouts: self.model_timeouts.clone(),
+            group_tiers: self.group_tiers.clone(),
+            max_request_duration: self.max_request_duration,
             max_retries: self.max_retries,
-            retry_status_codes: self.retry_status_codes.clone(),
-            ban: self.ban.clone(),
-            proxy_tokens: self.proxy_tokens.clone(),
-            admin_tokens: self.admin_tokens.clone(),
-            usage_inject_upstreams: self.usage_inject_upstreams.clone(),
+            slow_request_ms: self.slow_request_ms,
+            rewrite_response_model: self.rewrite_response_model,
+            detailed_request_log: self.detailed_request_log,
+            read_only: self.read_only,
+            first_byte_timeout: self.first_byte_timeout,
+            stream_idle_timeout: self.stream_idle_timeout,
+            billing_reserve: self.billing_reserve,
+            anonymous_models: self.anonymous_models.clone(),
+            anonymous_limiter: self.anonymous_limiter.clone(),
+            status_table: self.status_table.clone(),
+            select_strategy: self.select_strategy,
+            key_select: self.key_select,
+            latency_ewma_alpha: self.latency_ewma_alpha,
+            ban: ArcSwap::from(self.ban.load_full()),
+            error_rate: ArcSwapOption::new(self.error_rate.load_full()),
+            proxy_tokens: ArcSwapOption::new(self.proxy_tokens.load_full()),
+            admin_tokens: ArcSwap::from(self.admin_tokens.load_full()),
+            admin_ip_allowlist: ArcSwap::from(self.admin_ip_allowlist.load_full()),
+            usage_inject_upstreams: ArcSwapOption::new(self.usage_inject_upstreams.load_full()),
+            request_rules: ArcSwap::from(self.request_rules.load_full()),
+            strip_request_headers: self.strip_request_headers.clone(),
+            client_key_headers: self.client_key_headers.clone(),
+            proxy_cors: self.proxy_cors.clone(),
+            upstream_request_id_headers: self.upstream_request_id_headers.clone(),
+            max_model_len: self.max_model_len,
+            unknown_model_behavior: self.unknown_model_behavior,
+            default_upstream_id: self.default_upstream_id.clone(),
             store: self.store.clone(),
             billing: self.billing.clone(),
             model_routes_path: self.model_routes_path.clone(),
             upstreams_path: self.upstreams_path.clone(),
+            model_aliases_path: self.model_aliases_path.clone(),
+            model_aliases: ArcSwap::new(self.model_aliases.load_full()),
             snapshot: ArcSwap::from(self.snapshot.load_full()),
             sched_rr: Arc::new(AtomicUsize::new(self.sched_rr.load(std::sync::atomic::Ordering::Relaxed))),
+            upstreams_version: self.upstreams_version.clone(),
+            edit_lock: self.edit_lock.clone(),
+            shutdown: self.shutdown.clone(),
             client: self.client.clone(),
+            conn_stats: self.conn_stats.clone(),
             stats: self.stats.clone(),
             requests: self.requests.clone(),
+            model_stats: self.model_stats.clone(),
         }
     }
 }
@@ -86,29 +175,262 @@ pub struct Upstream {
     pub base_path: Arc<str>,
 
     pub weight: usize,
+    /// Smooth weighted round-robin state (nginx's `current_weight`), see `select_round_robin`.
+    pub rr_current: AtomicI64,
+    /// `upstreams[].enabled`; flipped in place by `set_upstream_enabled`, so a paused upstream
+    /// keeps its stats, models and cooldowns.
+    pub enabled: AtomicBool,
+    /// Per-upstream timeout (`upstreams[].timeout_ms`); global timeout when `None`.
+    pub timeout: Option<Duration>,
+    pub format: UpstreamFormat,
+    pub group: Option<Arc<str>>,
+    /// Explicit failover tier (`upstreams[].tier`); the group's tier applies when `None`.
+    pub tier: Option<u32>,
+    pub tags: Arc<[String]>,
+    /// Header the key is sent in (`upstreams[].auth_header`, default `Authorization`).
+    pub auth_header: HeaderName,
+    /// Prefix before the key in that header; empty sends the bare key.
+    pub auth_scheme: Arc<str>,
+    /// `upstreams[].host_header`: replaces the `Host` derived from the URI.
+    pub host_header: Option<hyper::header::HeaderValue>,
+    /// Azure OpenAI path rewriting; `None` for every other upstream.
+    pub azure: Option<AzureRouting>,
 
     pub keys: ArcSwap<Vec<Arc<KeyState>>>,
     pub key_rr: AtomicUsize,
+    /// Models routed here: discovered or stored routes, after `model_filter`.
     pub models: ArcSwap<AHashSet<String>>,
+    pub model_filter: ModelFilter,
+    /// `upstreams[].sse_usage_tail_only`: parse stream usage from the end of the stream only.
+    pub sse_usage_tail_only: bool,
 
     // Upstream-level circuit breaker (network/5xx).
     pub cooldown_until_ms: AtomicU64,
     pub fail_streak: AtomicU32,
+    /// Time of the last failure that fed `fail_streak` (for `ban.streak_reset_ms`).
+    pub last_fail_ms: AtomicU64,
+    /// End of a manual ban (`POST /upstreams/{id}/ban`); the cooldown is not lifted
+    /// automatically before this.
+    pub ban_until_ms: AtomicU64,
+    /// Half-open: once a cooldown has expired (`cooldown_until_ms` non-zero but past), one
+    /// request at a time is let through to test the upstream; set while it is outstanding.
+    pub probe_inflight: AtomicBool,
+    /// Requests selected onto this upstream whose response has not completed yet.
+    pub inflight: AtomicU64,
+    /// Smoothed response latency in microseconds; 0 until the first sample.
+    pub latency_ewma_us: AtomicU64,
+    // Rolling-window error-rate breaker (only fed when `ban.error_rate` is set).
+    pub error_window: ErrorWindow,
 
     pub stats: UpstreamStats,
 }
 
+pub struct AzureRouting {
+    pub api_version: Arc<str>,
+    /// Model -> deployment; unmapped models use the model name.
+    pub deployments: AHashMap<String, String>,
+}
+
 pub struct KeyState {
     pub key: Arc<str>,
     pub auth_header: hyper::header::HeaderValue,
     pub cooldown_until_ms: AtomicU64,
     pub fail_streak: AtomicU32,
+    /// Time of the last failure that fed `fail_streak` (for `ban.streak_reset_ms`).
+    pub last_fail_ms: AtomicU64,
+
+    // Per-key usage counters (upstream HTTP responses only).
+    pub requests_total: AtomicU64,
+    pub responses_2xx: AtomicU64,
+    pub responses_4xx: AtomicU64,
+    pub responses_5xx: AtomicU64,
+    pub last_used_ms: AtomicU64,
+
+    // Request rate-limit headers from the key's last response (`key_select = "remaining"`).
+    pub ratelimit_remaining: AtomicU64,
+    pub ratelimit_limit: AtomicU64,
+    /// When they were recorded; 0 if never.
+    pub ratelimit_seen_ms: AtomicU64,
+
+    /// When the key was added (0 for keys stored before this was recorded).
+    pub added_at_ms: u64,
+    /// Free-form note set when the key was added.
+    pub label: Option<Arc<str>>,
+}
+
+impl KeyState {
+    /// Remaining-requests readings older than this are treated as fully replenished.
+    const RATELIMIT_DECAY_MS: u64 = 60_000;
+
+    /// Apply a saved `CooldownRecord`; a cooldown that has expired meanwhile is dropped.
+    fn restore_breaker(&self, r: &CooldownRecord, now_ms: u64) {
+        let until = if r.cooldown_until_ms > now_ms { r.cooldown_until_ms } else { 0 };
+        self.cooldown_until_ms.store(until, Ordering::Relaxed);
+        self.fail_streak.store(r.fail_streak, Ordering::Relaxed);
+        self.last_fail_ms.store(r.last_fail_ms, Ordering::Relaxed);
+    }
+
+    /// The state `restore_breaker` takes back, if there is any worth saving.
+    fn cooldown_record(&self, now_ms: u64) -> Option<CooldownRecord> {
+        let r = CooldownRecord {
+            cooldown_until_ms: self.cooldown_until_ms.load(Ordering::Relaxed),
+            ban_until_ms: 0,
+            fail_streak: self.fail_streak.load(Ordering::Relaxed),
+            last_fail_ms: self.last_fail_ms.load(Ordering::Relaxed),
+        };
+        (r.cooldown_until_ms > now_ms || r.fail_streak > 0).then_some(r)
+    }
+
+    /// Record the request rate-limit headers (OpenAI `x-ratelimit-*-requests`, Anthropic
+    /// `anthropic-ratelimit-requests-*`) of a response sent with this key.
+    fn record_ratelimit(&self, headers: &hyper::HeaderMap, now_ms: u64) {
+        let get = |names: [&str; 2]| {
+            names
+                .iter()
+                .find_map(|n| headers.get(*n))
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+        };
+        let Some(remaining) = get(["x-ratelimit-remaining-requests", "anthropic-ratelimit-requests-remaining"]) else {
+            return;
+        };
+        let limit = get(["x-ratelimit-limit-requests", "anthropic-ratelimit-requests-limit"]).unwrap_or(0);
+        self.ratelimit_remaining.store(remaining, Ordering::Relaxed);
+        self.ratelimit_limit.store(limit, Ordering::Relaxed);
+        self.ratelimit_seen_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    /// Estimated remaining requests: the last reading, recovering linearly towards the limit
+    /// over `RATELIMIT_DECAY_MS`. Keys without a recent reading rank highest, so they get tried.
+    pub fn ratelimit_estimate(&self, now_ms: u64) -> u64 {
+        let seen = self.ratelimit_seen_ms.load(Ordering::Relaxed);
+        let age = now_ms.saturating_sub(seen);
+        if seen == 0 || age >= Self::RATELIMIT_DECAY_MS {
+            return u64::MAX;
+        }
+        let remaining = self.ratelimit_remaining.load(Ordering::Relaxed);
+        let limit = self.ratelimit_limit.load(Ordering::Relaxed);
+        if limit <= remaining {
+            return remaining;
+        }
+        remaining + (limit - remaining).saturating_mul(age) / Self::RATELIMIT_DECAY_MS
+    }
+}
+
+/// Circuit-breaker effect of an upstream response status.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BreakerAction {
+    /// Reachable and not the key's fault: reset the key streak.
+    None,
+    /// Key-level rate limit cooldown (`ban.rate_limit_ms`).
+    BanKey,
+    /// Key-level auth cooldown (`ban.auth_error_ms`).
+    BanKeyAuth,
+    /// Upstream-level cooldown (`ban.server_error_ms`).
+    BanUpstream,
+}
+
+#[derive(Clone, Copy)]
+pub struct StatusPolicy {
+    pub breaker: BreakerAction,
+    pub retry: bool,
+}
+
+/// Status code -> policy lookup, indexed by the numeric code.
+pub struct StatusTable {
+    entries: Box<[StatusPolicy]>,
+}
+
+impl StatusTable {
+    const LEN: usize = 600;
+
+    pub fn new(retry_codes: &[u16], overrides: &BTreeMap<u16, StatusAction>) -> Self {
+        let mut entries = Vec::with_capacity(Self::LEN);
+        for code in 0..Self::LEN as u16 {
+            let breaker = match code {
+                429 => BreakerAction::BanKey,
+                401 | 403 => BreakerAction::BanKeyAuth,
+                500..=599 => BreakerAction::BanUpstream,
+                _ => BreakerAction::None,
+            };
+            // Auth errors and 5xx move on to another key/upstream regardless of
+            // `retry_status_codes`; use `status_actions` = "pass" to opt a code out.
+            let retry = matches!(code, 401 | 403 | 500..=599) || retry_codes.contains(&code);
+            entries.push(StatusPolicy { breaker, retry });
+        }
+        for (code, action) in overrides {
+            let Some(entry) = entries.get_mut(*code as usize) else {
+                continue;
+            };
+            *entry = match action {
+                StatusAction::Retry => StatusPolicy { breaker: BreakerAction::None, retry: true },
+                StatusAction::BanKey => StatusPolicy { breaker: BreakerAction::BanKey, retry: true },
+                StatusAction::BanUpstream => {
+                    StatusPolicy { breaker: BreakerAction::BanUpstream, retry: true }
+                }
+                StatusAction::Pass => StatusPolicy { breaker: BreakerAction::None, retry: false },
+            };
+        }
+        Self {
+            entries: entries.into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, status: http::StatusCode) -> StatusPolicy {
+        self.entries
+            .get(status.as_u16() as usize)
+            .copied()
+            .unwrap_or(StatusPolicy {
+                breaker: BreakerAction::None,
+                retry: false,
+            })
+    }
+
+    /// Sorted list of codes that trigger a retry.
+    pub fn retry_codes(&self) -> Vec<u16> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.retry)
+            .map(|(code, _)| code as u16)
+            .collect()
+    }
 }
 
 #[derive(Clone)]
 pub struct Selected {
     pub upstream: Arc<Upstream>,
     pub key: Arc<KeyState>,
+    /// Drop guard: holds `upstream.inflight` up until the last clone is dropped.
+    pub _inflight: Arc<InflightGuard>,
+}
+
+pub struct InflightGuard {
+    upstream: Arc<Upstream>,
+    /// This request holds the upstream's half-open probe (`Upstream::probe_inflight`).
+    probe: AtomicBool,
+}
+
+impl InflightGuard {
+    fn new(u: Arc<Upstream>, probe: bool) -> Self {
+        u.inflight.fetch_add(1, Ordering::Relaxed);
+        Self { upstream: u, probe: AtomicBool::new(probe) }
+    }
+
+    /// Release the half-open probe, if this request holds it.
+    fn end_probe(&self) {
+        if self.probe.swap(false, Ordering::Relaxed) {
+            self.upstream.probe_inflight.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.end_probe();
+        self.upstream.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 /// Global stats (cheap atomics only).
@@ -127,10 +449,179 @@ pub struct Stats {
 
     pub errors_timeout: AtomicU64,
     pub errors_network: AtomicU64,
+    /// Breakdown of `errors_network` by cause (the rest are resets mid-request).
+    pub errors_dns: AtomicU64,
+    pub errors_connect: AtomicU64,
+    pub errors_tls: AtomicU64,
 
     pub latency_ns_total: AtomicU64,
     pub latency_count: AtomicU64,
     pub latency_ns_max: AtomicU64,
+    pub latency_hist: LatencyHistogram,
+
+    // Configuration changes (see `RouterState::record_reload`).
+    pub reload_count: AtomicU64,
+    pub reload_successes: AtomicU64,
+    pub reload_failures: AtomicU64,
+    /// Time of the last successful change; 0 if none since startup.
+    pub last_reload_ms: AtomicU64,
+}
+
+/// Log-linear latency histogram over microseconds: 8 sub-buckets per power of two
+/// (~12.5% relative error), plain atomic counters so recording stays lock-free.
+pub struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    const SUB_BITS: u32 = 3;
+    const SUB: usize = 1 << Self::SUB_BITS;
+    /// Highest tracked power of two (2^40 us, ~12 days); larger values land in the last bucket.
+    const MAX_EXP: u32 = 40;
+    const LEN: usize = (Self::MAX_EXP - Self::SUB_BITS + 2) as usize * Self::SUB;
+
+    pub fn new() -> Self {
+        let buckets: Vec<AtomicU64> = (0..Self::LEN).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            buckets: buckets.into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub fn record(&self, latency_ns: u64) {
+        let idx = Self::bucket_index(latency_ns / 1000);
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn bucket_index(us: u64) -> usize {
+        if us < Self::SUB as u64 {
+            return us as usize;
+        }
+        let exp = 63 - us.leading_zeros();
+        let sub = (us >> (exp - Self::SUB_BITS)) as usize & (Self::SUB - 1);
+        let idx = (exp - Self::SUB_BITS + 1) as usize * Self::SUB + sub;
+        idx.min(Self::LEN - 1)
+    }
+
+    /// Upper bound (exclusive) of a bucket, in microseconds.
+    fn bucket_upper_us(idx: usize) -> u64 {
+        if idx < Self::SUB {
+            return idx as u64 + 1;
+        }
+        let group = (idx / Self::SUB) as u32;
+        let sub = (idx % Self::SUB) as u64;
+        let shift = group - 1;
+        ((Self::SUB as u64 + sub) << shift) + (1u64 << shift)
+    }
+
+    /// Returns the latency (ms) at each quantile in `qs` (0.0..=1.0), or 0 when empty.
+    pub fn quantiles_ms(&self, qs: &[f64]) -> Vec<f64> {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return vec![0.0; qs.len()];
+        }
+        qs.iter()
+            .map(|q| {
+                let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+                let mut seen = 0u64;
+                for (idx, c) in counts.iter().enumerate() {
+                    seen += c;
+                    if seen >= rank {
+                        return Self::bucket_upper_us(idx) as f64 / 1000.0;
+                    }
+                }
+                Self::bucket_upper_us(Self::LEN - 1) as f64 / 1000.0
+            })
+            .collect()
+    }
+}
+
+pub const ERROR_WINDOW_SLOTS: usize = 10;
+
+/// Resolved `ban.error_rate` settings.
+#[derive(Clone, Copy)]
+pub struct ErrorRatePolicy {
+    pub slot_ms: u64,
+    pub min_samples: u64,
+    pub threshold: f64,
+    pub cooldown_ms: u64,
+}
+
+#[derive(Default)]
+struct ErrorSlot {
+    epoch: AtomicU64,
+    ok: AtomicU64,
+    err: AtomicU64,
+}
+
+/// Per-upstream success/failure counts over a ring of time slots.
+///
+/// Slots are reset lazily when first touched in a new epoch; concurrent resets may
+/// drop a few samples, which is fine for a health heuristic.
+#[derive(Default)]
+pub struct ErrorWindow {
+    slots: [ErrorSlot; ERROR_WINDOW_SLOTS],
+    /// Cooldown deadline set by the last trip; survives the reset done on HTTP responses.
+    pub tripped_until_ms: AtomicU64,
+}
+
+impl ErrorWindow {
+    fn record(&self, slot_ms: u64, now_ms: u64, failed: bool) {
+        let epoch = now_ms / slot_ms + 1;
+        let slot = &self.slots[(epoch % ERROR_WINDOW_SLOTS as u64) as usize];
+        let cur = slot.epoch.load(Ordering::Relaxed);
+        if cur != epoch
+            && slot
+                .epoch
+                .compare_exchange(cur, epoch, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            slot.ok.store(0, Ordering::Relaxed);
+            slot.err.store(0, Ordering::Relaxed);
+        }
+        if failed {
+            slot.err.fetch_add(1, Ordering::Relaxed);
+        } else {
+            slot.ok.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// (total, failed) over the slots still inside the window.
+    pub fn counts(&self, slot_ms: u64, now_ms: u64) -> (u64, u64) {
+        let epoch = now_ms / slot_ms + 1;
+        let oldest = epoch.saturating_sub(ERROR_WINDOW_SLOTS as u64 - 1);
+        let (mut total, mut failed) = (0u64, 0u64);
+        for slot in &self.slots {
+            let e = slot.epoch.load(Ordering::Relaxed);
+            if e >= oldest && e <= epoch {
+                let ok = slot.ok.load(Ordering::Relaxed);
+                let err = slot.err.load(Ordering::Relaxed);
+                total += ok + err;
+                failed += err;
+            }
+        }
+        (total, failed)
+    }
+
+    fn clear(&self) {
+        for slot in &self.slots {
+            slot.epoch.store(0, Ordering::Relaxed);
+            slot.ok.store(0, Ordering::Relaxed);
+            slot.err.store(0, Ordering::Relaxed);
+        }
+    }
 }
 
 pub struct UpstreamStats {
@@ -141,6 +632,11 @@ pub struct UpstreamStats {
     pub responses_5xx: AtomicU64,
     pub errors_timeout: AtomicU64,
     pub errors_network: AtomicU64,
+    pub errors_dns: AtomicU64,
+    pub errors_connect: AtomicU64,
+    pub errors_tls: AtomicU64,
+    /// Selections that found keys loaded but every one cooling down.
+    pub key_exhausted_total: AtomicU64,
 }
 
 impl Default for UpstreamStats {
@@ -153,10 +649,20 @@ impl Default for UpstreamStats {
             responses_5xx: AtomicU64::new(0),
             errors_timeout: AtomicU64::new(0),
             errors_network: AtomicU64::new(0),
+            errors_dns: AtomicU64::new(0),
+            errors_connect: AtomicU64::new(0),
+            errors_tls: AtomicU64::new(0),
+            key_exhausted_total: AtomicU64::new(0),
         }
     }
 }
 
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Stats {
     pub fn new() -> Self {
         let now = now_ms();
@@ -171,9 +677,17 @@ impl Stats {
             responses_5xx: AtomicU64::new(0),
             errors_timeout: AtomicU64::new(0),
             errors_network: AtomicU64::new(0),
+            errors_dns: AtomicU64::new(0),
+            errors_connect: AtomicU64::new(0),
+            errors_tls: AtomicU64::new(0),
             latency_ns_total: AtomicU64::new(0),
             latency_count: AtomicU64::new(0),
             latency_ns_max: AtomicU64::new(0),
+            latency_hist: LatencyHistogram::new(),
+            reload_count: AtomicU64::new(0),
+            reload_successes: AtomicU64::new(0),
+            reload_failures: AtomicU64::new(0),
+            last_reload_ms: AtomicU64::new(0),
         }
     }
 }
@@ -181,6 +695,12 @@ impl Stats {
 #[derive(Clone, serde::Serialize)]
 pub struct RequestLogEntry {
     pub ts_ms: u64,
+    /// `X-Request-Id`: the client's, or one generated by the proxy.
+    pub request_id: String,
+    /// The upstream's own request id (`upstream_request_id_headers`) from the last response,
+    /// for correlating with the upstream's support.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_request_id: Option<String>,
     pub client_ip: String,
     pub method: String,
     pub path: String,
@@ -193,6 +713,174 @@ pub struct RequestLogEntry {
     pub prompt_tokens: Option<u64>,
     pub completion_tokens: Option<u64>,
     pub total_tokens: Option<u64>,
+    /// Balance units charged for the response (`model_prices`, else 1:1 with tokens).
+    pub cost: Option<u64>,
+    /// Why the proxy gave up on the request; `None` when it completed normally. A retryable
+    /// upstream response that is relayed because no retry was left still records
+    /// `RetriesExhausted` or `NoAvailableKeys`.
+    pub failure_reason: Option<FailureReason>,
+    /// The `max_request_duration_ms` budget ran out before the request finished.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub budget_exhausted: bool,
+    /// Latency exceeded `slow_request_ms`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub slow: bool,
+    /// Served without an API key (`anonymous_models`); nothing was charged.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub anonymous: bool,
+    /// Every upstream attempt in order, the last one included (`detailed_request_log`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<AttemptInfo>,
+}
+
+/// One pass of the retry loop: which upstream and key were tried and how it went.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct AttemptInfo {
+    pub upstream_id: String,
+    /// Masked like the admin key listing (`sk-...abcd`).
+    pub key: String,
+    /// Upstream response status; absent when there was no response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// `timeout`, or the network error kind (`dns`, `connect`, `tls`, `other`), when there
+    /// was no response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+    /// Until the response headers arrived or the attempt failed.
+    pub latency_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// Missing/invalid model or unreadable body.
+    InvalidRequest,
+    ModelNotFound,
+    /// Every candidate upstream/key for the model was in cooldown or keyless.
+    NoAvailableKeys,
+    /// Last upstream response was retryable but the retry budget was used up.
+    RetriesExhausted,
+    UpstreamTimeout,
+    /// Connection dropped or broke mid-request.
+    UpstreamError,
+    /// Upstream host name did not resolve.
+    UpstreamDns,
+    /// TCP connect to the upstream failed (refused, unreachable).
+    UpstreamConnect,
+    /// TLS handshake with the upstream failed (bad certificate, protocol mismatch).
+    UpstreamTls,
+    /// Proxy-side failure building the upstream request.
+    Internal,
+    /// Client key used up its daily token quota.
+    QuotaExceeded,
+    /// Keyless request for a model not in `anonymous_models`.
+    ApiKeyRequired,
+    /// No upstream in the billing key's `allowed_upstreams` serves the model.
+    UpstreamNotAllowed,
+    /// Keyless client IP went over `anonymous_rpm`.
+    RateLimited,
+}
+
+/// Per-model counters. Updated under the table's read lock, so fields are atomics.
+#[derive(Default)]
+pub struct ModelStats {
+    pub requests: AtomicU64,
+    pub success: AtomicU64,
+    pub failure: AtomicU64,
+    pub prompt_tokens: AtomicU64,
+    pub completion_tokens: AtomicU64,
+    pub total_tokens: AtomicU64,
+}
+
+#[derive(serde::Serialize)]
+pub struct ModelStatsSnapshot {
+    pub model: String,
+    pub requests: u64,
+    pub success: u64,
+    pub failure: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+pub struct ModelStatsTable {
+    map: RwLock<AHashMap<String, ModelStats>>,
+}
+
+impl Default for ModelStatsTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ModelStatsTable {
+    /// Bound on distinct model names, since the name comes straight from the client.
+    const MAX_MODELS: usize = 1024;
+
+    pub fn new() -> Self {
+        Self {
+            map: RwLock::new(AHashMap::new()),
+        }
+    }
+
+    pub fn record(&self, model: &str, entry: &RequestLogEntry) {
+        {
+            let map = self.map.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(stats) = map.get(model) {
+                Self::apply(stats, entry);
+                return;
+            }
+            if map.len() >= Self::MAX_MODELS {
+                return;
+            }
+        }
+        let mut map = self.map.write().unwrap_or_else(|e| e.into_inner());
+        if map.len() >= Self::MAX_MODELS && !map.contains_key(model) {
+            return;
+        }
+        let stats = map.entry(model.to_string()).or_default();
+        Self::apply(stats, entry);
+    }
+
+    fn apply(stats: &ModelStats, entry: &RequestLogEntry) {
+        let (success, failure, _ignored) = classify_status(entry.status);
+        stats.requests.fetch_add(1, Ordering::Relaxed);
+        stats.success.fetch_add(success, Ordering::Relaxed);
+        stats.failure.fetch_add(failure, Ordering::Relaxed);
+        stats
+            .prompt_tokens
+            .fetch_add(entry.prompt_tokens.unwrap_or(0), Ordering::Relaxed);
+        stats
+            .completion_tokens
+            .fetch_add(entry.completion_tokens.unwrap_or(0), Ordering::Relaxed);
+        stats
+            .total_tokens
+            .fetch_add(entry.total_tokens.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// All models, highest token usage first.
+    pub fn snapshot(&self) -> Vec<ModelStatsSnapshot> {
+        let map = self.map.read().unwrap_or_else(|e| e.into_inner());
+        let mut out: Vec<ModelStatsSnapshot> = map
+            .iter()
+            .map(|(model, s)| ModelStatsSnapshot {
+                model: model.clone(),
+                requests: s.requests.load(Ordering::Relaxed),
+                success: s.success.load(Ordering::Relaxed),
+                failure: s.failure.load(Ordering::Relaxed),
+                prompt_tokens: s.prompt_tokens.load(Ordering::Relaxed),
+                completion_tokens: s.completion_tokens.load(Ordering::Relaxed),
+                total_tokens: s.total_tokens.load(Ordering::Relaxed),
+            })
+            .collect();
+        out.sort_by(|a, b| {
+            b.total_tokens
+                .cmp(&a.total_tokens)
+                .then(b.requests.cmp(&a.requests))
+                .then_with(|| a.model.cmp(&b.model))
+        });
+        out
+    }
 }
 
 #[derive(Clone, serde::Serialize)]
@@ -212,7 +900,7 @@ pub enum MetricsWindow {
 }
 
 impl MetricsWindow {
-    pub fn from_str(s: &str) -> Self {
+    pub fn parse(s: &str) -> Self {
         match s {
             "hour" => MetricsWindow::Hour,
             "day" => MetricsWindow::Day,
@@ -233,30 +921,39 @@ pub struct RequestsLog {
     entries: Mutex<VecDeque<RequestLogEntry>>,
     metrics: Mutex<RequestMetrics>,
     cap: usize,
-    tx: Option<mpsc::Sender<RequestLogEntry>>,
+    /// Writers persisting each entry (`request_log_backend`); none in read_only mode.
+    sinks: Vec<mpsc::Sender<LogWrite>>,
+}
+
+/// Message to the request log writer task.
+pub enum LogWrite {
+    Entry(Box<RequestLogEntry>),
+    /// Flush buffered lines to the file, then signal.
+    Flush(tokio::sync::oneshot::Sender<()>),
 }
 
 impl RequestsLog {
-    pub fn new(cap: usize, tx: Option<mpsc::Sender<RequestLogEntry>>) -> Self {
+    pub fn new(cap: usize, metrics: RequestMetrics, sinks: Vec<mpsc::Sender<LogWrite>>) -> Self {
         Self {
-            entries: Mutex::new(VecDeque::with_capacity(cap)),
-            metrics: Mutex::new(RequestMetrics::new()),
+            // Large capacities fill up gradually; don't reserve them all upfront.
+            entries: Mutex::new(VecDeque::with_capacity(cap.min(5000))),
+            metrics: Mutex::new(metrics),
             cap,
-            tx,
+            sinks,
         }
     }
 
     pub fn record(&self, entry: RequestLogEntry) {
-        if let Some(tx) = &self.tx {
-            let _ = tx.try_send(entry.clone());
+        for tx in &self.sinks {
+            let _ = tx.try_send(LogWrite::Entry(Box::new(entry.clone())));
         }
 
         {
             let mut entries = self.entries.lock().unwrap();
-            entries.push_back(entry.clone());
-            while entries.len() > self.cap {
+            while entries.len() >= self.cap {
                 entries.pop_front();
             }
+            entries.push_back(entry.clone());
         }
 
         {
@@ -265,9 +962,30 @@ impl RequestsLog {
         }
     }
 
-    pub fn recent(&self, limit: usize) -> Vec<RequestLogEntry> {
+    /// Wait until every entry queued so far is written by each sink. False when a writer is
+    /// gone or `timeout` passes first.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let flushed = async {
+            let mut ok = true;
+            for tx in &self.sinks {
+                let (done, rx) = tokio::sync::oneshot::channel();
+                ok &= tx.send(LogWrite::Flush(done)).await.is_ok() && rx.await.is_ok();
+            }
+            ok
+        };
+        tokio::time::timeout(timeout, flushed).await.unwrap_or(false)
+    }
+
+    /// Newest first; `filter` is applied before `limit`.
+    pub fn recent(&self, limit: usize, filter: &RequestLogFilter) -> Vec<RequestLogEntry> {
         let entries = self.entries.lock().unwrap();
-        entries.iter().rev().take(limit).cloned().collect()
+        entries
+            .iter()
+            .rev()
+            .filter(|e| filter.matches(e))
+            .take(limit)
+            .cloned()
+            .collect()
     }
 
     pub fn metrics_snapshot(&self, window: MetricsWindow) -> Vec<MetricsBucket> {
@@ -276,18 +994,45 @@ impl RequestsLog {
     }
 }
 
+/// Criteria for the recent-requests view; unset fields match everything.
+#[derive(Default)]
+pub struct RequestLogFilter {
+    pub upstream: Option<String>,
+    pub model: Option<String>,
+    /// First digit of the status (2 for `2xx`).
+    pub status_class: Option<u16>,
+}
+
+impl RequestLogFilter {
+    fn matches(&self, e: &RequestLogEntry) -> bool {
+        self.upstream.as_deref().is_none_or(|u| e.upstream_id.as_deref() == Some(u))
+            && self.model.as_deref().is_none_or(|m| e.model.as_deref() == Some(m))
+            && self.status_class.is_none_or(|c| e.status / 100 == c)
+    }
+}
+
 pub struct RequestMetrics {
     minute: VecDeque<MetricsBucket>,
     hour: VecDeque<MetricsBucket>,
     day: VecDeque<MetricsBucket>,
+    /// Bucket caps of the minute, hour and day windows.
+    caps: [usize; 3],
 }
 
 impl RequestMetrics {
-    pub fn new() -> Self {
+    const MAX_BUCKETS: usize = 10_000;
+
+    pub fn new(cfg: &RequestMetricsConfig) -> Self {
+        let cap = |v: Option<usize>, default| v.unwrap_or(default).clamp(1, Self::MAX_BUCKETS);
         Self {
             minute: VecDeque::new(),
             hour: VecDeque::new(),
             day: VecDeque::new(),
+            caps: [
+                cap(cfg.minute_buckets, 60),
+                cap(cfg.hour_buckets, 48),
+                cap(cfg.day_buckets, 30),
+            ],
         }
     }
 
@@ -295,9 +1040,10 @@ impl RequestMetrics {
         let (success, failure, ignored) = classify_status(entry.status);
         let ts_ms = entry.ts_ms;
 
-        update_bucket(&mut self.minute, ts_ms, 60_000, 60, success, failure, ignored);
-        update_bucket(&mut self.hour, ts_ms, 3_600_000, 48, success, failure, ignored);
-        update_bucket(&mut self.day, ts_ms, 86_400_000, 30, success, failure, ignored);
+        let [minute, hour, day] = self.caps;
+        update_bucket(&mut self.minute, ts_ms, 60_000, minute, success, failure, ignored);
+        update_bucket(&mut self.hour, ts_ms, 3_600_000, hour, success, failure, ignored);
+        update_bucket(&mut self.day, ts_ms, 86_400_000, day, success, failure, ignored);
     }
 
     pub fn snapshot(&self, window: MetricsWindow) -> Vec<MetricsBucket> {
@@ -310,116 +1056,291 @@ impl RequestMetrics {
 }
 
 impl RouterState {
-    pub fn new(cfg: Config) -> anyhow::Result<Self> {
-        let request_timeout = Duration::from_millis(cfg.request_timeout_ms);
+    pub fn new(cfg: Config) -> anyhow::Result<Arc<Self>> {
         let max_retries = cfg.max_retries.unwrap_or(5);
         let retry_status_codes = cfg.retry_status_codes.unwrap_or_else(|| vec![429]);
-        let retry_status_codes = Arc::new(retry_status_codes.into_iter().collect::<AHashSet<u16>>());
-
-        let proxy_tokens = cfg.proxy_tokens.and_then(|v| {
-            let mut set = AHashSet::with_capacity(v.len().max(1));
-            for t in v {
-                let t = t.trim();
-                if !t.is_empty() {
-                    set.insert(t.to_string());
-                }
-            }
-            if set.is_empty() {
-                None
-            } else {
-                Some(Arc::new(set))
-            }
-        });
+        let status_overrides: BTreeMap<u16, StatusAction> = cfg
+            .status_actions
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(code, action)| code.trim().parse::<u16>().ok().map(|c| (c, action)))
+            .collect();
+        let status_table = Arc::new(StatusTable::new(&retry_status_codes, &status_overrides));
+
+        let proxy_tokens = build_token_set(cfg.proxy_tokens);
+        let admin_tokens = Arc::new(build_admin_set(cfg.admin_tokens));
+        let admin_ip_allowlist = build_ip_allowlist(cfg.admin_ip_allowlist.as_deref());
+        let usage_inject_upstreams = build_token_set(cfg.usage_inject_upstreams);
+
+        let client_key_headers: Arc<[HeaderName]> = match cfg.client_key_headers {
+            Some(v) => v.iter().filter_map(|h| HeaderName::from_bytes(h.as_bytes()).ok()).collect(),
+            None => Arc::from([HeaderName::from_static("x-api-key"), HDR_AUTHORIZATION]),
+        };
 
-        let mut admin_set = AHashSet::with_capacity(cfg.admin_tokens.len().max(1));
-        for t in cfg.admin_tokens {
-            if !t.is_empty() {
-                admin_set.insert(t);
-            }
-        }
-        let admin_tokens = Arc::new(admin_set);
+        let probe_interval = cfg
+            .health_probe
+            .as_ref()
+            .filter(|h| h.enabled.unwrap_or(true))
+            .map(|h| Duration::from_millis(h.interval_ms.unwrap_or(5_000)));
 
-        let usage_inject_upstreams = cfg.usage_inject_upstreams.and_then(|v| {
-            let mut set = AHashSet::with_capacity(v.len().max(1));
-            for id in v {
-                let id = id.trim();
-                if !id.is_empty() {
-                    set.insert(id.to_string());
-                }
-            }
-            if set.is_empty() {
-                None
-            } else {
-                Some(Arc::new(set))
-            }
-        });
+        let error_rate = build_error_rate_policy(&cfg.ban);
 
         // Storage
+        let read_only = cfg.read_only.unwrap_or(false);
         let data_dir: PathBuf = cfg.data_dir;
-        let store = Arc::new(KeyStore::open(&data_dir)?);
-        let billing = Arc::new(BillingStore::new(&store)?);
+        let store = match storage::probe_data_dir(&data_dir) {
+            Err(e) if read_only => {
+                tracing::warn!(
+                    data_dir = %data_dir.display(),
+                    error = %e,
+                    "data_dir is not writable; serving from a temporary copy of its key store"
+                );
+                Arc::new(KeyStore::open_copy(&data_dir)?)
+            }
+            _ => Arc::new(KeyStore::open(&data_dir)?),
+        };
+        let model_prices: AHashMap<_, _> = cfg.model_prices.unwrap_or_default().into_iter().collect();
+        let billing = Arc::new(BillingStore::new(&store, model_prices, read_only)?);
         let model_routes_path = data_dir.join("models_routes.json");
         let upstreams_path = data_dir.join("upstreams.json");
+        let model_aliases_path = data_dir.join("model_aliases.json");
         let requests_log_path = data_dir.join("requests.jsonl");
-        let log_tx = start_request_log_writer(requests_log_path);
-        let requests = Arc::new(RequestsLog::new(5000, log_tx));
+        // read_only keeps the request log in memory (admin /requests) only.
+        let mut log_sinks = Vec::new();
+        if !read_only {
+            let backend = cfg.request_log_backend.unwrap_or_default();
+            if backend != RequestLogBackend::Sqlite {
+                let rotation = cfg.request_log_rotation.as_ref().map(LogRotation::from_config);
+                log_sinks.extend(start_request_log_writer(requests_log_path, rotation));
+            }
+            #[cfg(feature = "sqlite")]
+            if backend != RequestLogBackend::Jsonl {
+                let path = data_dir.join("requests.sqlite");
+                log_sinks.push(crate::sqlite_log::start_request_log_sqlite_writer(&path)?);
+            }
+        }
+        let log_capacity = cfg.request_log_capacity.unwrap_or(5000).clamp(100, 1_000_000);
+        let metrics = RequestMetrics::new(&cfg.request_metrics.unwrap_or_default());
+        let requests = Arc::new(RequestsLog::new(log_capacity, metrics, log_sinks));
 
         let mut upstream_configs = cfg.upstreams;
-        if let Ok(list) = load_upstreams_override(&upstreams_path) {
+        if let Some(list) = load_json_or_quarantine(&upstreams_path, load_upstreams_override, read_only) {
             upstream_configs = list;
-        } else if upstreams_path.exists() {
-            tracing::warn!(
-                path = %upstreams_path.display(),
-                "failed to load upstreams file"
-            );
         }
 
-        let snapshot = build_snapshot_from_configs(&upstream_configs, &store)?;
+        let group_tiers: AHashMap<String, u32> = cfg
+            .group_tiers
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(group, tier)| (group.trim().to_string(), tier))
+            .collect();
+
+        let snapshot = build_snapshot_from_configs(&upstream_configs, &store, &group_tiers, None)?;
 
         // HTTPS (and HTTP) connector.
+        let resolver = UpstreamResolver::new(
+            cfg.upstream_host_override.unwrap_or_default().into_iter().collect(),
+            cfg.dns_cache_ttl_s.unwrap_or(0).saturating_mul(1000),
+        );
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        // HttpsConnector decides the scheme; the inner connector must accept https URIs.
+        http.enforce_http(false);
         let https = HttpsConnectorBuilder::new()
             .with_native_roots()
             .https_or_http()
             .enable_http1()
-            .build();
+            .wrap_connector(http);
+        let conn_stats = Arc::new(ConnStatsTable::default());
+        let connector = CountingConnector::new(https, conn_stats.clone());
 
         let client = Client::builder()
             .pool_idle_timeout(Duration::from_secs(30))
             .pool_max_idle_per_host(64)
-            .build::<_, Body>(https);
+            .build::<_, Body>(connector);
 
-        if let Ok(routes) = load_model_routes(&model_routes_path) {
+        if let Some(routes) = load_json_or_quarantine(&model_routes_path, load_model_routes, read_only) {
             apply_loaded_routes(&routes, &snapshot.upstreams, &snapshot.upstream_index);
-        } else if model_routes_path.exists() {
-            tracing::warn!(
-                path = %model_routes_path.display(),
-                "failed to load model routes file"
-            );
         }
 
-        Ok(Self {
-            request_timeout,
+        let mut model_aliases = cfg.model_aliases.unwrap_or_default();
+        if let Some(file) = load_json_or_quarantine(&model_aliases_path, load_model_aliases, read_only) {
+            model_aliases = file.aliases;
+        }
+
+        let model_timeouts: AHashMap<String, Duration> = cfg
+            .model_timeouts
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(model, ms)| (model, Duration::from_millis(ms)))
+            .collect();
+
+        // Anonymous access is always rate limited unless `anonymous_rpm = 0` says otherwise.
+        let anonymous_limiter = Some(cfg.anonymous_rpm.unwrap_or(IpRateLimiter::DEFAULT_RPM))
+            .filter(|&n| n > 0 && cfg.anonymous_models.as_ref().is_some_and(|m| !m.is_empty()))
+            .map(|n| Arc::new(IpRateLimiter::new(n)));
+
+        let state = Arc::new(Self {
+            request_timeout_ms: AtomicU64::new(cfg.request_timeout_ms),
+            model_timeouts: Arc::new(model_timeouts),
+            group_tiers: Arc::new(group_tiers),
+            max_request_duration: cfg.max_request_duration_ms.map(Duration::from_millis),
             max_retries,
-            retry_status_codes,
-            ban: cfg.ban,
-            proxy_tokens,
-            admin_tokens,
-            usage_inject_upstreams,
+            slow_request_ms: cfg.slow_request_ms.unwrap_or(0),
+            rewrite_response_model: cfg.rewrite_response_model.unwrap_or(false),
+            detailed_request_log: cfg.detailed_request_log.unwrap_or(false),
+            read_only,
+            first_byte_timeout: cfg
+                .first_byte_timeout_ms
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            stream_idle_timeout: cfg
+                .stream_idle_timeout_ms
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            billing_reserve: cfg.billing_reserve.unwrap_or(0),
+            anonymous_models: Arc::new(cfg.anonymous_models.unwrap_or_default().into_iter().collect()),
+            anonymous_limiter,
+            status_table,
+            select_strategy: cfg.select_strategy.unwrap_or_default(),
+            key_select: cfg.key_select.unwrap_or_default(),
+            latency_ewma_alpha: cfg
+                .latency_routing
+                .as_ref()
+                .and_then(|l| l.ewma_alpha)
+                .unwrap_or(0.2),
+            ban: ArcSwap::from_pointee(cfg.ban),
+            error_rate: ArcSwapOption::new(error_rate.map(Arc::new)),
+            proxy_tokens: ArcSwapOption::new(proxy_tokens),
+            admin_tokens: ArcSwap::from(admin_tokens),
+            admin_ip_allowlist: ArcSwap::from_pointee(admin_ip_allowlist),
+            usage_inject_upstreams: ArcSwapOption::new(usage_inject_upstreams),
+            request_rules: ArcSwap::from_pointee(RequestRules::new(
+                cfg.request_rules.unwrap_or_default(),
+            )),
+            strip_request_headers: cfg
+                .strip_request_headers
+                .map(|v| Arc::new(HeaderDenylist::new(v))),
+            proxy_cors: cfg
+                .proxy_cors_origins
+                .map(|origins| Arc::new(ProxyCors::new(origins, &client_key_headers))),
+            client_key_headers,
+            upstream_request_id_headers: match cfg.upstream_request_id_headers {
+                // Names were checked in `Config::validate`.
+                Some(v) => v.iter().filter_map(|h| HeaderName::from_bytes(h.as_bytes()).ok()).collect(),
+                None => Arc::from([
+                    HeaderName::from_static("x-request-id"),
+                    HeaderName::from_static("request-id"),
+                ]),
+            },
+            max_model_len: cfg.max_model_len.unwrap_or(256),
+            unknown_model_behavior: cfg.unknown_model_behavior.unwrap_or_default(),
+            default_upstream_id: cfg.default_upstream_id.map(Arc::from),
             store,
             billing,
             model_routes_path,
             upstreams_path,
+            model_aliases_path,
+            model_aliases: ArcSwap::from_pointee(clean_model_aliases(model_aliases)),
             snapshot: ArcSwap::from(Arc::new(snapshot)),
             sched_rr: Arc::new(AtomicUsize::new(0)),
+            upstreams_version: Arc::new(AtomicU64::new(now_ms())),
+            edit_lock: Arc::new(std::sync::Mutex::new(())),
+            shutdown: Arc::new(tokio::sync::watch::channel(false).0),
             client,
+            conn_stats,
             stats: Arc::new(Stats::new()),
             requests,
-        })
+            model_stats: Arc::new(ModelStatsTable::new()),
+        });
+
+        if let Some(interval) = probe_interval {
+            start_health_probe(Arc::downgrade(&state), interval);
+        }
+        if let Some(secs) = cfg.model_refresh_interval_s.filter(|s| *s > 0) {
+            start_model_refresh(Arc::downgrade(&state), Duration::from_secs(secs));
+        }
+        start_billing_watchdog(Arc::downgrade(&state));
+        if !state.read_only {
+            start_cooldown_persist(Arc::downgrade(&state));
+        }
+
+        Ok(state)
+    }
+
+    /// Upstream timeout for a request on `model` sent to `upstream`: the `model_timeouts` entry,
+    /// else the upstream's `timeout_ms`, else the global default.
+    #[inline]
+    pub fn timeout_for(&self, model: &str, upstream: &Upstream) -> Duration {
+        self.model_timeouts
+            .get(model)
+            .copied()
+            .or(upstream.timeout)
+            .unwrap_or_else(|| self.request_timeout())
+    }
+
+    #[inline]
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    /// Apply the hot-reloadable parts of a freshly loaded config (SIGHUP): the settings in
+    /// `HOT_RELOADABLE`. Returns the names of those that changed; everything else needs a
+    /// restart.
+    pub fn apply_reload(&self, cfg: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        if self
+            .request_timeout_ms
+            .swap(cfg.request_timeout_ms, Ordering::Relaxed)
+            != cfg.request_timeout_ms
+        {
+            changed.push("request_timeout_ms");
+        }
+
+        if *self.ban.load_full() != cfg.ban {
+            self.error_rate
+                .store(build_error_rate_policy(&cfg.ban).map(Arc::new));
+            self.ban.store(Arc::new(cfg.ban.clone()));
+            changed.push("ban");
+        }
+
+        let proxy_tokens = build_token_set(cfg.proxy_tokens.clone());
+        if self.proxy_tokens.load_full() != proxy_tokens {
+            self.proxy_tokens.store(proxy_tokens);
+            changed.push("proxy_tokens");
+        }
+
+        let admin_tokens = build_admin_set(cfg.admin_tokens.clone());
+        if **self.admin_tokens.load() != admin_tokens {
+            self.admin_tokens.store(Arc::new(admin_tokens));
+            changed.push("admin_tokens");
+        }
+
+        let allowlist = build_ip_allowlist(cfg.admin_ip_allowlist.as_deref());
+        if **self.admin_ip_allowlist.load() != allowlist {
+            self.admin_ip_allowlist.store(Arc::new(allowlist));
+            changed.push("admin_ip_allowlist");
+        }
+
+        let usage_inject = build_token_set(cfg.usage_inject_upstreams.clone());
+        if self.usage_inject_upstreams.load_full() != usage_inject {
+            self.usage_inject_upstreams.store(usage_inject);
+            changed.push("usage_inject_upstreams");
+        }
+
+        let rules = RequestRules::new(cfg.request_rules.clone().unwrap_or_default());
+        if **self.request_rules.load() != rules {
+            self.request_rules.store(Arc::new(rules));
+            changed.push("request_rules");
+        }
+
+        changed
     }
 
     #[inline]
     pub fn authorize_proxy(&self, req: &Request<Body>) -> bool {
-        let Some(tokens) = &self.proxy_tokens else {
+        let tokens = self.proxy_tokens.load();
+        let Some(tokens) = tokens.as_deref() else {
             return true;
         };
         let Some(h) = req.headers().get("x-proxy-token") else {
@@ -437,42 +1358,96 @@ impl RouterState {
             return false;
         };
         match h.to_str() {
-            Ok(s) => self.admin_tokens.contains(s),
+            Ok(s) => self.admin_tokens.load().contains(s),
             Err(_) => false,
         }
     }
 
-    #[inline]
-    pub fn authorize_admin_token_str(&self, token: &str) -> bool {
-        self.admin_tokens.contains(token)
+    /// Cooldown state worth keeping across a restart, for `KeyStore::save_cooldowns`.
+    pub fn cooldown_records(&self, now_ms: u64) -> Vec<(String, CooldownRecord)> {
+        let snap = self.snapshot.load_full();
+        let mut out = Vec::new();
+        for u in snap.upstreams.iter() {
+            if let Some(r) = u.cooldown_record(now_ms) {
+                out.push((cooldown_record_id(&u.id, None), r));
+            }
+            for k in u.keys.load().iter() {
+                if let Some(r) = k.cooldown_record(now_ms) {
+                    out.push((cooldown_record_id(&u.id, Some(&k.key)), r));
+                }
+            }
+        }
+        out
     }
 
-    #[inline]
-    pub fn should_inject_usage(&self, upstream_id: &str) -> bool {
-        self.usage_inject_upstreams
-            .as_ref()
-            .map(|set| set.contains(upstream_id))
-            .unwrap_or(false)
+    /// Count a configuration change and log it under the `gptload::audit` target. `kind` is
+    /// one of "config" (SIGHUP), "keys", "models", "routes", "aliases" or "upstreams".
+    pub fn record_reload(&self, kind: &'static str, ok: bool) {
+        let count = self.stats.reload_count.fetch_add(1, Ordering::Relaxed) + 1;
+        if ok {
+            self.stats.reload_successes.fetch_add(1, Ordering::Relaxed);
+            self.stats.last_reload_ms.store(now_ms(), Ordering::Relaxed);
+            tracing::info!(target: "gptload::audit", kind, reload_count = count, "configuration changed");
+        } else {
+            self.stats.reload_failures.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(target: "gptload::audit", kind, reload_count = count, "configuration change failed");
+        }
     }
 
-    #[inline]
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once `begin_shutdown` has been called.
+    pub async fn shutdown_requested(&self) {
+        let mut rx = self.shutdown.subscribe();
+        let _ = rx.wait_for(|v| *v).await;
+    }
+
+    /// Whether `ip` may reach the admin surface (`admin_ip_allowlist`).
+    #[inline]
+    pub fn admin_ip_allowed(&self, ip: std::net::IpAddr) -> bool {
+        let list = self.admin_ip_allowlist.load();
+        list.is_empty() || list.iter().any(|net| net.contains(ip))
+    }
+
+    #[inline]
+    pub fn authorize_admin_token_str(&self, token: &str) -> bool {
+        self.admin_tokens.load().contains(token)
+    }
+
+    #[inline]
+    pub fn should_inject_usage(&self, upstream_id: &str) -> bool {
+        self.usage_inject_upstreams
+            .load()
+            .as_deref()
+            .map(|set| set.contains(upstream_id))
+            .unwrap_or(false)
+    }
+
+    #[inline]
     pub fn should_retry_status(&self, status: http::StatusCode) -> bool {
-        self.retry_status_codes.contains(&status.as_u16())
+        self.status_table.get(status).retry
     }
 
     pub fn retry_status_codes_sorted(&self) -> Vec<u16> {
-        let mut v: Vec<u16> = self.retry_status_codes.iter().copied().collect();
-        v.sort_unstable();
-        v
+        self.status_table.retry_codes()
     }
 
     #[inline]
     pub fn record_request(&self, entry: RequestLogEntry) {
+        if let Some(model) = entry.model.as_deref() {
+            self.model_stats.record(model, &entry);
+        }
         self.requests.record(entry);
     }
 
-    pub fn recent_requests(&self, limit: usize) -> Vec<RequestLogEntry> {
-        self.requests.recent(limit)
+    pub fn recent_requests(&self, limit: usize, filter: &RequestLogFilter) -> Vec<RequestLogEntry> {
+        self.requests.recent(limit, filter)
     }
 
     pub fn metrics_snapshot(&self, window: MetricsWindow) -> Vec<MetricsBucket> {
@@ -486,76 +1461,421 @@ impl RouterState {
     }
 
     /// Select an upstream + key. Returns None if **all** keys are in cooldown or no keys loaded.
-    pub fn select(&self, now_ms: u64) -> Option<Selected> {
-        let snap = self.snapshot.load_full();
-        let sched_len = snap.schedule.len();
-        if sched_len == 0 {
+    /// `allowed` (a billing key's `allowed_upstreams`) limits the candidates to those ids.
+    pub fn select(&self, allowed: Option<&[String]>, now_ms: u64) -> Option<Selected> {
+        self.select_inner(None, allowed, now_ms)
+    }
+
+    /// Select an upstream + key that supports the given model.
+    pub fn select_for_model(&self, model: &str, allowed: Option<&[String]>, now_ms: u64) -> Option<Selected> {
+        self.select_inner(Some(model), allowed, now_ms)
+    }
+
+    /// Select a key from one specific upstream (ignores models; respects cooldowns).
+    pub fn select_from_upstream(&self, upstream_id: &str, now_ms: u64) -> Option<Selected> {
+        let (_idx, u) = self.upstream_by_id(upstream_id)?;
+        if !Self::upstream_eligible(&u, None, now_ms) {
             return None;
         }
+        let k = u.select_key(self.key_select, now_ms)?;
+        self.selected(&u, k)
+    }
 
-        // Try up to schedule length to find an upstream with any available key.
-        for _ in 0..sched_len {
-            let rr = self.sched_rr.as_ref().fetch_add(1, Ordering::Relaxed);
-            let u_idx = snap.schedule[rr % sched_len];
+    /// Select for a proxied request. Same as `select_for_model`, except that the `sticky`
+    /// strategy routes by `client_key` (the billing API key).
+    pub fn select_for_client(
+        &self,
+        model: &str,
+        client_key: &str,
+        allowed: Option<&[String]>,
+        now_ms: u64,
+    ) -> Option<Selected> {
+        if self.select_strategy != SelectStrategy::Sticky {
+            return self.select_for_model(model, allowed, now_ms);
+        }
+        let snap = self.snapshot.load_full();
+        self.select_sticky(&snap, model, client_key, allowed, now_ms)
+    }
 
-            let u = &snap.upstreams[u_idx];
+    fn select_inner(&self, model: Option<&str>, allowed: Option<&[String]>, now_ms: u64) -> Option<Selected> {
+        let snap = self.snapshot.load_full();
+        let sel = Selection { model, allowed };
+        if snap.tiers.len() <= 1 {
+            return self.select_in_tier(&snap, sel, None, now_ms);
+        }
+        // Exhaust each tier, lowest first, before spilling to the next one.
+        snap.tiers
+            .iter()
+            .find_map(|&t| self.select_in_tier(&snap, sel, Some(t), now_ms))
+    }
 
-            let u_until = u.cooldown_until_ms.load(Ordering::Relaxed);
-            if u_until > now_ms {
-                continue;
+    fn select_in_tier(
+        &self,
+        snap: &RouterSnapshot,
+        sel: Selection<'_>,
+        tier: Option<u32>,
+        now_ms: u64,
+    ) -> Option<Selected> {
+        match self.select_strategy {
+            // Sticky needs a client key; without one it degrades to round-robin.
+            SelectStrategy::RoundRobin | SelectStrategy::Sticky => {
+                self.select_round_robin(snap, sel, tier, now_ms)
             }
-            if let Some(k) = u.select_key(now_ms) {
-                self.stats.upstream_selected_total.fetch_add(1, Ordering::Relaxed);
-                u.stats.selected_total.fetch_add(1, Ordering::Relaxed);
-                return Some(Selected {
-                    upstream: u.clone(),
-                    key: k,
-                });
+            SelectStrategy::LeastConn => self.select_least_conn(snap, sel, tier, now_ms),
+            SelectStrategy::Latency => self
+                .select_by_latency(snap, sel, tier, now_ms)
+                .or_else(|| self.select_round_robin(snap, sel, tier, now_ms)),
+        }
+    }
+
+    /// Failover tier of `u`: its own `tier` if set, else its group's; 0 when ungrouped or not
+    /// listed in `group_tiers`.
+    pub fn upstream_tier(&self, u: &Upstream) -> u32 {
+        upstream_tier(u, &self.group_tiers)
+    }
+
+    /// `upstream_eligible` restricted to `tier` (any tier when `None`) and `sel.allowed`.
+    #[inline]
+    fn candidate(&self, u: &Upstream, sel: Selection<'_>, tier: Option<u32>, now_ms: u64) -> bool {
+        tier.is_none_or(|t| self.upstream_tier(u) == t)
+            && sel.permits(u)
+            && Self::upstream_eligible(u, sel.model, now_ms)
+    }
+
+    #[inline]
+    fn upstream_eligible(u: &Upstream, model: Option<&str>, now_ms: u64) -> bool {
+        if !u.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+        if let Some(m) = model {
+            if !u.models.load().contains(m) {
+                return false;
             }
         }
+        u.accepts_requests(now_ms)
+    }
 
-        None
+    /// Hand out `key` on `u`. `None` if `u` is half-open and another request won its probe.
+    fn selected(&self, u: &Arc<Upstream>, key: Arc<KeyState>) -> Option<Selected> {
+        let probe = u.cooldown_until_ms.load(Ordering::Relaxed) != 0;
+        if probe
+            && u
+                .probe_inflight
+                .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return None;
+        }
+        self.stats.upstream_selected_total.fetch_add(1, Ordering::Relaxed);
+        u.stats.selected_total.fetch_add(1, Ordering::Relaxed);
+        Some(Selected {
+            upstream: u.clone(),
+            key,
+            _inflight: Arc::new(InflightGuard::new(u.clone(), probe)),
+        })
     }
 
-    /// Select an upstream + key that supports the given model.
-    pub fn select_for_model(&self, model: &str, now_ms: u64) -> Option<Selected> {
-        let snap = self.snapshot.load_full();
-        let sched_len = snap.schedule.len();
-        if sched_len == 0 {
+    /// Smooth weighted round-robin: every eligible upstream gains its weight, and the one with
+    /// the highest current weight is picked and gives back the eligible total. Picks interleave
+    /// (weights 5:1:1 give a a b a c a a) and a pass costs O(upstreams) whatever the weights.
+    /// The updates are atomic adds, so racing picks may break ties differently but never drift:
+    /// the current weights always sum to zero.
+    fn select_round_robin(
+        &self,
+        snap: &RouterSnapshot,
+        sel: Selection<'_>,
+        tier: Option<u32>,
+        now_ms: u64,
+    ) -> Option<Selected> {
+        // Upstreams whose keys are all cooling down; only allocated on that rare path.
+        let mut exhausted: Vec<usize> = Vec::new();
+
+        loop {
+            let mut total = 0i64;
+            let mut best: Option<(usize, i64)> = None;
+            for (idx, u) in snap.upstreams.iter().enumerate() {
+                if exhausted.contains(&idx) || !self.candidate(u, sel, tier, now_ms) {
+                    continue;
+                }
+                let w = u.weight as i64;
+                total += w;
+                let current = u.rr_current.fetch_add(w, Ordering::Relaxed) + w;
+                if best.is_none_or(|(_, b)| current > b) {
+                    best = Some((idx, current));
+                }
+            }
+
+            let (idx, _) = best?;
+            let u = &snap.upstreams[idx];
+            u.rr_current.fetch_sub(total, Ordering::Relaxed);
+            if let Some(s) = u.select_key(self.key_select, now_ms).and_then(|k| self.selected(u, k)) {
+                return Some(s);
+            }
+            exhausted.push(idx);
+        }
+    }
+
+    /// Pick the eligible upstream with the fewest inflight requests. Ties are broken by a
+    /// rotating start offset so equally idle upstreams share load.
+    fn select_least_conn(
+        &self,
+        snap: &RouterSnapshot,
+        sel: Selection<'_>,
+        tier: Option<u32>,
+        now_ms: u64,
+    ) -> Option<Selected> {
+        let n = snap.upstreams.len();
+        if n == 0 {
             return None;
         }
+        let start = self.sched_rr.as_ref().fetch_add(1, Ordering::Relaxed);
+        // Upstreams whose keys are all cooling down; only allocated on that rare path.
+        let mut exhausted: Vec<usize> = Vec::new();
+
+        loop {
+            let mut best: Option<(usize, u64)> = None;
+            for off in 0..n {
+                let idx = (start + off) % n;
+                if exhausted.contains(&idx) {
+                    continue;
+                }
+                let u = &snap.upstreams[idx];
+                if !self.candidate(u, sel, tier, now_ms) {
+                    continue;
+                }
+                let load = u.inflight.load(Ordering::Relaxed);
+                if best.is_none_or(|(_, b)| load < b) {
+                    best = Some((idx, load));
+                }
+            }
 
-        for _ in 0..sched_len {
-            let rr = self.sched_rr.as_ref().fetch_add(1, Ordering::Relaxed);
-            let u_idx = snap.schedule[rr % sched_len];
-            let u = &snap.upstreams[u_idx];
+            let (idx, _) = best?;
+            let u = &snap.upstreams[idx];
+            if let Some(s) = u.select_key(self.key_select, now_ms).and_then(|k| self.selected(u, k)) {
+                return Some(s);
+            }
+            exhausted.push(idx);
+        }
+    }
 
-            if !u.models.load().contains(model) {
+    /// Weighted random pick with `weight / latency_ewma`. Returns None (caller falls back to
+    /// round-robin) while any eligible upstream has no latency samples yet, or if the picked
+    /// upstream has no usable key.
+    fn select_by_latency(
+        &self,
+        snap: &RouterSnapshot,
+        sel: Selection<'_>,
+        tier: Option<u32>,
+        now_ms: u64,
+    ) -> Option<Selected> {
+        let mut total = 0f64;
+        for u in snap.upstreams.iter() {
+            if !self.candidate(u, sel, tier, now_ms) {
                 continue;
             }
+            let ewma = u.latency_ewma_us.load(Ordering::Relaxed);
+            if ewma == 0 {
+                return None;
+            }
+            total += u.weight as f64 / ewma as f64;
+        }
+        if total <= 0.0 {
+            return None;
+        }
 
-            let u_until = u.cooldown_until_ms.load(Ordering::Relaxed);
-            if u_until > now_ms {
+        let mut target = (fast_rand() >> 11) as f64 / (1u64 << 53) as f64 * total;
+
+        let mut picked = None;
+        for u in snap.upstreams.iter() {
+            if !self.candidate(u, sel, tier, now_ms) {
                 continue;
             }
-            if let Some(k) = u.select_key(now_ms) {
-                self.stats.upstream_selected_total.fetch_add(1, Ordering::Relaxed);
-                u.stats.selected_total.fetch_add(1, Ordering::Relaxed);
-                return Some(Selected {
-                    upstream: u.clone(),
-                    key: k,
-                });
+            let ewma = u.latency_ewma_us.load(Ordering::Relaxed).max(1);
+            target -= u.weight as f64 / ewma as f64;
+            picked = Some(u);
+            if target <= 0.0 {
+                break;
             }
         }
 
+        let u = picked?;
+        let k = u.select_key(self.key_select, now_ms)?;
+        self.selected(u, k)
+    }
+
+    /// Weighted rendezvous (HRW) hashing of `client_key` over the upstreams serving `model`.
+    ///
+    /// Upstreams are ranked per client key; the first one that is out of cooldown and has an
+    /// available key wins. When the preferred upstream is cooling down or all its keys are,
+    /// the request falls through to the next upstream in that key's ranking, which is itself
+    /// stable, so the client returns to its preferred upstream once it recovers. Adding or
+    /// removing an upstream only remaps the clients that ranked it first.
+    fn select_sticky(
+        &self,
+        snap: &RouterSnapshot,
+        model: &str,
+        client_key: &str,
+        allowed: Option<&[String]>,
+        now_ms: u64,
+    ) -> Option<Selected> {
+        let sel = Selection { model: Some(model), allowed };
+        let key_hash = fnv1a64(client_key.as_bytes());
+        let mut ranked: Vec<(u32, f64, usize)> = snap
+            .upstreams
+            .iter()
+            .enumerate()
+            .filter(|(_, u)| {
+                u.enabled.load(Ordering::Relaxed) && sel.permits(u) && u.models.load().contains(model)
+            })
+            .map(|(idx, u)| {
+                let h = mix64(key_hash ^ fnv1a64(u.id.as_bytes()));
+                // Map to (0, 1) and apply the weighted HRW score `-w / ln(h)`.
+                let unit = ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+                (self.upstream_tier(u), -(u.weight as f64) / unit.ln(), idx)
+            })
+            .collect();
+        // Lower tiers first (see `group_tiers`), then by score.
+        ranked.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)));
+
+        for (_, _, idx) in ranked {
+            let u = &snap.upstreams[idx];
+            if !u.accepts_requests(now_ms) {
+                continue;
+            }
+            if let Some(s) = u.select_key(self.key_select, now_ms).and_then(|k| self.selected(u, k)) {
+                return Some(s);
+            }
+        }
         None
     }
 
+    /// Whether any upstream in `allowed` serves `model` (exists at all when `None`), i.e. a
+    /// key restricted to `allowed` can be routed for it once cooldowns clear.
+    pub fn any_upstream_permitted(&self, model: Option<&str>, allowed: &[String]) -> bool {
+        let sel = Selection { model, allowed: Some(allowed) };
+        self.snapshot
+            .load()
+            .upstreams
+            .iter()
+            .any(|u| sel.permits(u) && model.is_none_or(|m| u.models.load().contains(m)))
+    }
+
+    /// Fold one attempt's latency (request sent to response headers) into the upstream's EWMA
+    /// (`latency_routing.ewma_alpha`).
+    pub fn record_upstream_latency(&self, u: &Upstream, latency_ns: u64) {
+        let sample = (latency_ns / 1_000).max(1);
+        let alpha = self.latency_ewma_alpha;
+        let _ = u
+            .latency_ewma_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cur| {
+                if cur == 0 {
+                    return Some(sample);
+                }
+                let next = alpha * sample as f64 + (1.0 - alpha) * cur as f64;
+                Some((next as u64).max(1))
+            });
+    }
+
+    /// Earliest time (ms) at which some upstream `select_for_model(model, allowed, ..)` could
+    /// pick has a usable key again, i.e. when a request that just failed selection could
+    /// succeed. Disabled upstreams and ones without keys never become available, so they are
+    /// skipped; `None` when no upstream is left.
+    pub fn soonest_available_ms(&self, model: &str, allowed: Option<&[String]>, now_ms: u64) -> Option<u64> {
+        let snap = self.snapshot.load_full();
+        let sel = Selection { model: Some(model), allowed };
+        let mut soonest: Option<u64> = None;
+        for u in snap.upstreams.iter() {
+            if !u.enabled.load(Ordering::Relaxed) || !sel.permits(u) || !u.models.load().contains(model) {
+                continue;
+            }
+            let keys = u.keys.load();
+            if keys.is_empty() {
+                continue;
+            }
+            let Some(key_at) = keys
+                .iter()
+                .map(|k| k.cooldown_until_ms.load(Ordering::Relaxed))
+                .min()
+            else {
+                continue;
+            };
+            let at = key_at
+                .max(u.cooldown_until_ms.load(Ordering::Relaxed))
+                .max(now_ms);
+            soonest = Some(soonest.map_or(at, |s| s.min(at)));
+        }
+        soonest
+    }
+
+    /// `Retry-After` for a request that found no available key: seconds until
+    /// `soonest_available_ms`, rounded up and at least 1.
+    pub fn retry_after_secs(&self, model: &str, allowed: Option<&[String]>, now_ms: u64) -> Option<u64> {
+        let at = self.soonest_available_ms(model, allowed, now_ms)?;
+        Some(at.saturating_sub(now_ms).div_ceil(1000).max(1))
+    }
+
     pub fn model_exists(&self, model: &str) -> bool {
         let snap = self.snapshot.load_full();
         snap.upstreams.iter().any(|u| u.models.load().contains(model))
     }
 
+    /// Dry run of `select_for_model`: every upstream serving `model` (and in `allowed`), in the
+    /// order selection would try them: lower tiers first, then the strategy's preference within
+    /// a tier (next smooth round-robin pick, fewest inflight, or largest latency share). Only
+    /// reads the snapshot; no cursor, counter or probe slot is touched. `sticky` depends on the
+    /// client key and is shown in round-robin order.
+    pub fn route_candidates(
+        &self,
+        model: &str,
+        allowed: Option<&[String]>,
+        now_ms: u64,
+    ) -> Vec<RouteCandidate> {
+        let snap = self.snapshot.load_full();
+        let sel = Selection { model: Some(model), allowed };
+        let preference = |u: &Upstream| match self.select_strategy {
+            SelectStrategy::RoundRobin | SelectStrategy::Sticky => {
+                (u.rr_current.load(Ordering::Relaxed) + u.weight as i64) as f64
+            }
+            SelectStrategy::LeastConn => -(u.inflight.load(Ordering::Relaxed) as f64),
+            SelectStrategy::Latency => match u.latency_ewma_us.load(Ordering::Relaxed) {
+                0 => 0.0,
+                ewma => u.weight as f64 / ewma as f64,
+            },
+        };
+        let mut ranked: Vec<(u32, f64, &Arc<Upstream>)> = snap
+            .upstreams
+            .iter()
+            .filter(|u| sel.permits(u) && u.models.load().contains(model))
+            .map(|u| (self.upstream_tier(u), preference(u), u))
+            .collect();
+        // Stable: ties keep config order, as in the selection loops.
+        ranked.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)));
+
+        ranked
+            .into_iter()
+            .map(|(tier, _, u)| {
+                let keys = u.keys.load();
+                let keys_available = keys
+                    .iter()
+                    .filter(|k| k.cooldown_until_ms.load(Ordering::Relaxed) <= now_ms)
+                    .count();
+                let enabled = u.enabled.load(Ordering::Relaxed);
+                let accepts = u.accepts_requests(now_ms);
+                RouteCandidate {
+                    upstream_id: u.id.to_string(),
+                    tier,
+                    weight: u.weight,
+                    enabled,
+                    cooling_down: !accepts,
+                    cooldown_until_ms: u.cooldown_until_ms.load(Ordering::Relaxed),
+                    keys_total: keys.len(),
+                    keys_available,
+                    eligible: enabled && accepts && keys_available > 0,
+                }
+            })
+            .collect()
+    }
+
     pub fn any_models_loaded(&self) -> bool {
         let snap = self.snapshot.load_full();
         snap.upstreams.iter().any(|u| !u.models.load().is_empty())
@@ -622,38 +1942,85 @@ impl RouterState {
     }
 
     async fn refresh_models_for_upstream(&self, upstream: Arc<Upstream>) -> anyhow::Result<usize> {
-        let models = self.fetch_models_for_upstream(upstream.clone()).await?;
+        let models = match self.fetch_models_for_upstream(upstream.clone()).await {
+            Ok(m) => m,
+            Err(e) => {
+                self.record_reload("models", false);
+                return Err(e);
+            }
+        };
         let count = models.len();
         upstream.models.store(Arc::new(models));
+        self.record_reload("models", true);
         Ok(count)
     }
     #[inline]
-    pub fn on_upstream_status(&self, sel: &Selected, status: http::StatusCode, now_ms: u64) {
+    pub fn on_upstream_status(
+        &self,
+        sel: &Selected,
+        status: http::StatusCode,
+        headers: &hyper::HeaderMap,
+        now_ms: u64,
+    ) {
         let u = &sel.upstream;
 
-        // HTTP response means upstream is reachable; clear upstream cooldown and streak.
+        // HTTP response means upstream is reachable; clear upstream cooldown and streak
+        // (an active error-rate trip or manual ban is kept).
         u.fail_streak.store(0, Ordering::Relaxed);
-        u.cooldown_until_ms.store(0, Ordering::Relaxed);
+        let held = u
+            .error_window
+            .tripped_until_ms
+            .load(Ordering::Relaxed)
+            .max(u.ban_until_ms.load(Ordering::Relaxed));
+        u.cooldown_until_ms
+            .store(if held > now_ms { held } else { 0 }, Ordering::Relaxed);
 
         // Upstream per-status stats
         inc_status(&u.stats, status);
 
+        // Key usage stats
+        let k = &sel.key;
+        k.requests_total.fetch_add(1, Ordering::Relaxed);
+        k.last_used_ms.store(now_ms, Ordering::Relaxed);
+        if status.is_success() {
+            k.responses_2xx.fetch_add(1, Ordering::Relaxed);
+        } else if status.is_client_error() {
+            k.responses_4xx.fetch_add(1, Ordering::Relaxed);
+        } else if status.is_server_error() {
+            k.responses_5xx.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if self.key_select == KeySelect::Remaining {
+            k.record_ratelimit(headers, now_ms);
+        }
+
         // Global per-status stats
         self.inc_global_status(status);
 
-        if status == http::StatusCode::TOO_MANY_REQUESTS {
-            // Key-level rate limit.
-            self.ban_key(&sel.key, self.ban.rate_limit_ms, now_ms);
-        } else if status == http::StatusCode::UNAUTHORIZED || status == http::StatusCode::FORBIDDEN {
+        let ban = self.ban.load();
+        let retry_after_max_ms = ban.retry_after_max_ms.unwrap_or(600_000);
+        match self.status_table.get(status).breaker {
+            // Key-level rate limit: the upstream's Retry-After when given, else backoff.
+            BreakerAction::BanKey => match retry_after_ms(headers, retry_after_max_ms, now_ms) {
+                Some(ms) => {
+                    self.bump_streak(&sel.key.fail_streak, &sel.key.last_fail_ms, now_ms);
+                    sel.key.cooldown_until_ms.store(now_ms.saturating_add(ms), Ordering::Relaxed);
+                }
+                None => self.ban_key(&sel.key, ban.rate_limit_ms, now_ms),
+            },
             // Key invalid / forbidden.
-            self.ban_key(&sel.key, self.ban.auth_error_ms, now_ms);
-        } else if status.is_server_error() {
+            BreakerAction::BanKeyAuth => self.ban_key(&sel.key, ban.auth_error_ms, now_ms),
             // Upstream 5xx: prefer upstream cooldown, not key cooldown.
-            self.ban_upstream(u, self.ban.server_error_ms, now_ms);
-        } else {
+            BreakerAction::BanUpstream => self.ban_upstream(u, ban.server_error_ms, now_ms),
             // Success or other 4xx: reset key streak.
-            sel.key.fail_streak.store(0, Ordering::Relaxed);
+            BreakerAction::None => sel.key.fail_streak.store(0, Ordering::Relaxed),
         }
+
+        // An upstream failure is whatever `status_actions` resolves to an upstream ban (5xx by
+        // default), so overrides count the same way here as for the streak breaker.
+        let failed = matches!(self.status_table.get(status).breaker, BreakerAction::BanUpstream);
+        self.record_error_rate(u, failed, now_ms);
+        sel._inflight.end_probe();
     }
 
     #[inline]
@@ -661,15 +2028,58 @@ impl RouterState {
         let u = &sel.upstream;
         self.stats.errors_timeout.fetch_add(1, Ordering::Relaxed);
         u.stats.errors_timeout.fetch_add(1, Ordering::Relaxed);
-        self.ban_upstream(u, self.ban.network_error_ms, now_ms);
+        self.ban_upstream(u, self.ban.load().network_error_ms, now_ms);
+        self.record_error_rate(u, true, now_ms);
+        sel._inflight.end_probe();
     }
 
     #[inline]
-    pub fn on_network_error(&self, sel: &Selected, now_ms: u64) {
+    pub fn on_network_error(&self, sel: &Selected, kind: NetErrorKind, now_ms: u64) {
         let u = &sel.upstream;
         self.stats.errors_network.fetch_add(1, Ordering::Relaxed);
         u.stats.errors_network.fetch_add(1, Ordering::Relaxed);
-        self.ban_upstream(u, self.ban.network_error_ms, now_ms);
+        let by_kind = match kind {
+            NetErrorKind::Dns => Some((&self.stats.errors_dns, &u.stats.errors_dns)),
+            NetErrorKind::Connect => Some((&self.stats.errors_connect, &u.stats.errors_connect)),
+            NetErrorKind::Tls => Some((&self.stats.errors_tls, &u.stats.errors_tls)),
+            NetErrorKind::Other => None,
+        };
+        if let Some((global, upstream)) = by_kind {
+            global.fetch_add(1, Ordering::Relaxed);
+            upstream.fetch_add(1, Ordering::Relaxed);
+        }
+        self.ban_upstream(u, self.ban.load().network_error_ms, now_ms);
+        self.record_error_rate(u, true, now_ms);
+        sel._inflight.end_probe();
+    }
+
+    /// Feed the rolling error window and trip the upstream when the failure ratio
+    /// exceeds `ban.error_rate.threshold`. No-op unless the feature is configured.
+    fn record_error_rate(&self, u: &Upstream, failed: bool, now_ms: u64) {
+        let Some(p) = self.error_rate.load_full() else {
+            return;
+        };
+        u.error_window.record(p.slot_ms, now_ms, failed);
+        if !failed {
+            return;
+        }
+        let (total, errs) = u.error_window.counts(p.slot_ms, now_ms);
+        // Trips at the threshold, not only above it, so `threshold = 1.0` can fire.
+        if total < p.min_samples || (errs as f64) < p.threshold * total as f64 {
+            return;
+        }
+        let until = now_ms.saturating_add(p.cooldown_ms);
+        u.error_window.tripped_until_ms.store(until, Ordering::Relaxed);
+        u.cooldown_until_ms.fetch_max(until, Ordering::Relaxed);
+        // Start from a clean window once the cooldown ends.
+        u.error_window.clear();
+        tracing::warn!(
+            upstream = %u.id,
+            total,
+            errors = errs,
+            cooldown_ms = p.cooldown_ms,
+            "upstream error rate over threshold; cooling down"
+        );
     }
 
     #[inline]
@@ -685,26 +2095,49 @@ impl RouterState {
         }
     }
 
-    fn ban_key(&self, key: &KeyState, base_ms: u64, now_ms: u64) {
-        let streak = key.fail_streak.fetch_add(1, Ordering::Relaxed) + 1;
-        let max_pow = self.ban.max_backoff_pow.min(30);
-        let pow = (streak - 1).min(max_pow);
-        let mult = 1u64 << pow;
+    /// `base_ms` doubled per failure in the streak (up to `max_backoff_pow`, capped at
+    /// `max_ban_ms` unless the base itself is larger), spread by `ban.jitter` so things banned
+    /// together do not all come back in the same instant.
+    fn backoff_ms(&self, streak: u32, base_ms: u64) -> u64 {
+        let ban = self.ban.load();
+        let pow = (streak - 1).min(ban.max_backoff_pow.min(30));
+        let cap = ban.max_ban_ms.unwrap_or(300_000).max(base_ms);
+        let ban_ms = base_ms.saturating_mul(1u64 << pow).min(cap);
+        match ban.jitter {
+            Some(j) if j > 0.0 => {
+                // Uniform in [-1, 1) from the top 53 bits.
+                let r = (fast_rand() >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+                (ban_ms as f64 * (1.0 + j * r)) as u64
+            }
+            _ => ban_ms,
+        }
+    }
 
-        let ban_ms = base_ms.saturating_mul(mult);
-        let until = now_ms.saturating_add(ban_ms);
+    /// Count a failure and return the new streak. With `ban.streak_reset_ms`, a failure that
+    /// long after the previous one starts a new streak, so intermittent failures don't keep
+    /// escalating the backoff.
+    fn bump_streak(&self, streak: &AtomicU32, last_fail_ms: &AtomicU64, now_ms: u64) -> u32 {
+        let last = last_fail_ms.swap(now_ms, Ordering::Relaxed);
+        let reset_ms = self.ban.load().streak_reset_ms.unwrap_or(0);
+        if reset_ms > 0 && last > 0 && now_ms.saturating_sub(last) >= reset_ms {
+            streak.store(1, Ordering::Relaxed);
+            return 1;
+        }
+        streak.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn ban_key(&self, key: &KeyState, base_ms: u64, now_ms: u64) {
+        let streak = self.bump_streak(&key.fail_streak, &key.last_fail_ms, now_ms);
+        let until = now_ms.saturating_add(self.backoff_ms(streak, base_ms));
 
         key.cooldown_until_ms.store(until, Ordering::Relaxed);
     }
 
     fn ban_upstream(&self, u: &Upstream, base_ms: u64, now_ms: u64) {
-        let streak = u.fail_streak.fetch_add(1, Ordering::Relaxed) + 1;
-        let max_pow = self.ban.max_backoff_pow.min(30);
-        let pow = (streak - 1).min(max_pow);
-        let mult = 1u64 << pow;
-
-        let ban_ms = base_ms.saturating_mul(mult);
-        let until = now_ms.saturating_add(ban_ms);
+        let streak = self.bump_streak(&u.fail_streak, &u.last_fail_ms, now_ms);
+        let until = now_ms
+            .saturating_add(self.backoff_ms(streak, base_ms))
+            .max(u.ban_until_ms.load(Ordering::Relaxed));
 
         u.cooldown_until_ms.store(until, Ordering::Relaxed);
     }
@@ -712,6 +2145,7 @@ impl RouterState {
     pub fn record_latency(&self, latency_ns: u64) {
         self.stats.latency_ns_total.fetch_add(latency_ns, Ordering::Relaxed);
         self.stats.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.stats.latency_hist.record(latency_ns);
 
         // Update max with CAS loop.
         let mut cur = self.stats.latency_ns_max.load(Ordering::Relaxed);
@@ -741,10 +2175,95 @@ impl RouterState {
             .body(Body::from(body))
             .unwrap_or_else(|_| Response::new(Body::from("proxy_error")))
     }
+
+    /// 429 in OpenAI's rate-limit shape (`type = "rate_limit_error"`) so client SDKs apply
+    /// their own backoff; `code` marks it as proxy-originated.
+    pub fn rate_limit_error(message: &str, code: &str) -> Response<Body> {
+        let body = format!(
+            r#"{{"error":{{"message":"{}","type":"rate_limit_error","param":null,"code":"{}"}}}}"#,
+            escape_json(message),
+            escape_json(code)
+        );
+        Response::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap_or_else(|_| Response::new(Body::from("rate_limit_error")))
+    }
 }
 
 impl Upstream {
-    fn select_key(&self, now_ms: u64) -> Option<Arc<KeyState>> {
+    /// Take over `prev`'s circuit-breaker state (cooldown, manual ban, fail streak) when the
+    /// upstream is rebuilt under the same id.
+    fn inherit_breaker(&self, prev: &Upstream) {
+        let copy = |to: &AtomicU64, from: &AtomicU64| {
+            to.store(from.load(Ordering::Relaxed), Ordering::Relaxed)
+        };
+        copy(&self.cooldown_until_ms, &prev.cooldown_until_ms);
+        copy(&self.ban_until_ms, &prev.ban_until_ms);
+        copy(&self.last_fail_ms, &prev.last_fail_ms);
+        self.fail_streak
+            .store(prev.fail_streak.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Apply a saved `CooldownRecord`; cooldowns that have expired meanwhile are dropped.
+    fn restore_breaker(&self, r: &CooldownRecord, now_ms: u64) {
+        let live = |until: u64| if until > now_ms { until } else { 0 };
+        self.cooldown_until_ms
+            .store(live(r.cooldown_until_ms.max(r.ban_until_ms)), Ordering::Relaxed);
+        self.ban_until_ms.store(live(r.ban_until_ms), Ordering::Relaxed);
+        self.fail_streak.store(r.fail_streak, Ordering::Relaxed);
+        self.last_fail_ms.store(r.last_fail_ms, Ordering::Relaxed);
+    }
+
+    /// The state `restore_breaker` takes back, if there is any worth saving.
+    fn cooldown_record(&self, now_ms: u64) -> Option<CooldownRecord> {
+        let r = CooldownRecord {
+            cooldown_until_ms: self.cooldown_until_ms.load(Ordering::Relaxed),
+            ban_until_ms: self.ban_until_ms.load(Ordering::Relaxed),
+            fail_streak: self.fail_streak.load(Ordering::Relaxed),
+            last_fail_ms: self.last_fail_ms.load(Ordering::Relaxed),
+        };
+        (r.cooldown_until_ms > now_ms || r.ban_until_ms > now_ms || r.fail_streak > 0).then_some(r)
+    }
+
+    /// Not cooling down, and not half-open with its probe request still outstanding.
+    #[inline]
+    pub fn accepts_requests(&self, now_ms: u64) -> bool {
+        let until = self.cooldown_until_ms.load(Ordering::Relaxed);
+        until <= now_ms && (until == 0 || !self.probe_inflight.load(Ordering::Relaxed))
+    }
+
+    /// Lift the upstream's cooldown (including an error-rate trip or manual ban) and reset its fail streak;
+    /// with `include_keys`, the same for every key. Returns whether the upstream was cooling
+    /// down or had a streak, and how many keys were.
+    pub fn clear_cooldown(&self, include_keys: bool, now_ms: u64) -> (bool, usize) {
+        let cooling = self.cooldown_until_ms.swap(0, Ordering::Relaxed) > now_ms;
+        let upstream_cleared = cooling | (self.fail_streak.swap(0, Ordering::Relaxed) > 0);
+        self.error_window.tripped_until_ms.store(0, Ordering::Relaxed);
+        self.ban_until_ms.store(0, Ordering::Relaxed);
+        let mut keys_cleared = 0;
+        if include_keys {
+            for k in self.keys.load().iter() {
+                let cooling = k.cooldown_until_ms.swap(0, Ordering::Relaxed) > now_ms;
+                if cooling | (k.fail_streak.swap(0, Ordering::Relaxed) > 0) {
+                    keys_cleared += 1;
+                }
+            }
+        }
+        (upstream_cleared, keys_cleared)
+    }
+
+    /// Manual ban: take the upstream out of rotation for `duration_ms` (0 lifts the ban).
+    /// Returns the new `cooldown_until_ms`.
+    pub fn ban(&self, duration_ms: u64, now_ms: u64) -> u64 {
+        let until = if duration_ms == 0 { 0 } else { now_ms.saturating_add(duration_ms) };
+        self.ban_until_ms.store(until, Ordering::Relaxed);
+        self.cooldown_until_ms.store(until, Ordering::Relaxed);
+        until
+    }
+
+    pub fn select_key(&self, mode: KeySelect, now_ms: u64) -> Option<Arc<KeyState>> {
         let keys_arc = self.keys.load_full();
         let keys = keys_arc.as_ref();
         let n = keys.len();
@@ -752,16 +2271,62 @@ impl Upstream {
             return None;
         }
 
-        let start = self.key_rr.fetch_add(1, Ordering::Relaxed);
-        for i in 0..n {
-            let idx = (start + i) % n;
-            let k = &keys[idx];
-            let until = k.cooldown_until_ms.load(Ordering::Relaxed);
-            if until <= now_ms {
-                return Some(k.clone());
+        let first_available = |start: usize| {
+            (0..n)
+                .map(|i| &keys[(start + i) % n])
+                .find(|k| k.cooldown_until_ms.load(Ordering::Relaxed) <= now_ms)
+        };
+        let start = match mode {
+            KeySelect::RoundRobin => self.key_rr.fetch_add(1, Ordering::Relaxed),
+            KeySelect::Random | KeySelect::Remaining => fast_rand() as usize,
+        } % n;
+        let Some(k) = first_available(start) else {
+            self.stats.key_exhausted_total.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        if mode != KeySelect::Remaining || n == 1 {
+            return Some(k.clone());
+        }
+        // Power of two choices: cheap, and unlike always taking the best key it does not send
+        // a burst to one key before its responses lower the estimate.
+        let other = first_available(fast_rand() as usize % n).unwrap_or(k);
+        let best = if other.ratelimit_estimate(now_ms) > k.ratelimit_estimate(now_ms) { other } else { k };
+        Some(best.clone())
+    }
+
+    /// Like `build_uri`, but for Azure upstreams first rewrites the OpenAI `/v1/...` path to the
+    /// deployment of `model` (`/openai/...` when `model` is empty or for `/v1/models`) and sets
+    /// `api-version`. Other upstreams get `build_uri` unchanged.
+    pub fn build_model_uri(&self, path_and_query: &PathAndQuery, model: &str) -> anyhow::Result<Uri> {
+        let Some(azure) = &self.azure else {
+            return self.build_uri(path_and_query);
+        };
+        let pq = path_and_query.as_str();
+        let (path, query) = match pq.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (pq, None),
+        };
+        let rest = path.strip_prefix("/v1/").unwrap_or(path.trim_start_matches('/'));
+        let mut out = if model.is_empty() || rest == "models" {
+            format!("/openai/{rest}")
+        } else {
+            let deployment = azure.deployments.get(model).map_or(model, String::as_str);
+            // Escaping keeps the name inside its segment; dot segments would still climb out.
+            if matches!(deployment, "." | "..") {
+                return Err(InvalidDeployment.into());
+            }
+            format!("/openai/deployments/{}/{rest}", percent_encode_segment(deployment))
+        };
+        out.push('?');
+        for kv in query.into_iter().flat_map(|q| q.split('&')) {
+            if !kv.is_empty() && kv.split('=').next() != Some("api-version") {
+                out.push_str(kv);
+                out.push('&');
             }
         }
-        None
+        out.push_str("api-version=");
+        out.push_str(&azure.api_version);
+        self.build_uri(&out.parse()?)
     }
 
     /// Builds an absolute URI to upstream by combining base scheme+authority and request path/query.
@@ -799,11 +2364,50 @@ impl Upstream {
         }
     }
 
+    /// Scheme used when `auth_scheme` is not configured.
+    fn default_auth_scheme(&self) -> &'static str {
+        if self.auth_header == HDR_AUTHORIZATION {
+            "Bearer"
+        } else {
+            ""
+        }
+    }
+
+    /// Put `key` on an outgoing request using the upstream's auth scheme.
+    pub fn set_auth(&self, headers: &mut hyper::HeaderMap, key: &KeyState) {
+        headers.remove(HDR_AUTHORIZATION);
+        match self.format {
+            UpstreamFormat::OpenAi => {
+                headers.insert(self.auth_header.clone(), key.auth_header.clone());
+            }
+            UpstreamFormat::Anthropic => {
+                if let Ok(v) = hyper::header::HeaderValue::from_str(&key.key) {
+                    headers.insert("x-api-key", v);
+                }
+                headers
+                    .entry("anthropic-version")
+                    .or_insert(hyper::header::HeaderValue::from_static(crate::anthropic::API_VERSION));
+            }
+        }
+    }
+
     pub fn keys_len(&self) -> usize {
         self.keys.load().len()
     }
 }
 
+/// Top-level config keys `RouterState::apply_reload` applies; a reload that changes any other
+/// key is reported as needing a restart.
+pub const HOT_RELOADABLE: &[&str] = &[
+    "request_timeout_ms",
+    "ban",
+    "proxy_tokens",
+    "admin_tokens",
+    "admin_ip_allowlist",
+    "usage_inject_upstreams",
+    "request_rules",
+];
+
 /// Remove hop-by-hop headers that should not be forwarded.
 #[inline]
 pub fn sanitize_hop_headers(headers: &mut hyper::HeaderMap) {
@@ -820,6 +2424,300 @@ pub fn sanitize_hop_headers(headers: &mut hyper::HeaderMap) {
     headers.remove("x-admin-token");
 }
 
+/// One upstream of a `route_candidates` dry run.
+#[derive(Debug, serde::Serialize)]
+pub struct RouteCandidate {
+    pub upstream_id: String,
+    pub tier: u32,
+    pub weight: usize,
+    pub enabled: bool,
+    /// Cooling down, or half-open with its probe request outstanding.
+    pub cooling_down: bool,
+    pub cooldown_until_ms: u64,
+    pub keys_total: usize,
+    /// Keys not cooling down.
+    pub keys_available: usize,
+    /// Enabled, accepting requests and with a key to spare: selection could pick it now.
+    pub eligible: bool,
+}
+
+/// What a selection is restricted to: upstreams serving `model` (any when `None`) whose id is
+/// in `allowed` (any when `None`).
+#[derive(Clone, Copy)]
+struct Selection<'a> {
+    model: Option<&'a str>,
+    allowed: Option<&'a [String]>,
+}
+
+impl Selection<'_> {
+    #[inline]
+    fn permits(&self, u: &Upstream) -> bool {
+        self.allowed.is_none_or(|ids| ids.iter().any(|id| **id == *u.id))
+    }
+}
+
+/// Fixed one-minute windows of request counts per client IP (`anonymous_rpm`). IPv6 clients
+/// are counted per /64, since a single host can rotate through its whole prefix.
+pub struct IpRateLimiter {
+    rpm: u32,
+    windows: Mutex<IpWindows>,
+}
+
+#[derive(Default)]
+struct IpWindows {
+    map: AHashMap<IpAddr, (u64, u32)>,
+    last_prune_ms: u64,
+}
+
+impl IpRateLimiter {
+    const WINDOW_MS: u64 = 60_000;
+    /// Tracked clients at most; when the map is full, clients not already in it are rejected
+    /// until expired windows are pruned.
+    const MAX_TRACKED: usize = 65_536;
+    /// Applied when `anonymous_models` is set but `anonymous_rpm` is not.
+    pub const DEFAULT_RPM: u32 = 60;
+
+    pub fn new(rpm: u32) -> Self {
+        Self {
+            rpm,
+            windows: Mutex::new(IpWindows::default()),
+        }
+    }
+
+    /// Count a request from `ip`. `Err` carries the ms until it may retry.
+    pub fn check(&self, ip: &str, now_ms: u64) -> Result<(), u64> {
+        let bucket = match ip.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+            Ok(IpAddr::V6(v6)) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+            Ok(v4) => v4,
+            // Not expected from a socket address; such clients share one bucket.
+            Err(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let mut w = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        // Drop expired windows at most once per window length, not on every request.
+        if now_ms >= w.last_prune_ms + Self::WINDOW_MS {
+            w.last_prune_ms = now_ms;
+            w.map.retain(|_, (start, _)| now_ms < *start + Self::WINDOW_MS);
+        }
+        let full = w.map.len() >= Self::MAX_TRACKED;
+        let (start, count) = match w.map.entry(bucket) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(_) if full => {
+                return Err((w.last_prune_ms + Self::WINDOW_MS).saturating_sub(now_ms).max(1));
+            }
+            std::collections::hash_map::Entry::Vacant(e) => e.insert((now_ms, 0)),
+        };
+        if now_ms >= *start + Self::WINDOW_MS {
+            *start = now_ms;
+            *count = 0;
+        }
+        if *count >= self.rpm {
+            return Err(*start + Self::WINDOW_MS - now_ms);
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// `upstreams[].model_allow` / `model_deny`: which of an upstream's models may be routed to it.
+/// Patterns support `*` wildcards; deny wins, and an empty allow list allows everything.
+#[derive(Default)]
+pub struct ModelFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl ModelFilter {
+    pub fn new(allow: Option<Vec<String>>, deny: Option<Vec<String>>) -> Self {
+        let clean = |v: Option<Vec<String>>| -> Vec<String> {
+            v.unwrap_or_default()
+                .into_iter()
+                .map(|p| p.trim().to_string())
+                .filter(|p| !p.is_empty())
+                .collect()
+        };
+        Self { allow: clean(allow), deny: clean(deny) }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, model: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, model)))
+            && !self.deny.iter().any(|p| glob_match(p, model))
+    }
+
+    pub fn apply(&self, mut models: AHashSet<String>) -> AHashSet<String> {
+        if !self.is_empty() {
+            models.retain(|m| self.permits(m));
+        }
+        models
+    }
+}
+
+/// `request_rules`: declarative edits of JSON request bodies, matched by model and upstream.
+#[derive(Default, PartialEq)]
+pub struct RequestRules {
+    rules: Vec<RequestRule>,
+}
+
+impl RequestRules {
+    pub fn new(rules: Vec<RequestRule>) -> Self {
+        Self { rules }
+    }
+
+    /// A copy of `body` with every rule matching `model` and `upstream` applied in order, or
+    /// `None` when no rule matches or the body isn't a JSON object (forward it unchanged).
+    pub fn apply(
+        &self,
+        body: Option<&serde_json::Value>,
+        model: &str,
+        upstream: &str,
+    ) -> Option<serde_json::Value> {
+        let any = |pats: &Option<Vec<String>>, s: &str| {
+            pats.as_ref().is_none_or(|p| p.iter().any(|p| glob_match(p, s)))
+        };
+        let matches = |r: &&RequestRule| any(&r.models, model) && any(&r.upstreams, upstream);
+        if !self.rules.iter().any(|r| matches(&r)) {
+            return None;
+        }
+        let mut body = body.filter(|v| v.is_object())?.clone();
+        let obj = body.as_object_mut()?;
+        for rule in self.rules.iter().filter(matches) {
+            for (from, to) in rule.rename.iter().flatten() {
+                if let Some(v) = obj.remove(from) {
+                    obj.insert(to.clone(), v);
+                }
+            }
+            for field in rule.remove.iter().flatten() {
+                obj.remove(field);
+            }
+            for (field, v) in rule.defaults.iter().flatten() {
+                obj.entry(field.clone()).or_insert_with(|| v.clone());
+            }
+            for (field, v) in rule.set.iter().flatten() {
+                obj.insert(field.clone(), v.clone());
+            }
+        }
+        Some(body)
+    }
+}
+
+/// Match `s` against `pattern`, where `*` matches any run of characters (including none).
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    // Position of the last `*` seen and the input position it is currently matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if pi < p.len() && p[pi] == s[si] {
+            pi += 1;
+            si += 1;
+        } else if let Some((sp, ss)) = star {
+            // Let the last `*` swallow one more character and retry.
+            pi = sp + 1;
+            si = ss + 1;
+            star = Some((sp, ss + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+/// CORS for the proxy endpoints (`proxy_cors_origins`).
+pub struct ProxyCors {
+    any_origin: bool,
+    /// Lowercased, without a trailing `/` (see `Config::normalize`).
+    origins: AHashSet<String>,
+    /// `Access-Control-Allow-Headers`: the usual request headers plus `client_key_headers`.
+    allow_headers: HeaderValue,
+}
+
+impl ProxyCors {
+    const ALLOW_METHODS: HeaderValue = HeaderValue::from_static("GET, POST, OPTIONS");
+    const EXPOSE_HEADERS: HeaderValue = HeaderValue::from_static("x-request-id, retry-after");
+    const MAX_AGE: HeaderValue = HeaderValue::from_static("600");
+
+    pub fn new(origins: Vec<String>, key_headers: &[HeaderName]) -> Self {
+        let mut headers: Vec<&str> = vec!["content-type", "x-proxy-token", "x-request-id"];
+        for h in key_headers {
+            if !headers.contains(&h.as_str()) {
+                headers.push(h.as_str());
+            }
+        }
+        Self {
+            any_origin: origins.iter().any(|o| o == "*"),
+            origins: origins.into_iter().filter(|o| o != "*").collect(),
+            allow_headers: HeaderValue::from_str(&headers.join(", "))
+                .unwrap_or(HeaderValue::from_static("*")),
+        }
+    }
+
+    /// The request's `Origin` if it may make cross-origin calls.
+    pub fn allowed_origin(&self, headers: &hyper::HeaderMap) -> Option<HeaderValue> {
+        let origin = headers.get(hyper::header::ORIGIN)?;
+        let allowed = self.any_origin
+            || origin
+                .to_str()
+                .is_ok_and(|o| self.origins.contains(&o.trim_end_matches('/').to_ascii_lowercase()));
+        allowed.then(|| origin.clone())
+    }
+
+    /// Answer a preflight from an allowed origin.
+    pub fn preflight(&self, origin: HeaderValue) -> Response<Body> {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = http::StatusCode::NO_CONTENT;
+        let h = resp.headers_mut();
+        h.insert(hyper::header::ACCESS_CONTROL_ALLOW_METHODS, Self::ALLOW_METHODS);
+        h.insert(hyper::header::ACCESS_CONTROL_ALLOW_HEADERS, self.allow_headers.clone());
+        h.insert(hyper::header::ACCESS_CONTROL_MAX_AGE, Self::MAX_AGE);
+        Self::apply(h, origin);
+        resp
+    }
+
+    /// Mark a response as readable by `origin` (replacing any CORS headers from upstream).
+    pub fn apply(headers: &mut hyper::HeaderMap, origin: HeaderValue) {
+        headers.insert(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(hyper::header::ACCESS_CONTROL_EXPOSE_HEADERS, Self::EXPOSE_HEADERS);
+        headers.append(hyper::header::VARY, HeaderValue::from_static("origin"));
+    }
+}
+
+/// Operator-configured request headers to drop (`strip_request_headers`).
+pub struct HeaderDenylist {
+    exact: AHashSet<String>,
+    prefixes: Vec<String>,
+}
+
+impl HeaderDenylist {
+    /// Entries are expected lowercased (see `Config::normalize`).
+    pub fn new(entries: Vec<String>) -> Self {
+        let mut exact = AHashSet::new();
+        let mut prefixes = Vec::new();
+        for e in entries {
+            match e.strip_suffix('*') {
+                Some(p) => prefixes.push(p.to_string()),
+                None => {
+                    exact.insert(e);
+                }
+            }
+        }
+        Self { exact, prefixes }
+    }
+
+    #[inline]
+    pub fn matches(&self, name: &HeaderName) -> bool {
+        // HeaderName is always lowercase.
+        let n = name.as_str();
+        self.exact.contains(n) || self.prefixes.iter().any(|p| n.starts_with(p.as_str()))
+    }
+}
+
 #[inline]
 fn inc_status(stats: &UpstreamStats, status: http::StatusCode) {
     if status.is_success() {
@@ -833,8 +2731,20 @@ fn inc_status(stats: &UpstreamStats, status: http::StatusCode) {
     }
 }
 
+/// Key header used when `auth_header` is not configured: `api-key` for Azure, else `Authorization`.
+fn default_auth_header(azure: bool) -> HeaderName {
+    if azure {
+        HeaderName::from_static("api-key")
+    } else {
+        HDR_AUTHORIZATION
+    }
+}
+
 fn parse_upstream(u: UpstreamConfig, weight: usize) -> anyhow::Result<Arc<Upstream>> {
     let name_for_err = u.id.clone();
+    if u.timeout_ms == Some(0) {
+        anyhow::bail!("upstream {}: timeout_ms must be > 0", name_for_err);
+    }
 
     let base: Uri = u.base_url.parse()?;
 
@@ -849,6 +2759,22 @@ fn parse_upstream(u: UpstreamConfig, weight: usize) -> anyhow::Result<Arc<Upstre
     let base_path = base.path().trim_end_matches('/').to_string();
     let base_path = if base_path == "/" { String::new() } else { base_path };
 
+    crate::config::validate_upstream_options(&u)
+        .map_err(|e| anyhow::anyhow!("upstream {}: {}", name_for_err, e))?;
+    let azure = u.azure_api_version.as_deref().map(|v| AzureRouting {
+        api_version: Arc::from(v),
+        deployments: u.azure_deployments.clone().unwrap_or_default().into_iter().collect(),
+    });
+    let auth_header = match u.auth_header.as_deref().map(str::trim) {
+        Some(h) => HeaderName::from_bytes(h.as_bytes())?,
+        None => default_auth_header(azure.is_some()),
+    };
+    let auth_scheme = match u.auth_scheme.as_deref() {
+        Some(s) => s,
+        None if auth_header == HDR_AUTHORIZATION => "Bearer",
+        None => "",
+    };
+
     let upstream = Upstream {
         id: Arc::<str>::from(u.id),
         base_url: Arc::<str>::from(u.base_url.clone()),
@@ -856,39 +2782,225 @@ fn parse_upstream(u: UpstreamConfig, weight: usize) -> anyhow::Result<Arc<Upstre
         base_authority: authority,
         base_path: Arc::<str>::from(base_path),
         weight,
+        rr_current: AtomicI64::new(0),
+        enabled: AtomicBool::new(u.enabled.unwrap_or(true)),
+        timeout: u.timeout_ms.map(Duration::from_millis),
+        format: u.format.unwrap_or_default(),
+        group: u
+            .group
+            .as_deref()
+            .map(str::trim)
+            .filter(|g| !g.is_empty())
+            .map(Arc::from),
+        tier: u.tier,
+        tags: {
+            let mut tags: Vec<String> = u
+                .tags
+                .unwrap_or_default()
+                .iter()
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+            tags.sort();
+            tags.dedup();
+            Arc::from(tags)
+        },
+        auth_header,
+        auth_scheme: Arc::from(auth_scheme),
+        host_header: match u.host_header.as_deref().map(str::trim) {
+            Some(h) => Some(hyper::header::HeaderValue::from_str(h)?),
+            None => None,
+        },
+        azure,
         keys: ArcSwap::from_pointee(Vec::new()),
         key_rr: AtomicUsize::new(0),
+        model_filter: ModelFilter::new(u.model_allow, u.model_deny),
+        sse_usage_tail_only: u.sse_usage_tail_only.unwrap_or(false),
         models: ArcSwap::from_pointee(AHashSet::new()),
         cooldown_until_ms: AtomicU64::new(0),
         fail_streak: AtomicU32::new(0),
+        last_fail_ms: AtomicU64::new(0),
+        ban_until_ms: AtomicU64::new(0),
+        probe_inflight: AtomicBool::new(false),
+        inflight: AtomicU64::new(0),
+        latency_ewma_us: AtomicU64::new(0),
+        error_window: ErrorWindow::default(),
         stats: UpstreamStats::default(),
     };
 
     Ok(Arc::new(upstream))
 }
 
-pub fn build_key_states(keys: Vec<String>) -> anyhow::Result<Arc<Vec<Arc<KeyState>>>> {
+/// Trimmed, non-empty token/id set; `None` when nothing is left (feature off).
+fn build_token_set(list: Option<Vec<String>>) -> Option<Arc<AHashSet<String>>> {
+    let v = list?;
+    let mut set = AHashSet::with_capacity(v.len().max(1));
+    for t in v {
+        let t = t.trim();
+        if !t.is_empty() {
+            set.insert(t.to_string());
+        }
+    }
+    if set.is_empty() {
+        None
+    } else {
+        Some(Arc::new(set))
+    }
+}
+
+/// Entries were validated with the config; anything unparsable is skipped.
+fn build_ip_allowlist(list: Option<&[String]>) -> Vec<IpNet> {
+    list.unwrap_or_default()
+        .iter()
+        .filter_map(|s| IpNet::parse(s).ok())
+        .collect()
+}
+
+fn build_admin_set(list: Vec<String>) -> AHashSet<String> {
+    let mut admin_set = AHashSet::with_capacity(list.len().max(1));
+    for t in list {
+        if !t.is_empty() {
+            admin_set.insert(t);
+        }
+    }
+    admin_set
+}
+
+fn build_error_rate_policy(ban: &BanConfig) -> Option<ErrorRatePolicy> {
+    ban.error_rate.as_ref().map(|er| {
+        let window_ms = er.window_ms.unwrap_or(60_000).max(1);
+        ErrorRatePolicy {
+            slot_ms: window_ms.div_ceil(ERROR_WINDOW_SLOTS as u64).max(1),
+            min_samples: er.min_samples.unwrap_or(20).max(1),
+            threshold: er.threshold.unwrap_or(0.5),
+            cooldown_ms: er.cooldown_ms.unwrap_or(ban.server_error_ms),
+        }
+    })
+}
+
+/// FNV-1a, used where hashes must be stable across restarts (sticky routing).
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+/// SplitMix64 finalizer.
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Changes for `update_upstream`. Optional fields left `None` keep the current value
+/// (an empty `group`/`tags` clears it).
+pub struct UpstreamUpdate {
+    pub base_url: String,
+    pub weight: Option<usize>,
+    /// `Some(None)` or `Some(Some(0))` clears the override.
+    pub timeout_ms: Option<Option<u64>>,
+    pub format: Option<UpstreamFormat>,
+    pub group: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// `Some(None)` clears the upstream's own tier, falling back to its group's.
+    pub tier: Option<Option<u32>>,
+    pub model_allow: Option<Vec<String>>,
+    pub model_deny: Option<Vec<String>>,
+    pub sse_usage_tail_only: Option<bool>,
+    pub auth_header: Option<String>,
+    pub auth_scheme: Option<String>,
+    pub host_header: Option<String>,
+    pub azure_api_version: Option<String>,
+    pub azure_deployments: Option<BTreeMap<String, String>>,
+}
+
+/// Cooldown requested by a `Retry-After` header (delay seconds or HTTP-date), clamped to
+/// 1s..=`max_ms`. None when absent, unparseable, or `max_ms` is 0.
+fn retry_after_ms(headers: &hyper::HeaderMap, max_ms: u64, now_ms: u64) -> Option<u64> {
+    if max_ms == 0 {
+        return None;
+    }
+    let v = headers.get(hyper::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    let ms = match v.parse::<u64>() {
+        Ok(secs) => secs.saturating_mul(1000),
+        Err(_) => {
+            let at = httpdate::parse_http_date(v).ok()?;
+            let at_ms = at.duration_since(std::time::UNIX_EPOCH).ok()?.as_millis() as u64;
+            at_ms.saturating_sub(now_ms)
+        }
+    };
+    Some(ms.clamp(1000, max_ms.max(1000)))
+}
+
+/// Build key states whose precomputed header value is `"<auth_scheme> <key>"` (or the bare key
+/// when `auth_scheme` is empty).
+pub fn build_key_states(
+    keys: Vec<(String, KeyMeta)>,
+    auth_scheme: &str,
+) -> anyhow::Result<Arc<Vec<Arc<KeyState>>>> {
     let mut out: Vec<Arc<KeyState>> = Vec::with_capacity(keys.len());
-    for k in keys {
+    for (k, meta) in keys {
         let k = k.trim();
         if k.is_empty() {
             continue;
         }
         let key_arc: Arc<str> = Arc::<str>::from(k.to_string());
-        let auth_header =
-            hyper::header::HeaderValue::from_str(&format!("Bearer {}", key_arc)).map_err(|_| {
-                anyhow::anyhow!("invalid key (cannot be used in HTTP header)")
-            })?;
+        let value = if auth_scheme.is_empty() {
+            key_arc.to_string()
+        } else {
+            format!("{auth_scheme} {key_arc}")
+        };
+        let auth_header = hyper::header::HeaderValue::from_str(&value)
+            .map_err(|_| anyhow::anyhow!("invalid key (cannot be used in HTTP header)"))?;
         out.push(Arc::new(KeyState {
             key: key_arc,
             auth_header,
             cooldown_until_ms: AtomicU64::new(0),
             fail_streak: AtomicU32::new(0),
+            last_fail_ms: AtomicU64::new(0),
+            requests_total: AtomicU64::new(0),
+            responses_2xx: AtomicU64::new(0),
+            responses_4xx: AtomicU64::new(0),
+            responses_5xx: AtomicU64::new(0),
+            last_used_ms: AtomicU64::new(0),
+            ratelimit_remaining: AtomicU64::new(0),
+            ratelimit_limit: AtomicU64::new(0),
+            ratelimit_seen_ms: AtomicU64::new(0),
+            added_at_ms: meta.added_at_ms,
+            label: meta.label.map(Arc::from),
         }));
     }
     Ok(Arc::new(out))
 }
 
+/// `CooldownRecord` id of an upstream (`key` None) or of one of its keys.
+fn cooldown_record_id(upstream_id: &str, key: Option<&str>) -> String {
+    match key {
+        Some(k) => format!("{upstream_id}\0{k}"),
+        None => upstream_id.to_string(),
+    }
+}
+
+/// Carry cooldowns and fail streaks over from `previous` to the same keys in `keys`, so a reload
+/// doesn't put banned keys back into rotation. Keys not in `previous` start fresh.
+pub fn inherit_key_breakers(keys: &[Arc<KeyState>], previous: &[Arc<KeyState>]) {
+    if previous.is_empty() {
+        return;
+    }
+    let by_key: AHashMap<&str, &KeyState> = previous.iter().map(|k| (&*k.key, &**k)).collect();
+    for k in keys {
+        if let Some(prev) = by_key.get(&*k.key) {
+            k.cooldown_until_ms
+                .store(prev.cooldown_until_ms.load(Ordering::Relaxed), Ordering::Relaxed);
+            k.fail_streak.store(prev.fail_streak.load(Ordering::Relaxed), Ordering::Relaxed);
+            k.last_fail_ms.store(prev.last_fail_ms.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+}
+
 fn parse_models_response(body: &[u8]) -> anyhow::Result<AHashSet<String>> {
     let v: serde_json::Value = serde_json::from_slice(body)?;
     let data = v
@@ -912,17 +3024,105 @@ fn load_upstreams_override(path: &Path) -> anyhow::Result<Vec<UpstreamConfig>> {
 }
 
 fn write_upstreams_override(path: &Path, upstreams: &[UpstreamConfig]) -> anyhow::Result<()> {
-    let s = serde_json::to_string_pretty(upstreams)?;
-    std::fs::write(path, s)?;
-    Ok(())
+    write_json_atomic(path, &upstreams)
 }
 
 fn write_model_routes(path: &Path, routes: &ModelRoutesFile) -> anyhow::Result<()> {
-    let s = serde_json::to_string_pretty(routes)?;
-    std::fs::write(path, s)?;
+    write_json_atomic(path, routes)
+}
+
+/// Write via a synced temp file and rename, so a crash never leaves a half-written file.
+fn write_json_atomic<T: serde::Serialize + ?Sized>(path: &Path, value: &T) -> anyhow::Result<()> {
+    let s = serde_json::to_string_pretty(value)?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    {
+        let mut f = std::fs::File::create(&tmp)?;
+        std::io::Write::write_all(&mut f, s.as_bytes())?;
+        f.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)?;
     Ok(())
 }
 
+/// Load a data_dir JSON file at startup. A missing file is `None`; one that exists but can't
+/// be parsed is renamed to `<name>.corrupt.<ts_ms>` (kept for inspection) and also `None`,
+/// so the caller falls back to the config file.
+fn load_json_or_quarantine<T>(
+    path: &Path,
+    load: fn(&Path) -> anyhow::Result<T>,
+    read_only: bool,
+) -> Option<T> {
+    if !path.exists() {
+        return None;
+    }
+    match load(path) {
+        Ok(v) => Some(v),
+        Err(e) if read_only => {
+            tracing::error!(
+                path = %path.display(),
+                error = %e,
+                "corrupt data file left in place (read_only); its contents were NOT loaded"
+            );
+            None
+        }
+        Err(e) => {
+            let mut dest = path.as_os_str().to_owned();
+            dest.push(format!(".corrupt.{}", now_ms()));
+            let dest = PathBuf::from(dest);
+            match std::fs::rename(path, &dest) {
+                Ok(()) => tracing::error!(
+                    path = %path.display(),
+                    moved_to = %dest.display(),
+                    error = %e,
+                    "corrupt data file quarantined; its contents were NOT loaded"
+                ),
+                Err(re) => tracing::error!(
+                    path = %path.display(),
+                    error = %e,
+                    rename_error = %re,
+                    "corrupt data file could not be quarantined; its contents were NOT loaded"
+                ),
+            }
+            None
+        }
+    }
+}
+
+/// A model that cannot name an Azure deployment (`.` or `..`); the client's fault, not ours.
+#[derive(Debug)]
+pub struct InvalidDeployment;
+
+impl std::fmt::Display for InvalidDeployment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("model is not a valid deployment name")
+    }
+}
+
+impl std::error::Error for InvalidDeployment {}
+
+/// An `If-Match` version that no longer matches: someone else changed the resource first.
+#[derive(Debug)]
+pub struct VersionConflict {
+    pub current: u64,
+}
+
+impl std::fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "modified concurrently; current version is {}", self.current)
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+fn check_version(expected: Option<u64>, current: u64) -> anyhow::Result<()> {
+    match expected {
+        Some(v) if v != current => Err(VersionConflict { current }.into()),
+        _ => Ok(()),
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct ModelRoutesFile {
     pub updated_at_ms: u64,
@@ -930,6 +3130,27 @@ pub struct ModelRoutesFile {
     pub upstreams: BTreeMap<String, Vec<String>>,
 }
 
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ModelAliasesFile {
+    pub updated_at_ms: u64,
+    pub aliases: BTreeMap<String, String>,
+}
+
+fn load_model_aliases(path: &Path) -> anyhow::Result<ModelAliasesFile> {
+    let s = std::fs::read_to_string(path)?;
+    let file: ModelAliasesFile = serde_json::from_str(&s)?;
+    Ok(file)
+}
+
+/// Trimmed alias map without empty or self-referencing entries.
+fn clean_model_aliases(aliases: BTreeMap<String, String>) -> AHashMap<String, String> {
+    aliases
+        .into_iter()
+        .map(|(alias, model)| (alias.trim().to_string(), model.trim().to_string()))
+        .filter(|(alias, model)| !alias.is_empty() && !model.is_empty() && alias != model)
+        .collect()
+}
+
 fn load_model_routes(path: &Path) -> anyhow::Result<ModelRoutesFile> {
     let s = std::fs::read_to_string(path)?;
     let routes: ModelRoutesFile = serde_json::from_str(&s)?;
@@ -949,6 +3170,33 @@ fn routes_has_upstream(routes: &ModelRoutesFile, upstream_id: &str) -> bool {
 }
 
 impl RouterState {
+    /// Upstream model name for a client-facing alias.
+    pub fn resolve_model_alias(&self, model: &str) -> Option<String> {
+        self.model_aliases.load().get(model).cloned()
+    }
+
+    pub fn get_model_aliases(&self) -> ModelAliasesFile {
+        ModelAliasesFile {
+            updated_at_ms: now_ms(),
+            aliases: self.model_aliases.load().iter().map(|(a, m)| (a.clone(), m.clone())).collect(),
+        }
+    }
+
+    /// Replace all aliases and persist them to `model_aliases.json`.
+    pub fn save_model_aliases(
+        &self,
+        aliases: BTreeMap<String, String>,
+    ) -> anyhow::Result<ModelAliasesFile> {
+        let cleaned = clean_model_aliases(aliases);
+        let file = ModelAliasesFile {
+            updated_at_ms: now_ms(),
+            aliases: cleaned.iter().map(|(a, m)| (a.clone(), m.clone())).collect(),
+        };
+        write_json_atomic(&self.model_aliases_path, &file)?;
+        self.model_aliases.store(Arc::new(cleaned));
+        Ok(file)
+    }
+
     pub fn get_model_routes(&self) -> ModelRoutesFile {
         match load_model_routes(&self.model_routes_path) {
             Ok(routes) => routes,
@@ -956,10 +3204,21 @@ impl RouterState {
         }
     }
 
+    /// `updated_at_ms` of the saved routes file (0 before the first save); the routes ETag.
+    pub fn model_routes_version(&self) -> u64 {
+        load_model_routes(&self.model_routes_path).map_or(0, |r| r.updated_at_ms)
+    }
+
+    /// Replace the routes. With `expected` (from `If-Match`), fails with `VersionConflict`
+    /// unless it equals `model_routes_version()`.
     pub fn save_model_routes(
         &self,
         upstreams: BTreeMap<String, Vec<String>>,
+        expected: Option<u64>,
     ) -> anyhow::Result<ModelRoutesFile> {
+        let _edit = self.edit_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let current = self.model_routes_version();
+        check_version(expected, current)?;
         let snap = self.snapshot.load_full();
         for id in upstreams.keys() {
             if !snap.upstream_index.contains_key(id) {
@@ -993,7 +3252,8 @@ impl RouterState {
         }
 
         let routes = ModelRoutesFile {
-            updated_at_ms: now_ms(),
+            // Strictly increasing, so two saves within a millisecond get distinct versions.
+            updated_at_ms: now_ms().max(current + 1),
             models,
             upstreams: upstreams_clean,
         };
@@ -1003,7 +3263,9 @@ impl RouterState {
         Ok(routes)
     }
 
-    pub fn add_upstream(&self, cfg: UpstreamConfig) -> anyhow::Result<()> {
+    pub fn add_upstream(&self, cfg: UpstreamConfig, expected: Option<u64>) -> anyhow::Result<()> {
+        let _edit = self.edit_lock.lock().unwrap_or_else(|e| e.into_inner());
+        check_version(expected, self.upstreams_version.load(Ordering::Relaxed))?;
         let mut list = self.current_upstream_configs();
         if list.iter().any(|u| u.id == cfg.id) {
             anyhow::bail!("upstream id already exists");
@@ -1013,13 +3275,62 @@ impl RouterState {
         Ok(())
     }
 
-    pub fn update_upstream(&self, id: &str, base_url: String, weight: Option<usize>) -> anyhow::Result<()> {
+    pub fn update_upstream(
+        &self,
+        id: &str,
+        update: UpstreamUpdate,
+        expected: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let _edit = self.edit_lock.lock().unwrap_or_else(|e| e.into_inner());
+        check_version(expected, self.upstreams_version.load(Ordering::Relaxed))?;
         let mut list = self.current_upstream_configs();
         let mut found = false;
         for u in list.iter_mut() {
             if u.id == id {
-                u.base_url = base_url.clone();
-                u.weight = weight;
+                u.base_url = update.base_url.clone();
+                u.weight = update.weight;
+                if let Some(t) = update.timeout_ms {
+                    u.timeout_ms = t.filter(|t| *t > 0);
+                }
+                if update.format.is_some() {
+                    u.format = update.format;
+                }
+                if update.group.is_some() {
+                    u.group = update.group.clone();
+                }
+                if update.tags.is_some() {
+                    u.tags = update.tags.clone();
+                }
+                if let Some(t) = update.tier {
+                    u.tier = t;
+                }
+                if update.model_allow.is_some() {
+                    u.model_allow = update.model_allow.clone().filter(|v| !v.is_empty());
+                }
+                if update.model_deny.is_some() {
+                    u.model_deny = update.model_deny.clone().filter(|v| !v.is_empty());
+                }
+                if update.sse_usage_tail_only.is_some() {
+                    u.sse_usage_tail_only = update.sse_usage_tail_only.filter(|v| *v);
+                }
+                if update.auth_header.is_some() {
+                    u.auth_header = update.auth_header.clone().filter(|h| !h.is_empty());
+                }
+                if update.auth_scheme.is_some() {
+                    u.auth_scheme = update.auth_scheme.clone();
+                }
+                if update.host_header.is_some() {
+                    u.host_header = update.host_header.clone().filter(|h| !h.is_empty());
+                }
+                if update.azure_api_version.is_some() {
+                    u.azure_api_version = update.azure_api_version.clone().filter(|v| !v.is_empty());
+                    if u.azure_api_version.is_none() {
+                        u.azure_deployments = None;
+                    }
+                }
+                if update.azure_deployments.is_some() {
+                    u.azure_deployments = update.azure_deployments.clone().filter(|m| !m.is_empty());
+                }
                 found = true;
                 break;
             }
@@ -1031,7 +3342,42 @@ impl RouterState {
         Ok(())
     }
 
-    pub fn delete_upstream(&self, id: &str, delete_keys: bool) -> anyhow::Result<()> {
+    /// Take an upstream out of rotation or put it back, in place: unlike `update_upstream` the
+    /// snapshot is not rebuilt, so its keys, models, cooldowns and stats carry on. Returns
+    /// whether the state changed; the change is saved to `upstreams.json` either way.
+    pub fn set_upstream_enabled(
+        &self,
+        id: &str,
+        enabled: bool,
+        expected: Option<u64>,
+    ) -> anyhow::Result<bool> {
+        let _edit = self.edit_lock.lock().unwrap_or_else(|e| e.into_inner());
+        check_version(expected, self.upstreams_version.load(Ordering::Relaxed))?;
+        let Some((_, u)) = self.upstream_by_id(id) else {
+            anyhow::bail!("unknown upstream id");
+        };
+        let previous = u.enabled.swap(enabled, Ordering::Relaxed);
+        // The override file is built from live state, so flip first and undo if the write fails.
+        if let Err(e) = write_upstreams_override(&self.upstreams_path, &self.current_upstream_configs()) {
+            u.enabled.store(previous, Ordering::Relaxed);
+            return Err(e);
+        }
+        let changed = previous != enabled;
+        if changed {
+            let v = self.upstreams_version.load(Ordering::Relaxed);
+            self.upstreams_version.store(now_ms().max(v + 1), Ordering::Relaxed);
+        }
+        Ok(changed)
+    }
+
+    pub fn delete_upstream(
+        &self,
+        id: &str,
+        delete_keys: bool,
+        expected: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let _edit = self.edit_lock.lock().unwrap_or_else(|e| e.into_inner());
+        check_version(expected, self.upstreams_version.load(Ordering::Relaxed))?;
         let mut list = self.current_upstream_configs();
         let before = list.len();
         list.retain(|u| u.id != id);
@@ -1041,12 +3387,13 @@ impl RouterState {
         self.replace_upstreams(list)?;
         if delete_keys {
             let empty: Vec<String> = Vec::new();
-            self.store.replace_keys(id, &empty)?;
+            self.store.replace_keys(id, &empty, None)?;
         }
         Ok(())
     }
 
-    fn build_model_routes(&self) -> ModelRoutesFile {
+    /// Routes as currently applied in memory (saved routes after each upstream's model filter).
+    pub fn build_model_routes(&self) -> ModelRoutesFile {
         let mut models: BTreeMap<String, Vec<String>> = BTreeMap::new();
         let mut upstreams: BTreeMap<String, Vec<String>> = BTreeMap::new();
 
@@ -1079,7 +3426,7 @@ impl RouterState {
     }
 
     fn persist_model_routes(&self) -> anyhow::Result<()> {
-        if !self.any_models_loaded() {
+        if self.read_only || !self.any_models_loaded() {
             return Ok(());
         }
         let routes = self.build_model_routes();
@@ -1088,12 +3435,19 @@ impl RouterState {
     }
 
     fn replace_upstreams(&self, configs: Vec<UpstreamConfig>) -> anyhow::Result<()> {
-        let snapshot = build_snapshot_from_configs(&configs, &self.store)?;
+        let snapshot = build_snapshot_from_configs(
+            &configs,
+            &self.store,
+            &self.group_tiers,
+            Some(&self.snapshot.load()),
+        )?;
         if let Ok(routes) = load_model_routes(&self.model_routes_path) {
             apply_routes_to_upstreams(&routes, &snapshot.upstreams, &snapshot.upstream_index);
         }
-        self.snapshot.store(Arc::new(snapshot));
         write_upstreams_override(&self.upstreams_path, &configs)?;
+        self.snapshot.store(Arc::new(snapshot));
+        let v = self.upstreams_version.load(Ordering::Relaxed);
+        self.upstreams_version.store(now_ms().max(v + 1), Ordering::Relaxed);
         self.cleanup_model_routes()?;
         Ok(())
     }
@@ -1106,6 +3460,26 @@ impl RouterState {
                 id: u.id.to_string(),
                 base_url: u.base_url.to_string(),
                 weight: Some(u.weight),
+                enabled: (!u.enabled.load(Ordering::Relaxed)).then_some(false),
+                timeout_ms: u.timeout.map(|t| t.as_millis() as u64),
+                format: Some(u.format),
+                group: u.group.as_deref().map(str::to_string),
+                tags: (!u.tags.is_empty()).then(|| u.tags.to_vec()),
+                tier: u.tier,
+                model_allow: (!u.model_filter.allow.is_empty()).then(|| u.model_filter.allow.clone()),
+                model_deny: (!u.model_filter.deny.is_empty()).then(|| u.model_filter.deny.clone()),
+                sse_usage_tail_only: u.sse_usage_tail_only.then_some(true),
+                auth_header: (u.auth_header != default_auth_header(u.azure.is_some()))
+                    .then(|| u.auth_header.as_str().to_string()),
+                auth_scheme: (u.auth_scheme.as_ref() != u.default_auth_scheme())
+                    .then(|| u.auth_scheme.to_string()),
+                host_header: u.host_header.as_ref().and_then(|h| h.to_str().ok()).map(str::to_string),
+                azure_api_version: u.azure.as_ref().map(|a| a.api_version.to_string()),
+                azure_deployments: u
+                    .azure
+                    .as_ref()
+                    .filter(|a| !a.deployments.is_empty())
+                    .map(|a| a.deployments.clone().into_iter().collect()),
             })
             .collect()
     }
@@ -1143,6 +3517,78 @@ impl RouterState {
         Ok(())
     }
 
+    /// Periodic `model_refresh_interval_s` sweep: re-fetch each upstream's models one at a time,
+    /// spread over half the interval, skipping upstreams in cooldown or without keys. An empty
+    /// answer keeps the current set (more likely an upstream glitch than every model gone).
+    async fn refresh_models_periodic(&self, interval: Duration) {
+        const MAX_STAGGER: Duration = Duration::from_secs(10);
+        let snap = self.snapshot.load_full();
+        let stagger = (interval / 2 / snap.upstreams.len().max(1) as u32).min(MAX_STAGGER);
+        let mut changed = false;
+        for (i, u) in snap.upstreams.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(stagger).await;
+            }
+            if u.cooldown_until_ms.load(Ordering::Relaxed) > now_ms() || u.keys.load().is_empty() {
+                continue;
+            }
+            let models = match self.fetch_models_for_upstream(u.clone()).await {
+                Ok(m) => m,
+                Err(e) => {
+                    tracing::warn!(upstream = %u.id, error = %e, "periodic model refresh failed");
+                    continue;
+                }
+            };
+            let old = u.models.load_full();
+            if models.is_empty() && !old.is_empty() {
+                tracing::warn!(upstream = %u.id, "periodic model refresh returned no models; keeping current set");
+                continue;
+            }
+            let mut added: Vec<&str> = models.iter().filter(|m| !old.contains(*m)).map(String::as_str).collect();
+            let mut removed: Vec<&str> = old.iter().filter(|m| !models.contains(*m)).map(String::as_str).collect();
+            if added.is_empty() && removed.is_empty() {
+                continue;
+            }
+            added.sort_unstable();
+            removed.sort_unstable();
+            tracing::info!(upstream = %u.id, ?added, ?removed, "upstream models changed");
+            u.models.store(Arc::new(models));
+            changed = true;
+        }
+        if changed {
+            if let Err(e) = self.persist_model_routes() {
+                tracing::warn!(error = %e, "model routes persist failed");
+            }
+        }
+    }
+
+    /// Probe upstreams in cooldown with `GET /v1/models`; clear the cooldown of any that answer.
+    async fn probe_cooled_upstreams(self: &Arc<Self>) {
+        let now = now_ms();
+        let snap = self.snapshot.load_full();
+        let mut probes = tokio::task::JoinSet::new();
+        for u in snap.upstreams.iter() {
+            // Manually banned upstreams stay out until the ban ends.
+            if u.cooldown_until_ms.load(Ordering::Relaxed) <= now
+                || u.ban_until_ms.load(Ordering::Relaxed) > now
+                || u.keys.load().is_empty()
+            {
+                continue;
+            }
+            let state = self.clone();
+            let u = u.clone();
+            probes.spawn(async move {
+                if state.fetch_models_for_upstream(u.clone()).await.is_ok() {
+                    u.fail_streak.store(0, Ordering::Relaxed);
+                    u.error_window.tripped_until_ms.store(0, Ordering::Relaxed);
+                    u.cooldown_until_ms.store(0, Ordering::Relaxed);
+                    tracing::info!(upstream = %u.id, "health probe ok; cooldown cleared");
+                }
+            });
+        }
+        while probes.join_next().await.is_some() {}
+    }
+
     async fn fetch_models_for_upstream(
         &self,
         upstream: Arc<Upstream>,
@@ -1156,14 +3602,20 @@ impl RouterState {
             .or_else(|| keys.first().cloned())
             .ok_or_else(|| anyhow::anyhow!("no keys loaded"))?;
 
-        let uri = upstream.build_uri(&PathAndQuery::from_static("/v1/models"))?;
+        let uri = upstream.build_model_uri(&PathAndQuery::from_static("/v1/models"), "")?;
         let mut req = Request::builder()
             .method(Method::GET)
             .uri(uri)
             .body(Body::empty())?;
-        req.headers_mut().insert(HDR_AUTHORIZATION, key.auth_header.clone());
+        upstream.set_auth(req.headers_mut(), &key);
+        if let Some(host) = &upstream.host_header {
+            req.headers_mut().insert(HOST, host.clone());
+        }
 
-        let resp = match tokio::time::timeout(self.request_timeout, self.client.request(req)).await {
+        let resp = match tokio::time::timeout(
+            upstream.timeout.unwrap_or_else(|| self.request_timeout()),
+            self.client.request(req),
+        ).await {
             Ok(Ok(resp)) => resp,
             Ok(Err(e)) => return Err(e.into()),
             Err(_) => anyhow::bail!("upstream request timeout"),
@@ -1174,22 +3626,46 @@ impl RouterState {
         }
 
         let body = hyper::body::to_bytes(resp.into_body()).await?;
-        parse_models_response(&body)
+        Ok(upstream.model_filter.apply(parse_models_response(&body)?))
+    }
+}
+
+fn upstream_tier(u: &Upstream, group_tiers: &AHashMap<String, u32>) -> u32 {
+    if let Some(t) = u.tier {
+        return t;
     }
+    u.group
+        .as_deref()
+        .and_then(|g| group_tiers.get(g))
+        .copied()
+        .unwrap_or(0)
 }
 
+/// Build the routing snapshot. Upstreams and keys that already exist in `previous` (same id,
+/// same key) keep their cooldowns and fail streaks; at startup (no `previous`) they are
+/// restored from the store's saved cooldowns instead.
 fn build_snapshot_from_configs(
     configs: &[UpstreamConfig],
     store: &KeyStore,
+    group_tiers: &AHashMap<String, u32>,
+    previous: Option<&RouterSnapshot>,
 ) -> anyhow::Result<RouterSnapshot> {
-    const MAX_WEIGHT: usize = 100;
+    const MAX_WEIGHT: usize = 1_000_000;
     if configs.is_empty() {
         anyhow::bail!("no upstreams configured");
     }
 
     let mut upstreams: Vec<Arc<Upstream>> = Vec::new();
     let mut upstream_index: AHashMap<String, usize> = AHashMap::new();
-    let mut schedule: Vec<usize> = Vec::new();
+
+    let saved: AHashMap<String, CooldownRecord> = match previous {
+        Some(_) => AHashMap::new(),
+        None => store.load_cooldowns().map(AHashMap::from_iter).unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "saved cooldowns could not be read; starting without them");
+            AHashMap::new()
+        }),
+    };
+    let now = now_ms();
 
     for u_cfg in configs.iter().cloned() {
         if upstream_index.contains_key(&u_cfg.id) {
@@ -1201,19 +3677,32 @@ fn build_snapshot_from_configs(
         upstream_index.insert(u.id.to_string(), idx);
 
         let keys = store.load_all_keys(&u.id)?;
-        let key_states = build_key_states(keys)?;
-        u.keys.store(key_states);
-
-        for _ in 0..weight {
-            schedule.push(idx);
+        let key_states = build_key_states(keys, &u.auth_scheme)?;
+        let old = previous.and_then(|p| p.upstream_index.get(&*u.id).map(|&i| &p.upstreams[i]));
+        if let Some(old) = old {
+            u.inherit_breaker(old);
+            inherit_key_breakers(&key_states, &old.keys.load());
+        } else if !saved.is_empty() {
+            if let Some(r) = saved.get(&*u.id) {
+                u.restore_breaker(r, now);
+            }
+            for k in key_states.iter() {
+                if let Some(r) = saved.get(&cooldown_record_id(&u.id, Some(&k.key))) {
+                    k.restore_breaker(r, now);
+                }
+            }
         }
+        u.keys.store(key_states);
         upstreams.push(u);
     }
 
+    let mut tiers: Vec<u32> = upstreams.iter().map(|u| upstream_tier(u, group_tiers)).collect();
+    tiers.sort_unstable();
+    tiers.dedup();
     Ok(RouterSnapshot {
         upstreams,
         upstream_index,
-        schedule,
+        tiers,
     })
 }
 
@@ -1227,7 +3716,7 @@ fn apply_routes_to_upstreams(
         if let Some(idx) = upstream_index.get(id) {
             for model in models {
                 let m = model.trim();
-                if !m.is_empty() {
+                if !m.is_empty() && upstreams[*idx].model_filter.permits(m) {
                     per_upstream[*idx].insert(m.to_string());
                 }
             }
@@ -1313,16 +3802,188 @@ fn update_bucket(
     }
 }
 
-fn start_request_log_writer(path: PathBuf) -> Option<mpsc::Sender<RequestLogEntry>> {
-    let (tx, mut rx) = mpsc::channel::<RequestLogEntry>(2048);
+fn start_health_probe(state: std::sync::Weak<RouterState>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(interval);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            state.probe_cooled_upstreams().await;
+        }
+    });
+}
+
+/// `model_refresh_interval_s`: the first sweep runs one interval after startup (startup itself
+/// fetches missing models).
+fn start_model_refresh(state: std::sync::Weak<RouterState>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            state.refresh_models_periodic(interval).await;
+        }
+    });
+}
+
+/// Save cooldowns and fail streaks every few seconds when they changed, so a restart during an
+/// outage doesn't put banned keys and upstreams straight back into rotation.
+fn start_cooldown_persist(state: std::sync::Weak<RouterState>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(5));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut last: Vec<(String, CooldownRecord)> = Vec::new();
+        loop {
+            tick.tick().await;
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let records = state.cooldown_records(now_ms());
+            if records == last {
+                continue;
+            }
+            let store = state.store.clone();
+            let saved = records.clone();
+            match tokio::task::spawn_blocking(move || store.save_cooldowns(&saved)).await {
+                Ok(Ok(())) => last = records,
+                Ok(Err(e)) => tracing::warn!(error = %e, "saving cooldowns failed"),
+                Err(e) => tracing::warn!(error = %e, "saving cooldowns failed"),
+            }
+        }
+    });
+}
+
+/// Log when the billing persist thread stops heartbeating (and when it recovers), so lost
+/// billing durability doesn't go unnoticed.
+fn start_billing_watchdog(state: std::sync::Weak<RouterState>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(5));
+        let mut was_healthy = true;
+        loop {
+            tick.tick().await;
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let health = state.billing.persister_health();
+            if !health.healthy {
+                tracing::error!(
+                    last_heartbeat_ms = health.last_heartbeat_ms,
+                    "billing persist thread is not responding; balance changes are not being saved"
+                );
+            } else if !was_healthy {
+                tracing::info!("billing persist thread recovered");
+            }
+            was_healthy = health.healthy;
+        }
+    });
+}
+
+/// `request_log_rotation` with defaults applied.
+struct LogRotation {
+    /// 0: no size limit.
+    max_bytes: u64,
+    daily: bool,
+    gzip: bool,
+    /// 0: keep every rotated file.
+    keep: usize,
+}
+
+impl LogRotation {
+    fn from_config(cfg: &RequestLogRotationConfig) -> Self {
+        Self {
+            max_bytes: cfg.max_bytes.unwrap_or(0),
+            daily: cfg.daily.unwrap_or(false),
+            gzip: cfg.gzip.unwrap_or(false),
+            keep: cfg.keep.unwrap_or(0),
+        }
+    }
+}
+
+const DAY_MS: u64 = 86_400_000;
+
+async fn open_request_log(path: &Path) -> std::io::Result<(tokio::fs::File, u64)> {
+    let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    let size = file.metadata().await?.len();
+    Ok((file, size))
+}
+
+/// Rename the full log to `<path>.<unix ms>`. Compressing it and pruning old files is left
+/// to the returned blocking task, so the writer can carry on meanwhile.
+async fn rotate_request_log(
+    path: &Path,
+    rotation: &LogRotation,
+) -> std::io::Result<tokio::task::JoinHandle<()>> {
+    let rotated = PathBuf::from(format!("{}.{}", path.display(), now_ms()));
+    tokio::fs::rename(path, &rotated).await?;
+    tracing::info!(path = %rotated.display(), "request log rotated");
+
+    let (path, gzip, keep) = (path.to_path_buf(), rotation.gzip, rotation.keep);
+    Ok(tokio::task::spawn_blocking(move || {
+        if gzip {
+            if let Err(e) = gzip_file(&rotated) {
+                tracing::warn!(path = %rotated.display(), error = %e, "request log gzip failed");
+            }
+        }
+        if keep > 0 {
+            prune_rotated_logs(&path, keep);
+        }
+    }))
+}
+
+/// Replace `path` with `path.gz`.
+fn gzip_file(path: &Path) -> std::io::Result<()> {
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let mut src = std::fs::File::open(path)?;
+    let dst = std::fs::File::create(&gz_path)?;
+    let mut enc = flate2::write::GzEncoder::new(dst, flate2::Compression::default());
+    std::io::copy(&mut src, &mut enc)?;
+    enc.finish()?.sync_all()?;
+    std::fs::remove_file(path)
+}
+
+/// Delete all but the newest `keep` rotated files of the log at `path`.
+fn prune_rotated_logs(path: &Path, keep: usize) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let prefix = format!("{name}.");
+    let mut rotated: Vec<(u64, PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let file_name = e.file_name();
+            let rest = file_name.to_str()?.strip_prefix(&prefix)?;
+            let ts = rest.strip_suffix(".gz").unwrap_or(rest).parse::<u64>().ok()?;
+            Some((ts, e.path()))
+        })
+        .collect();
+    rotated.sort_unstable_by_key(|r| std::cmp::Reverse(r.0));
+    for (_, old) in rotated.into_iter().skip(keep) {
+        match std::fs::remove_file(&old) {
+            Ok(()) => tracing::info!(path = %old.display(), "old request log deleted"),
+            Err(e) => {
+                tracing::warn!(path = %old.display(), error = %e, "request log delete failed")
+            }
+        }
+    }
+}
+
+fn start_request_log_writer(
+    path: PathBuf,
+    rotation: Option<LogRotation>,
+) -> Option<mpsc::Sender<LogWrite>> {
+    let (tx, mut rx) = mpsc::channel::<LogWrite>(2048);
 
     tokio::spawn(async move {
-        let file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&path)
-            .await;
-        let mut file = match file {
+        let (mut file, mut size) = match open_request_log(&path).await {
             Ok(f) => f,
             Err(e) => {
                 tracing::warn!(path = %path.display(), error = %e, "request log open failed");
@@ -1332,15 +3993,28 @@ fn start_request_log_writer(path: PathBuf) -> Option<mpsc::Sender<RequestLogEntr
 
         let mut pending = 0usize;
         let mut tick = tokio::time::interval(Duration::from_secs(1));
+        let mut day = now_ms() / DAY_MS;
+        // Compression/pruning of the previous rotation; awaited before the next one starts.
+        let mut post_rotate: Option<tokio::task::JoinHandle<()>> = None;
 
         loop {
             tokio::select! {
-                entry = rx.recv() => {
-                    let Some(entry) = entry else { break; };
+                msg = rx.recv() => {
+                    let entry = match msg {
+                        Some(LogWrite::Entry(entry)) => entry,
+                        Some(LogWrite::Flush(done)) => {
+                            let _ = file.flush().await;
+                            pending = 0;
+                            let _ = done.send(());
+                            continue;
+                        }
+                        None => break,
+                    };
                     if let Ok(line) = serde_json::to_string(&entry) {
                         if file.write_all(line.as_bytes()).await.is_ok() {
                             let _ = file.write_all(b"\n").await;
                             pending += 1;
+                            size += line.len() as u64 + 1;
                         }
                     }
                     if pending >= 256 {
@@ -1355,9 +4029,44 @@ fn start_request_log_writer(path: PathBuf) -> Option<mpsc::Sender<RequestLogEntr
                     }
                 }
             }
+
+            let Some(rotation) = &rotation else {
+                continue;
+            };
+            let today = now_ms() / DAY_MS;
+            let full = rotation.max_bytes > 0 && size >= rotation.max_bytes;
+            let new_day = rotation.daily && today != day && size > 0;
+            day = today;
+            if !(full || new_day) {
+                continue;
+            }
+            let _ = file.flush().await;
+            pending = 0;
+            if let Some(prev) = post_rotate.take() {
+                let _ = prev.await;
+            }
+            match rotate_request_log(&path, rotation).await {
+                Ok(post) => post_rotate = Some(post),
+                Err(e) => {
+                    // Keep appending to the current file; retried after max_bytes more.
+                    tracing::warn!(path = %path.display(), error = %e, "request log rotate failed");
+                    size = 0;
+                    continue;
+                }
+            }
+            match open_request_log(&path).await {
+                Ok((f, len)) => (file, size) = (f, len),
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "request log open failed");
+                    return;
+                }
+            }
         }
 
         let _ = file.flush().await;
+        if let Some(post) = post_rotate {
+            let _ = post.await;
+        }
     });
 
     Some(tx)
@@ -1378,3 +4087,273 @@ fn escape_json(s: &str) -> String {
     }
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const MODEL: &str = "gpt-4o-mini";
+
+    /// A `RouterState` over upstreams `u0`, `u1`, ... with the given weights, two keys each and
+    /// every upstream serving `MODEL`; `extra` is appended to the config. Call from within a
+    /// tokio runtime.
+    fn router(name: &str, weights: &[usize], extra: &str) -> (Arc<RouterState>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("gptload-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut toml = format!(
+            r#"
+listen_addr = "127.0.0.1:0"
+request_timeout_ms = 30000
+admin_tokens = ["t"]
+data_dir = "{}"
+
+[ban]
+rate_limit_ms = 30000
+server_error_ms = 5000
+network_error_ms = 5000
+auth_error_ms = 300000
+max_backoff_pow = 7
+"#,
+            dir.display()
+        );
+        toml.push_str(extra);
+        for (i, w) in weights.iter().enumerate() {
+            toml.push_str(&format!("\n[[upstreams]]\nid = \"u{i}\"\nbase_url = \"http://127.0.0.1:9\"\nweight = {w}\n"));
+        }
+        let config_path = dir.join("config.toml");
+        fs::write(&config_path, toml).unwrap();
+        let state = RouterState::new(Config::load(config_path.to_str().unwrap()).unwrap()).unwrap();
+
+        let models: Arc<AHashSet<String>> = Arc::new([MODEL.to_string()].into_iter().collect());
+        for u in state.snapshot.load().upstreams.iter() {
+            let keys = [format!("sk-{}-a", u.id), format!("sk-{}-b", u.id)];
+            state.store.add_keys(&u.id, &keys, None).unwrap();
+            u.keys.store(build_key_states(state.store.load_all_keys(&u.id).unwrap(), &u.auth_scheme).unwrap());
+            u.models.store(models.clone());
+        }
+        (state, dir)
+    }
+
+    /// Selections per upstream id over `n` calls.
+    fn tally(n: usize, mut select: impl FnMut() -> Option<Selected>) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for _ in 0..n {
+            let s = select().expect("an upstream is available");
+            *counts.entry(s.upstream.id.to_string()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    fn assert_share(counts: &BTreeMap<String, usize>, id: &str, n: usize, expected: f64) {
+        let share = counts.get(id).copied().unwrap_or(0) as f64 / n as f64;
+        assert!((share - expected).abs() < 0.02, "{id}: share {share:.3}, expected {expected:.3} ({counts:?})");
+    }
+
+    #[tokio::test]
+    async fn select_follows_weights() {
+        let (state, dir) = router("weights", &[3, 1], "");
+        let n = 4_000;
+        let counts = tally(n, || state.select(None, now_ms()));
+        assert_share(&counts, "u0", n, 0.75);
+        assert_share(&counts, "u1", n, 0.25);
+        let counts = tally(n, || state.select_for_model(MODEL, None, now_ms()));
+        assert_share(&counts, "u0", n, 0.75);
+        assert_share(&counts, "u1", n, 0.25);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn select_skips_cooling_keys_without_skewing_weights() {
+        let (state, dir) = router("half-cooled", &[3, 1], "");
+        for u in state.snapshot.load().upstreams.iter() {
+            u.keys.load()[0].cooldown_until_ms.store(u64::MAX, Ordering::Relaxed);
+        }
+        let n = 4_000;
+        let counts = tally(n, || state.select_for_model(MODEL, None, now_ms()));
+        assert_share(&counts, "u0", n, 0.75);
+        assert_share(&counts, "u1", n, 0.25);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn select_never_picks_a_fully_cooled_upstream() {
+        let (state, dir) = router("cooled", &[1, 3, 1], "");
+        let snap = state.snapshot.load_full();
+        // u1 by its keys, u2 by its own breaker.
+        cool_keys(&snap.upstreams[1], u64::MAX);
+        snap.upstreams[2].cooldown_until_ms.store(u64::MAX, Ordering::Relaxed);
+        let counts = tally(1_000, || state.select(None, now_ms()));
+        assert_eq!(counts.len(), 1, "{counts:?}");
+        let counts = tally(1_000, || state.select_for_model(MODEL, None, now_ms()));
+        assert_eq!(counts.len(), 1, "{counts:?}");
+        assert_eq!(counts.get("u0"), Some(&1_000));
+
+        snap.upstreams[0].cooldown_until_ms.store(u64::MAX, Ordering::Relaxed);
+        assert!(state.select(None, now_ms()).is_none());
+        assert!(state.select_for_model(MODEL, None, now_ms()).is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    fn upstream(base_url: &str) -> Arc<Upstream> {
+        let cfg: UpstreamConfig = toml::from_str(&format!("id = \"u\"\nbase_url = \"{base_url}\"")).unwrap();
+        parse_upstream(cfg, 1).unwrap()
+    }
+
+    fn join(base_url: &str, target: &str) -> String {
+        let uri = upstream(base_url).build_uri(&target.parse().unwrap()).unwrap();
+        assert_eq!(uri.scheme_str(), Some("http"));
+        assert_eq!(uri.authority().map(|a| a.as_str()), Some("up:8080"));
+        uri.path_and_query().unwrap().as_str().to_string()
+    }
+
+    fn cool_keys(u: &Upstream, until: u64) {
+        for k in u.keys.load().iter() {
+            k.cooldown_until_ms.store(until, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_after_points_at_the_nearest_selectable_upstream() {
+        let (state, dir) = router("retry-after", &[1, 1, 1], "");
+        let snap = state.snapshot.load_full();
+        let now = now_ms();
+        // Nothing cooling: a retry can succeed right away, reported as the 1 s minimum.
+        assert_eq!(state.soonest_available_ms(MODEL, None, now), Some(now));
+        assert_eq!(state.retry_after_secs(MODEL, None, now), Some(1));
+
+        cool_keys(&snap.upstreams[0], now + 2_500);
+        cool_keys(&snap.upstreams[1], now + 10_000);
+        cool_keys(&snap.upstreams[2], now + 20_000);
+        assert_eq!(state.retry_after_secs(MODEL, None, now), Some(3));
+        // The upstream's own breaker wins when it ends later than its keys' cooldowns.
+        snap.upstreams[0].cooldown_until_ms.store(now + 15_000, Ordering::Relaxed);
+        assert_eq!(state.retry_after_secs(MODEL, None, now), Some(10));
+
+        let only_u2 = ["u2".to_string()];
+        assert_eq!(state.retry_after_secs(MODEL, Some(&only_u2), now), Some(20));
+        assert_eq!(state.retry_after_secs("other-model", None, now), None);
+
+        // Disabled upstreams and ones without keys never become available.
+        snap.upstreams[1].enabled.store(false, Ordering::Relaxed);
+        assert_eq!(state.retry_after_secs(MODEL, None, now), Some(15));
+        snap.upstreams[0].keys.store(Arc::new(Vec::new()));
+        assert_eq!(state.retry_after_secs(MODEL, None, now), Some(20));
+        snap.upstreams[2].enabled.store(false, Ordering::Relaxed);
+        assert_eq!(state.soonest_available_ms(MODEL, None, now), None);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn error_rate_counts_what_status_actions_resolve_to_an_upstream_ban() {
+        let extra = r#"
+[ban.error_rate]
+min_samples = 4
+threshold = 0.5
+
+[status_actions]
+429 = "ban_upstream"
+503 = "pass"
+"#;
+        let (state, dir) = router("error-rate", &[1], extra);
+        let sel = state.select(None, now_ms()).unwrap();
+        let u = &sel.upstream;
+        let headers = hyper::HeaderMap::new();
+        let tripped = || u.error_window.tripped_until_ms.load(Ordering::Relaxed) > now_ms();
+
+        // A 5xx passed through is not an upstream failure, however many there are.
+        for _ in 0..8 {
+            state.on_upstream_status(&sel, http::StatusCode::SERVICE_UNAVAILABLE, &headers, now_ms());
+        }
+        assert!(!tripped());
+        // A 429 mapped to ban_upstream is: 4 of 12 stays under the threshold, 8 of 16 trips.
+        for _ in 0..4 {
+            state.on_upstream_status(&sel, http::StatusCode::TOO_MANY_REQUESTS, &headers, now_ms());
+        }
+        assert!(!tripped());
+        for _ in 0..4 {
+            state.on_upstream_status(&sel, http::StatusCode::TOO_MANY_REQUESTS, &headers, now_ms());
+        }
+        assert!(tripped());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn build_uri_without_base_path_forwards_the_target() {
+        for base in ["http://up:8080", "http://up:8080/"] {
+            assert_eq!(join(base, "/v1/chat/completions"), "/v1/chat/completions");
+            assert_eq!(join(base, "/"), "/");
+        }
+    }
+
+    #[test]
+    fn build_uri_prefixes_the_base_path_once() {
+        for base in ["http://up:8080/prefix", "http://up:8080/prefix/", "http://up:8080/prefix//"] {
+            assert_eq!(join(base, "/v1/chat/completions"), "/prefix/v1/chat/completions");
+            assert_eq!(join(base, "/"), "/prefix/");
+        }
+        assert_eq!(join("http://up:8080/a/b", "/v1/models"), "/a/b/v1/models");
+    }
+
+    #[test]
+    fn build_uri_prefixes_v1_verbatim() {
+        // The base path is never merged with the request path: a base of `/v1` repeats it.
+        assert_eq!(join("http://up:8080/v1", "/v1/chat/completions"), "/v1/v1/chat/completions");
+        assert_eq!(join("http://up:8080/v1/", "/v1/models"), "/v1/v1/models");
+        assert_eq!(join("http://up:8080/v1", "/chat/completions"), "/v1/chat/completions");
+        assert_eq!(join("http://up:8080/openai/v1", "/v1/chat/completions"), "/openai/v1/v1/chat/completions");
+    }
+
+    #[test]
+    fn build_uri_keeps_the_query_verbatim() {
+        for (base, prefix) in [("http://up:8080", ""), ("http://up:8080/prefix", "/prefix"), ("http://up:8080/v1", "/v1")] {
+            assert_eq!(
+                join(base, "/v1/chat/completions?q=a%2Fb%20c&x=%E4%BD%A0"),
+                format!("{prefix}/v1/chat/completions?q=a%2Fb%20c&x=%E4%BD%A0")
+            );
+            assert_eq!(join(base, "/v1/chat/completions?"), format!("{prefix}/v1/chat/completions?"));
+            assert_eq!(join(base, "/v1/models?a=1&a=2"), format!("{prefix}/v1/models?a=1&a=2"));
+        }
+    }
+
+    /// Every `len`-long sequence of items from `pool`.
+    fn sequences<'a>(pool: &[&'a str], len: usize) -> Vec<Vec<&'a str>> {
+        (0..len).fold(vec![Vec::new()], |acc, _| {
+            acc.iter()
+                .flat_map(|prefix| pool.iter().map(move |s| [prefix.as_slice(), &[*s]].concat()))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn build_uri_joins_any_base_and_target() {
+        let pool = ["v1", "openai", "v1beta", "a%20b", "x.y", "~u"];
+        let queries = [None, Some(""), Some("a=1"), Some("q=a%2Fb%20c&x=%E4%BD%A0&a=1&a=2")];
+        for base_segments in (0..=2).flat_map(|n| sequences(&pool, n)) {
+            let base_path = base_segments.iter().map(|s| format!("/{s}")).collect::<String>();
+            for trailing in ["", "/"] {
+                let u = upstream(&format!("http://up:8080{base_path}{trailing}"));
+                for target_segments in (1..=2).flat_map(|n| sequences(&pool, n)) {
+                    let path = target_segments.join("/");
+                    for query in queries {
+                        let query = query.map(|q| format!("?{q}")).unwrap_or_default();
+                        // Without a base path the target is forwarded as-is, so it needs its slash.
+                        let leading: &[&str] = if base_path.is_empty() { &["/"] } else { &["/", ""] };
+                        for lead in leading {
+                            let target = format!("{lead}{path}{query}");
+                            let uri = u.build_uri(&target.parse().unwrap()).unwrap();
+                            let expected = format!("{base_path}/{path}{query}");
+                            assert_eq!(uri.path_and_query().unwrap().as_str(), expected, "{base_path}{trailing} + {target}");
+                            assert_eq!(uri.path(), format!("{base_path}/{path}"));
+                            assert_eq!(uri.query(), query.strip_prefix('?'));
+                            assert_eq!(uri.authority().unwrap().as_str(), "up:8080");
+                            // Well-formed: it survives a round trip through the parser unchanged.
+                            assert_eq!(uri.to_string().parse::<Uri>().unwrap(), uri);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
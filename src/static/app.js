@@ -5,6 +5,7 @@
   const authStatus = document.getElementById('authStatus');
 
   const statsPre = document.getElementById('stats');
+  const latencyInfo = document.getElementById('latencyInfo');
 
   const requestsWindowSelect = document.getElementById('requestsWindow');
   const refreshRequestsChartBtn = document.getElementById('refreshRequestsChart');
@@ -117,7 +118,7 @@
       const cdText = cdMs > Date.now() ? formatDuration(cdMs - Date.now()) : '-';
       const cdClass = cdMs > Date.now() ? 'bad' : 'muted';
       tr.innerHTML = `
-        <td class="mono">${escapeHtml(u.id)}</td>
+        <td class="mono">${escapeHtml(u.id)}${u.group ? ` <span class="muted small">[${escapeHtml(u.group)}]</span>` : ''}</td>
         <td class="mono small">${escapeHtml(u.base_url)}</td>
         <td>${u.weight}</td>
         <td class="${keysClass}">${healthy}/${banned}</td>
@@ -128,6 +129,7 @@
         <td class="mono small">${u.responses_5xx || 0}</td>
         <td class="mono small">${u.errors_network || 0}</td>
         <td class="mono small">${u.errors_timeout || 0}</td>
+        <td class="mono small">${u.conn_open || 0}/${u.conn_opened || 0}</td>
       `;
       upstreamsTableBody.appendChild(tr);
     }
@@ -258,13 +260,14 @@
       const statusClass = status >= 200 && status < 300 ? 'ok' : (status === 404 ? 'muted' : 'bad');
       const tokens = r.total_tokens != null
         ? `${r.prompt_tokens || 0}/${r.completion_tokens || 0}/${r.total_tokens}`
+          + (r.cost != null && r.cost !== r.total_tokens ? ` (计费 ${r.cost})` : '')
         : '-';
       const bytes = `${r.req_bytes || 0}/${r.resp_bytes || 0}`;
       tr.innerHTML = `
         <td class="small">${new Date(r.ts_ms).toLocaleTimeString()}</td>
         <td class="mono small">${escapeHtml(r.client_ip || '')}</td>
         <td class="mono small">${escapeHtml(r.model || '-')}</td>
-        <td class="${statusClass}">${status}</td>
+        <td class="${statusClass}">${status}${r.failure_reason ? ` <span class="muted small">${escapeHtml(r.failure_reason)}</span>` : ''}</td>
         <td class="mono small">${r.latency_ms || 0}</td>
         <td class="mono small">${tokens}</td>
         <td class="mono small">${bytes}</td>
@@ -668,6 +671,7 @@
         try {
           const json = JSON.parse(data);
           statsPre.textContent = JSON.stringify(json, null, 2);
+          renderLatency(json);
         } catch (e) {
           statsPre.textContent = data;
         }
@@ -680,6 +684,12 @@
     return buf;
   }
 
+  function renderLatency(s) {
+    if (!latencyInfo || !s) return;
+    const fmt = v => (v || 0).toFixed(1);
+    latencyInfo.textContent = `latency ms ｜ avg ${fmt(s.latency_avg_ms)} ｜ p50 ${fmt(s.latency_p50_ms)} ｜ p95 ${fmt(s.latency_p95_ms)} ｜ p99 ${fmt(s.latency_p99_ms)} ｜ max ${fmt(s.latency_max_ms)}`;
+  }
+
   async function startStatsStream() {
     stopStatsStream();
     const t = getToken();
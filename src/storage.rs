@@ -1,4 +1,6 @@
 
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
 use std::path::Path;
 
 pub struct KeyStore {
@@ -8,8 +10,142 @@ pub struct KeyStore {
 pub struct AddKeysResult {
     pub inserted: usize,
     pub existed: usize,
-    /// Keys that were newly inserted (not previously present).
-    pub inserted_keys: Vec<String>,
+    /// Keys that were newly inserted (not previously present), with their stored metadata.
+    pub inserted_keys: Vec<(String, KeyMeta)>,
+}
+
+/// Per-key metadata stored as the sled value. Keys written before metadata existed have an
+/// empty value, which decodes as `added_at_ms = 0` and no label.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct KeyMeta {
+    #[serde(default)]
+    pub added_at_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl KeyMeta {
+    fn new(label: Option<&str>) -> Self {
+        Self {
+            added_at_ms: crate::util::now_ms(),
+            label: label.map(str::to_string),
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        if bytes.is_empty() {
+            return Self::default();
+        }
+        serde_json::from_slice(bytes).unwrap_or_default()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+}
+
+/// One key in an `export_json` file.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum ExportedKey {
+    WithMeta {
+        key: String,
+        #[serde(flatten)]
+        meta: KeyMeta,
+    },
+    Bare(String),
+}
+
+/// Circuit-breaker state of an upstream or key, saved so cooldowns survive a restart
+/// (`cooldowns` tree; keyed by upstream id, or `upstream_id\0key` for a key).
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CooldownRecord {
+    #[serde(default)]
+    pub cooldown_until_ms: u64,
+    /// Manual ban end (upstreams only).
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub ban_until_ms: u64,
+    #[serde(default)]
+    pub fail_streak: u32,
+    #[serde(default)]
+    pub last_fail_ms: u64,
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+/// Create `data_dir` and apply `data_dir_mode` (unix only; ignored elsewhere).
+pub fn prepare_data_dir(data_dir: &Path, mode: Option<u32>) -> anyhow::Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(data_dir, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| anyhow::anyhow!("set data_dir mode {mode:o} on {}: {e}", data_dir.display()))?;
+    }
+    #[cfg(not(unix))]
+    if mode.is_some() {
+        tracing::warn!("data_dir_mode is only supported on unix; ignoring");
+    }
+    Ok(())
+}
+
+/// Check that files can be created in `data_dir` (creating the directory if needed). Fails on
+/// a read-only mount or without write permission.
+pub fn probe_data_dir(data_dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+    let probe = data_dir.join(format!(".gptload-write-probe-{}", std::process::id()));
+    File::create(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+/// Copy the directory tree at `from` to `to` (which must not exist yet).
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Take the exclusive `data_dir/gptload.lock` lock (flock-style) and record our pid in it.
+/// The lock lives as long as the returned file; the OS drops it if the process dies.
+pub fn lock_data_dir(data_dir: &Path) -> anyhow::Result<File> {
+    let path = data_dir.join("gptload.lock");
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| anyhow::anyhow!("open lockfile {}: {e}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = holder.trim();
+            anyhow::bail!(
+                "data_dir {} is in use by another gptload-rs instance{} (set data_dir_lock = false to share it)",
+                data_dir.display(),
+                if holder.is_empty() { String::new() } else { format!(" (pid {holder})") }
+            );
+        }
+        Err(TryLockError::Error(e)) => {
+            anyhow::bail!("lock {}: {e}", path.display());
+        }
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(file)
 }
 
 impl KeyStore {
@@ -20,6 +156,22 @@ impl KeyStore {
         Ok(Self { db })
     }
 
+    /// Open a private copy of `data_dir/keys_db`, for a read_only instance whose data_dir is not
+    /// writable (sled cannot open a database it may not write to). The copy lives in the temp
+    /// dir and changes to it are never written back.
+    pub fn open_copy(data_dir: &Path) -> anyhow::Result<Self> {
+        let src = data_dir.join("keys_db");
+        let copy = std::env::temp_dir().join(format!("gptload-keys_db-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&copy);
+        if src.is_dir() {
+            copy_dir(&src, &copy).map_err(|e| {
+                anyhow::anyhow!("copy {} to {}: {e}", src.display(), copy.display())
+            })?;
+        }
+        let db = sled::open(&copy)?;
+        Ok(Self { db })
+    }
+
     fn tree_name(upstream_id: &str) -> String {
         format!("u:{}", upstream_id)
     }
@@ -38,21 +190,23 @@ impl KeyStore {
         Ok(t.len())
     }
 
-    /// Add keys. Keys are unique by DB key; duplicates are counted as `existed`.
+    /// Add keys. Keys are unique by DB key; duplicates are counted as `existed` and keep their
+    /// existing metadata. New keys are stamped with the current time and `label`.
     ///
     /// Returns (inserted, existed, inserted_keys).
-    pub fn add_keys(&self, upstream_id: &str, keys: &[String]) -> anyhow::Result<AddKeysResult> {
+    pub fn add_keys(&self, upstream_id: &str, keys: &[String], label: Option<&str>) -> anyhow::Result<AddKeysResult> {
         let t = self.open_upstream_tree(upstream_id)?;
         let mut inserted = 0usize;
         let mut existed = 0usize;
         let mut inserted_keys = Vec::new();
 
+        let meta = KeyMeta::new(label);
+        let value = meta.encode();
         for k in keys {
             let kb = k.as_bytes();
-            let prev = t.insert(kb, &[] as &[u8])?;
-            if prev.is_none() {
+            if t.compare_and_swap(kb, None as Option<&[u8]>, Some(value.as_slice()))?.is_ok() {
                 inserted += 1;
-                inserted_keys.push(k.clone());
+                inserted_keys.push((k.clone(), meta.clone()));
             } else {
                 existed += 1;
             }
@@ -65,15 +219,45 @@ impl KeyStore {
         })
     }
 
-    /// Replace all keys for upstream with the provided list.
-    pub fn replace_keys(&self, upstream_id: &str, keys: &[String]) -> anyhow::Result<()> {
+    /// Replace all keys for upstream with the provided list. Keys that were already present keep
+    /// their metadata; new ones are stamped with the current time and `label`.
+    ///
+    /// Returns the stored keys with their metadata, in the order given.
+    pub fn replace_keys(
+        &self,
+        upstream_id: &str,
+        keys: &[String],
+        label: Option<&str>,
+    ) -> anyhow::Result<Vec<(String, KeyMeta)>> {
+        self.replace_keys_with_meta(upstream_id, keys.iter().map(|k| (k.clone(), None)), label)
+    }
+
+    /// `replace_keys` where a key may bring its own metadata, which then wins over both the
+    /// stored metadata and a fresh stamp.
+    fn replace_keys_with_meta(
+        &self,
+        upstream_id: &str,
+        keys: impl IntoIterator<Item = (String, Option<KeyMeta>)>,
+        label: Option<&str>,
+    ) -> anyhow::Result<Vec<(String, KeyMeta)>> {
         let t = self.open_upstream_tree(upstream_id)?;
+        let mut previous: ahash::AHashMap<Vec<u8>, KeyMeta> = ahash::AHashMap::with_capacity(t.len());
+        for item in t.iter() {
+            let (k, v) = item?;
+            previous.insert(k.to_vec(), KeyMeta::decode(&v));
+        }
         t.clear()?;
-        for k in keys {
-            t.insert(k.as_bytes(), &[] as &[u8])?;
+        let fresh = KeyMeta::new(label);
+        let mut out = Vec::new();
+        for (k, meta) in keys {
+            let meta = meta
+                .or_else(|| previous.get(k.as_bytes()).cloned())
+                .unwrap_or_else(|| fresh.clone());
+            t.insert(k.as_bytes(), meta.encode())?;
+            out.push((k, meta));
         }
         t.flush()?;
-        Ok(())
+        Ok(out)
     }
 
     pub fn delete_keys(&self, upstream_id: &str, keys: &[String]) -> anyhow::Result<usize> {
@@ -88,29 +272,30 @@ impl KeyStore {
         Ok(removed)
     }
 
-    pub fn load_all_keys(&self, upstream_id: &str) -> anyhow::Result<Vec<String>> {
+    pub fn load_all_keys(&self, upstream_id: &str) -> anyhow::Result<Vec<(String, KeyMeta)>> {
         let t = self.open_upstream_tree(upstream_id)?;
         let mut out = Vec::with_capacity(t.len());
         for item in t.iter() {
-            let (k, _v) = item?;
+            let (k, v) = item?;
             let s = std::str::from_utf8(&k)
                 .map_err(|_| anyhow::anyhow!("invalid utf-8 key in db for upstream {}", upstream_id))?;
-            out.push(s.to_string());
+            out.push((s.to_string(), KeyMeta::decode(&v)));
         }
         Ok(out)
     }
 
-    /// Export DB to a JSON file (best-effort). Useful for backup.
+    /// Export DB to a JSON file (best-effort). Useful for backup. Each key is written as
+    /// `{"key", "added_at_ms", "label"}` so `import_json` restores its metadata.
     pub fn export_json(&self, path: &Path) -> anyhow::Result<()> {
         use serde::Serialize;
         use std::collections::BTreeMap;
 
         #[derive(Serialize)]
         struct Export {
-            upstreams: BTreeMap<String, Vec<String>>,
+            upstreams: BTreeMap<String, Vec<ExportedKey>>,
         }
 
-        let mut upstreams: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut upstreams: BTreeMap<String, Vec<ExportedKey>> = BTreeMap::new();
 
         for name in self.db.tree_names() {
             let name = String::from_utf8_lossy(&name).to_string();
@@ -121,8 +306,11 @@ impl KeyStore {
             let t = self.db.open_tree(&name)?;
             let mut keys = Vec::with_capacity(t.len());
             for item in t.iter() {
-                let (k, _v) = item?;
-                keys.push(String::from_utf8_lossy(&k).to_string());
+                let (k, v) = item?;
+                keys.push(ExportedKey::WithMeta {
+                    key: String::from_utf8_lossy(&k).to_string(),
+                    meta: KeyMeta::decode(&v),
+                });
             }
             upstreams.insert(upstream_id, keys);
         }
@@ -134,24 +322,56 @@ impl KeyStore {
     }
 
     /// Import keys from a JSON file. This replaces keys for upstreams included in the file.
+    /// Keys may be bare strings (older exports), which keep any stored metadata.
     pub fn import_json(&self, path: &Path) -> anyhow::Result<()> {
         use serde::Deserialize;
         use std::collections::BTreeMap;
 
         #[derive(Deserialize)]
         struct Export {
-            upstreams: BTreeMap<String, Vec<String>>,
+            upstreams: BTreeMap<String, Vec<ExportedKey>>,
         }
 
         let s = std::fs::read_to_string(path)?;
         let export: Export = serde_json::from_str(&s)?;
 
         for (upstream_id, keys) in export.upstreams {
-            self.replace_keys(&upstream_id, &keys)?;
+            let keys = keys.into_iter().map(|k| match k {
+                ExportedKey::Bare(key) => (key, None),
+                ExportedKey::WithMeta { key, meta } => (key, Some(meta)),
+            });
+            self.replace_keys_with_meta(&upstream_id, keys, None)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the saved cooldown state with `records`.
+    pub fn save_cooldowns(&self, records: &[(String, CooldownRecord)]) -> anyhow::Result<()> {
+        let t = self.db.open_tree("cooldowns")?;
+        let mut batch = sled::Batch::default();
+        for k in t.iter().keys() {
+            batch.remove(k?);
+        }
+        for (k, record) in records {
+            batch.insert(k.as_bytes(), serde_json::to_vec(record)?);
         }
+        t.apply_batch(batch)?;
         Ok(())
     }
 
+    /// Saved cooldown state; undecodable entries are skipped.
+    pub fn load_cooldowns(&self) -> anyhow::Result<Vec<(String, CooldownRecord)>> {
+        let t = self.db.open_tree("cooldowns")?;
+        let mut out = Vec::with_capacity(t.len());
+        for item in t.iter() {
+            let (k, v) = item?;
+            if let (Ok(k), Ok(record)) = (std::str::from_utf8(&k), serde_json::from_slice(&v)) {
+                out.push((k.to_string(), record));
+            }
+        }
+        Ok(out)
+    }
+
     pub fn flush(&self) -> anyhow::Result<()> {
         self.db.flush()?;
         Ok(())
@@ -0,0 +1,44 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Build a TLS acceptor from PEM files (certificate chain + PKCS#8/RSA/EC private key).
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|e| anyhow::anyhow!("tls: read {cert_path}: {e}"))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        anyhow::bail!("tls: no certificates found in {cert_path}");
+    }
+
+    let mut key = None;
+    let mut reader = BufReader::new(File::open(key_path)?);
+    for item in rustls_pemfile::read_all(&mut reader)
+        .map_err(|e| anyhow::anyhow!("tls: read {key_path}: {e}"))?
+    {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::ECKey(k) => {
+                key = Some(PrivateKey(k));
+                break;
+            }
+            _ => {}
+        }
+    }
+    let Some(key) = key else {
+        anyhow::bail!("tls: no private key found in {key_path}");
+    };
+
+    let mut cfg = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    // The server speaks HTTP/1.1 only (same as the plain listener).
+    cfg.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(cfg)))
+}
@@ -1,4 +1,8 @@
 
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[inline]
@@ -9,6 +13,16 @@ pub fn now_ms() -> u64 {
         .as_millis() as u64
 }
 
+/// Show only enough of a key to tell keys apart: `sk-...abcd`. Short keys keep less.
+pub fn mask_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    let (head, tail) = if chars.len() >= 12 { (3, 4) } else { (0, chars.len() / 4) };
+    let mut out: String = chars[..head].iter().collect();
+    out.push_str("...");
+    out.extend(&chars[chars.len() - tail..]);
+    out
+}
+
 /// Very small query parser for `?a=b&c=d`.
 /// Returns value for `key` if present. No percent-decoding (tokens are expected to be simple).
 #[inline]
@@ -23,3 +37,105 @@ pub fn query_get<'a>(uri: &'a http::Uri, key: &'a str) -> Option<&'a str> {
     }
     None
 }
+
+/// Decode `%XX` escapes and `+` in a query value (e.g. a model name like `org%2Fmodel`).
+/// Invalid escapes are kept as-is.
+pub fn percent_decode(v: &str) -> String {
+    let b = v.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 3 <= b.len() => {
+                let hex = std::str::from_utf8(&b[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Percent-encode `v` for use as one URI path segment: everything but RFC 3986 unreserved
+/// characters is escaped, so `/`, `?`, `#` and spaces cannot leave the segment.
+pub fn percent_encode_segment(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    for &b in v.as_bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// Fast per-thread pseudo-random number (xorshift64*). Not cryptographic; for load spreading.
+#[inline]
+pub fn fast_rand() -> u64 {
+    thread_local! {
+        static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        rng.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+/// An IP network in CIDR form (`10.0.0.0/8`, `::1/128`); a bare address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid CIDR {s}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| anyhow::anyhow!("invalid CIDR {s}: prefix must be 0..={max}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    /// IPv4-mapped IPv6 addresses (dual-stack listeners) match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,118 @@
+#!/usr/bin/env python3
+"""
+Check that proxied requests are billed by their usage, end to end.
+
+Registers a mock upstream twice on a running proxy: one upstream answers normally, the other
+always fails with 500. Each case sends a chat request for a model whose response carries
+known usage and checks the billing key's balance after:
+- plain and gzip JSON, plain and gzip SSE streams: charged prompt + completion tokens
+- a 500 without usage: not charged
+
+The test models have no `model_prices` entry, so they cost one balance unit per token.
+Pricing, reservations and saturation are covered by the unit tests in src/billing.rs.
+"""
+
+import gzip
+import json
+import sys
+import time
+import requests
+
+from mock_upstream import PROXY_URL, MockUpstream, admin, mock_upstreams
+
+OK_UPSTREAM = "billing-ok"
+FAIL_UPSTREAM = "billing-fail"
+API_KEY = "sk-billing-test"
+BALANCE = 1000000
+
+# Usage reported for every successful response: costs PROMPT + COMPLETION units.
+PROMPT, COMPLETION = 11, 7
+USAGE = {"prompt_tokens": PROMPT, "completion_tokens": COMPLETION, "total_tokens": PROMPT + COMPLETION}
+
+OK_MODELS = ["billing-json", "billing-json-gzip", "billing-sse", "billing-sse-gzip"]
+FAIL_MODEL = "billing-5xx"
+
+
+def sse_body() -> bytes:
+    chunk = {"id": "c1", "object": "chat.completion.chunk", "choices": [{"index": 0, "delta": {"content": "hi"}}]}
+    last = {"id": "c1", "object": "chat.completion.chunk", "choices": [], "usage": USAGE}
+    return f"data: {json.dumps(chunk)}\n\ndata: {json.dumps(last)}\n\ndata: [DONE]\n\n".encode()
+
+
+class Upstream(MockUpstream):
+    def models(self) -> list:
+        # The two upstreams use different keys; tell them apart by that.
+        return [FAIL_MODEL] if FAIL_UPSTREAM in self.headers.get("Authorization", "") else OK_MODELS
+
+    def do_POST(self):
+        model = json.loads(self.read_body() or b"{}").get("model", "")
+        if model == FAIL_MODEL:
+            self.send_json(500, {"error": {"message": "boom", "type": "server_error"}})
+            return
+        if model.startswith("billing-sse"):
+            content_type, data = "text/event-stream", sse_body()
+        else:
+            content_type = "application/json"
+            data = json.dumps({
+                "id": "c1",
+                "object": "chat.completion",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}}],
+                "usage": USAGE,
+            }).encode()
+        if model.endswith("-gzip"):
+            self.send(200, gzip.compress(data), content_type, {"Content-Encoding": "gzip"})
+        else:
+            self.send(200, data, content_type)
+
+
+def balance(key: str) -> int:
+    return admin("GET", f"/billing/keys/{key}").json()["balance"]
+
+
+def chat(key: str, model: str, stream: bool) -> requests.Response:
+    resp = requests.post(
+        f"{PROXY_URL}/v1/chat/completions",
+        json={"model": model, "stream": stream, "messages": [{"role": "user", "content": "hi"}]},
+        headers={"Authorization": f"Bearer {key}"},
+        timeout=30
+    )
+    resp.content  # read the whole body; billing settles once it has been relayed
+    return resp
+
+
+def settled_balance(key: str, expected: int) -> int:
+    # The charge lands just after the last byte is relayed; give it a moment.
+    for _ in range(20):
+        current = balance(key)
+        if current == expected:
+            break
+        time.sleep(0.05)
+    return current
+
+
+def main() -> int:
+    # (model, stream, expected charge)
+    cases = [
+        ("billing-json", False, PROMPT + COMPLETION),
+        ("billing-json-gzip", False, PROMPT + COMPLETION),
+        ("billing-sse", True, PROMPT + COMPLETION),
+        ("billing-sse-gzip", True, PROMPT + COMPLETION),
+        (FAIL_MODEL, False, 0),
+    ]
+
+    with mock_upstreams(Upstream, {OK_UPSTREAM: {}, FAIL_UPSTREAM: {}}, {API_KEY: BALANCE}):
+        ok = True
+        expected = BALANCE
+        for model, stream, charge in cases:
+            resp = chat(API_KEY, model, stream)
+            expected -= charge
+            got = settled_balance(API_KEY, expected)
+            passed = got == expected
+            mark = "✅" if passed else "❌"
+            print(f"{mark} {model}: HTTP {resp.status_code}, balance {got} (expected {expected})")
+            ok &= passed
+        return 0 if ok else 1
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,80 @@
+#!/usr/bin/env python3
+"""
+Check that a chunked client request reaches the upstream with a correct Content-Length.
+
+Registers a mock upstream on a running proxy. A chat request is then sent with
+`Transfer-Encoding: chunked` (no Content-Length) and the mock checks what it received:
+- `Content-Length` equals the length of the body it read
+- no `Transfer-Encoding` header
+- the body is the JSON the client sent
+"""
+
+import json
+import sys
+import requests
+
+from mock_upstream import PROXY_URL, MockUpstream, mock_upstreams
+
+UPSTREAM = "chunked-request-test"
+MODEL = "chunked-request-model"
+API_KEY = "sk-chunked-request-test"
+
+# What the mock saw on the chat request.
+received = {}
+
+
+class Upstream(MockUpstream):
+    protocol_version = "HTTP/1.1"
+    MODELS = [MODEL]
+
+    def do_POST(self):
+        length = self.headers.get("Content-Length")
+        # Without a usable length the proxy's request would hang here; read what's promised.
+        body = self.rfile.read(int(length)) if length else b""
+        received["content_length"] = length
+        received["transfer_encoding"] = self.headers.get("Transfer-Encoding")
+        received["body"] = body
+        self.send_json(200, {
+            "id": "c1",
+            "object": "chat.completion",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}}],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2},
+        })
+
+
+def chunks(data: bytes, size: int = 16):
+    # A generator body makes requests send Transfer-Encoding: chunked.
+    for i in range(0, len(data), size):
+        yield data[i:i + size]
+
+
+def main() -> int:
+    with mock_upstreams(Upstream, {UPSTREAM: {}}, {API_KEY: 1000000}):
+        payload = json.dumps({"model": MODEL, "messages": [{"role": "user", "content": "hello " * 20}]})
+        resp = requests.post(
+            f"{PROXY_URL}/v1/chat/completions",
+            data=chunks(payload.encode()),
+            headers={"Authorization": f"Bearer {API_KEY}", "Content-Type": "application/json"},
+            timeout=30
+        )
+        body = received.get("body", b"")
+        length = received.get("content_length")
+        print(f"📤 HTTP {resp.status_code}, upstream Content-Length={length}, "
+              f"Transfer-Encoding={received.get('transfer_encoding')}, body={len(body)} bytes")
+
+        ok = resp.status_code == 200
+        if length is not None and int(length) == len(body) and body and json.loads(body)["model"] == MODEL:
+            print("✅ upstream got a Content-Length matching the body")
+        else:
+            print("❌ Content-Length missing or wrong")
+            ok = False
+        if received.get("transfer_encoding") is None:
+            print("✅ no Transfer-Encoding forwarded")
+        else:
+            print("❌ Transfer-Encoding forwarded")
+            ok = False
+        return 0 if ok else 1
+
+
+if __name__ == "__main__":
+    sys.exit(main())
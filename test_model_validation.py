@@ -0,0 +1,50 @@
+#!/usr/bin/env python3
+"""
+Check that oversized `model` values are rejected before routing (400 invalid_model)
+"""
+
+import os
+import sys
+import requests
+
+# Configuration
+PROXY_URL = "http://localhost:8080"
+API_KEY = os.environ.get("API_KEY", "sk-test")
+MAX_MODEL_LEN = 256  # must match max_model_len in config
+
+HEADERS = {
+    "Content-Type": "application/json",
+    "Authorization": f"Bearer {API_KEY}"
+}
+
+def post_model(model: str) -> requests.Response:
+    return requests.post(
+        f"{PROXY_URL}/v1/chat/completions",
+        json={"model": model, "messages": [{"role": "user", "content": "hi"}]},
+        headers=HEADERS,
+        timeout=30
+    )
+
+def main() -> int:
+    failures = 0
+
+    for size in (MAX_MODEL_LEN + 1, 1024 * 1024):
+        resp = post_model("m" * size)
+        try:
+            code = resp.json().get("error", {}).get("code")
+        except ValueError:
+            code = None
+        ok = resp.status_code == 400 and code == "invalid_model"
+        print(f"{'✅' if ok else '❌'} model len={size}: HTTP {resp.status_code} code={code}")
+        failures += 0 if ok else 1
+
+    # At the limit the request must get past validation (any error other than invalid_model).
+    resp = post_model("m" * MAX_MODEL_LEN)
+    ok = "invalid_model" not in resp.text
+    print(f"{'✅' if ok else '❌'} model len={MAX_MODEL_LEN}: HTTP {resp.status_code}")
+    failures += 0 if ok else 1
+
+    return 1 if failures else 0
+
+if __name__ == "__main__":
+    sys.exit(main())
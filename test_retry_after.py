@@ -0,0 +1,61 @@
+#!/usr/bin/env python3
+"""
+Check that a 503 caused by every key cooling down carries a Retry-After header
+matching the nearest cooldown.
+
+Registers a mock upstream that always answers 429 on a running proxy, trips its only key,
+then expects 503 + Retry-After <= ban.rate_limit_ms. Which upstreams Retry-After considers
+and how it rounds are covered by the unit tests in src/state.rs.
+"""
+
+import sys
+import requests
+
+from mock_upstream import PROXY_URL, MockUpstream, mock_upstreams
+
+RATE_LIMIT_MS = 30000  # must match [ban].rate_limit_ms in config
+
+UPSTREAM_ID = "retry-after-mock"
+MODEL = "retry-after-mock-model"
+API_KEY = "sk-retry-after-test"
+
+
+class Upstream(MockUpstream):
+    MODELS = [MODEL]
+
+    def do_POST(self):
+        self.read_body()
+        self.send_json(429, {"error": {"message": "rate limited", "code": "rate_limit"}})
+
+
+def chat() -> requests.Response:
+    return requests.post(
+        f"{PROXY_URL}/v1/chat/completions",
+        json={"model": MODEL, "messages": [{"role": "user", "content": "hi"}]},
+        headers={"Authorization": f"Bearer {API_KEY}"},
+        timeout=30
+    )
+
+
+def main() -> int:
+    with mock_upstreams(Upstream, {UPSTREAM_ID: {}}, {API_KEY: 1000000}):
+        # First request trips the key (429 -> key cooldown).
+        first = chat()
+        print(f"📤 first request: HTTP {first.status_code}")
+
+        resp = chat()
+        retry_after = resp.headers.get("Retry-After")
+        print(f"📤 second request: HTTP {resp.status_code}, Retry-After={retry_after}")
+
+        ok = (
+            resp.status_code == 503
+            and retry_after is not None
+            and retry_after.isdigit()
+            and 1 <= int(retry_after) <= RATE_LIMIT_MS // 1000
+        )
+        print("✅ Retry-After reflects the nearest cooldown" if ok else "❌ unexpected response")
+        return 0 if ok else 1
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,92 @@
+#!/usr/bin/env python3
+"""
+Check that stream usage injection follows the upstream format.
+
+Registers one mock upstream twice on a running proxy: once as an OpenAI-format upstream and
+once as an Anthropic-format one, each serving its own model. A streaming chat request is
+sent to each and the body the mock received is checked:
+- openai: `stream_options.include_usage` is injected
+- anthropic: the Messages body carries no `stream_options` (usage is always reported)
+
+Both upstream ids must be listed in `usage_inject_upstreams` in the proxy config. How the body
+is rewritten for each format is covered by the unit tests in src/proxy.rs.
+"""
+
+import json
+import sys
+import requests
+
+from mock_upstream import PROXY_URL, MockUpstream, mock_upstreams
+
+# Both must be in usage_inject_upstreams.
+OPENAI_UPSTREAM = "usage-inject-openai"
+ANTHROPIC_UPSTREAM = "usage-inject-anthropic"
+OPENAI_MODEL = "usage-inject-openai-model"
+ANTHROPIC_MODEL = "usage-inject-anthropic-model"
+API_KEY = "sk-usage-inject-test"
+
+# Request bodies received by the mock, keyed by path.
+received = {}
+
+OPENAI_SSE = (
+    'data: {"id":"c1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"hi"}}]}\n\n'
+    'data: {"id":"c1","object":"chat.completion.chunk","choices":[],'
+    '"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}\n\n'
+    "data: [DONE]\n\n"
+)
+
+ANTHROPIC_SSE = (
+    'event: message_start\ndata: {"type":"message_start","message":{"id":"m1","model":"x",'
+    '"usage":{"input_tokens":1,"output_tokens":0}}}\n\n'
+    'event: content_block_delta\ndata: {"type":"content_block_delta","index":0,'
+    '"delta":{"type":"text_delta","text":"hi"}}\n\n'
+    'event: message_delta\ndata: {"type":"message_delta","delta":{"stop_reason":"end_turn"},'
+    '"usage":{"output_tokens":1}}\n\n'
+    'event: message_stop\ndata: {"type":"message_stop"}\n\n'
+)
+
+
+class Upstream(MockUpstream):
+    def models(self) -> list:
+        # The Anthropic upstream authenticates with x-api-key; tell them apart by that.
+        return [ANTHROPIC_MODEL] if self.headers.get("x-api-key") else [OPENAI_MODEL]
+
+    def do_POST(self):
+        received[self.path] = json.loads(self.read_body() or b"{}")
+        sse = ANTHROPIC_SSE if self.path == "/v1/messages" else OPENAI_SSE
+        self.send(200, sse.encode(), "text/event-stream")
+
+
+def stream_chat(model: str) -> requests.Response:
+    return requests.post(
+        f"{PROXY_URL}/v1/chat/completions",
+        json={"model": model, "stream": True, "messages": [{"role": "user", "content": "hi"}]},
+        headers={"Authorization": f"Bearer {API_KEY}"},
+        timeout=30
+    )
+
+
+def main() -> int:
+    upstreams = {OPENAI_UPSTREAM: {}, ANTHROPIC_UPSTREAM: {"format": "anthropic"}}
+    with mock_upstreams(Upstream, upstreams, {API_KEY: 1000000}):
+        ok = True
+
+        resp = stream_chat(OPENAI_MODEL)
+        sent = received.get("/v1/chat/completions", {})
+        injected = sent.get("stream_options", {}).get("include_usage") is True
+        print(f"📤 openai: HTTP {resp.status_code}, stream_options={sent.get('stream_options')}")
+        print("✅ openai upstream got include_usage" if injected else "❌ include_usage missing")
+        ok &= resp.status_code == 200 and injected
+
+        resp = stream_chat(ANTHROPIC_MODEL)
+        sent = received.get("/v1/messages")
+        clean = sent is not None and "stream_options" not in sent
+        print(f"📤 anthropic: HTTP {resp.status_code}, keys={sorted(sent or {})}")
+        print("✅ anthropic upstream got no stream_options" if clean else "❌ unexpected body")
+        ok &= resp.status_code == 200 and clean
+
+        return 0 if ok else 1
+
+
+if __name__ == "__main__":
+    sys.exit(main())